@@ -1,19 +1,32 @@
-use std::convert::TryFrom;
+#[cfg(feature = "hooks")]
+use std::cell::RefCell;
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+};
 
-use dbg::{McbOp, TraceEvent};
+use dbg::{McbOp, TraceEvent, WatchKind};
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    cartridge::compute_header_checksum,
+    cheats::Cheat,
     dbg,
-    io::{InterruptSource, IrqController, Joypad, Serial, Timer, APU, PPU},
+    io::{
+        Infrared, InterruptSource, IrqController, Joypad, Rtc, RtcState, Serial, Timer, APU, PPU,
+    },
     mem::{MemR, MemRW, MemW, Memory},
+    Model,
 };
 
 // Specifies which Memory Bank Controller (if any) is used in the cartridge.
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum MbcType {
     None,
     Mbc1,
     Mbc3,
+    Mbc5,
 }
 
 // The error type returned when parsing the MBC type code fails.
@@ -25,14 +38,36 @@ impl TryFrom<u8> for MbcType {
 
     fn try_from(n: u8) -> Result<Self, Self::Error> {
         match n {
-            0x00 => Ok(MbcType::None),
+            0x00 | 0x08 | 0x09 => Ok(MbcType::None),
             0x01..=0x03 => Ok(MbcType::Mbc1),
             0x0f..=0x13 => Ok(MbcType::Mbc3),
+            0x19..=0x1E => Ok(MbcType::Mbc5),
             _ => Err(McbTypeError(n)),
         }
     }
 }
 
+/// A hook called on every bus read, returning the value to be presented to the reader.
+#[cfg(feature = "hooks")]
+pub type ReadHook = Box<dyn FnMut(u16, u8) -> u8 + Send>;
+
+/// A hook called on every bus write, returning the value to be written, or `None` to block it.
+#[cfg(feature = "hooks")]
+pub type WriteHook = Box<dyn FnMut(u16, u8) -> Option<u8> + Send>;
+
+// Returns whether the given cartridge type code includes a battery-backed RAM.
+fn has_battery(n: u8) -> bool {
+    matches!(
+        n,
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+    )
+}
+
+// Returns whether the given cartridge type code includes a rumble motor.
+fn has_rumble(n: u8) -> bool {
+    matches!(n, 0x1C..=0x1E)
+}
+
 // Specifies the ROM size of the cartridge in 16KB banks.
 #[derive(Debug)]
 pub struct RomBanks(usize);
@@ -87,16 +122,79 @@ impl TryFrom<u8> for RamBanks {
     }
 }
 
+/// Reads the mapper and the ROM and RAM sizes from the header of `rom`,
+/// failing if the image is too small to hold a header or if any of them is not supported.
+fn parse_header(rom: &[u8]) -> Result<(MbcType, RomBanks, RamBanks), TraceEvent> {
+    if rom.len() < 0x150 {
+        return Err(TraceEvent::RomTooSmall(rom.len()));
+    }
+
+    let mbc = MbcType::try_from(rom[0x147])
+        .map_err(|McbTypeError(n)| TraceEvent::UnsupportedMbcType(n))?;
+    let rom_banks = RomBanks::try_from(rom[0x148])
+        .map_err(|RomSizeError(n)| TraceEvent::UnsupportedRomSize(n))?;
+    let ram_banks = RamBanks::try_from(rom[0x149])
+        .map_err(|RamSizeError(n)| TraceEvent::UnsupportedRamSize(n))?;
+
+    Ok((mbc, rom_banks, ram_banks))
+}
+
+/// Verifies the header checksum stored at 0x14D, which covers bytes 0x134-0x14C.
+fn has_valid_header(rom: &[u8]) -> bool {
+    matches!(compute_header_checksum(rom), Some(sum) if rom.get(0x14D) == Some(&sum))
+}
+
+/// Banking registers of the MBC1 mapper.
+#[derive(Default, Serialize, Deserialize)]
+struct Mbc1Regs {
+    /// Lower 5 bits of the ROM bank number (0x2000-0x3FFF)
+    bank_lo: u8,
+    /// Upper 2 bits of the ROM bank number, or the RAM bank number (0x4000-0x5FFF)
+    bank_hi: u8,
+    /// Whether the upper bits also apply to 0x0000-0x3FFF and cartridge RAM (0x6000-0x7FFF)
+    advanced_mode: bool,
+}
+
+/// Banking registers of the MBC5 mapper.
+#[derive(Serialize, Deserialize)]
+struct Mbc5Regs {
+    /// 9-bit ROM bank number (0x2000-0x2FFF for the lower 8 bits, 0x3000-0x3FFF for bit 8)
+    rom_bank: u16,
+    /// State of the rumble motor, driven by bit 3 of the RAM bank register on rumble carts
+    rumble_on: bool,
+}
+
+impl Default for Mbc5Regs {
+    fn default() -> Mbc5Regs {
+        Mbc5Regs {
+            rom_bank: 1,
+            rumble_on: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Bus {
+    // The ROM is not part of the machine state, only its checksum is,
+    // so that a state can't be restored on another cartridge
+    #[serde(skip)]
     rom_banks: Vec<Memory>,
+    rom_checksum: u16,
+    pub rom_00: usize,
     pub rom_nn: usize,
 
     ram_banks: Vec<Memory>,
     pub ram_nn: usize,
 
+    /// Boot ROM mapped over 0x0000-0x00FF at power on, if any
+    #[serde(skip)]
+    boot_rom: Vec<u8>,
+    boot_rom_mapped: bool,
+
     pub hram: Memory,
     pub wram_00: Memory,
-    pub wram_nn: Memory,
+    wram_banks: Vec<Memory>,
+    pub wram_nn: usize,
 
     pub apu: APU,
     pub ppu: PPU,
@@ -104,53 +202,145 @@ pub struct Bus {
     pub sdt: Serial,
     pub joy: Joypad,
     pub itr: IrqController,
+    pub irp: Infrared,
 
     mbc: MbcType,
+    mbc1: Mbc1Regs,
+    mbc5: Mbc5Regs,
+    rtc: Rtc,
+    /// RTC register mapped at 0xA000-0xBFFF in place of a RAM bank (MBC3 only)
+    rtc_reg: Option<u8>,
+    battery: bool,
+    rumble: bool,
+    model: Model,
+
+    // CGB speed switch (KEY1)
+    double_speed: bool,
+    speed_switch_armed: bool,
+    /// Whether the PPU and APU skip the current cycle, in double speed mode
+    slow_cycle: bool,
+
+    #[serde(skip)]
+    frozen: HashSet<u16>,
+    #[serde(skip)]
+    watchpoints: HashMap<u16, WatchKind>,
+    /// The last access which triggered a watchpoint, if any, until it's reported
+    #[serde(skip)]
+    watch_hit: Cell<Option<TraceEvent>>,
+    #[serde(skip)]
+    cheats: Vec<Cheat>,
+
+    #[cfg(feature = "coverage")]
+    #[serde(skip)]
+    pub(crate) coverage: dbg::Coverage,
+
+    #[cfg(feature = "io-log")]
+    #[serde(skip)]
+    pub(crate) io_log: dbg::IoLog,
+
+    #[cfg(feature = "hooks")]
+    #[serde(skip)]
+    read_hook: RefCell<Option<ReadHook>>,
+    #[cfg(feature = "hooks")]
+    #[serde(skip)]
+    write_hook: Option<WriteHook>,
 }
 
 impl Default for Bus {
     fn default() -> Bus {
+        Bus::with_model(Model::Dmg)
+    }
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    /// Creates a bus with the memory layout and peripherals of the given hardware model.
+    ///
+    /// On CGB, the WRAM is split in eight 4KB banks, seven of which are switchable.
+    pub fn with_model(model: Model) -> Bus {
+        let wram_banks = match model {
+            Model::Dmg => 1,
+            Model::Cgb => 7,
+        };
+
         Bus {
             rom_banks: vec![],
+            rom_checksum: 0,
+            rom_00: 0,
             rom_nn: 1,
 
             ram_banks: vec![],
             ram_nn: 0,
 
+            boot_rom: vec![],
+            boot_rom_mapped: false,
+
             hram: Memory::new(127),
             wram_00: Memory::new(0x1000),
-            wram_nn: Memory::new(0x1000),
+            wram_banks: (0..wram_banks).map(|_| Memory::new(0x1000)).collect(),
+            wram_nn: 1,
 
             apu: APU::default(),
-            ppu: PPU::new(),
+            ppu: PPU::with_model(model),
             tim: Timer::new(),
             sdt: Serial::new(),
             joy: Joypad::new(),
             itr: IrqController::new(),
+            irp: Infrared::new(),
 
             mbc: MbcType::None,
+            mbc1: Mbc1Regs::default(),
+            mbc5: Mbc5Regs::default(),
+            rtc: Rtc::new(),
+            rtc_reg: None,
+            battery: false,
+            rumble: false,
+            model,
+
+            double_speed: false,
+            speed_switch_armed: false,
+            slow_cycle: false,
+
+            frozen: HashSet::new(),
+            watchpoints: HashMap::new(),
+            watch_hit: Cell::new(None),
+            cheats: Vec::new(),
+
+            #[cfg(feature = "coverage")]
+            coverage: dbg::Coverage::new(),
+
+            #[cfg(feature = "io-log")]
+            io_log: dbg::IoLog::new(),
+
+            #[cfg(feature = "hooks")]
+            read_hook: RefCell::new(None),
+            #[cfg(feature = "hooks")]
+            write_hook: None,
         }
     }
-}
 
-impl Bus {
-    pub fn new() -> Bus {
-        Bus::default()
+    /// Returns the hardware model being emulated.
+    pub fn model(&self) -> Model {
+        self.model
     }
 
+    /// Loads `rom` into the cartridge slot.
+    ///
+    /// The header is validated before anything is changed, so if `rom` is too small
+    /// or declares an unsupported mapper, ROM or RAM size, the bus is left untouched.
     pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), TraceEvent> {
-        // Filter out ROMs using unsupported emulator features (eg. CGB-only mode)
-        if rom[0x143] == 0xC0 {
-            return Err(TraceEvent::CgbNotSupported);
-        }
+        // NOTE: CGB-only carts are accepted on a DMG too, as they detect the model
+        // on their own and show a "requires Game Boy Color" screen, like real hardware does.
+        let (mbc, rom_banks, ram_banks) = parse_header(rom)?;
 
-        // Check MBC type in the ROM header
-        self.mbc = MbcType::try_from(rom[0x147])
-            .map_err(|McbTypeError(n)| TraceEvent::UnsupportedMbcType(n))?;
+        self.mbc = mbc;
+        self.battery = has_battery(rom[0x147]);
+        self.rumble = has_rumble(rom[0x147]);
 
         // Allocate ROM and RAM banks depending on the ROM header
-        let rom_banks = RomBanks::try_from(rom[0x148]).unwrap();
-        let ram_banks = RamBanks::try_from(rom[0x149]).unwrap();
 
         for _ in 0..rom_banks.0 {
             self.rom_banks.push(Memory::new(0x4000));
@@ -159,29 +349,461 @@ impl Bus {
             self.ram_banks.push(Memory::new(0x2000));
         }
 
-        // Load ROM into its allocated banks
-        for (n, chunk) in rom.chunks(0x4000).enumerate() {
+        // Load ROM into its allocated banks, ignoring any data past the declared size
+        for (bank, chunk) in self.rom_banks.iter_mut().zip(rom.chunks(0x4000)) {
             for (i, b) in chunk.iter().enumerate() {
-                self.rom_banks[n].write(i as u16, *b)?;
+                bank.write(i as u16, *b)?;
             }
         }
+        self.rom_checksum = self.global_checksum();
 
         Ok(())
     }
 
+    /// Loads a ROM image which starts `offset` bytes into `dump`.
+    ///
+    /// The header found at `offset` must have a valid checksum, otherwise the image is rejected.
+    /// Anything following the ROM (ie. past the size declared in the header) is ignored.
+    pub fn load_rom_at_offset(&mut self, dump: &[u8], offset: usize) -> Result<(), TraceEvent> {
+        match dump.get(offset..) {
+            Some(rom) if has_valid_header(rom) => self.load_rom(rom),
+            _ => Err(TraceEvent::InvalidRomHeader(offset)),
+        }
+    }
+
+    /// Returns the contents of ROM bank `n`, regardless of the bank currently mapped in.
+    pub fn rom_bank(&self, n: usize) -> Option<&Memory> {
+        self.rom_banks.get(n)
+    }
+
+    /// Returns whether the header checksum stored at 0x14D matches the loaded ROM header.
+    pub fn header_checksum_valid(&self) -> bool {
+        matches!(self.rom_banks.first(), Some(bank) if has_valid_header(bank.as_slice()))
+    }
+
+    /// Returns the global checksum stored in the ROM header at 0x14E-0x14F.
+    pub fn stored_global_checksum(&self) -> u16 {
+        match self.rom_banks.first() {
+            Some(bank) => u16::from_be_bytes([bank.as_slice()[0x14E], bank.as_slice()[0x14F]]),
+            None => 0,
+        }
+    }
+
+    /// Computes the global checksum of the loaded ROM, ie. the sum of all its bytes
+    /// except for the two bytes of the stored checksum itself.
+    ///
+    /// NOTE: only the ROM size declared in the header is loaded, so any trailing data
+    /// in the original dump doesn't contribute to the checksum.
+    pub fn global_checksum(&self) -> u16 {
+        let sum = self
+            .rom_banks
+            .iter()
+            .flat_map(|bank| bank.as_slice().iter())
+            .fold(0u16, |sum, b| sum.wrapping_add(u16::from(*b)));
+
+        let stored = self.stored_global_checksum();
+        sum.wrapping_sub(stored >> 8).wrapping_sub(stored & 0xFF)
+    }
+
+    /// Replaces the cartridge with `rom`, resetting every peripheral and clearing all RAM.
+    ///
+    /// If `rom` is too small or declares an unsupported mapper, ROM or RAM size,
+    /// the current cartridge is left in place.
+    pub fn insert_rom(&mut self, rom: &[u8]) -> Result<(), TraceEvent> {
+        parse_header(rom)?;
+
+        self.rom_banks.clear();
+        self.ram_banks.clear();
+        self.reset(true);
+        self.rtc = Rtc::new();
+
+        self.load_rom(rom)
+    }
+
+    /// Resets every peripheral and clears all RAM, keeping the loaded ROM and mapper in place.
+    ///
+    /// Battery-backed cartridge RAM is preserved, unless `hard` is true.
+    pub fn reset(&mut self, hard: bool) {
+        let mut ram_banks = std::mem::take(&mut self.ram_banks);
+
+        if hard || !self.battery {
+            for bank in ram_banks.iter_mut() {
+                for b in bank.as_mut_slice() {
+                    *b = 0;
+                }
+            }
+        }
+
+        let model = self.model;
+        let mut prev = std::mem::replace(
+            self,
+            Bus {
+                ram_banks,
+                ..Bus::with_model(model)
+            },
+        );
+
+        // The cartridge stays in place, along with the host and debugging settings
+        self.rom_checksum = prev.rom_checksum;
+        self.mbc = std::mem::replace(&mut prev.mbc, MbcType::None);
+        self.rtc = std::mem::take(&mut prev.rtc);
+        self.battery = prev.battery;
+        self.rumble = prev.rumble;
+        self.take_host_state(&mut prev);
+
+        if !self.boot_rom.is_empty() {
+            self.power_on();
+        }
+    }
+
+    /// Maps `boot` over 0x0000-0x00FF, until a write to 0xFF50 unmaps it.
+    ///
+    /// The peripherals which the boot ROM initializes by itself are put back
+    /// to their power-on state, rather than to their post-boot one.
+    pub fn set_boot_rom(&mut self, boot: &[u8]) {
+        self.boot_rom = boot.to_vec();
+        self.power_on();
+    }
+
+    /// Returns whether the boot ROM is still mapped over the cartridge.
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    fn power_on(&mut self) {
+        self.boot_rom_mapped = true;
+        self.tim.sys_counter.0 = 0;
+        self.itr.ifg.0 = 0;
+        self.ppu.write(0xFF40, 0x00).ok();
+    }
+
+    /// Returns whether this state was saved with the same ROM loaded as `other`.
+    pub(crate) fn same_rom_as(&self, other: &Bus) -> bool {
+        self.rom_checksum == other.rom_checksum
+    }
+
+    /// Takes over the ROM, debugging and host settings of `prev`, which are not part of the state.
+    pub(crate) fn take_host_state(&mut self, prev: &mut Bus) {
+        self.rom_banks = std::mem::take(&mut prev.rom_banks);
+        self.boot_rom = std::mem::take(&mut prev.boot_rom);
+        self.take_debug_state(prev);
+    }
+
+    /// Like `take_host_state`, but leaves the ROM and boot ROM of `prev` alone,
+    /// eg. when the new instance has a cartridge of its own.
+    pub(crate) fn take_debug_state(&mut self, prev: &mut Bus) {
+        self.frozen = std::mem::take(&mut prev.frozen);
+        self.watchpoints = std::mem::take(&mut prev.watchpoints);
+        self.cheats = std::mem::take(&mut prev.cheats);
+
+        #[cfg(feature = "coverage")]
+        {
+            self.coverage = std::mem::take(&mut prev.coverage);
+        }
+        #[cfg(feature = "io-log")]
+        {
+            self.io_log = std::mem::take(&mut prev.io_log);
+        }
+        #[cfg(feature = "hooks")]
+        {
+            self.read_hook = std::mem::take(&mut prev.read_hook);
+            self.write_hook = prev.write_hook.take();
+        }
+
+        self.ppu.take_host_state(&mut prev.ppu);
+        self.apu.take_host_state(&mut prev.apu);
+        self.sdt.take_host_state(&mut prev.sdt);
+    }
+
+    /// Returns whether the CPU is running in CGB double speed mode.
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Performs the speed switch requested through KEY1, if any, when STOP is executed.
+    ///
+    /// Like any STOP, this also resets DIV.
+    pub(crate) fn switch_speed(&mut self) -> Result<(), TraceEvent> {
+        if self.speed_switch_armed {
+            self.double_speed = !self.double_speed;
+            self.speed_switch_armed = false;
+            self.tim.write(0xFF04, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the cartridge rumble motor is currently turned on.
+    ///
+    /// This is always false for carts without a rumble motor.
+    pub fn rumble_active(&self) -> bool {
+        self.rumble && self.mbc5.rumble_on
+    }
+
+    /// Returns the state of the cartridge real-time clock, if it has a battery-backed one.
+    ///
+    /// See [`Rtc`] for the register layout.
+    pub fn rtc_state(&self) -> Option<RtcState> {
+        match self.mbc {
+            MbcType::Mbc3 if self.battery => Some(self.rtc.state()),
+            _ => None,
+        }
+    }
+
+    /// Restores the cartridge real-time clock from a previous call to `rtc_state`,
+    /// advancing it by the time elapsed since then.
+    pub fn set_rtc_state(&mut self, state: RtcState) {
+        self.rtc.set_state(state);
+    }
+
+    /// Returns an iterator over all the addresses from which an instruction was fetched.
+    #[cfg(feature = "coverage")]
+    pub fn executed_addresses(&self) -> impl Iterator<Item = u16> + '_ {
+        self.coverage.executed_addresses()
+    }
+
+    /// Returns the execution coverage collected so far.
+    #[cfg(feature = "coverage")]
+    pub fn coverage(&self) -> &dbg::Coverage {
+        &self.coverage
+    }
+
+    /// Returns the log of IO register writes.
+    #[cfg(feature = "io-log")]
+    pub fn io_log(&self) -> &dbg::IoLog {
+        &self.io_log
+    }
+
+    /// Returns the log of IO register writes, eg. to enable or clear it.
+    #[cfg(feature = "io-log")]
+    pub fn io_log_mut(&mut self) -> &mut dbg::IoLog {
+        &mut self.io_log
+    }
+
+    /// Freezes the RAM location at `addr`, so that its value is kept constant.
+    ///
+    /// Writes to a frozen location are silently dropped, except for the ones made through `poke`.
+    /// Only work RAM (including its echo), high RAM and cartridge RAM can be frozen:
+    /// returns whether `addr` belongs to any of them. Frozen locations are kept across resets.
+    pub fn freeze(&mut self, addr: u16) -> bool {
+        match freezable_addr(addr) {
+            Some(addr) => {
+                self.frozen.insert(addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Unfreezes the RAM location at `addr`.
+    pub fn unfreeze(&mut self, addr: u16) {
+        if let Some(addr) = freezable_addr(addr) {
+            self.frozen.remove(&addr);
+        }
+    }
+
+    /// Returns whether the RAM location at `addr` is frozen.
+    pub fn is_frozen(&self, addr: u16) -> bool {
+        matches!(freezable_addr(addr), Some(addr) if self.frozen.contains(&addr))
+    }
+
+    /// Returns the set of frozen locations.
+    ///
+    /// Locations in echo RAM are reported at their work RAM address.
+    pub fn frozen(&self) -> &HashSet<u16> {
+        &self.frozen
+    }
+
+    /// Enables `cheat`, unless it is already.
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        if !self.cheats.contains(&cheat) {
+            self.cheats.push(cheat);
+        }
+    }
+
+    /// Disables `cheat`, returning whether it was enabled.
+    pub fn remove_cheat(&mut self, cheat: &Cheat) -> bool {
+        let len = self.cheats.len();
+        self.cheats.retain(|c| c != cheat);
+        self.cheats.len() != len
+    }
+
+    /// Returns the enabled cheats, in the order they were added.
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    /// Writes the values of the enabled GameShark codes to RAM, as done once per frame.
+    pub(crate) fn apply_ram_cheats(&mut self) {
+        let cheats = std::mem::take(&mut self.cheats);
+
+        for cheat in &cheats {
+            if let Cheat::RamWrite { addr, value } = *cheat {
+                // A write which fails (eg. to disabled cartridge RAM) is just dropped
+                self.write_mapped(addr, value).ok();
+            }
+        }
+        self.cheats = cheats;
+    }
+
+    /// Returns the byte read from ROM at `addr`, once patched by the enabled Game Genie codes.
+    fn patch_rom(&self, addr: u16, byte: u8) -> u8 {
+        for cheat in &self.cheats {
+            if let Cheat::RomPatch {
+                addr: at,
+                value,
+                compare,
+            } = *cheat
+            {
+                if at == addr && compare.unwrap_or(byte) == byte {
+                    return value;
+                }
+            }
+        }
+        byte
+    }
+
+    /// Sets a watchpoint on `addr`, replacing any previous one on the same address.
+    ///
+    /// An access of the given kind raises a `TraceEvent::Watchpoint` once the current
+    /// CPU cycle is over (see `take_watch_hit`). Watchpoints are kept across resets.
+    pub fn set_watchpoint(&mut self, addr: u16, kind: WatchKind) {
+        self.watchpoints.insert(addr, kind);
+    }
+
+    /// Clears the watchpoint on `addr`, if any.
+    pub fn clear_watchpoint(&mut self, addr: u16) {
+        self.watchpoints.remove(&addr);
+    }
+
+    /// Returns the watched addresses, along with the kind of accesses being watched.
+    pub fn watchpoints(&self) -> &HashMap<u16, WatchKind> {
+        &self.watchpoints
+    }
+
+    /// Returns the last access which triggered a watchpoint since the previous call, if any.
+    pub(crate) fn take_watch_hit(&self) -> Option<TraceEvent> {
+        self.watch_hit.take()
+    }
+
+    /// Records the access to `addr` if it triggers a watchpoint.
+    fn check_watchpoint(&self, addr: u16, is_write: bool, value: u8) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        if matches!(self.watchpoints.get(&addr), Some(kind) if kind.triggers_on(is_write)) {
+            self.watch_hit.set(Some(TraceEvent::Watchpoint {
+                addr,
+                is_write,
+                value,
+            }));
+        }
+    }
+
+    /// Writes `val` to `addr` on behalf of the debugger, even if the location is frozen.
+    pub fn poke(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
+        self.write_mapped(addr, val)
+    }
+
+    /// Installs a hook which is called on every bus read with the address and the value read.
+    ///
+    /// The value returned by the hook is presented to the reader in place of the original one,
+    /// which can be used to implement eg. Game Genie codes. The hook runs after the mapper
+    /// has resolved the access, so it sees the contents of the currently selected bank.
+    ///
+    /// NOTE: debugger accesses and OAM DMA go through the bus too, so they are hooked as well.
+    #[cfg(feature = "hooks")]
+    pub fn set_read_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(u16, u8) -> u8 + Send + 'static,
+    {
+        self.read_hook = RefCell::new(Some(Box::new(hook)));
+    }
+
+    /// Installs a hook which is called on every bus write with the address and the value written.
+    ///
+    /// The hook runs before the write reaches the mapper or any peripheral: returning `Some(val)`
+    /// writes `val` instead of the original value, while returning `None` blocks the write.
+    /// This also applies to writes to the mapper registers (eg. bank switching).
+    #[cfg(feature = "hooks")]
+    pub fn set_write_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(u16, u8) -> Option<u8> + Send + 'static,
+    {
+        self.write_hook = Some(Box::new(hook));
+    }
+
+    /// Removes any installed read and write hooks.
+    #[cfg(feature = "hooks")]
+    pub fn clear_hooks(&mut self) {
+        self.read_hook = RefCell::new(None);
+        self.write_hook = None;
+    }
+
+    /// Returns whether the cartridge RAM is battery-backed, ie. whether it should be persisted.
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// Returns a copy of the cartridge RAM contents, if it is battery-backed.
+    ///
+    /// Carts without a battery (or without RAM at all) return `None`,
+    /// since their RAM is lost when the console is powered off.
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        if !self.battery || self.ram_banks.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.ram_banks
+                .iter()
+                .flat_map(|bank| bank.as_slice().iter().copied())
+                .collect(),
+        )
+    }
+
+    /// Restores the cartridge RAM contents from a previous call to `save_ram`.
+    ///
+    /// If the size of `data` doesn't match the cartridge RAM size (eg. a save file from
+    /// another emulator), any exceeding data is ignored and any missing data is zero-filled.
+    pub fn load_ram(&mut self, data: &[u8]) {
+        let ram = self
+            .ram_banks
+            .iter_mut()
+            .flat_map(|bank| bank.as_mut_slice().iter_mut());
+
+        for (b, v) in ram.zip(data.iter().chain(std::iter::repeat(&0))) {
+            *b = *v;
+        }
+    }
+
     /// Advances the system peripheral/memory bus by a single M-cycle.
+    ///
+    /// In CGB double speed mode, the components clocked by the CPU (the timer, the serial port
+    /// and OAM DMA) keep advancing on every call, so they run twice as fast in real time.
+    /// The PPU and APU are instead advanced every other call, so that they keep running
+    /// at normal speed.
     pub fn tick(&mut self) -> Result<(), TraceEvent> {
         if let Some((src, dst)) = self.ppu.advance_dma_xfer() {
-            let b = self.read(src)?;
+            // The DMA controller owns the bus, so it's not subject to its own access restrictions
+            let b = self.read_mapped(src)?;
+            let b = self.read_through_hook(src, b);
             self.ppu.write_to_oam(dst, b)?;
         }
 
-        self.ppu.tick();
-        self.apu.tick();
+        if !self.slow_cycle {
+            self.ppu.tick(1);
+            self.apu.tick(self.div_apu_bit());
+        }
+        self.slow_cycle = self.double_speed && !self.slow_cycle;
+
         self.tim.tick();
+        self.sdt.tick();
 
-        // Fetch interrupt requests from interrupt sources
-        if let Some(irq) = self.ppu.get_and_clear_irq() {
+        // Fetch interrupt requests from interrupt sources.
+        // The PPU can raise both VBlank and STAT at the start of line 144, and both
+        // IF bits must be set on the same cycle, so drain all its pending requests.
+        while let Some(irq) = self.ppu.get_and_clear_irq() {
             self.itr.set_irq(irq.into());
         }
         if let Some(irq) = self.tim.get_and_clear_irq() {
@@ -193,8 +815,12 @@ impl Bus {
         if let Some(irq) = self.sdt.get_and_clear_irq() {
             self.itr.set_irq(irq.into());
         }
+        if let Some(irq) = self.joy.get_and_clear_irq() {
+            self.itr.set_irq(irq.into());
+        }
 
-        Ok(())
+        // Check this last, so that all peripherals are up-to-date when pausing
+        self.ppu.check_dot_breakpoint()
     }
 
     fn ram_enable(&mut self, _val: u8) -> Result<(), TraceEvent> {
@@ -202,20 +828,68 @@ impl Bus {
         Ok(())
     }
 
-    fn rom_select(&mut self, val: u8) -> Result<(), TraceEvent> {
+    fn rom_select(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
+        match self.mbc {
+            MbcType::Mbc1 => {
+                self.mbc1.bank_lo = val & 0x1F;
+                self.update_mbc1_banks();
+                return Ok(());
+            }
+            MbcType::Mbc5 => {
+                // Unlike other MBCs, bank 0 can be mapped at 0x4000-0x7FFF too
+                self.mbc5.rom_bank = match addr {
+                    0x2000..=0x2FFF => (self.mbc5.rom_bank & 0x100) | u16::from(val),
+                    _ => (self.mbc5.rom_bank & 0xFF) | (u16::from(val & 0x01) << 8),
+                };
+                self.rom_nn = usize::from(self.mbc5.rom_bank) % self.rom_banks.len().max(1);
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        // MBC3 only has 7 bits of ROM bank number
+        let val = match self.mbc {
+            MbcType::Mbc3 => val & 0x7F,
+            _ => val,
+        };
+
         self.rom_nn = if val == 0 {
             1
         } else {
             // TODO is this remainder here the correct way of handling bank number overflow?
             // Some ROMs (eg. blargg's dmg_sound-2) seem to rely on this behavior.
-            usize::from(val) % self.rom_banks.len()
+            usize::from(val) % self.rom_banks.len().max(1)
         };
         Ok(())
     }
 
     fn ram_rom_select(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
+        match self.mbc {
+            MbcType::Mbc1 => {
+                self.mbc1.bank_hi = val & 0x03;
+                self.update_mbc1_banks();
+                return Ok(());
+            }
+            MbcType::Mbc5 => {
+                // On rumble carts, bit 3 drives the motor instead of selecting a RAM bank
+                let bank = if self.rumble {
+                    self.mbc5.rumble_on = val & 0x08 != 0;
+                    val & 0x07
+                } else {
+                    val & 0x0F
+                };
+                self.ram_nn = usize::from(bank) % self.ram_banks.len().max(1);
+                return Ok(());
+            }
+            _ => (),
+        }
+
         match val {
-            0x00..=0x03 => self.ram_nn = val.into(),
+            0x00..=0x03 => {
+                self.ram_nn = val.into();
+                self.rtc_reg = None;
+            }
+            0x08..=0x0C if matches!(self.mbc, MbcType::Mbc3) => self.rtc_reg = Some(val),
             _ => return Err(TraceEvent::InvalidMbcOp(McbOp::Write(addr), val)),
         };
         Ok(())
@@ -223,17 +897,58 @@ impl Bus {
 
     fn mbc_write_op(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
         match self.mbc {
+            MbcType::Mbc1 => {
+                self.mbc1.advanced_mode = val & 0x01 != 0;
+                self.update_mbc1_banks();
+                Ok(())
+            }
             MbcType::Mbc3 => {
-                // TODO latch RTC register value
+                self.rtc.write_latch(val);
                 Ok(())
             }
+            // MBC5 has no registers in this range
+            MbcType::Mbc5 => Ok(()),
             _ => Err(TraceEvent::InvalidMbcOp(McbOp::Write(addr), val)),
         }
     }
 
-    fn write_to_cgb_functions(&mut self, addr: u16, _val: u8) -> Result<(), TraceEvent> {
+    /// Maps in the ROM and RAM banks selected by the MBC1 registers.
+    ///
+    /// Bank 0 can't be selected through the lower bits, which select bank 1 instead.
+    /// As a consequence, banks 0x20, 0x40 and 0x60 can only be mapped at 0x0000-0x3FFF,
+    /// which happens in advanced banking mode. Bank numbers wrap around the ROM size.
+    fn update_mbc1_banks(&mut self) {
+        let lo = usize::from(self.mbc1.bank_lo.max(1));
+        let hi = usize::from(self.mbc1.bank_hi);
+        let rom_banks = self.rom_banks.len().max(1);
+        let ram_banks = self.ram_banks.len().max(1);
+
+        self.rom_nn = ((hi << 5) | lo) % rom_banks;
+
+        if self.mbc1.advanced_mode {
+            self.rom_00 = (hi << 5) % rom_banks;
+            self.ram_nn = hi % ram_banks;
+        } else {
+            self.rom_00 = 0;
+            self.ram_nn = 0;
+        }
+    }
+
+    fn wram_select(&mut self, val: u8) -> Result<(), TraceEvent> {
+        // Bank 0 can't be mapped in the switchable area, 1 is selected instead
+        self.wram_nn = usize::from(val & 0x07).max(1);
+        Ok(())
+    }
+
+    fn write_to_cgb_functions(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
         match addr {
-            0xFF4D => Err(TraceEvent::CgbSpeedSwitchReq),
+            0xFF4D => {
+                // The switch itself happens on the next STOP, see `switch_speed`
+                if self.is_cgb() {
+                    self.speed_switch_armed = val & 0x01 != 0;
+                }
+                Err(TraceEvent::CgbSpeedSwitchReq)
+            }
             _ => Ok(()),
         }
     }
@@ -241,52 +956,228 @@ impl Bus {
 
 impl MemR for Bus {
     fn read(&self, addr: u16) -> Result<u8, TraceEvent> {
+        let val = if self.blocked_by_dma(addr) {
+            0xFF
+        } else {
+            self.read_mapped(addr)?
+        };
+
+        let val = self.read_through_hook(addr, val);
+        self.check_watchpoint(addr, false, val);
+
+        Ok(val)
+    }
+
+    /// Unlike `read`, this doesn't go through hooks and watchpoints.
+    fn peek(&self, addr: u16) -> Option<u8> {
+        if self.blocked_by_dma(addr) {
+            Some(0xFF)
+        } else {
+            self.read_mapped(addr).ok()
+        }
+    }
+}
+
+impl MemW for Bus {
+    fn write(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
+        #[cfg(feature = "hooks")]
+        let val = match self.write_hook.as_mut() {
+            Some(hook) => match hook(addr, val) {
+                Some(val) => val,
+                None => return Ok(()),
+            },
+            None => val,
+        };
+
+        self.check_watchpoint(addr, true, val);
+
+        if self.is_frozen(addr) || self.blocked_by_dma(addr) {
+            return Ok(());
+        }
+
+        #[cfg(feature = "io-log")]
+        self.io_log.record(addr, val);
+
+        self.write_mapped(addr, val)
+    }
+}
+
+impl Bus {
+    fn is_cgb(&self) -> bool {
+        self.model == Model::Cgb
+    }
+
+    /// Returns the level of the DIV bit clocking the APU frame sequencer at 512 Hz.
+    ///
+    /// This is DIV bit 4, or bit 5 in double speed mode, where DIV runs twice as fast.
+    fn div_apu_bit(&self) -> bool {
+        self.tim
+            .sys_counter
+            .bit(if self.double_speed { 13 } else { 12 })
+    }
+
+    /// Returns the WRAM bank currently mapped at 0xD000-0xDFFF.
+    fn wram_bank(&self) -> &Memory {
+        &self.wram_banks[(self.wram_nn - 1) % self.wram_banks.len()]
+    }
+
+    fn wram_bank_mut(&mut self) -> &mut Memory {
+        let n = (self.wram_nn - 1) % self.wram_banks.len();
+        &mut self.wram_banks[n]
+    }
+
+    /// Returns the memory bus through which `addr` is reached, if it's not internal to the CPU.
+    fn memory_bus(&self, addr: u16) -> Option<MemoryBus> {
+        match addr {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => Some(MemoryBus::External),
+            0x8000..=0x9FFF => Some(MemoryBus::Video),
+            0xC000..=0xFDFF if self.is_cgb() => Some(MemoryBus::Wram),
+            0xC000..=0xFDFF => Some(MemoryBus::External),
+            _ => None,
+        }
+    }
+
+    /// Returns whether an access to `addr` is cut off by an ongoing OAM DMA transfer.
+    ///
+    /// While the transfer is in progress, the DMA controller owns the bus it's reading from,
+    /// so the CPU can't reach anything on it: reads yield 0xFF and writes are ignored.
+    /// This also applies to debugger accesses going through the bus. OAM is handled by the PPU.
+    fn blocked_by_dma(&self, addr: u16) -> bool {
+        match (self.ppu.dma_source(), self.memory_bus(addr)) {
+            (Some(src), Some(bus)) => self.memory_bus(src) == Some(bus),
+            _ => false,
+        }
+    }
+
+    /// Passes the value read from `addr` through the read hook, if any is installed.
+    #[cfg_attr(not(feature = "hooks"), allow(unused_variables))]
+    fn read_through_hook(&self, addr: u16, val: u8) -> u8 {
+        #[cfg(feature = "hooks")]
+        if let Some(hook) = self.read_hook.borrow_mut().as_mut() {
+            return hook(addr, val);
+        }
+
+        val
+    }
+
+    /// Reads from the cartridge RAM bank or RTC register currently mapped at 0xA000-0xBFFF.
+    fn read_cart_ram(&self, addr: u16) -> Result<u8, TraceEvent> {
+        match self.rtc_reg {
+            Some(reg) => Ok(self.rtc.read(reg)),
+            None => self
+                .ram_banks
+                .get(self.ram_nn)
+                .map_or(Ok(0xFF), |bank| bank.read(addr - 0xA000)),
+        }
+    }
+
+    /// Writes to the cartridge RAM bank or RTC register currently mapped at 0xA000-0xBFFF.
+    fn write_cart_ram(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
+        match self.rtc_reg {
+            Some(reg) => {
+                self.rtc.write(reg, val);
+                Ok(())
+            }
+            None => self
+                .ram_banks
+                .get_mut(self.ram_nn)
+                .map_or(Ok(()), |bank| bank.write(addr - 0xA000, val)),
+        }
+    }
+
+    /// Returns the value read from the unusable area at 0xFEA0-0xFEFF.
+    ///
+    /// A DMG reads 0x00, unless OAM is blocked by a DMA transfer, in which case it reads 0xFF.
+    /// A CGB (revision E) returns the upper nibble of the address repeated twice.
+    ///
+    /// TODO: accesses during OAM scan should also trigger the DMG OAM corruption bug.
+    fn read_unusable(&self, addr: u16) -> u8 {
+        let hi = (addr & 0xF0) as u8;
+
+        match self.model {
+            Model::Cgb => hi | (hi >> 4),
+            Model::Dmg if self.ppu.dma_source().is_some() => 0xFF,
+            Model::Dmg => 0x00,
+        }
+    }
+
+    /// Reads from the memory-mapped device at `addr`.
+    fn read_mapped(&self, addr: u16) -> Result<u8, TraceEvent> {
         match addr {
-            0x0000..=0x3FFF => self.rom_banks[0].read(addr),
-            0x4000..=0x7FFF => self.rom_banks[self.rom_nn].read(addr - 0x4000),
+            0x0000..=0x00FF if self.boot_rom_mapped => Ok(self
+                .boot_rom
+                .get(usize::from(addr))
+                .copied()
+                .unwrap_or(0xFF)),
+            // Reading the ROM area with no cartridge loaded is a bus fault
+            0x0000..=0x3FFF => self
+                .rom_bank(self.rom_00)
+                .ok_or(TraceEvent::BusFault(addr))?
+                .read(addr)
+                .map(|b| self.patch_rom(addr, b)),
+            0x4000..=0x7FFF => self
+                .rom_bank(self.rom_nn)
+                .ok_or(TraceEvent::BusFault(addr))?
+                .read(addr - 0x4000)
+                .map(|b| self.patch_rom(addr, b)),
             0x8000..=0x9FFF => self.ppu.read(addr),
-            0xA000..=0xBFFF => self.ram_banks[self.ram_nn].read(addr - 0xA000),
+            0xA000..=0xBFFF => self.read_cart_ram(addr),
             0xC000..=0xCFFF => self.wram_00.read(addr - 0xC000),
-            0xD000..=0xDFFF => self.wram_nn.read(addr - 0xD000),
+            0xD000..=0xDFFF => self.wram_bank().read(addr - 0xD000),
             0xE000..=0xEFFF => self.wram_00.read(addr - 0xE000),
-            0xF000..=0xFDFF => self.wram_nn.read(addr - 0xF000),
+            0xF000..=0xFDFF => self.wram_bank().read(addr - 0xF000),
             0xFE00..=0xFE9F => self.ppu.read(addr),
+            0xFEA0..=0xFEFF => Ok(self.read_unusable(addr)),
             0xFF00..=0xFF00 => self.joy.read(addr),
             0xFF01..=0xFF02 => self.sdt.read(addr),
             0xFF04..=0xFF07 => self.tim.read(addr),
             0xFF10..=0xFF3F => self.apu.read(addr),
             0xFF40..=0xFF4B => self.ppu.read(addr),
+            0xFF4D if self.is_cgb() => {
+                Ok(0x7E | (u8::from(self.double_speed) << 7) | u8::from(self.speed_switch_armed))
+            }
+            0xFF4F if self.is_cgb() => self.ppu.read(addr),
+            0xFF56 if self.is_cgb() => self.irp.read(addr),
+            0xFF68..=0xFF6B if self.is_cgb() => self.ppu.read(addr),
+            0xFF70 if self.is_cgb() => Ok(0xF8 | self.wram_nn as u8),
             0xFF80..=0xFFFE => self.hram.read(addr - 0xFF80),
             0xFF0F | 0xFFFF => self.itr.read(addr),
             _ => Ok(0xFF),
         }
     }
-}
 
-impl MemW for Bus {
-    fn write(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
+    /// Writes to the memory-mapped device at `addr`.
+    fn write_mapped(&mut self, addr: u16, val: u8) -> Result<(), TraceEvent> {
         match addr {
             0x0000..=0x1FFF => self.ram_enable(val),
-            0x2000..=0x3FFF => self.rom_select(val),
+            0x2000..=0x3FFF => self.rom_select(addr, val),
             0x4000..=0x5FFF => self.ram_rom_select(addr, val),
             0x6000..=0x7FFF => self.mbc_write_op(addr, val),
             0x8000..=0x9FFF => self.ppu.write(addr, val),
-            0xA000..=0xBFFF => self
-                .ram_banks
-                .get_mut(self.ram_nn)
-                .map_or(Ok(()), |bank| bank.write(addr - 0xA000, val)),
+            0xA000..=0xBFFF => self.write_cart_ram(addr, val),
             0xC000..=0xCFFF => self.wram_00.write(addr - 0xC000, val),
-            0xD000..=0xDFFF => self.wram_nn.write(addr - 0xD000, val),
+            0xD000..=0xDFFF => self.wram_bank_mut().write(addr - 0xD000, val),
             0xE000..=0xEFFF => self.wram_00.write(addr - 0xE000, val),
-            0xF000..=0xFDFF => self.wram_nn.write(addr - 0xF000, val),
+            0xF000..=0xFDFF => self.wram_bank_mut().write(addr - 0xF000, val),
             0xFE00..=0xFE9F => self.ppu.write(addr, val),
+            0xFEA0..=0xFEFF => Ok(()), // Writes to the unusable area are ignored
             0xFF00..=0xFF00 => self.joy.write(addr, val),
             0xFF01..=0xFF02 => self.sdt.write(addr, val),
             0xFF04..=0xFF07 => self.tim.write(addr, val),
             0xFF10..=0xFF3F => self.apu.write(addr, val),
             0xFF40..=0xFF4B => self.ppu.write(addr, val),
+            0xFF4F if self.is_cgb() => self.ppu.write(addr, val),
             0xFF4C..=0xFF4F => self.write_to_cgb_functions(addr, val),
-            0xFF51..=0xFF7F => self.write_to_cgb_functions(addr, val),
+            0xFF50 => {
+                // Once unmapped, the boot ROM stays so until the next reset
+                self.boot_rom_mapped = false;
+                Ok(())
+            }
+            0xFF51..=0xFF55 => self.write_to_cgb_functions(addr, val),
+            0xFF56 if self.is_cgb() => self.irp.write(addr, val),
+            0xFF68..=0xFF6B if self.is_cgb() => self.ppu.write(addr, val),
+            0xFF70 if self.is_cgb() => self.wram_select(val),
+            0xFF56..=0xFF7F => self.write_to_cgb_functions(addr, val),
             0xFF80..=0xFFFE => self.hram.write(addr - 0xFF80, val),
             0xFF0F | 0xFFFF => self.itr.write(addr, val),
             _ => Ok(()),
@@ -295,3 +1186,567 @@ impl MemW for Bus {
 }
 
 impl MemRW for Bus {}
+
+/// Buses connecting the CPU and the OAM DMA controller to memory.
+///
+/// On a CGB, work RAM has a bus of its own, while on a DMG it shares the cartridge one.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum MemoryBus {
+    External,
+    Video,
+    Wram,
+}
+
+/// Returns the canonical address of a location that can be frozen, if `addr` is one.
+fn freezable_addr(addr: u16) -> Option<u16> {
+    match addr {
+        0xA000..=0xDFFF | 0xFF80..=0xFFFE => Some(addr),
+        0xE000..=0xFDFF => Some(addr - 0x2000),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_rom(cart_type: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = cart_type;
+        rom[0x149] = ram_size;
+        rom
+    }
+
+    #[test]
+    fn accesses_without_a_cartridge_raise_bus_faults() {
+        let mut bus = Bus::new();
+
+        assert!(matches!(
+            bus.read(0x0100),
+            Err(TraceEvent::BusFault(0x0100))
+        ));
+        assert!(matches!(
+            bus.read(0x4000),
+            Err(TraceEvent::BusFault(0x4000))
+        ));
+
+        // Bank switching must not trip over the missing ROM either
+        bus.write(0x2000, 0x05).unwrap();
+        assert!(matches!(
+            bus.read(0x7FFF),
+            Err(TraceEvent::BusFault(0x7FFF))
+        ));
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected_without_side_effects() {
+        let mut rom = make_rom(0x00, 0x00);
+        rom[0x200] = 0x5A;
+
+        let mut bus = Bus::new();
+        bus.load_rom(&rom).unwrap();
+
+        let mut bad_rom_size = make_rom(0x00, 0x00);
+        bad_rom_size[0x148] = 0x42;
+        let bad_ram_size = make_rom(0x00, 0x42);
+
+        assert!(matches!(
+            Bus::new().load_rom(&[]),
+            Err(TraceEvent::RomTooSmall(0))
+        ));
+        assert!(matches!(
+            bus.insert_rom(&rom[..0x100]),
+            Err(TraceEvent::RomTooSmall(0x100))
+        ));
+        assert!(matches!(
+            bus.insert_rom(&bad_rom_size),
+            Err(TraceEvent::UnsupportedRomSize(0x42))
+        ));
+        assert!(matches!(
+            bus.insert_rom(&bad_ram_size),
+            Err(TraceEvent::UnsupportedRamSize(0x42))
+        ));
+
+        // The previous cartridge is left in place
+        assert_eq!(bus.read(0x0200).unwrap(), 0x5A);
+    }
+
+    #[test]
+    fn ram_without_battery_is_not_saved() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x08, 0x02)).unwrap();
+
+        bus.write(0xA000, 0x42).unwrap();
+        bus.write(0xBFFF, 0x24).unwrap();
+
+        assert_eq!(bus.read(0xA000).unwrap(), 0x42);
+        assert_eq!(bus.read(0xBFFF).unwrap(), 0x24);
+        assert!(!bus.has_battery());
+        assert!(bus.save_ram().is_none());
+    }
+
+    #[test]
+    fn mismatched_save_data_is_truncated_or_padded() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x03, 0x03)).unwrap();
+
+        bus.write(0xBFFF, 0x24).unwrap();
+        bus.load_ram(&[0x42; 0x1000]);
+        assert_eq!(bus.read(0xAFFF).unwrap(), 0x42);
+        assert_eq!(bus.read(0xB000).unwrap(), 0x00);
+        assert_eq!(bus.read(0xBFFF).unwrap(), 0x00);
+
+        bus.load_ram(&[0x42; 0x10000]);
+        assert_eq!(bus.save_ram().unwrap(), vec![0x42; 0x8000]);
+    }
+
+    #[test]
+    fn reset_preserves_battery_ram_only() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x09, 0x02)).unwrap();
+
+        bus.write(0xA000, 0x42).unwrap();
+        bus.write(0xC000, 0x24).unwrap();
+
+        bus.reset(false);
+        assert_eq!(bus.read(0xA000).unwrap(), 0x42);
+        assert_eq!(bus.read(0xC000).unwrap(), 0x00);
+
+        bus.reset(true);
+        assert_eq!(bus.read(0xA000).unwrap(), 0x00);
+    }
+
+    #[test]
+    #[cfg(feature = "hooks")]
+    fn hooks_override_accesses() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        bus.set_read_hook(|addr, val| if addr == 0x0100 { 0xAF } else { val });
+        bus.set_write_hook(|addr, val| if addr == 0xC001 { None } else { Some(val) });
+
+        bus.write(0xC000, 0x42).unwrap();
+        bus.write(0xC001, 0x42).unwrap();
+
+        assert_eq!(bus.read(0x0100).unwrap(), 0xAF);
+        assert_eq!(bus.read(0xC000).unwrap(), 0x42);
+        assert_eq!(bus.read(0xC001).unwrap(), 0x00);
+
+        bus.clear_hooks();
+        assert_eq!(bus.read(0x0100).unwrap(), 0x00);
+    }
+
+    fn run_dma(bus: &mut Bus, src: u8) {
+        bus.write(0xFF46, src).unwrap();
+
+        // Account for the startup delay as well
+        for _ in 0..162 {
+            bus.tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn dma_from_high_source_reads_wram() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        for i in 0..0xA0 {
+            bus.write(0xC000 + i, i as u8).unwrap();
+            bus.write(0xDF00 + i, !(i as u8)).unwrap();
+        }
+
+        // 0xE0 is mirrored to 0xC0 (echo RAM)
+        run_dma(&mut bus, 0xE0);
+        for i in 0..0xA0 {
+            assert_eq!(bus.read(0xFE00 + i).unwrap(), i as u8);
+        }
+
+        // 0xFF is mirrored to 0xDF, bypassing OAM and IO space
+        run_dma(&mut bus, 0xFF);
+        for i in 0..0xA0 {
+            assert_eq!(bus.read(0xFE00 + i).unwrap(), !(i as u8));
+        }
+    }
+
+    #[test]
+    fn dma_from_missing_cart_ram_reads_open_bus() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        run_dma(&mut bus, 0xA0);
+        for i in 0..0xA0 {
+            assert_eq!(bus.read(0xFE00 + i).unwrap(), 0xFF);
+        }
+    }
+
+    #[test]
+    fn mbc1_switches_rom_and_ram_banks() {
+        // 2MB ROM with 32KB of RAM, each ROM bank tagged with its own number
+        let mut rom = vec![0; 128 * 0x4000];
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x06;
+        rom[0x149] = 0x03;
+        for n in 1..128 {
+            rom[n * 0x4000] = n as u8;
+        }
+
+        let mut bus = Bus::new();
+        bus.load_rom(&rom).unwrap();
+        assert_eq!(bus.read(0x4000).unwrap(), 1);
+
+        // Bank 0 is remapped to bank 1, and only the lower 5 bits are used
+        bus.write(0x2000, 0x00).unwrap();
+        assert_eq!(bus.read(0x4000).unwrap(), 1);
+        bus.write(0x2000, 0xFF).unwrap();
+        assert_eq!(bus.read(0x4000).unwrap(), 0x1F);
+
+        // Upper bits extend the bank number, still skipping the remapped banks
+        bus.write(0x4000, 0x01).unwrap();
+        assert_eq!(bus.read(0x4000).unwrap(), 0x3F);
+        bus.write(0x2000, 0x00).unwrap();
+        assert_eq!(bus.read(0x4000).unwrap(), 0x21);
+        assert_eq!(bus.read(0x0000).unwrap(), 0x00);
+
+        // In advanced mode, the upper bits apply to the lower area and RAM too
+        bus.write(0xA000, 0x42).unwrap();
+        bus.write(0x6000, 0x01).unwrap();
+        assert_eq!(bus.read(0x0000).unwrap(), 0x20);
+        assert_eq!(bus.read(0xA000).unwrap(), 0x00);
+        bus.write(0xA000, 0x24).unwrap();
+
+        bus.write(0x6000, 0x00).unwrap();
+        assert_eq!(bus.read(0x0000).unwrap(), 0x00);
+        assert_eq!(bus.read(0xA000).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn mbc3_maps_rtc_registers() {
+        let mut rom = make_rom(0x10, 0x03);
+        rom[0x148] = 0x01;
+        rom.resize(4 * 0x4000, 0x33);
+
+        let mut bus = Bus::new();
+        bus.load_rom(&rom).unwrap();
+        bus.write(0xA000, 0x42).unwrap();
+
+        bus.write(0x2000, 0x83).unwrap();
+        assert_eq!(bus.read(0x4000).unwrap(), 0x33);
+
+        // Writes to RTC registers are visible right away, and survive a latch
+        bus.write(0x4000, 0x0C).unwrap();
+        bus.write(0xA000, 0x40).unwrap();
+        bus.write(0x4000, 0x0B).unwrap();
+        bus.write(0xA000, 0x12).unwrap();
+        bus.write(0x6000, 0x00).unwrap();
+        bus.write(0x6000, 0x01).unwrap();
+        assert_eq!(bus.read(0xA000).unwrap(), 0x12);
+        assert_eq!(
+            bus.rtc_state().map(|state| state.latched),
+            Some([0, 0, 0, 0x12, 0x40])
+        );
+
+        // Selecting a RAM bank maps RAM back in
+        bus.write(0x4000, 0x00).unwrap();
+        assert_eq!(bus.read(0xA000).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn mbc5_selects_any_of_512_banks() {
+        // 8MB ROM with 128KB of RAM and a rumble motor, each ROM bank tagged with its number
+        let mut rom = vec![0; 512 * 0x4000];
+        rom[0x147] = 0x1E;
+        rom[0x148] = 0x08;
+        rom[0x149] = 0x04;
+        for n in 0..512 {
+            rom[n * 0x4000 + 1] = (n >> 8) as u8;
+            rom[n * 0x4000 + 2] = n as u8;
+        }
+
+        let mut bus = Bus::new();
+        bus.load_rom(&rom).unwrap();
+
+        let bank = |bus: &Bus| {
+            u16::from(bus.read(0x4001).unwrap()) << 8 | u16::from(bus.read(0x4002).unwrap())
+        };
+
+        assert_eq!(bank(&bus), 1);
+        bus.write(0x2000, 0x00).unwrap();
+        assert_eq!(bank(&bus), 0);
+        bus.write(0x2000, 0x34).unwrap();
+        bus.write(0x3000, 0x01).unwrap();
+        assert_eq!(bank(&bus), 0x134);
+        bus.write(0x2000, 0xFF).unwrap();
+        assert_eq!(bank(&bus), 0x1FF);
+
+        // Bit 3 of the RAM bank register drives the rumble motor
+        bus.write(0xA000, 0x42).unwrap();
+        bus.write(0x4000, 0x09).unwrap();
+        assert!(bus.rumble_active());
+        assert_eq!(bus.read(0xA000).unwrap(), 0x00);
+        bus.write(0x4000, 0x00).unwrap();
+        assert!(!bus.rumble_active());
+        assert_eq!(bus.read(0xA000).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn dma_blocks_the_source_bus_until_done() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        bus.write(0xC000, 0x42).unwrap();
+        bus.write(0x8000, 0x24).unwrap();
+        bus.write(0xFF80, 0x99).unwrap();
+
+        bus.write(0xFF46, 0xC0).unwrap();
+        bus.tick().unwrap();
+        bus.tick().unwrap();
+
+        for _ in 0..160 {
+            // OAM and the external bus are cut off, while VRAM and HRAM are still accessible
+            assert_eq!(bus.read(0xFE00).unwrap(), 0xFF);
+            assert_eq!(bus.read(0xC000).unwrap(), 0xFF);
+            assert_eq!(bus.read(0x0100).unwrap(), 0xFF);
+            assert_eq!(bus.read(0x8000).unwrap(), 0x24);
+            assert_eq!(bus.read(0xFF80).unwrap(), 0x99);
+            bus.tick().unwrap();
+        }
+
+        assert_eq!(bus.read(0xFE00).unwrap(), 0x42);
+        assert_eq!(bus.read(0xC000).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn unusable_area_ignores_writes() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        for addr in 0xFEA0..=0xFEFF {
+            bus.write(addr, 0x42).unwrap();
+            assert_eq!(bus.read(addr).unwrap(), 0x00);
+        }
+
+        let mut bus = Bus::with_model(Model::Cgb);
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        bus.write(0xFEA0, 0x42).unwrap();
+        assert_eq!(bus.read(0xFEA0).unwrap(), 0xAA);
+        assert_eq!(bus.read(0xFEF5).unwrap(), 0xFF);
+    }
+
+    #[test]
+    fn unmapped_io_registers_read_ff_and_ignore_writes() {
+        let unmapped = [
+            0xFF03, 0xFF08, 0xFF0E, 0xFF4C, 0xFF4E, 0xFF57, 0xFF6C, 0xFF7F,
+        ];
+
+        for &model in [Model::Dmg, Model::Cgb].iter() {
+            let mut bus = Bus::with_model(model);
+            bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+            for &addr in unmapped.iter() {
+                bus.write(addr, 0x42).unwrap();
+                assert_eq!(bus.read(addr).unwrap(), 0xFF, "{:04X}", addr);
+            }
+        }
+
+        // CGB-only registers are unmapped on DMG
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        for &addr in [0xFF4F, 0xFF56, 0xFF68, 0xFF70].iter() {
+            bus.write(addr, 0x00).unwrap();
+            assert_eq!(bus.read(addr).unwrap(), 0xFF, "{:04X}", addr);
+        }
+    }
+
+    #[test]
+    fn frozen_ram_ignores_cpu_writes() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        bus.write(0xC010, 0x42).unwrap();
+        assert!(bus.freeze(0xC010));
+        assert!(bus.freeze(0xFF90));
+        assert!(!bus.freeze(0xFF40));
+
+        bus.write(0xC010, 0x24).unwrap();
+        bus.write(0xE010, 0x24).unwrap();
+        bus.write(0xFF90, 0x24).unwrap();
+        assert_eq!(bus.read(0xC010).unwrap(), 0x42);
+        assert_eq!(bus.read(0xFF90).unwrap(), 0x00);
+        assert!(bus.is_frozen(0xE010));
+
+        // The debugger can still change a frozen value
+        bus.poke(0xC010, 0x99).unwrap();
+        assert_eq!(bus.read(0xC010).unwrap(), 0x99);
+
+        bus.reset(false);
+        assert!(bus.is_frozen(0xC010));
+
+        bus.unfreeze(0xE010);
+        bus.write(0xC010, 0x24).unwrap();
+        assert_eq!(bus.read(0xC010).unwrap(), 0x24);
+        assert_eq!(bus.frozen().len(), 1);
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut bus = Bus::with_model(Model::Cgb);
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        bus.write(0xC123, 0x42).unwrap();
+        assert_eq!(bus.read(0xE123).unwrap(), 0x42);
+
+        bus.write(0xE124, 0x24).unwrap();
+        assert_eq!(bus.read(0xC124).unwrap(), 0x24);
+
+        // The upper half mirrors the switchable bank, up to 0xDDFF
+        bus.write(0xFF70, 0x03).unwrap();
+        bus.write(0xDDFF, 0x99).unwrap();
+        assert_eq!(bus.read(0xFDFF).unwrap(), 0x99);
+        bus.write(0xF000, 0x66).unwrap();
+        assert_eq!(bus.read(0xD000).unwrap(), 0x66);
+
+        bus.write(0xFF70, 0x04).unwrap();
+        assert_ne!(bus.read(0xFDFF).unwrap(), 0x99);
+    }
+
+    #[test]
+    fn wram_is_banked_on_cgb_only() {
+        let mut bus = Bus::with_model(Model::Cgb);
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        for n in 0..8 {
+            bus.write(0xFF70, n).unwrap();
+            bus.write(0xD000, 0x10 + n).unwrap();
+        }
+
+        // Bank 0 selects bank 1
+        bus.write(0xFF70, 0x00).unwrap();
+        assert_eq!(bus.read(0xFF70).unwrap(), 0xF9);
+        assert_eq!(bus.read(0xD000).unwrap(), 0x11);
+
+        bus.write(0xFF70, 0x05).unwrap();
+        assert_eq!(bus.read(0xD000).unwrap(), 0x15);
+        assert_eq!(bus.read(0xF000).unwrap(), 0x15);
+
+        let mut bus = Bus::with_model(Model::Dmg);
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        bus.write(0xD000, 0x42).unwrap();
+        bus.write(0xFF70, 0x05).unwrap();
+        assert_eq!(bus.read(0xFF70).unwrap(), 0xFF);
+        assert_eq!(bus.read(0xD000).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn vblank_and_stat_irqs_are_raised_together() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x00, 0x00)).unwrap();
+
+        // Enable the mode 1 STAT interrupt, plus both interrupts in IE
+        bus.write(0xFF41, 0x10).unwrap();
+        bus.write(0xFFFF, 0x03).unwrap();
+
+        while bus.ppu.ly() != 143 {
+            bus.tick().unwrap();
+        }
+        bus.write(0xFF0F, 0x00).unwrap();
+
+        while bus.ppu.ly() == 143 {
+            assert_eq!(bus.read(0xFF0F).unwrap() & 0x1F, 0x00);
+            bus.tick().unwrap();
+        }
+
+        // Both bits are set on the first cycle of line 144, and VBlank is serviced first
+        assert_eq!(bus.read(0xFF0F).unwrap() & 0x1F, 0x03);
+        assert_eq!(bus.itr.get_pending_irq(), Some(0));
+
+        bus.itr.clear_irq(0);
+        assert_eq!(bus.itr.get_pending_irq(), Some(1));
+    }
+
+    #[test]
+    fn battery_backed_ram_is_saved_and_restored() {
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x09, 0x02)).unwrap();
+
+        bus.write(0xA123, 0x42).unwrap();
+
+        let sav = bus.save_ram().unwrap();
+        assert_eq!(sav.len(), 0x2000);
+
+        let mut bus = Bus::new();
+        bus.load_rom(&make_rom(0x09, 0x02)).unwrap();
+        bus.load_ram(&sav);
+
+        assert_eq!(bus.read(0xA123).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn frame_sequencer_follows_div_at_both_speeds() {
+        for &double_speed in &[false, true] {
+            let mut bus = Bus::with_model(Model::Cgb);
+
+            if double_speed {
+                bus.write(0xFF4D, 0x01).unwrap_err();
+                bus.switch_speed().unwrap();
+            }
+
+            // Record the number of cycles between frame sequencer steps
+            let mut step = bus.apu.frame_sequencer_step();
+            let mut last = None;
+            let mut periods = vec![];
+
+            for cycle in 0..8 * 4096 {
+                bus.tick().unwrap();
+
+                if bus.apu.frame_sequencer_step() != step {
+                    step = bus.apu.frame_sequencer_step();
+                    periods.extend(last.map(|last| cycle - last));
+                    last = Some(cycle);
+                }
+            }
+
+            let expected = 2048 * if double_speed { 2 } else { 1 };
+            assert!(periods.len() >= 6);
+            assert!(periods.iter().all(|&p| p == expected));
+        }
+    }
+
+    #[test]
+    fn timer_runs_at_cpu_speed() {
+        for &double_speed in &[false, true] {
+            let mut bus = Bus::with_model(Model::Cgb);
+
+            if double_speed {
+                assert!(bus.write(0xFF4D, 0x01).is_err());
+                assert_eq!(bus.read(0xFF4D).unwrap(), 0x7F);
+                bus.switch_speed().unwrap();
+                assert_eq!(bus.read(0xFF4D).unwrap(), 0xFE);
+            }
+
+            // Count TIMA increments at 262144 Hz during a whole frame
+            bus.write(0xFF07, 0x05).unwrap();
+
+            let start = (bus.ppu.ly(), bus.ppu.dot());
+            let mut tima = bus.read(0xFF05).unwrap();
+            let mut increments = 0;
+
+            loop {
+                bus.tick().unwrap();
+
+                let val = bus.read(0xFF05).unwrap();
+                increments += u32::from(val.wrapping_sub(tima));
+                tima = val;
+
+                if (bus.ppu.ly(), bus.ppu.dot()) == start && !bus.slow_cycle {
+                    break;
+                }
+            }
+
+            let expected = 70224 / 16 * if double_speed { 2 } else { 1 };
+            assert_eq!(increments, expected);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+use crate::dbg::TraceEvent;
+
+/// Metadata stored in the cartridge header, at 0x0134-0x014D of the ROM.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CartridgeHeader {
+    /// Game title, in upper case ASCII.
+    pub title: String,
+    /// CGB support flag (0x80: CGB-enhanced, 0xC0: CGB-only).
+    pub cgb_flag: u8,
+    /// Cartridge type, ie. the mapper and any additional hardware.
+    pub cart_type: u8,
+    /// ROM size code.
+    pub rom_size: u8,
+    /// External RAM size code.
+    pub ram_size: u8,
+    /// Checksum of the bytes at 0x0134-0x014C.
+    pub header_checksum: u8,
+}
+
+impl CartridgeHeader {
+    /// Parses the header of `rom`, validating its checksum like the boot ROM does.
+    ///
+    /// A ROM too short to contain a header is reported as `TraceEvent::InvalidRomHeader`.
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, TraceEvent> {
+        let computed = compute_header_checksum(rom).ok_or(TraceEvent::InvalidRomHeader(0))?;
+        let stored = rom[0x14D];
+
+        if computed != stored {
+            return Err(TraceEvent::HeaderChecksumMismatch(stored, computed));
+        }
+
+        let cgb_flag = rom[0x143];
+
+        // On CGB carts, the last byte of the title is taken by the CGB flag
+        let title = if cgb_flag & 0x80 != 0 {
+            &rom[0x134..0x143]
+        } else {
+            &rom[0x134..=0x143]
+        };
+
+        let title = title
+            .iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '?'
+                }
+            })
+            .collect::<String>();
+
+        Ok(CartridgeHeader {
+            title: title.trim_end().to_string(),
+            cgb_flag,
+            cart_type: rom[0x147],
+            rom_size: rom[0x148],
+            ram_size: rom[0x149],
+            header_checksum: stored,
+        })
+    }
+}
+
+/// Computes the checksum of the header of `rom` (bytes 0x0134-0x014C),
+/// or returns `None` if the ROM is too short to contain a header.
+pub(crate) fn compute_header_checksum(rom: &[u8]) -> Option<u8> {
+    rom.get(0x134..=0x14C).map(|header| {
+        header
+            .iter()
+            .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = include_bytes!("../../roms/blargg/cpu_instrs.gb");
+
+    #[test]
+    fn header_is_parsed_and_validated() {
+        let header = CartridgeHeader::parse(ROM).unwrap();
+
+        assert_eq!(header.title, "CPU_INSTRS");
+        assert_eq!(header.cgb_flag, 0x80);
+        assert_eq!(header.cart_type, 0x01);
+        assert_eq!(header.rom_size, 0x01);
+        assert_eq!(header.ram_size, 0x00);
+
+        let mut rom = ROM.to_vec();
+        rom[0x134] ^= 0xFF;
+
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(TraceEvent::HeaderChecksumMismatch(..))
+        ));
+        assert!(matches!(
+            CartridgeHeader::parse(&ROM[..0x100]),
+            Err(TraceEvent::InvalidRomHeader(0))
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+/// The error type returned when a cheat code can't be parsed.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CheatError {
+    #[error("invalid cheat code: {0}")]
+    InvalidCode(String),
+}
+
+/// A cheat, as decoded from a Game Genie or GameShark code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cheat {
+    /// Game Genie code: reads from ROM at `addr` return `value` instead of the original
+    /// byte, but only if it is `compare` (when given), since the address may be banked.
+    RomPatch {
+        addr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    /// GameShark code: `value` is written to RAM at `addr` once per frame.
+    RamWrite { addr: u16, value: u8 },
+}
+
+impl Cheat {
+    /// Parses a Game Genie code (`ABC-DEF` or `ABC-DEF-GHI`) or a GameShark code (`ttvvllhh`).
+    ///
+    /// In Game Genie codes, `AB` is the new value and `FCDE` the address, with `F` inverted.
+    /// `GI` is the value to compare against, XORed with 0xBA and rotated left by two,
+    /// while `H` is not used.
+    ///
+    /// In GameShark codes, `vv` is the value and `hhll` the address. The type `tt` selects
+    /// a RAM bank for some codes, but it is ignored: the value is written to whichever bank
+    /// is currently mapped.
+    pub fn parse(code: &str) -> Result<Cheat, CheatError> {
+        let invalid = || CheatError::InvalidCode(code.to_string());
+
+        let digits = code
+            .trim()
+            .chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+
+        let byte = |i: usize| (digits[i] << 4) | digits[i + 1];
+
+        match digits.len() {
+            6 | 9 => {
+                let addr = (u16::from(digits[5] ^ 0xF) << 12)
+                    | (u16::from(digits[2]) << 8)
+                    | u16::from(byte(3));
+
+                let compare = if digits.len() == 9 {
+                    Some(((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xBA)
+                } else {
+                    None
+                };
+
+                Ok(Cheat::RomPatch {
+                    addr,
+                    value: byte(0),
+                    compare,
+                })
+            }
+            8 if !code.contains('-') => Ok(Cheat::RamWrite {
+                addr: (u16::from(byte(6)) << 8) | u16::from(byte(4)),
+                value: byte(2),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cheat_codes_are_parsed() {
+        assert_eq!(
+            Cheat::parse("00A-17B-C49"),
+            Ok(Cheat::RomPatch {
+                addr: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC8),
+            })
+        );
+        assert_eq!(
+            Cheat::parse("3ef-93f"),
+            Ok(Cheat::RomPatch {
+                addr: 0x0F93,
+                value: 0x3E,
+                compare: None,
+            })
+        );
+        assert_eq!(
+            Cheat::parse(" 01630AD0 "),
+            Ok(Cheat::RamWrite {
+                addr: 0xD00A,
+                value: 0x63,
+            })
+        );
+
+        for code in &["", "00A-17B-C4", "0163-0AD0", "01630AD0X", "00G-17B-C49"] {
+            assert!(Cheat::parse(code).is_err(), "{:?} should be rejected", code);
+        }
+    }
+}
@@ -1,6 +1,88 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+use bitflags::bitflags;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    cpu::{Condition, OPCODES},
+    dbg,
+    io::{IrqFlags, Latch},
+    mem::{MemR, MemRW},
+    Model,
+};
+
+bitflags! {
+    /// The flags stored in the upper nibble of the F register.
+    pub struct Flags: u8 {
+        /// Zero flag
+        const Z = 0b_1000_0000;
+        /// Subtract flag
+        const N = 0b_0100_0000;
+        /// Half-carry flag
+        const H = 0b_0010_0000;
+        /// Carry flag
+        const C = 0b_0001_0000;
+    }
+}
+
+/// The error type returned when parsing a `Flags` string fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlagsParseError(String);
+
+impl fmt::Display for FlagsParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid flags string: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for FlagsParseError {}
+
+impl FromStr for Flags {
+    type Err = FlagsParseError;
+
+    /// Parses flags in "ZNHC" order, where each unset flag is replaced by a dash (eg. "Z-HC").
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars = s.chars().collect::<Vec<_>>();
 
-use crate::{cpu::OPCODES, dbg, io::Latch, mem::MemRW};
+        if chars.len() != 4 {
+            return Err(FlagsParseError(s.to_string()));
+        }
+
+        let mut flags = Flags::empty();
+
+        for (c, (name, flag)) in chars.iter().zip(Flags::NAMES.iter()) {
+            match c {
+                '-' => (),
+                c if c == name => flags |= *flag,
+                _ => return Err(FlagsParseError(s.to_string())),
+            }
+        }
+
+        Ok(flags)
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, flag) in Flags::NAMES.iter() {
+            write!(f, "{}", if self.contains(*flag) { *name } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl Flags {
+    const NAMES: [(char, Flags); 4] = [
+        ('Z', Flags::Z),
+        ('N', Flags::N),
+        ('H', Flags::H),
+        ('C', Flags::C),
+    ];
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct OpcodeInfo(
@@ -12,7 +94,7 @@ pub struct OpcodeInfo(
     pub u8,              // Cycles if branch not taken
 );
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MemoryAddressing {
     A16, // (a16)
     IO,  // ($ff00 + a8)
@@ -23,14 +105,14 @@ pub enum MemoryAddressing {
     SP,  // (SP)
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum OperandLocation {
     Register,
     Immediate,
     Memory(MemoryAddressing),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum CpuState {
     FetchOpcode,
     FetchByte0,
@@ -41,7 +123,21 @@ pub enum CpuState {
     Delay(u8),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuState::FetchOpcode => write!(f, "Fetch opcode"),
+            CpuState::FetchByte0 => write!(f, "Fetch byte 0"),
+            CpuState::FetchByte1 => write!(f, "Fetch byte 1"),
+            CpuState::FetchMemory0 => write!(f, "Fetch memory 0"),
+            CpuState::FetchMemory1 => write!(f, "Fetch memory 1"),
+            CpuState::Writeback => write!(f, "Writeback"),
+            CpuState::Delay(n) => write!(f, "Delay ({})", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum WritebackOp {
     Write8(u16, u8),
     Write16(u16, u16),
@@ -49,7 +145,15 @@ pub enum WritebackOp {
     Return,
 }
 
-#[derive(Clone)]
+/// A return address pushed by a call, an RST or an interrupt dispatch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CallFrame {
+    /// Where the return address is stored on the stack
+    sp: u16,
+    ret: u16,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CPU {
     // Registers
     pub af: u16,
@@ -61,6 +165,8 @@ pub struct CPU {
 
     // Misc
     pub halted: Latch<bool>,
+    /// IME. EI only loads the new value, which is latched when the next instruction starts,
+    /// so interrupts are not serviced until that instruction completes. DI resets it at once.
     pub intr_enabled: Latch<bool>,
 
     // Execution-related members
@@ -75,17 +181,62 @@ pub struct CPU {
     pub remaining_cycles: u8,
 
     // Debug
+    #[serde(skip)]
     paused: bool,
+    #[serde(skip)]
     breakpoints: HashSet<u16>,
-    pub call_stack: Vec<u16>,
+    #[serde(skip)]
+    conditional_breakpoints: HashMap<u16, Condition>,
+    #[serde(skip)]
+    call_stack: Vec<CallFrame>,
+    #[serde(skip)]
+    step_target: Option<(u16, u16)>,
+    #[serde(skip)]
+    exec_regions: Option<dbg::ExecRegions>,
 
     // Hacks/workarounds
     pub halt_bug: bool,
     ignore_next_halt: bool,
+    /// Set when a STOP following a write to KEY1 is executed, for the bus to switch speed
+    pub speed_switch: bool,
+}
+
+// Mnemonics are serialized as plain strings and resolved back
+// to their `'static` counterparts in the opcode table.
+impl Serialize for OpcodeInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.0, self.1, self.2, self.3, self.4, self.5).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for OpcodeInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (mnemonic, dst, src, size, taken, not_taken) =
+            <(String, OperandLocation, OperandLocation, u8, u8, u8)>::deserialize(deserializer)?;
+
+        let mnemonic = OPCODES
+            .iter()
+            .map(|info| info.0)
+            .find(|&m| m == mnemonic)
+            .ok_or_else(|| de::Error::custom(format!("unknown mnemonic {}", mnemonic)))?;
+
+        Ok(OpcodeInfo(mnemonic, dst, src, size, taken, not_taken))
+    }
 }
 
 impl Default for CPU {
     fn default() -> CPU {
+        CPU::post_boot()
+    }
+}
+
+impl CPU {
+    pub fn new() -> CPU {
+        CPU::default()
+    }
+
+    /// Creates a CPU whose registers hold the values left by the DMG boot ROM.
+    pub fn post_boot() -> CPU {
         CPU {
             af: 0x01B0,
             bc: 0x0013,
@@ -109,17 +260,42 @@ impl Default for CPU {
 
             paused: false,
             breakpoints: HashSet::new(),
-            call_stack: vec![0x0100],
+            conditional_breakpoints: HashMap::new(),
+            call_stack: Vec::new(),
+            step_target: None,
+            exec_regions: None,
 
             halt_bug: false,
             ignore_next_halt: false,
+            speed_switch: false,
         }
     }
-}
 
-impl CPU {
-    pub fn new() -> CPU {
-        CPU::default()
+    /// Creates a CPU whose registers hold the values left by the boot ROM of `model`.
+    pub fn with_model(model: Model) -> CPU {
+        match model {
+            Model::Dmg => CPU::post_boot(),
+            Model::Cgb => CPU {
+                af: 0x1180,
+                bc: 0x0000,
+                de: 0xFF56,
+                hl: 0x000D,
+                ..CPU::post_boot()
+            },
+        }
+    }
+
+    /// Creates a CPU in its power-on state, ready to run the boot ROM from 0x0000.
+    pub fn at_power_on() -> CPU {
+        CPU {
+            af: 0x0000,
+            bc: 0x0000,
+            de: 0x0000,
+            hl: 0x0000,
+            sp: 0x0000,
+            pc: 0x0000,
+            ..CPU::post_boot()
+        }
     }
 
     pub fn tick(&mut self, bus: &mut impl MemRW) -> Result<(), dbg::TraceEvent> {
@@ -152,13 +328,6 @@ impl CPU {
             }
         };
 
-        // The HALT bug prevents PC from being incremented on the instruction
-        // following a HALT, under certain conditions.
-        if self.halt_bug {
-            self.halt_bug = false;
-            self.pc = saved_ctx.pc;
-        }
-
         match res {
             Err(dbg::TraceEvent::CgbSpeedSwitchReq) => {
                 // A speed switch in CGB is followed by a STOP which should be ignored.
@@ -171,6 +340,11 @@ impl CPU {
                 // Restore previous state on error. Note that this is for debugging purposes only,
                 // the side effects of the instruction (eg. memory writes) are NOT rolled back.
                 *self = saved_ctx;
+
+                // Step targets are one-shot, so they must not be restored along with the rest
+                if let dbg::TraceEvent::StepComplete(_) = e {
+                    self.step_target = None;
+                }
                 Err(e)
             }
             Ok(()) => {
@@ -178,6 +352,7 @@ impl CPU {
                 if *self.halted.loaded() && self.ignore_next_halt {
                     self.ignore_next_halt = false;
                     self.halted.reset(false);
+                    self.speed_switch = self.opcode == 0x10 && !self.cb_mode;
                 }
                 Ok(())
             }
@@ -185,16 +360,45 @@ impl CPU {
     }
 
     fn fetch_opcode(&mut self, bus: &mut impl MemRW) -> Result<(), dbg::TraceEvent> {
-        // Handle breakpoints at the current position
-        if !self.paused() && self.breakpoints.contains(&self.pc) {
-            self.pause();
-            return Err(dbg::TraceEvent::Breakpoint(self.pc));
-        } else {
-            self.resume();
+        // Handle breakpoints and illegal execution at the current position
+        if !self.paused() {
+            if self.breakpoints.contains(&self.pc) {
+                self.pause();
+                return Err(dbg::TraceEvent::Breakpoint(self.pc));
+            }
+
+            if let Some(cond) = self.conditional_breakpoints.get(&self.pc) {
+                if cond.eval(self, bus) {
+                    self.pause();
+                    return Err(dbg::TraceEvent::Breakpoint(self.pc));
+                }
+            }
+
+            if let Some((addr, sp)) = self.step_target {
+                if self.pc == addr && self.sp >= sp {
+                    self.pause();
+                    return Err(dbg::TraceEvent::StepComplete(self.pc));
+                }
+            }
+
+            if let Some(allowed) = self.exec_regions {
+                if !allowed.allows(self.pc) {
+                    self.pause();
+                    return Err(dbg::TraceEvent::ExecuteFromIllegalRegion(self.pc));
+                }
+            }
         }
+        self.resume();
 
         // Fetch opcode and reset internal state
         self.opcode = self.fetch_pc(bus)?;
+
+        // The HALT bug prevents PC from being incremented past the opcode following a HALT,
+        // so the byte after it is read twice (see `GameBoy::tick` for the trigger conditions)
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
         self.info = OPCODES[self.opcode as usize];
         self.operand = 0;
         self.cb_mode = self.opcode == 0xCB;
@@ -356,10 +560,31 @@ impl CPU {
         }
     }
 
-    pub fn jump_to_isr(&mut self, bus: &mut impl MemRW, addr: u16) -> Result<(), dbg::TraceEvent> {
-        // Push PC onto the stack
-        self.sp -= 2;
-        self.store_word(bus, self.sp, self.pc)?;
+    /// Dispatches the highest-priority pending interrupt (VBlank first, Joypad last):
+    /// pushes PC, acknowledges the interrupt in IF and jumps to its vector.
+    ///
+    /// The interrupt is only selected after the high byte of PC has been pushed.
+    /// If that write disables every pending interrupt in IE (ie. when SP was 0x0000),
+    /// the dispatch is cancelled and execution resumes from 0x0000 instead.
+    pub fn jump_to_isr(&mut self, bus: &mut impl MemRW) -> Result<(), dbg::TraceEvent> {
+        self.push_call_frame(self.pc);
+
+        self.sp = self.sp.wrapping_sub(1);
+        bus.write(self.sp, (self.pc >> 8) as u8)?;
+
+        let pending = self.pending_interrupts(bus)?;
+        let id = pending.bits().trailing_zeros();
+
+        let addr = if pending.is_empty() {
+            0x0000
+        } else {
+            let requested = self.requested_interrupts(bus)?;
+            bus.write(0xFF0F, requested.bits() & !(1 << id))?;
+            0x0040 + 0x08 * id as u16
+        };
+
+        self.sp = self.sp.wrapping_sub(1);
+        bus.write(self.sp, self.pc as u8)?;
 
         // Jump to ISR
         self.pc = addr;
@@ -405,21 +630,148 @@ impl CPU {
         self.paused
     }
 
+    /// Returns the micro-step the CPU is going to perform on the next M-cycle.
+    pub fn micro_state(&self) -> CpuState {
+        self.state
+    }
+
     pub fn set_breakpoint(&mut self, addr: u16) {
         self.breakpoints.insert(addr);
     }
 
+    /// Sets a breakpoint at `addr` which is only hit when `cond` holds,
+    /// replacing any other conditional breakpoint at the same address.
+    pub fn set_conditional_breakpoint(&mut self, addr: u16, cond: Condition) {
+        self.conditional_breakpoints.insert(addr, cond);
+    }
+
+    /// Clears both the plain and the conditional breakpoint at `addr`, if any.
     pub fn clear_breakpoint(&mut self, addr: u16) {
         self.breakpoints.remove(&addr);
+        self.conditional_breakpoints.remove(&addr);
+    }
+
+    /// Records a call returning to `ret`, whose address is about to be pushed on the stack.
+    pub(crate) fn push_call_frame(&mut self, ret: u16) {
+        // Frames above SP have been popped already, either by a return or by hand
+        while matches!(self.call_stack.last(), Some(frame) if frame.sp < self.sp) {
+            self.call_stack.pop();
+        }
+
+        self.call_stack.push(CallFrame {
+            sp: self.sp.wrapping_sub(2),
+            ret,
+        });
+    }
+
+    /// Returns the return addresses of the calls in progress, starting from the outermost one.
+    ///
+    /// Calls are tracked against SP, so that return addresses which were popped from
+    /// the stack by hand (eg. to tail call another function) don't linger.
+    pub fn call_stack(&self) -> Vec<u16> {
+        self.call_stack
+            .iter()
+            .take_while(|frame| frame.sp >= self.sp)
+            .map(|frame| frame.ret)
+            .collect()
     }
 
+    /// Returns where the call at PC (if any) returns to, along with the value of SP
+    /// once it has returned, to be used as a step target to step over it.
+    pub fn step_over_target(&self, mem: &impl MemR) -> Result<Option<(u16, u16)>, dbg::TraceEvent> {
+        let instr = self.disasm(mem, self.pc)?;
+
+        // CALL a16 and its conditional variants, and RST n
+        let is_call = matches!(
+            instr.opcode,
+            0xC4 | 0xCC
+                | 0xCD
+                | 0xD4
+                | 0xDC
+                | 0xC7
+                | 0xCF
+                | 0xD7
+                | 0xDF
+                | 0xE7
+                | 0xEF
+                | 0xF7
+                | 0xFF
+        );
+
+        Ok(if is_call {
+            Some((self.pc.wrapping_add(u16::from(instr.size)), self.sp))
+        } else {
+            None
+        })
+    }
+
+    /// Returns where the innermost call in progress returns to, along with the value
+    /// of SP once it has returned, to be used as a step target to step out of it.
+    pub fn step_out_target(&self) -> Option<(u16, u16)> {
+        self.call_stack
+            .iter()
+            .take_while(|frame| frame.sp >= self.sp)
+            .last()
+            .map(|frame| (frame.ret, frame.sp.wrapping_add(2)))
+    }
+
+    /// Sets a one-shot breakpoint at `(addr, sp)`, which is hit at `addr` only once SP is
+    /// back at `sp` or above, so that recursive calls don't hit it too early.
+    /// Hitting it raises `TraceEvent::StepComplete`. Passing `None` clears it.
+    pub fn set_step_target(&mut self, target: Option<(u16, u16)>) {
+        self.step_target = target;
+    }
+
+    /// Returns whether a plain or conditional breakpoint is set at `addr`.
     pub fn breakpoint_at(&self, addr: u16) -> bool {
-        self.breakpoints.contains(&addr)
+        self.breakpoints.contains(&addr) || self.conditional_breakpoints.contains_key(&addr)
     }
 
     pub fn breakpoints(&self) -> &HashSet<u16> {
         &self.breakpoints
     }
+
+    pub fn conditional_breakpoints(&self) -> &HashMap<u16, Condition> {
+        &self.conditional_breakpoints
+    }
+
+    /// Takes over the debugging settings of `prev`, which are not part of the state.
+    pub(crate) fn take_host_state(&mut self, prev: &mut CPU) {
+        self.breakpoints = std::mem::take(&mut prev.breakpoints);
+        self.conditional_breakpoints = std::mem::take(&mut prev.conditional_breakpoints);
+        self.exec_regions = prev.exec_regions;
+    }
+
+    /// Restricts the regions from which code can be executed.
+    ///
+    /// When set, fetching an opcode from any other region raises
+    /// `TraceEvent::ExecuteFromIllegalRegion`, which usually points to a wild jump.
+    /// Passing `None` disables the check.
+    pub fn set_exec_regions(&mut self, allowed: Option<dbg::ExecRegions>) {
+        self.exec_regions = allowed;
+    }
+
+    /// Returns the regions from which code can be executed, if restricted.
+    pub fn exec_regions(&self) -> Option<dbg::ExecRegions> {
+        self.exec_regions
+    }
+
+    /// Returns the interrupts enabled in the IE register.
+    pub fn enabled_interrupts(&self, bus: &impl MemR) -> Result<IrqFlags, dbg::TraceEvent> {
+        Ok(IrqFlags::from_bits_truncate(bus.read(0xFFFF)?))
+    }
+
+    /// Returns the interrupts requested in the IF register.
+    pub fn requested_interrupts(&self, bus: &impl MemR) -> Result<IrqFlags, dbg::TraceEvent> {
+        Ok(IrqFlags::from_bits_truncate(bus.read(0xFF0F)?))
+    }
+
+    /// Returns the interrupts which are both enabled and requested.
+    ///
+    /// These are serviced as soon as IME is set, or just wake the CPU from HALT otherwise.
+    pub fn pending_interrupts(&self, bus: &impl MemR) -> Result<IrqFlags, dbg::TraceEvent> {
+        Ok(self.enabled_interrupts(bus)? & self.requested_interrupts(bus)?)
+    }
 }
 
 #[rustfmt::skip]
@@ -451,4 +803,70 @@ impl CPU {
     pub fn set_sf(&mut self, v: bool) { self.set_f((self.f() & (!0x40)) | (u8::from(v) << 6)); }
     pub fn set_hc(&mut self, v: bool) { self.set_f((self.f() & (!0x20)) | (u8::from(v) << 5)); }
     pub fn set_cy(&mut self, v: bool) { self.set_f((self.f() & (!0x10)) | (u8::from(v) << 4)); }
+
+    pub fn flags(&self) -> Flags { Flags::from_bits_truncate(self.f()) }
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.set_zf(flags.contains(Flags::Z));
+        self.set_sf(flags.contains(Flags::N));
+        self.set_hc(flags.contains(Flags::H));
+        self.set_cy(flags.contains(Flags::C));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_parse_and_display() {
+        assert_eq!("Z-HC".parse::<Flags>(), Ok(Flags::Z | Flags::H | Flags::C));
+        assert_eq!("----".parse::<Flags>(), Ok(Flags::empty()));
+        assert_eq!("ZNHC".parse::<Flags>(), Ok(Flags::all()));
+
+        assert!("Z-H".parse::<Flags>().is_err());
+        assert!("N---".parse::<Flags>().is_err());
+
+        assert_eq!((Flags::N | Flags::C).to_string(), "-N-C");
+    }
+
+    #[test]
+    fn pending_interrupts_are_enabled_and_requested() {
+        use crate::{io::IrqController, mem::MemW};
+
+        let cpu = CPU::new();
+        let mut itr = IrqController::new();
+
+        itr.write(0xFFFF, 0xE5).unwrap();
+        itr.write(0xFF0F, 0x0C).unwrap();
+
+        let enabled = IrqFlags::VBLANK | IrqFlags::TIMER;
+        let requested = IrqFlags::TIMER | IrqFlags::SERIAL;
+
+        assert_eq!(cpu.enabled_interrupts(&itr).unwrap(), enabled);
+        assert_eq!(cpu.requested_interrupts(&itr).unwrap(), requested);
+        assert_eq!(cpu.pending_interrupts(&itr).unwrap(), IrqFlags::TIMER);
+    }
+
+    #[test]
+    fn set_flags_keeps_lower_nibble_clear() {
+        let mut cpu = CPU::new();
+
+        cpu.af = 0x12FF;
+        cpu.set_flags("-N-C".parse().unwrap());
+
+        assert_eq!(cpu.af, 0x1250);
+        assert_eq!(cpu.flags(), Flags::N | Flags::C);
+        assert!(!cpu.zf() && cpu.sf() && !cpu.hc() && cpu.cy());
+    }
+
+    #[test]
+    fn micro_state_is_exposed() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.micro_state(), CpuState::FetchOpcode);
+
+        cpu.state = CpuState::Delay(2);
+        assert_eq!(cpu.micro_state(), CpuState::Delay(2));
+        assert_eq!(cpu.micro_state().to_string(), "Delay (2)");
+        assert_eq!(CpuState::FetchMemory1.to_string(), "Fetch memory 1");
+    }
 }
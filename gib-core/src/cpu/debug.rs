@@ -10,12 +10,108 @@ pub enum Immediate {
     Imm16(u16),
 }
 
+/// A CPU register whose value can be tested by a breakpoint `Condition`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+/// The value tested by a breakpoint `Condition`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operand {
+    /// The current value of a register.
+    Register(Register),
+    /// The byte currently stored at an address.
+    Memory(u16),
+}
+
+/// The comparison performed by a breakpoint `Condition`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A condition which must hold for a conditional breakpoint to be hit,
+/// eg. `A == 0x10` or `[0xFF44] >= 0x90`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub cmp: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn new(operand: Operand, cmp: Comparison, value: u16) -> Condition {
+        Condition {
+            operand,
+            cmp,
+            value,
+        }
+    }
+
+    /// Evaluates the condition against the current state of `cpu` and `mem`.
+    ///
+    /// Memory is peeked at, so evaluating a condition doesn't trigger watchpoints.
+    /// A condition on an address which can't be read never holds.
+    pub fn eval(&self, cpu: &CPU, mem: &impl MemR) -> bool {
+        let lhs = match self.operand {
+            Operand::Register(r) => match r {
+                Register::A => u16::from(cpu.a()),
+                Register::F => u16::from(cpu.f()),
+                Register::B => u16::from(cpu.b()),
+                Register::C => u16::from(cpu.c()),
+                Register::D => u16::from(cpu.d()),
+                Register::E => u16::from(cpu.e()),
+                Register::H => u16::from(cpu.h()),
+                Register::L => u16::from(cpu.l()),
+                Register::AF => cpu.af,
+                Register::BC => cpu.bc,
+                Register::DE => cpu.de,
+                Register::HL => cpu.hl,
+                Register::SP => cpu.sp,
+            },
+            Operand::Memory(addr) => match mem.peek(addr) {
+                Some(val) => u16::from(val),
+                None => return false,
+            },
+        };
+
+        match self.cmp {
+            Comparison::Eq => lhs == self.value,
+            Comparison::Ne => lhs != self.value,
+            Comparison::Lt => lhs < self.value,
+            Comparison::Le => lhs <= self.value,
+            Comparison::Gt => lhs > self.value,
+            Comparison::Ge => lhs >= self.value,
+        }
+    }
+}
+
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Instruction {
     pub opcode: u8,
     pub mnemonic: &'static str,
     pub imm: Option<Immediate>,
     pub size: u8,
+    /// Destination of a jump, call or restart, if it can be known statically
+    pub target: Option<u16>,
 }
 
 impl CPU {
@@ -34,11 +130,63 @@ impl CPU {
             _ => unreachable!(),
         };
 
+        let target = match (opcode, imm) {
+            // JP a16, CALL a16 and their conditional variants
+            (0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA, Some(Immediate::Imm16(a16)))
+            | (0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC, Some(Immediate::Imm16(a16))) => Some(a16),
+            // JR r8 is relative to the address of the next instruction
+            (0x18 | 0x20 | 0x28 | 0x30 | 0x38, Some(Immediate::Imm8(r8))) => {
+                Some(addr.wrapping_add(2).wrapping_add(r8 as i8 as u16))
+            }
+            // RST n
+            (0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF, _) => {
+                Some(u16::from(opcode & 0x38))
+            }
+            _ => None,
+        };
+
         Ok(Instruction {
             opcode,
             mnemonic: info.0,
             imm,
             size: info.3,
+            target,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{MemW, Memory};
+
+    #[test]
+    fn branch_targets_are_resolved() {
+        let mut mem = Memory::new(0x200);
+        let code: &[(u16, &[u8])] = &[
+            (0x0100, &[0xC3, 0x50, 0x01]), // JP $0150
+            (0x0103, &[0xDC, 0x34, 0x12]), // CALL C,$1234
+            (0x0106, &[0x18, 0xFE]),       // JR -2
+            (0x0108, &[0x20, 0x05]),       // JR NZ,+5
+            (0x010A, &[0xEF]),             // RST $28
+            (0x010B, &[0x21, 0x50, 0x01]), // LD HL,$0150
+            (0x010E, &[0xE9]),             // JP (HL)
+        ];
+        for (addr, bytes) in code {
+            for (i, b) in bytes.iter().enumerate() {
+                mem.write(addr + i as u16, *b).unwrap();
+            }
+        }
+
+        let cpu = CPU::new();
+        let target = |addr| cpu.disasm(&mem, addr).unwrap().target;
+
+        assert_eq!(target(0x0100), Some(0x0150));
+        assert_eq!(target(0x0103), Some(0x1234));
+        assert_eq!(target(0x0106), Some(0x0106));
+        assert_eq!(target(0x0108), Some(0x010F));
+        assert_eq!(target(0x010A), Some(0x0028));
+        assert_eq!(target(0x010B), None);
+        assert_eq!(target(0x010E), None);
+    }
+}
@@ -26,9 +26,9 @@ macro_rules! call {
     ($cpu:ident, $cond:expr, $to:expr) => {{
         if $cond {
             $cpu.write_op = Some(WritebackOp::Push($cpu.pc));
+            $cpu.push_call_frame($cpu.pc);
             $cpu.pc = $to;
             $cpu.branch_taken = true;
-            $cpu.call_stack.push($cpu.pc);
         }
     }};
 }
@@ -36,7 +36,6 @@ macro_rules! call {
 macro_rules! ret {
     ($cpu:ident, $cond:expr) => {{
         if $cond {
-            $cpu.call_stack.pop();
             $cpu.write_op = Some(WritebackOp::Return);
             $cpu.branch_taken = true;
         }
@@ -507,20 +506,23 @@ impl CPU {
             0x3F => { self.set_sf(false); self.set_hc(false); self.set_cy(!self.cy()); }
 
             0x27 => {
+                // After an addition, adjust any digit out of the BCD range (or which overflowed).
+                // After a subtraction, only the digits which borrowed need adjusting.
+                // In both cases, the carry is never cleared.
                 if !self.sf() {
                     if self.cy() || self.a() > 0x99 {
-                        self.set_a(self.a() + 0x60);
+                        self.set_a(self.a().wrapping_add(0x60));
                         self.set_cy(true);
                     }
                     if self.hc() || (self.a() & 0x0f) > 0x09 {
-                        self.set_a(self.a() + 0x06);
+                        self.set_a(self.a().wrapping_add(0x06));
                     }
                 } else {
                     if self.cy() {
-                        self.set_a(self.a() - 0x60);
+                        self.set_a(self.a().wrapping_sub(0x60));
                     }
                     if self.hc() {
-                        self.set_a(self.a() - 0x06);
+                        self.set_a(self.a().wrapping_sub(0x06));
                     }
                 }
 
@@ -928,6 +930,11 @@ impl CPU {
                 let v = set!(7, self.operand as u8);
                 self.write_op = Some(WritebackOp::Write8(self.hl, v));
             }
+
+            // All CB opcodes are defined, but don't bring down the emulator
+            // if an entry ever goes missing
+            #[allow(unreachable_patterns)]
+            _ => return Err(dbg::TraceEvent::IllegalCbInstructionFault(self.opcode)),
         };
 
         Ok(())
@@ -1676,6 +1683,40 @@ mod test {
         }
     }
 
+    #[test]
+    fn daa_adjusts_bcd_results() {
+        // (opcode, A, B, A after DAA, flags after DAA)
+        let table = [
+            (0x80, 0x15, 0x27, 0x42, "----"), // ADD A, B
+            (0x80, 0x09, 0x08, 0x17, "----"),
+            (0x80, 0x99, 0x01, 0x00, "Z--C"),
+            (0x80, 0x90, 0x90, 0x80, "---C"),
+            (0x80, 0x00, 0x00, 0x00, "Z---"),
+            (0x90, 0x42, 0x15, 0x27, "-N--"), // SUB B
+            (0x90, 0x10, 0x20, 0x90, "-N-C"),
+            (0x90, 0x33, 0x33, 0x00, "ZN--"),
+            (0x90, 0x00, 0x01, 0x99, "-N-C"),
+        ];
+
+        for &(op, a, b, result, flags) in table.iter() {
+            CpuTest::new(2, vec![op, 0x27])
+                .setup(move |cpu| {
+                    cpu.set_a(a);
+                    cpu.set_b(b);
+                })
+                .run(|cpu, _| {
+                    assert_eq!(cpu.a(), result, "{:02X} op {:02X}", a, b);
+                    assert_eq!(
+                        cpu.flags(),
+                        flags.parse().unwrap(),
+                        "{:02X} op {:02X}",
+                        a,
+                        b
+                    );
+                });
+        }
+    }
+
     #[test]
     fn prefix_cb_opcodes_work() {
         // CB r
@@ -1695,6 +1736,20 @@ mod test {
             .run(|_, _| {});
     }
 
+    #[test]
+    fn all_prefix_cb_opcodes_are_decoded() {
+        for op in 0_u8..=255 {
+            // Both register and (HL) variants complete within 4 cycles
+            CpuTest::new(4, vec![0xCB, op, 0x00, 0x00, 0x00, 0x00])
+                .setup(|cpu| {
+                    cpu.hl = 0x5;
+                })
+                .run(|cpu, _| {
+                    assert_eq!(cpu.state, FetchOpcode);
+                });
+        }
+    }
+
     #[test]
     fn opcode_timings_are_correct() {
         for op in 0_u8..=255 {
@@ -1717,4 +1772,39 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn call_stack_follows_sp() {
+        // 0x00: CALL $10 ; JR -2
+        // 0x10: CALL $20 ; JR -2
+        // 0x20: CALL $30 ; JR -2
+        // 0x30: POP HL   ; JP $40
+        let mut memory = vec![0u8; 0x100];
+        memory[0x00..0x05].copy_from_slice(&[0xCD, 0x10, 0x00, 0x18, 0xFE]);
+        memory[0x10..0x15].copy_from_slice(&[0xCD, 0x20, 0x00, 0x18, 0xFE]);
+        memory[0x20..0x25].copy_from_slice(&[0xCD, 0x30, 0x00, 0x18, 0xFE]);
+        memory[0x30..0x34].copy_from_slice(&[0xE1, 0xC3, 0x40, 0x00]);
+
+        // 0x40: JR -2, so the call returning to 0x23 has been dropped by hand
+        let mut looping = memory.clone();
+        looping[0x40..0x42].copy_from_slice(&[0x18, 0xFE]);
+
+        CpuTest::new(100, looping)
+            .setup(|cpu| cpu.sp = 0x00FE)
+            .run(|cpu, _| {
+                assert_eq!(cpu.pc & 0xF0, 0x40);
+                assert_eq!(cpu.call_stack(), vec![0x0003, 0x0013]);
+            });
+
+        // 0x40: RET, which goes back to the call before it
+        let mut returning = memory;
+        returning[0x40] = 0xC9;
+
+        CpuTest::new(100, returning)
+            .setup(|cpu| cpu.sp = 0x00FE)
+            .run(|cpu, _| {
+                assert_eq!(cpu.pc & 0xF0, 0x10);
+                assert_eq!(cpu.call_stack(), vec![0x0003]);
+            });
+    }
 }
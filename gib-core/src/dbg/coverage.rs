@@ -0,0 +1,87 @@
+/// Execution coverage of a ROM run.
+///
+/// Keeps track of the addresses at which an instruction was fetched, and of which opcodes
+/// were executed at least once. This can be used to tell code from data in a ROM.
+///
+/// NOTE: addresses are tracked in the CPU address space, so code in switchable ROM banks
+/// is merged across banks.
+#[derive(Clone)]
+pub struct Coverage {
+    addrs: Vec<u64>,
+    opcodes: [u64; 4],
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage {
+            addrs: vec![0; 0x10000 / 64],
+            opcodes: [0; 4],
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Records the execution of `opcode` at address `addr`.
+    pub fn mark(&mut self, addr: u16, opcode: u8) {
+        self.addrs[usize::from(addr) / 64] |= 1 << (addr % 64);
+        self.opcodes[usize::from(opcode) / 64] |= 1 << (opcode % 64);
+    }
+
+    /// Returns whether an instruction was fetched from `addr`.
+    pub fn is_executed(&self, addr: u16) -> bool {
+        self.addrs[usize::from(addr) / 64] & (1 << (addr % 64)) != 0
+    }
+
+    /// Returns an iterator over all the addresses from which an instruction was fetched,
+    /// in ascending order.
+    pub fn executed_addresses(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..=0xFFFF).filter(move |&addr| self.is_executed(addr))
+    }
+
+    /// Returns an iterator over all the opcodes executed at least once, in ascending order.
+    ///
+    /// CB-prefixed instructions are reported as opcode 0xCB.
+    pub fn executed_opcodes(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=0xFF)
+            .filter(move |&op: &u8| self.opcodes[usize::from(op) / 64] & (1 << (op % 64)) != 0)
+    }
+
+    /// Clears the coverage information collected so far.
+    pub fn clear(&mut self) {
+        *self = Coverage::default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coverage_tracks_addresses_and_opcodes() {
+        let mut cov = Coverage::new();
+
+        cov.mark(0x0100, 0x00);
+        cov.mark(0x0101, 0xC3);
+        cov.mark(0xFFFF, 0xCB);
+        cov.mark(0x0100, 0x00);
+
+        assert!(cov.is_executed(0x0101));
+        assert!(!cov.is_executed(0x0102));
+
+        assert_eq!(
+            cov.executed_addresses().collect::<Vec<_>>(),
+            vec![0x0100, 0x0101, 0xFFFF]
+        );
+        assert_eq!(
+            cov.executed_opcodes().collect::<Vec<_>>(),
+            vec![0x00, 0xC3, 0xCB]
+        );
+
+        cov.clear();
+        assert_eq!(cov.executed_addresses().count(), 0);
+    }
+}
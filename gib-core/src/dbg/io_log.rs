@@ -0,0 +1,108 @@
+use std::io::{self, Write};
+
+/// A single write to an IO register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoWrite {
+    pub cycle: u64,
+    pub addr: u16,
+    pub val: u8,
+}
+
+/// Log of the writes to the IO registers (0xFF00-0xFF7F and IE), along with the clock cycle
+/// at which they happened.
+///
+/// This is meant to be diffed against register logs captured on real hardware,
+/// so it is much narrower than a full instruction trace. Logging is disabled by default.
+#[derive(Clone, Default)]
+pub struct IoLog {
+    enabled: bool,
+    cycle: u64,
+    writes: Vec<IoWrite>,
+}
+
+impl IoLog {
+    pub fn new() -> IoLog {
+        IoLog::default()
+    }
+
+    /// Starts or stops recording writes.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Returns whether writes are being recorded.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Sets the clock cycle which is assigned to the following writes.
+    pub(crate) fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+
+    /// Records a write of `val` to `addr`, if logging is enabled and `addr` is an IO register.
+    pub fn record(&mut self, addr: u16, val: u8) {
+        if self.enabled && matches!(addr, 0xFF00..=0xFF7F | 0xFFFF) {
+            self.writes.push(IoWrite {
+                cycle: self.cycle,
+                addr,
+                val,
+            });
+        }
+    }
+
+    /// Returns the writes recorded so far, in chronological order.
+    pub fn writes(&self) -> &[IoWrite] {
+        &self.writes[..]
+    }
+
+    /// Clears the writes recorded so far.
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+
+    /// Dumps the recorded writes to `w` in CSV format, one `cycle,addr,value` line per write.
+    ///
+    /// Addresses and values are in hexadecimal.
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "cycle,addr,value")?;
+
+        for write in self.writes.iter() {
+            writeln!(w, "{},{:04X},{:02X}", write.cycle, write.addr, write.val)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn io_writes_are_logged_as_csv() {
+        let mut log = IoLog::new();
+
+        // Nothing is recorded until enabled
+        log.record(0xFF40, 0x91);
+        assert!(log.writes().is_empty());
+
+        log.set_enabled(true);
+        log.set_cycle(100);
+        log.record(0xFF40, 0x91);
+        log.record(0xC000, 0x42);
+        log.record(0xFF80, 0x42);
+        log.set_cycle(104);
+        log.record(0xFFFF, 0x1F);
+
+        let mut csv = Vec::new();
+        log.write_csv(&mut csv).unwrap();
+
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "cycle,addr,value\n100,FF40,91\n104,FFFF,1F\n"
+        );
+
+        log.clear();
+        assert!(log.writes().is_empty());
+    }
+}
@@ -1,5 +1,21 @@
 use std::{fmt, ops::RangeInclusive};
 
+use bitflags::bitflags;
+
+#[cfg(feature = "coverage")]
+pub use coverage::*;
+#[cfg(feature = "io-log")]
+pub use io_log::*;
+pub use symbols::*;
+pub use trace::*;
+
+#[cfg(feature = "coverage")]
+mod coverage;
+#[cfg(feature = "io-log")]
+mod io_log;
+mod symbols;
+mod trace;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryType {
     RomBank(u8),
@@ -95,6 +111,45 @@ impl MemoryType {
     }
 }
 
+bitflags! {
+    /// Memory regions from which the CPU is allowed to execute code.
+    pub struct ExecRegions: u16 {
+        const ROM       = 0b_0000_0000_0001;
+        const VRAM      = 0b_0000_0000_0010;
+        const ERAM      = 0b_0000_0000_0100;
+        const WRAM      = 0b_0000_0000_1000;
+        const ECHO      = 0b_0000_0001_0000;
+        const OAM       = 0b_0000_0010_0000;
+        const IO        = 0b_0000_0100_0000;
+        const HRAM      = 0b_0000_1000_0000;
+        const NOT_USABLE = 0b_0001_0000_0000;
+
+        // Code is commonly run from ROM, and copied to RAM for speed or bank switching
+        const DEFAULT = Self::ROM.bits | Self::ERAM.bits | Self::WRAM.bits | Self::HRAM.bits;
+    }
+}
+
+impl ExecRegions {
+    /// Returns whether code can be executed from `addr`.
+    pub fn allows(self, addr: u16) -> bool {
+        use MemoryType::*;
+
+        let region = match MemoryType::at(addr) {
+            RomBank(_) => ExecRegions::ROM,
+            VideoRam => ExecRegions::VRAM,
+            ExternalRam => ExecRegions::ERAM,
+            WorkRamBank(_) => ExecRegions::WRAM,
+            EchoRam(_) => ExecRegions::ECHO,
+            SpriteMemory => ExecRegions::OAM,
+            IoSpace => ExecRegions::IO,
+            HighRam => ExecRegions::HRAM,
+            NotUsable => ExecRegions::NOT_USABLE,
+        };
+
+        self.contains(region)
+    }
+}
+
 impl fmt::Display for MemoryType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use MemoryType::*;
@@ -113,6 +168,60 @@ impl fmt::Display for MemoryType {
     }
 }
 
+/// Fixed addresses at which execution can start, along with a short description:
+/// the RST vectors, the interrupt vectors and the cartridge entry point.
+pub const ENTRY_POINTS: [(u16, &str); 14] = [
+    (0x0000, "RST 00"),
+    (0x0008, "RST 08"),
+    (0x0010, "RST 10"),
+    (0x0018, "RST 18"),
+    (0x0020, "RST 20"),
+    (0x0028, "RST 28"),
+    (0x0030, "RST 30"),
+    (0x0038, "RST 38"),
+    (0x0040, "VBlank ISR"),
+    (0x0048, "LCD STAT ISR"),
+    (0x0050, "Timer ISR"),
+    (0x0058, "Serial ISR"),
+    (0x0060, "Joypad ISR"),
+    (0x0100, "Cartridge entry"),
+];
+
+/// Returns the description of the entry point at `addr`, if there is one.
+pub fn entry_point_label(addr: u16) -> Option<&'static str> {
+    ENTRY_POINTS
+        .iter()
+        .find(|(entry, _)| *entry == addr)
+        .map(|(_, label)| *label)
+}
+
+/// The kind of memory accesses which trigger a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    /// Returns whether a read (or write, if `is_write`) triggers the watchpoint.
+    pub fn triggers_on(self, is_write: bool) -> bool {
+        match self {
+            WatchKind::Read => !is_write,
+            WatchKind::Write => is_write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+fn access_kind(is_write: &bool) -> &'static str {
+    if *is_write {
+        "write"
+    } else {
+        "read"
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum McbOp {
     Write(u16),
@@ -130,20 +239,44 @@ impl fmt::Display for McbOp {
 pub enum TraceEvent {
     #[error("Breakpoint reached: 0x{0:04X}")]
     Breakpoint(u16),
+    #[error("Step completed: 0x{0:04X}")]
+    StepComplete(u16),
+    #[error("Dot breakpoint reached: LY={0}, dot={1}")]
+    DotBreakpoint(u8, u16),
+    #[error("Watchpoint hit: {} 0x{value:02X} @ 0x{addr:04X}", access_kind(.is_write))]
+    Watchpoint {
+        addr: u16,
+        is_write: bool,
+        value: u8,
+    },
     #[error("Illegal opcode: {0:02X}")]
     IllegalInstructionFault(u8),
+    #[error("Illegal opcode: CB {0:02X}")]
+    IllegalCbInstructionFault(u8),
     #[error("Bus fault accessing 0x{0:04X}")]
     BusFault(u16),
     #[error("Memory fault accessing 0x{0:04X}")]
     MemFault(u16),
     #[error("Unsupported MBC: {0:02X}")]
     UnsupportedMbcType(u8),
+    #[error("Unsupported ROM size: {0:02X}")]
+    UnsupportedRomSize(u8),
+    #[error("Unsupported RAM size: {0:02X}")]
+    UnsupportedRamSize(u8),
+    #[error("ROM image too small: {0} bytes")]
+    RomTooSmall(usize),
     #[error("Invalid MBC operation: {0} = {1:02X}")]
     InvalidMbcOp(McbOp, u8),
     #[error("CGB speed switch request")]
     CgbSpeedSwitchReq,
     #[error("Unsupported CGB operation: {0:04X}")]
     UnsupportedCgbOp(u16),
-    #[error("CGB mode not supported")]
-    CgbNotSupported,
+    #[error("No valid ROM header at offset 0x{0:X}")]
+    InvalidRomHeader(usize),
+    #[error("Header checksum mismatch: stored {0:02X}, computed {1:02X}")]
+    HeaderChecksumMismatch(u8, u8),
+    #[error("APU register 0x{0:04X} written while powered off")]
+    ApuWriteWhileOff(u16),
+    #[error("Execution from illegal region: 0x{0:04X}")]
+    ExecuteFromIllegalRegion(u16),
 }
@@ -0,0 +1,112 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+/// Labels loaded from a symbol file, as produced by rgbds (`rgblink -n`) or WLA-DX.
+///
+/// Each label is given as `bank:addr name`, with both numbers in hex:
+///
+/// ```text
+/// ; File generated by rgblink
+/// 00:0150 Main
+/// 00:0247 Main.loop
+/// ```
+///
+/// Comments (starting with `;`), section headers (eg. `[labels]`) and any line
+/// which is not a label are skipped. If several labels share the same address,
+/// the first one is kept.
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    labels: BTreeMap<(u16, u16), String>,
+}
+
+impl SymbolTable {
+    /// Parses the contents of a symbol file.
+    pub fn parse(s: &str) -> SymbolTable {
+        let mut labels = BTreeMap::new();
+
+        for line in s.lines() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+
+            let mut fields = line.split_whitespace();
+            let (loc, name) = match (fields.next(), fields.next()) {
+                (Some(loc), Some(name)) => (loc, name),
+                _ => continue,
+            };
+
+            let (bank, addr) = match loc.split_once(':') {
+                Some((bank, addr)) => (bank, addr),
+                None => continue,
+            };
+
+            if let (Ok(bank), Ok(addr)) =
+                (u16::from_str_radix(bank, 16), u16::from_str_radix(addr, 16))
+            {
+                labels
+                    .entry((bank, addr))
+                    .or_insert_with(|| name.to_string());
+            }
+        }
+
+        SymbolTable { labels }
+    }
+
+    /// Reads and parses the symbol file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<SymbolTable> {
+        Ok(SymbolTable::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Returns the number of labels in the table.
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Returns the label at `addr` in `bank`, if any.
+    pub fn lookup(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.labels.get(&(bank, addr)).map(String::as_str)
+    }
+
+    /// Returns the closest label at or before `addr` in `bank`, along with the offset
+    /// of `addr` from it, eg. to name the function a return address belongs to.
+    pub fn nearest(&self, bank: u16, addr: u16) -> Option<(&str, u16)> {
+        self.labels
+            .range((bank, 0)..=(bank, addr))
+            .next_back()
+            .map(|(&(_, at), name)| (name.as_str(), addr - at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_files_are_parsed() {
+        let syms = SymbolTable::parse(
+            "; File generated by rgblink\n\
+             [labels]\n\
+             00:0150 Main\n\
+             00:0247 Main.loop ; the main loop\n\
+             00:0247 Duplicate\n\
+             01:4000 Bank1Start\n\
+             0002:4010 WideBank\n\
+             \n\
+             [definitions]\n\
+             00000010 NOT_A_LABEL\n\
+             zz:0000 Garbage\n",
+        );
+
+        assert_eq!(syms.len(), 4);
+        assert_eq!(syms.lookup(0, 0x0150), Some("Main"));
+        assert_eq!(syms.lookup(0, 0x0247), Some("Main.loop"));
+        assert_eq!(syms.lookup(1, 0x4000), Some("Bank1Start"));
+        assert_eq!(syms.lookup(2, 0x4010), Some("WideBank"));
+        assert_eq!(syms.lookup(1, 0x0150), None);
+
+        assert_eq!(syms.nearest(0, 0x024A), Some(("Main.loop", 3)));
+        assert_eq!(syms.nearest(0, 0x0100), None);
+        assert_eq!(syms.nearest(1, 0x7FFF), Some(("Bank1Start", 0x3FFF)));
+    }
+}
@@ -0,0 +1,199 @@
+use std::{fmt, io, str::FromStr};
+
+use super::TraceEvent;
+
+/// CPU state before the execution of an instruction, as found in instruction trace logs.
+///
+/// The text format is the one used by Gameboy Doctor, which most emulators can produce
+/// (eg. SameBoy or BGB through a custom log format), with one instruction per line:
+///
+/// ```text
+/// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+/// ```
+///
+/// Any field other than the CPU registers (eg. `PCMEM`) is ignored when parsing,
+/// as is anything following a `;`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TraceLine {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+impl TraceLine {
+    /// Returns the names of the registers whose value differs between `self` and `other`.
+    pub fn diff(&self, other: &TraceLine) -> Vec<&'static str> {
+        let regs8 = [
+            ("A", self.a, other.a),
+            ("F", self.f, other.f),
+            ("B", self.b, other.b),
+            ("C", self.c, other.c),
+            ("D", self.d, other.d),
+            ("E", self.e, other.e),
+            ("H", self.h, other.h),
+            ("L", self.l, other.l),
+        ];
+        let regs16 = [("SP", self.sp, other.sp), ("PC", self.pc, other.pc)];
+
+        regs8
+            .iter()
+            .filter(|(_, x, y)| x != y)
+            .map(|(name, _, _)| *name)
+            .chain(
+                regs16
+                    .iter()
+                    .filter(|(_, x, y)| x != y)
+                    .map(|(name, _, _)| *name),
+            )
+            .collect()
+    }
+}
+
+impl fmt::Display for TraceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X}",
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc
+        )
+    }
+}
+
+/// The error type returned when a trace log line can't be parsed.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("malformed trace line: {0:?}")]
+pub struct TraceParseError(String);
+
+/// Names of the registers in a trace log line, each one of which must appear exactly once.
+const TRACE_REGS: [&str; 10] = ["A", "F", "B", "C", "D", "E", "H", "L", "SP", "PC"];
+
+impl FromStr for TraceLine {
+    type Err = TraceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || TraceParseError(s.to_string());
+
+        let mut line = TraceLine::default();
+        // One bit per register in `TRACE_REGS`
+        let mut found = 0u16;
+
+        let fields = s.split(';').next().unwrap_or_default();
+
+        for field in fields.split_whitespace() {
+            let (name, val) = field.split_once(':').ok_or_else(err)?;
+
+            let bit = match TRACE_REGS.iter().position(|&reg| reg == name) {
+                Some(idx) => 1 << idx,
+                None => continue,
+            };
+            if found & bit != 0 {
+                return Err(err());
+            }
+            found |= bit;
+
+            let reg8 = match name {
+                "A" => &mut line.a,
+                "F" => &mut line.f,
+                "B" => &mut line.b,
+                "C" => &mut line.c,
+                "D" => &mut line.d,
+                "E" => &mut line.e,
+                "H" => &mut line.h,
+                "L" => &mut line.l,
+                "SP" => {
+                    line.sp = u16::from_str_radix(val, 16).map_err(|_| err())?;
+                    continue;
+                }
+                "PC" => {
+                    line.pc = u16::from_str_radix(val, 16).map_err(|_| err())?;
+                    continue;
+                }
+                _ => continue,
+            };
+
+            *reg8 = u8::from_str_radix(val, 16).map_err(|_| err())?;
+        }
+
+        // Every register must be present
+        if found != (1 << TRACE_REGS.len()) - 1 {
+            return Err(err());
+        }
+        Ok(line)
+    }
+}
+
+/// The first instruction at which the emulation diverges from a reference log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogMismatch {
+    /// Line of the reference log (starting from 1) which was not matched.
+    pub line: usize,
+    pub expected: TraceLine,
+    pub actual: TraceLine,
+}
+
+impl fmt::Display for LogMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "line {}: mismatch in {}",
+            self.line,
+            self.expected.diff(&self.actual).join(", ")
+        )?;
+        writeln!(f, "  expected: {}", self.expected)?;
+        write!(f, "  actual:   {}", self.actual)
+    }
+}
+
+/// The error type returned when comparing the emulation against a reference log fails.
+#[derive(thiserror::Error, Debug)]
+pub enum LogCompareError {
+    #[error("could not read reference log: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {0}: {1}")]
+    Parse(usize, TraceParseError),
+    #[error("line {0}: {1}")]
+    Emulation(usize, TraceEvent),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_lines_roundtrip() {
+        let s = "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02";
+        let line: TraceLine = s.parse().unwrap();
+
+        assert_eq!(line.a, 0x01);
+        assert_eq!(line.l, 0x4D);
+        assert_eq!(line.sp, 0xFFFE);
+        assert_eq!(line.pc, 0x0100);
+        assert!(s.starts_with(&line.to_string()));
+
+        assert!("A:01 F:B0 B:00".parse::<TraceLine>().is_err());
+        assert!("A:ZZ F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100"
+            .parse::<TraceLine>()
+            .is_err());
+    }
+
+    #[test]
+    fn trace_lines_have_each_register_once() {
+        // Ten fields, but L is missing
+        assert!("A:01 A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 SP:FFFE PC:0100"
+            .parse::<TraceLine>()
+            .is_err());
+        // Every register, one of them twice
+        assert!(
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PC:0101"
+                .parse::<TraceLine>()
+                .is_err()
+        );
+    }
+}
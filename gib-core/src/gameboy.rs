@@ -1,29 +1,96 @@
-use std::sync::Arc;
+use std::{
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Arc,
+};
 
 use crossbeam::queue::ArrayQueue;
+use serde::{Deserialize, Serialize};
 
-use crate::{bus::Bus, cpu::CPU, dbg, io::JoypadState};
+use crate::{
+    bus::Bus,
+    cartridge::CartridgeHeader,
+    cheats::{Cheat, CheatError},
+    cpu::{CpuState, Instruction, CPU, OPCODES},
+    dbg,
+    io::{JoypadState, RtcState, SerialLink, SpriteOrder},
+    mem::{MemR, Memory},
+    state::{self, RewindBuffer, StateError, STATE_VERSION},
+};
 
 pub const CPU_CLOCK: u64 = 4_194_304; // Hz
 pub const HSYNC_CLOCK: u64 = 9_198; // Hz
 
+/// Size of the LCD, in pixels.
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
 const CYCLES_PER_HSYNC: u64 = CPU_CLOCK / HSYNC_CLOCK;
+const CYCLES_PER_FRAME: u64 = CYCLES_PER_HSYNC * 154;
+
+/// The hardware model being emulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Model {
+    /// The original Game Boy (DMG).
+    Dmg,
+    /// The Game Boy Color (CGB).
+    Cgb,
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Model::Dmg => write!(f, "DMG"),
+            Model::Cgb => write!(f, "CGB"),
+        }
+    }
+}
+
+impl Model {
+    /// Returns the model best suited to run `rom`, based on the CGB flag in its header.
+    ///
+    /// Both CGB-enhanced and CGB-only carts are run on a CGB.
+    pub fn detect(rom: &[u8]) -> Model {
+        match rom.get(0x143) {
+            Some(flag) if flag & 0x80 != 0 => Model::Cgb,
+            _ => Model::Dmg,
+        }
+    }
+}
 
+#[derive(Serialize, Deserialize)]
 pub struct GameBoy {
+    #[serde(deserialize_with = "state::check_version")]
+    version: u32,
+
     cpu: CPU,
     bus: Bus,
 
+    // Header of the loaded cartridge, if valid
+    header: Option<CartridgeHeader>,
+
     cycles: u64,
+
+    // Tapped keys, along with the cycle at which they will be released
+    taps: Vec<(JoypadState, u64)>,
+
+    #[serde(skip)]
+    rewind: RewindBuffer,
+
+    // Last frame rendered, updated at the start of each VBlank
+    #[serde(skip)]
+    frame_buffer: Vec<u8>,
+
+    // Destination of the instruction trace, if enabled
+    #[serde(skip)]
+    trace: Option<Box<dyn Write + Send>>,
 }
 
 impl Default for GameBoy {
     fn default() -> GameBoy {
-        GameBoy {
-            cpu: CPU::new(),
-            bus: Bus::new(),
-
-            cycles: 0x18FCC,
-        }
+        GameBoy::with_model(Model::Dmg)
     }
 }
 
@@ -33,11 +100,158 @@ impl GameBoy {
         GameBoy::default()
     }
 
+    /// Create a new instance emulating the given hardware model.
+    pub fn with_model(model: Model) -> GameBoy {
+        GameBoy {
+            version: STATE_VERSION,
+
+            cpu: CPU::with_model(model),
+            bus: Bus::with_model(model),
+
+            header: None,
+
+            cycles: 0x18FCC,
+
+            taps: Vec::new(),
+
+            rewind: RewindBuffer::default(),
+
+            frame_buffer: vec![0xFF; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+
+            trace: None,
+        }
+    }
+
+    /// Create a new DMG instance which starts by running `boot`, ie. the 256-byte boot ROM.
+    ///
+    /// The boot ROM is mapped over 0x0000-0x00FF until it unmaps itself by writing to 0xFF50,
+    /// and it's then up to it to bring the hardware to its post-boot state.
+    /// The same happens again on every reset.
+    pub fn with_boot_rom(boot: &[u8]) -> GameBoy {
+        let mut gb = GameBoy::default();
+        gb.bus.set_boot_rom(boot);
+        gb.reset_cpu();
+        gb
+    }
+
+    /// Returns the hardware model being emulated.
+    pub fn model(&self) -> Model {
+        self.bus.model()
+    }
+
+    /// Loads `rom` into the cartridge slot.
+    ///
+    /// ROMs with a corrupt header are still loaded, as many test and homebrew ROMs
+    /// don't bother with checksums, but then no header is reported by `header`.
     pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), dbg::TraceEvent> {
-        self.bus.load_rom(rom)
+        self.bus.load_rom(rom)?;
+        self.header = CartridgeHeader::parse(rom).ok();
+        Ok(())
+    }
+
+    /// Returns the header of the loaded cartridge, if its checksum is valid.
+    pub fn header(&self) -> Option<&CartridgeHeader> {
+        self.header.as_ref()
+    }
+
+    /// Loads a ROM image which begins `offset` bytes into a larger dump.
+    ///
+    /// This is meant for dumps of flash carts and multi-game carts, where a menu program
+    /// is followed by one or more games stored back-to-back: given the offset of a game,
+    /// it can be run directly without splitting the dump beforehand. Bank switching
+    /// between the games (eg. GB Memory mappers) is not emulated.
+    ///
+    /// The header at `offset` is validated against its checksum, so that a wrong offset
+    /// is reported as `TraceEvent::InvalidRomHeader` rather than running garbage.
+    pub fn load_rom_at_offset(
+        &mut self,
+        dump: &[u8],
+        offset: usize,
+    ) -> Result<(), dbg::TraceEvent> {
+        self.bus.load_rom_at_offset(dump, offset)?;
+        self.header = CartridgeHeader::parse(&dump[offset..]).ok();
+        Ok(())
+    }
+
+    /// Swaps the cartridge with `rom` and resets the Game Boy to its post-boot state,
+    /// reusing the existing instance rather than creating a new one.
+    ///
+    /// The battery-backed RAM of the previous cartridge, if any, is returned so that
+    /// the caller can save it before it's lost. The emulated model and the audio sink are kept,
+    /// but the CPU is reset too, so breakpoints must be set again by the caller if needed.
+    /// If `rom` can't be loaded, the previous cartridge is left in place.
+    pub fn insert_rom(&mut self, rom: &[u8]) -> Result<Option<Vec<u8>>, dbg::TraceEvent> {
+        let ram = self.save_ram();
+
+        self.bus.insert_rom(rom)?;
+        self.header = CartridgeHeader::parse(rom).ok();
+        self.reset();
+        self.rewind.clear();
+
+        // Unlike a plain reset, the breakpoints were set for the previous cartridge
+        self.cpu.take_host_state(&mut CPU::default());
+
+        Ok(ram)
+    }
+
+    /// Returns whether the header checksum of the loaded ROM is valid.
+    ///
+    /// A mismatch usually means that the ROM dump is corrupted.
+    pub fn header_checksum_valid(&self) -> bool {
+        self.bus.header_checksum_valid()
+    }
+
+    /// Computes the global checksum of the loaded ROM (see `Bus::global_checksum`).
+    ///
+    /// Real hardware doesn't verify it, but it can tell ROMs apart, eg. to match save states.
+    pub fn rom_global_checksum(&self) -> u16 {
+        self.bus.global_checksum()
+    }
+
+    /// Returns the global checksum stored in the header of the loaded ROM.
+    pub fn stored_global_checksum(&self) -> u16 {
+        self.bus.stored_global_checksum()
+    }
+
+    /// Resets the Game Boy to its post-boot state, keeping the loaded ROM in place.
+    ///
+    /// All RAM is cleared, except for battery-backed cartridge RAM.
+    /// The audio sink, if any, is preserved, and so is all the debugging state
+    /// (see `take_debug_state`).
+    pub fn reset(&mut self) {
+        self.bus.reset(false);
+        self.reset_cpu();
+        self.taps.clear();
+        self.clear_frame_buffer();
+    }
+
+    /// Like `reset`, but also wipes battery-backed cartridge RAM.
+    pub fn hard_reset(&mut self) {
+        self.bus.reset(true);
+        self.reset_cpu();
+        self.taps.clear();
+        self.clear_frame_buffer();
+    }
+
+    /// Puts the CPU in the state it's found in when the first instruction is executed,
+    /// ie. at the start of the boot ROM if there is one, or after it otherwise.
+    /// Breakpoints and the other debugging settings of the CPU are kept.
+    fn reset_cpu(&mut self) {
+        let mut cpu = if self.bus.boot_rom_mapped() {
+            self.cycles = 0;
+            CPU::at_power_on()
+        } else {
+            self.cycles = GameBoy::default().cycles;
+            CPU::with_model(self.model())
+        };
+
+        cpu.take_host_state(&mut self.cpu);
+        self.cpu = cpu;
     }
 
     pub fn step(&mut self) -> Result<(), dbg::TraceEvent> {
+        let in_vblank = self.bus.ppu.ly() >= 144;
+
         // The first tick fetches the opcode
         self.tick()?;
 
@@ -49,12 +263,173 @@ impl GameBoy {
         // Finally, handle any interrupts that arised
         self.handle_irqs()?;
 
+        // At the start of each VBlank, the frame is complete: apply the RAM cheats,
+        // render it and take a rewind snapshot
+        if !in_vblank && self.bus.ppu.ly() >= 144 {
+            self.bus.apply_ram_cheats();
+            self.bus.ppu.rasterize(&mut self.frame_buffer);
+
+            if self.rewind.enabled() {
+                self.take_rewind_snapshot();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the current CPU state in instruction trace format.
+    pub fn trace_line(&self) -> dbg::TraceLine {
+        dbg::TraceLine {
+            a: self.cpu.a(),
+            f: self.cpu.f(),
+            b: self.cpu.b(),
+            c: self.cpu.c(),
+            d: self.cpu.d(),
+            e: self.cpu.e(),
+            h: self.cpu.h(),
+            l: self.cpu.l(),
+            sp: self.cpu.sp,
+            pc: self.cpu.pc,
+        }
+    }
+
+    /// Starts logging every instruction executed to `writer`, one line per instruction.
+    ///
+    /// Each line holds the CPU state before the instruction is fetched, followed by
+    /// the bytes at PC and the mnemonic of the opcode, eg.:
+    ///
+    /// ```text
+    /// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02 ; NOP
+    /// ```
+    ///
+    /// This is the format produced by Gameboy Doctor and SameBoy, and it can be read back
+    /// as a [`dbg::TraceLine`]. If writing fails, tracing stops.
+    pub fn set_trace(&mut self, writer: Box<dyn Write + Send>) {
+        self.trace = Some(writer);
+    }
+
+    /// Stops logging instructions, returning the writer after flushing it.
+    pub fn take_trace(&mut self) -> Option<Box<dyn Write + Send>> {
+        let mut writer = self.trace.take()?;
+        writer.flush().ok();
+        Some(writer)
+    }
+
+    /// Enables the Game Genie or GameShark `code` (see `Cheat::parse` for the formats).
+    ///
+    /// Game Genie codes patch ROM reads as long as they are enabled, while GameShark codes
+    /// write to RAM at the start of each VBlank.
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), CheatError> {
+        self.bus.add_cheat(Cheat::parse(code)?);
         Ok(())
     }
 
+    /// Disables the cheat `code`, returning whether it was enabled.
+    pub fn remove_cheat(&mut self, code: &str) -> bool {
+        match Cheat::parse(code) {
+            Ok(cheat) => self.bus.remove_cheat(&cheat),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the trace entry of the instruction about to be fetched (see `set_trace`).
+    fn trace_entry(&self) -> String {
+        let pc = self.cpu.pc;
+        let mem = [0, 1, 2, 3].map(|i| self.bus.peek(pc.wrapping_add(i)).unwrap_or(0xFF));
+
+        format!(
+            "{} PCMEM:{:02X},{:02X},{:02X},{:02X} ; {}",
+            self.trace_line(),
+            mem[0],
+            mem[1],
+            mem[2],
+            mem[3],
+            OPCODES[usize::from(mem[0])].0
+        )
+    }
+
+    /// Runs the emulation in lockstep with a reference instruction log (see [`dbg::TraceLine`]),
+    /// stopping at the first instruction whose CPU state differs from the expected one.
+    ///
+    /// Returns `None` if the whole log is matched. Empty lines in the log are skipped.
+    pub fn compare_to_log<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<Option<dbg::LogMismatch>, dbg::LogCompareError> {
+        let log = BufReader::new(File::open(path)?);
+
+        for (i, line) in log.lines().enumerate() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let expected: dbg::TraceLine = line
+                .parse()
+                .map_err(|e| dbg::LogCompareError::Parse(i + 1, e))?;
+            let actual = self.trace_line();
+
+            if actual != expected {
+                return Ok(Some(dbg::LogMismatch {
+                    line: i + 1,
+                    expected,
+                    actual,
+                }));
+            }
+
+            self.step()
+                .map_err(|e| dbg::LogCompareError::Emulation(i + 1, e))?;
+        }
+
+        Ok(None)
+    }
+
     fn tick(&mut self) -> Result<(), dbg::TraceEvent> {
+        // An opcode is fetched on this tick if the CPU is ready for it and not halted
+        #[cfg(feature = "coverage")]
+        let fetch_pc =
+            if self.cpu.state == crate::cpu::CpuState::FetchOpcode && !*self.cpu.halted.loaded() {
+                Some(self.cpu.pc)
+            } else {
+                None
+            };
+
+        // The entry is only written once the fetch succeeded, since it's retried after an event
+        let trace_entry = match self.trace {
+            Some(_) if self.cpu.state == CpuState::FetchOpcode && !*self.cpu.halted.loaded() => {
+                Some(self.trace_entry())
+            }
+            _ => None,
+        };
+
+        #[cfg(feature = "io-log")]
+        self.bus.io_log.set_cycle(self.cycles);
+
+        // Only accesses made by the CPU are watched, so drop any made by the debugger meanwhile
+        self.bus.take_watch_hit();
+
         self.cpu.tick(&mut self.bus)?;
 
+        if let (Some(entry), Some(writer)) = (trace_entry, self.trace.as_mut()) {
+            if writeln!(writer, "{}", entry).is_err() {
+                self.trace = None;
+            }
+        }
+
+        // The access which hit a watchpoint has been performed, so report it at the end
+        // of the cycle instead of rolling back the CPU like other events do
+        let watch_hit = self.bus.take_watch_hit();
+
+        if std::mem::take(&mut self.cpu.speed_switch) {
+            self.bus.switch_speed()?;
+        }
+
+        #[cfg(feature = "coverage")]
+        if let Some(pc) = fetch_pc {
+            self.bus.coverage.mark(pc, self.cpu.opcode);
+        }
+
         // Section 4.10 of "The Cycle-Accurate GameBoy Docs"
         // =================================================
         // The HALT bug triggers if a HALT instruction is executed when IME = 0 && (IE & IF) != 0.
@@ -68,29 +443,45 @@ impl GameBoy {
             self.cpu.halted.reset(false);
         }
 
-        self.bus.tick()?;
+        // Account for the elapsed cycle even if the bus raised an event
+        let res = self.bus.tick();
 
-        self.cycles += 4;
+        // Cycles are counted at normal speed, so that they keep measuring real time
+        self.cycles += if self.bus.double_speed() { 2 } else { 4 };
 
-        Ok(())
+        self.release_tapped_keys();
+
+        res?;
+        watch_hit.map_or(Ok(()), Err)
     }
 
     fn handle_irqs(&mut self) -> Result<(), dbg::TraceEvent> {
-        if let Some(id) = self.bus.itr.get_pending_irq() {
-            let addr = (0x40 + 0x08 * id) as u16;
-
+        if self.bus.itr.pending_irqs() {
             self.cpu.halted.reset(false);
 
             // If IME = 1, disable HALT mode (if in it), set IME = 0,
-            // clear IF and run the corresponding ISR.
+            // and run the ISR of the highest-priority interrupt, clearing its IF bit.
             // If IME = 0, simply leave HALT mode.
+            //
+            // Cycle breakdown when waking up from HALT (verified against Gekkio's
+            // halt_ime0_nointr_timing, halt_ime1_timing and halt_ime1_timing2):
+            //  - HALT itself takes 4 clocks, then the CPU idles 4 clocks at a time
+            //  - the request is noticed at the end of the idle cycle in which IF gets set,
+            //    which accounts for the 4 extra wake-up clocks compared to a running CPU
+            //  - with IME = 1, the 20 clocks dispatch follows (24 clocks in total),
+            //    otherwise the instruction after HALT is fetched right away (4 clocks in total)
             if *self.cpu.intr_enabled.value() {
                 self.cpu.intr_enabled.reset(false);
-                self.bus.itr.clear_irq(id);
 
                 // Jump to interrupt service routing and wait 5 cycles until
-                // the jump has been performed.
-                self.cpu.jump_to_isr(&mut self.bus, addr)?;
+                // the jump has been performed: 2 wait states, 2 cycles to push PC
+                // and 1 to set PC to the vector, for 20 clocks in total.
+                self.cpu.jump_to_isr(&mut self.bus)?;
+
+                // The dispatch is completed on the next step if the push hit a watchpoint
+                if let Some(hit) = self.bus.take_watch_hit() {
+                    return Err(hit);
+                }
 
                 while self.cpu.executing {
                     self.tick()?;
@@ -100,8 +491,26 @@ impl GameBoy {
         Ok(())
     }
 
+    /// Runs the emulation until the PPU moves to the next scanline (ie. LY changes).
+    ///
+    /// Since instructions are executed atomically, this returns at the end of the first
+    /// instruction completed on the new scanline. If an event occurs mid-line, it is returned
+    /// immediately and the remainder of the line is left to be executed.
+    /// While the LCD is off, the emulation is run for the duration of a scanline instead.
+    pub fn step_scanline(&mut self) -> Result<(), dbg::TraceEvent> {
+        let ly = self.bus.ppu.ly();
+
+        // LY stays at 0 while the LCD is off, so stop after a line's worth of cycles
+        let until = self.cycles + CYCLES_PER_HSYNC;
+
+        while self.bus.ppu.ly() == ly && self.cycles < until {
+            self.step()?;
+        }
+        Ok(())
+    }
+
     pub fn run_for_vblank(&mut self) -> Result<(), dbg::TraceEvent> {
-        let until = self.cycles + (CYCLES_PER_HSYNC * 154);
+        let until = self.cycles + CYCLES_PER_FRAME;
 
         while self.cycles < until {
             self.step()?;
@@ -109,14 +518,154 @@ impl GameBoy {
         Ok(())
     }
 
+    /// Runs the emulation for `n` frames' worth of cycles, returning the number of
+    /// clock cycles executed.
+    ///
+    /// This is meant for headless runs, eg. of test ROMs. If an event occurs,
+    /// it is returned immediately and the remaining frames are not executed.
+    pub fn run_frames(&mut self, n: u32) -> Result<u64, dbg::TraceEvent> {
+        let start = self.cycles;
+
+        for _ in 0..n {
+            self.run_for_vblank()?;
+        }
+        Ok(self.cycles - start)
+    }
+
+    /// Runs the emulation until `predicate` holds, checking it after every instruction,
+    /// or until at least `max_cycles` clock cycles have been executed.
+    ///
+    /// Returns the number of clock cycles executed: since both conditions end the run,
+    /// check the predicate again to tell whether it was met. If an event occurs,
+    /// it is returned immediately.
+    pub fn run_until<F>(&mut self, predicate: F, max_cycles: u64) -> Result<u64, dbg::TraceEvent>
+    where
+        F: Fn(&GameBoy) -> bool,
+    {
+        let start = self.cycles;
+
+        while !predicate(self) && self.cycles - start < max_cycles {
+            self.step()?;
+        }
+        Ok(self.cycles - start)
+    }
+
+    /// Takes a snapshot of the whole machine, which can be restored with `load_state`.
+    ///
+    /// The snapshot starts with the version of its layout (see `STATE_VERSION`),
+    /// so that incompatible snapshots are rejected when loaded.
+    pub fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("machine state is always serializable")
+    }
+
+    /// Restores a snapshot of the machine taken with `save_state`.
+    ///
+    /// The ROM is not part of the snapshot, so the one it was taken with must be loaded.
+    /// Only the emulated machine is restored: the audio sink, breakpoints, watchpoints,
+    /// frozen locations, cheats, rendering settings and rewind buffer are kept as they are.
+    /// If the snapshot is invalid, the current state is left untouched.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut gb: GameBoy = bincode::deserialize(state)?;
+
+        if !gb.bus.same_rom_as(&self.bus) {
+            return Err(StateError::RomMismatch);
+        }
+
+        gb.cpu.take_host_state(&mut self.cpu);
+        gb.bus.take_host_state(&mut self.bus);
+        gb.rewind = std::mem::take(&mut self.rewind);
+        gb.frame_buffer = std::mem::take(&mut self.frame_buffer);
+        gb.trace = self.trace.take();
+
+        *self = gb;
+        Ok(())
+    }
+
+    /// Takes over the debugging and host settings of `prev`, which are not part of the machine
+    /// state: breakpoints, watchpoints, frozen locations, cheats, hooks, rendering and audio
+    /// settings, the serial link and the instruction trace.
+    ///
+    /// The cartridge is not taken over, so this can be used to replace an instance with
+    /// a new one running the same ROM, eg. on a different model.
+    pub fn take_debug_state(&mut self, prev: &mut GameBoy) {
+        self.cpu.take_host_state(&mut prev.cpu);
+        self.bus.take_debug_state(&mut prev.bus);
+        self.trace = prev.trace.take();
+    }
+
+    /// Enables rewinding, keeping up to `max_frames` snapshots (one per frame)
+    /// and up to `max_bytes` bytes of them, whichever limit is hit first.
+    ///
+    /// Snapshots don't include the ROM, so they are usually a few dozen KBs each
+    /// (depending on the amount of cartridge RAM). Passing 0 for either limit disables rewinding.
+    pub fn set_rewind_limits(&mut self, max_frames: usize, max_bytes: usize) {
+        self.rewind.set_limits(max_frames, max_bytes);
+    }
+
+    /// Returns the number of frames that can currently be rewound.
+    pub fn rewind_frames(&self) -> usize {
+        self.rewind.len()
+    }
+
+    /// Goes back to the most recent rewind snapshot, dropping it from the buffer.
+    ///
+    /// Calling this once per frame scrubs the emulation backwards in real time.
+    /// Returns `false` if there is nothing left to rewind.
+    pub fn rewind(&mut self) -> bool {
+        let snapshot = match self.rewind.pop() {
+            Some(snapshot) => snapshot,
+            None => return false,
+        };
+
+        let res = self.load_state(&snapshot);
+        self.rewind.recycle(snapshot);
+
+        res.is_ok()
+    }
+
+    fn take_rewind_snapshot(&mut self) {
+        let mut buf = self.rewind.take_spare();
+
+        bincode::serialize_into(&mut buf, &*self).expect("machine state is always serializable");
+        self.rewind.push(buf);
+    }
+
     /// Sets the audio sink for the sound peripheral, along with the required sample rate.
     /// The emulation speed will be limited by the specified sample rate.
     /// This is very useful for "sync-by-audio"-style emulator.
+    ///
+    /// Samples are interleaved stereo pairs, left first, so `sample_rate` is in pairs per second.
     pub fn set_audio_sink(&mut self, sink: Arc<ArrayQueue<i16>>, sample_rate: f32) {
         self.bus.apu.set_sample_rate(sample_rate);
         self.bus.apu.set_audio_sink(sink);
     }
 
+    /// Returns the contents of the cartridge RAM, if it is battery-backed.
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.bus.save_ram()
+    }
+
+    /// Restores the cartridge RAM from a previous call to `save_ram`.
+    pub fn load_ram(&mut self, data: &[u8]) {
+        self.bus.load_ram(data);
+    }
+
+    /// Returns whether the cartridge rumble motor is currently turned on.
+    pub fn rumble_active(&self) -> bool {
+        self.bus.rumble_active()
+    }
+
+    /// Returns the state of the cartridge real-time clock, if it has a battery-backed one.
+    pub fn rtc_state(&self) -> Option<RtcState> {
+        self.bus.rtc_state()
+    }
+
+    /// Restores the cartridge real-time clock from a previous call to `rtc_state`,
+    /// advancing it by the time elapsed since then.
+    pub fn set_rtc_state(&mut self, state: RtcState) {
+        self.bus.set_rtc_state(state);
+    }
+
     /// Marks the given key as pressed.
     pub fn press_key(&mut self, key: JoypadState) {
         self.bus.joy.set_pressed_keys(key);
@@ -127,10 +676,209 @@ impl GameBoy {
         self.bus.joy.set_release_keys(key);
     }
 
+    /// Enables or disables capturing the text sent through the serial port,
+    /// to be retrieved with `take_serial_output`.
+    ///
+    /// Capture is disabled by default, since the text piles up until it is taken.
+    pub fn set_capture_serial_output(&mut self, enabled: bool) {
+        self.bus.sdt.set_capture_output(enabled);
+    }
+
+    /// Returns the text sent through the serial port since the last call, clearing it.
+    ///
+    /// Once capture is enabled with `set_capture_serial_output`, every byte written to SB
+    /// before starting a transfer with the internal clock is captured, which is how test ROMs
+    /// (eg. Blargg's) report their results. Bytes which are not valid UTF-8 are replaced
+    /// with `U+FFFD`.
+    pub fn take_serial_output(&mut self) -> String {
+        String::from_utf8_lossy(&self.bus.sdt.take_output()).into_owned()
+    }
+
+    /// Plugs in a link cable connected to `link`, replacing the current one.
+    ///
+    /// The link is not part of the machine state, so it is kept across resets and state loads.
+    pub fn set_serial_link(&mut self, link: Box<dyn SerialLink>) {
+        self.bus.sdt.set_link(Some(link));
+    }
+
+    /// Unplugs the link cable, if any, returning its other end.
+    pub fn take_serial_link(&mut self) -> Option<Box<dyn SerialLink>> {
+        self.bus.sdt.take_link()
+    }
+
+    /// Marks the given key as pressed or not pressed.
+    ///
+    /// Pressing a key in a row selected through P1 (0xFF00) requests the joypad interrupt.
+    pub fn set_button(&mut self, key: JoypadState, pressed: bool) {
+        if pressed {
+            self.press_key(key);
+        } else {
+            self.release_key(key);
+        }
+    }
+
+    /// Presses the given key for exactly one frame, then releases it automatically.
+    ///
+    /// The release happens one full frame (70224 clock cycles) after the tap, whatever the
+    /// current beam position is. This way, a game polling the joypad once per frame (usually
+    /// during VBlank) sees the key pressed exactly once, and a tap followed by a call to
+    /// `run_for_vblank` leaves the key released afterwards.
+    ///
+    /// Tapping a key which is already tapped restarts its frame. The key is released when
+    /// the tap expires even if it was also pressed with `press_key` in the meantime.
+    pub fn tap_button(&mut self, key: JoypadState) {
+        self.taps.retain(|(tapped, _)| *tapped != key);
+        self.taps.push((key, self.cycles + CYCLES_PER_FRAME));
+
+        self.press_key(key);
+    }
+
+    /// Releases tapped keys whose frame is over.
+    fn release_tapped_keys(&mut self) {
+        let cycles = self.cycles;
+        let joy = &mut self.bus.joy;
+
+        self.taps.retain(|(key, until)| {
+            if cycles >= *until {
+                joy.set_release_keys(*key);
+            }
+            cycles < *until
+        });
+    }
+
+    /// Breaks execution when the beam reaches `dot` on scanline `ly`.
+    pub fn set_dot_breakpoint(&mut self, ly: u8, dot: u16) {
+        self.bus.ppu.set_dot_breakpoint(ly, dot);
+    }
+
+    /// Clears the dot breakpoint, if any.
+    pub fn clear_dot_breakpoint(&mut self) {
+        self.bus.ppu.clear_dot_breakpoint();
+    }
+
+    /// Sets a watchpoint on `addr` (see `Bus::set_watchpoint`).
+    pub fn set_watchpoint(&mut self, addr: u16, kind: dbg::WatchKind) {
+        self.bus.set_watchpoint(addr, kind);
+    }
+
+    /// Clears the watchpoint on `addr`, if any.
+    pub fn clear_watchpoint(&mut self, addr: u16) {
+        self.bus.clear_watchpoint(addr);
+    }
+
+    /// Enables or disables video rendering, eg. to speed up headless runs.
+    ///
+    /// While video is disabled, `rasterize` leaves the buffer untouched (so it will contain
+    /// stale data), but the PPU timings and interrupts are still emulated accurately.
+    pub fn set_video_enabled(&mut self, enabled: bool) {
+        self.bus.ppu.set_rendering_enabled(enabled);
+    }
+
+    /// Enables or disables reporting of writes to the sound registers while the APU is off.
+    ///
+    /// See `APU::set_report_writes_while_off` for details.
+    pub fn set_report_apu_writes_while_off(&mut self, enabled: bool) {
+        self.bus.apu.set_report_writes_while_off(enabled);
+    }
+
+    /// Returns whether writes to the sound registers while the APU is off are reported.
+    pub fn report_apu_writes_while_off(&self) -> bool {
+        self.bus.apu.report_writes_while_off()
+    }
+
+    /// Enables or disables the high-pass filter applied to the audio output.
+    ///
+    /// See `APU::set_high_pass_filter` for details.
+    pub fn set_audio_high_pass_filter(&mut self, enabled: bool) {
+        self.bus.apu.set_high_pass_filter(enabled);
+    }
+
+    /// Returns whether the high-pass filter is applied to the audio output.
+    pub fn audio_high_pass_filter(&self) -> bool {
+        self.bus.apu.high_pass_filter()
+    }
+
+    /// Returns whether video rendering is enabled.
+    pub fn video_enabled(&self) -> bool {
+        self.bus.ppu.rendering_enabled()
+    }
+
+    /// Overrides the order in which overlapping sprites are drawn.
+    ///
+    /// See `PPU::set_sprite_order` for details.
+    pub fn set_sprite_order(&mut self, order: SpriteOrder) {
+        self.bus.ppu.set_sprite_order(order);
+    }
+
+    /// Returns the order in which overlapping sprites are drawn.
+    pub fn sprite_order(&self) -> SpriteOrder {
+        self.bus.ppu.sprite_order()
+    }
+
     pub fn rasterize(&self, vbuf: &mut [u8]) {
         self.bus.ppu.rasterize(vbuf);
     }
 
+    /// Returns the current frame at the native resolution, as a buffer in U8U8U8U8 RGBA format.
+    ///
+    /// Rendering is done entirely in software, so this works headless too, eg. to compare
+    /// the output of test ROMs against reference images. Each line is drawn when the PPU
+    /// is done with it, so in the middle of a frame, the lines below LY still hold the
+    /// previous one. If video was disabled with `set_video_enabled`, the returned frame
+    /// is all white.
+    pub fn frame(&self) -> Vec<u8> {
+        let mut vbuf = vec![0xFF; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        self.bus.ppu.rasterize(&mut vbuf);
+        vbuf
+    }
+
+    /// Returns the last complete frame, as a 160x144 buffer in U8U8U8U8 RGBA format
+    /// (4 bytes per pixel, row by row from the top-left corner).
+    ///
+    /// Unlike `frame`, which includes the lines drawn so far, this buffer is only
+    /// updated at the start of each VBlank, so it never shows a frame being drawn.
+    /// Before the first frame, it is all white, like when the LCD is off.
+    /// If video was disabled with `set_video_enabled`, it is left untouched.
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame_buffer
+    }
+
+    fn clear_frame_buffer(&mut self) {
+        for b in self.frame_buffer.iter_mut() {
+            *b = 0xFF;
+        }
+    }
+
+    /// Returns the full 256x256 background map rendered with the current VRAM contents
+    /// and palette, as a buffer in U8U8U8U8 RGBA format.
+    ///
+    /// If `scrolled` is true, the map is wrapped so that the viewport starts at the origin.
+    pub fn dump_background(&self, scrolled: bool) -> Vec<u8> {
+        let mut vbuf = vec![0; 256 * 256 * 4];
+        self.bus.ppu.rasterize_bg_map(&mut vbuf, scrolled);
+        vbuf
+    }
+
+    /// Returns the full 256x256 window map rendered with the current VRAM contents
+    /// and palette, as a buffer in U8U8U8U8 RGBA format.
+    pub fn dump_window(&self) -> Vec<u8> {
+        let mut vbuf = vec![0; 256 * 256 * 4];
+        self.bus.ppu.rasterize_win_map(&mut vbuf);
+        vbuf
+    }
+
+    /// Returns the fixed addresses at which execution can start in any ROM,
+    /// ie. the RST and interrupt vectors, and the cartridge entry point.
+    ///
+    /// See [`dbg::ENTRY_POINTS`] for a description of each of them.
+    pub fn entry_points() -> Vec<u16> {
+        dbg::ENTRY_POINTS.iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Returns the number of clock cycles elapsed since power on.
+    ///
+    /// Cycles are counted at the normal 4.19 MHz clock, even in CGB double speed mode,
+    /// where each CPU M-cycle only accounts for 2 clock cycles.
     pub fn clock_cycles(&self) -> u64 {
         self.cycles
     }
@@ -146,4 +894,1219 @@ impl GameBoy {
     pub fn bus(&self) -> &Bus {
         &self.bus
     }
+
+    /// Returns an iterator over the instructions in ROM bank `bank`, along with their address.
+    ///
+    /// The bank is read directly from the cartridge, so it doesn't need to be mapped in.
+    /// Addresses are the ones the bank would have once mapped, ie. starting at 0x0000
+    /// for bank 0 and at 0x4000 for any other bank.
+    ///
+    /// NOTE: this is a naive linear sweep, so any data stored in the bank is decoded
+    /// as instructions too, and it may throw the sweep out of alignment with the real code.
+    pub fn disasm_bank(&self, bank: usize) -> impl Iterator<Item = (u16, Instruction)> + '_ {
+        let base = if bank == 0 { 0x0000 } else { 0x4000 };
+        let view = self.bus.rom_bank(bank).map(|mem| RomBankView { base, mem });
+        let mut addr = base;
+
+        std::iter::from_fn(move || {
+            // Stops at the end of the bank, or at an instruction crossing it
+            let instr = self.cpu.disasm(view.as_ref()?, addr).ok()?;
+            let at = addr;
+
+            addr += u16::from(instr.size);
+            Some((at, instr))
+        })
+    }
+}
+
+/// A ROM bank seen at the address it would be mapped to.
+struct RomBankView<'a> {
+    base: u16,
+    mem: &'a Memory,
+}
+
+impl MemR for RomBankView<'_> {
+    fn read(&self, addr: u16) -> Result<u8, dbg::TraceEvent> {
+        match addr.checked_sub(self.base) {
+            Some(offset) if usize::from(offset) < self.mem.as_slice().len() => {
+                self.mem.read(offset)
+            }
+            _ => Err(dbg::TraceEvent::MemFault(addr)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROM: &[u8] = include_bytes!("../../roms/blargg/cpu_instrs.gb");
+
+    #[test]
+    fn state_roundtrip_resumes_identically() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        for _ in 0..30 {
+            gb.run_for_vblank().unwrap();
+        }
+
+        let mut restored = GameBoy::new();
+        restored.load_rom(ROM).unwrap();
+        restored.load_state(&gb.save_state()).unwrap();
+
+        for _ in 0..30 {
+            gb.run_for_vblank().unwrap();
+            restored.run_for_vblank().unwrap();
+        }
+
+        let mut expected = vec![0; 160 * 144 * 4];
+        let mut actual = vec![0; 160 * 144 * 4];
+
+        gb.rasterize(&mut expected);
+        restored.rasterize(&mut actual);
+
+        assert_eq!(gb.clock_cycles(), restored.clock_cycles());
+        assert_eq!(gb.cpu().pc, restored.cpu().pc);
+        assert_eq!(gb.cpu().af, restored.cpu().af);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn divergence_from_reference_log_is_reported() {
+        let mut reference = GameBoy::new();
+        reference.load_rom(ROM).unwrap();
+
+        let mut log = String::new();
+        for _ in 0..100 {
+            log += &format!("{} PCMEM:00,00,00,00\n", reference.trace_line());
+            reference.step().unwrap();
+        }
+
+        let path = std::env::temp_dir().join(format!("gib-trace-{}.log", std::process::id()));
+        std::fs::write(&path, &log).unwrap();
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+        assert_eq!(gb.compare_to_log(&path).unwrap(), None);
+
+        // Alter the register A on line 50
+        let mut lines: Vec<String> = log.lines().map(String::from).collect();
+        lines[49].replace_range(2..4, "5A");
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+        let mismatch = gb.compare_to_log(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mismatch.line, 50);
+        assert_eq!(mismatch.expected.a, 0x5A);
+        assert_eq!(mismatch.expected.diff(&mismatch.actual), ["A"]);
+    }
+
+    #[test]
+    fn reset_behaves_like_power_cycle() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        for _ in 0..30 {
+            gb.run_for_vblank().unwrap();
+        }
+
+        gb.reset();
+
+        let mut fresh = GameBoy::new();
+        fresh.load_rom(ROM).unwrap();
+
+        for _ in 0..30 {
+            gb.run_for_vblank().unwrap();
+            fresh.run_for_vblank().unwrap();
+        }
+
+        let mut expected = vec![0; 160 * 144 * 4];
+        let mut actual = vec![0; 160 * 144 * 4];
+
+        fresh.rasterize(&mut expected);
+        gb.rasterize(&mut actual);
+
+        assert_eq!(gb.clock_cycles(), fresh.clock_cycles());
+        assert_eq!(gb.cpu().pc, fresh.cpu().pc);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn step_scanline_advances_ly_by_one() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        for _ in 0..200 {
+            let ly = gb.bus().ppu.ly();
+            let lcd_on = gb.bus().ppu.lcd_enabled();
+
+            gb.step_scanline().unwrap();
+
+            if !gb.bus().ppu.lcd_enabled() {
+                assert_eq!(gb.bus().ppu.ly(), 0);
+            } else if lcd_on {
+                assert_eq!(gb.bus().ppu.ly(), (ly + 1) % 154);
+            }
+        }
+    }
+
+    #[test]
+    fn reset_keeps_model_and_register_defaults() {
+        let mut gb = GameBoy::with_model(Model::Cgb);
+        gb.load_rom(ROM).unwrap();
+        gb.run_for_vblank().unwrap();
+
+        gb.reset();
+        assert_eq!(gb.model(), Model::Cgb);
+        assert_eq!(gb.cpu().af, 0x1180);
+
+        let gb = GameBoy::with_model(Model::Dmg);
+        assert_eq!(gb.cpu().af, 0x01B0);
+    }
+
+    #[test]
+    fn debug_state_survives_reset_and_model_switch() {
+        use crate::{
+            cpu::{Comparison, Condition, Operand, Register},
+            dbg::WatchKind,
+        };
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        let cond = Condition::new(Operand::Register(Register::A), Comparison::Eq, 0x10);
+        gb.cpu_mut().set_breakpoint(0x0150);
+        gb.cpu_mut().set_conditional_breakpoint(0x0200, cond);
+        gb.cpu_mut()
+            .set_exec_regions(Some(dbg::ExecRegions::DEFAULT));
+        gb.bus.freeze(0xC000);
+        gb.set_watchpoint(0xC100, WatchKind::Write);
+        gb.add_cheat("01630AD0").unwrap();
+        gb.bus.ppu.set_scanline_capture(true);
+        gb.set_dot_breakpoint(10, 100);
+        #[cfg(feature = "hooks")]
+        gb.bus
+            .set_read_hook(|addr, val| if addr == 0xC200 { 0x42 } else { val });
+        #[cfg(feature = "io-log")]
+        gb.bus.io_log_mut().set_enabled(true);
+        #[cfg(feature = "coverage")]
+        gb.step().unwrap();
+
+        let check = |gb: &GameBoy| {
+            assert!(gb.cpu().breakpoints().contains(&0x0150));
+            assert_eq!(gb.cpu().conditional_breakpoints().get(&0x0200), Some(&cond));
+            assert_eq!(gb.cpu().exec_regions(), Some(dbg::ExecRegions::DEFAULT));
+            assert!(gb.bus.frozen().contains(&0xC000));
+            assert_eq!(gb.bus.watchpoints().get(&0xC100), Some(&WatchKind::Write));
+            assert_eq!(gb.bus.cheats().len(), 1);
+            assert!(gb.bus.ppu.scanline_capture_enabled());
+            assert_eq!(gb.bus.ppu.dot_breakpoint(), Some((10, 100)));
+            #[cfg(feature = "hooks")]
+            assert_eq!(crate::mem::MemR::read(&gb.bus, 0xC200).unwrap(), 0x42);
+            #[cfg(feature = "io-log")]
+            assert!(gb.bus.io_log().is_enabled());
+            #[cfg(feature = "coverage")]
+            assert!(gb.bus.executed_addresses().next().is_some());
+        };
+
+        gb.reset();
+        check(&gb);
+
+        gb.hard_reset();
+        check(&gb);
+
+        let mut cgb = GameBoy::with_model(Model::Cgb);
+        cgb.load_rom(ROM).unwrap();
+        cgb.take_debug_state(&mut gb);
+        check(&cgb);
+    }
+
+    #[test]
+    fn model_is_detected_from_header() {
+        let mut rom = ROM.to_vec();
+
+        rom[0x143] = 0x00;
+        assert_eq!(Model::detect(&rom), Model::Dmg);
+        rom[0x143] = 0x80;
+        assert_eq!(Model::detect(&rom), Model::Cgb);
+        rom[0x143] = 0xC0;
+        assert_eq!(Model::detect(&rom), Model::Cgb);
+    }
+
+    #[test]
+    fn rom_is_loaded_at_offset() {
+        let mut dump = vec![0xAA; 0x8000];
+        dump.extend_from_slice(ROM);
+        dump.extend_from_slice(&[0x55; 0x4000]);
+
+        let mut gb = GameBoy::new();
+        assert!(matches!(
+            gb.load_rom_at_offset(&dump, 0x4000),
+            Err(dbg::TraceEvent::InvalidRomHeader(0x4000))
+        ));
+        assert!(gb.load_rom_at_offset(&dump, dump.len() + 1).is_err());
+
+        let mut gb = GameBoy::new();
+        gb.load_rom_at_offset(&dump, 0x8000).unwrap();
+
+        let mut fresh = GameBoy::new();
+        fresh.load_rom(ROM).unwrap();
+
+        for _ in 0..30 {
+            gb.run_for_vblank().unwrap();
+            fresh.run_for_vblank().unwrap();
+        }
+
+        assert_eq!(gb.cpu().pc, fresh.cpu().pc);
+        assert_eq!(gb.cpu().af, fresh.cpu().af);
+    }
+
+    #[test]
+    fn bank_is_disassembled_without_mapping_it() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // Bank 1 is mapped in, so it can be compared with the bus contents
+        let mut next = 0x4000;
+        for (addr, instr) in gb.disasm_bank(1) {
+            assert_eq!(addr, next);
+            assert_eq!(instr, gb.cpu().disasm(gb.bus(), addr).unwrap());
+            next += u16::from(instr.size);
+        }
+        assert!(next > 0x7FFD);
+
+        // Bank 2 is not, but it is still disassembled from the ROM contents
+        let (addr, instr) = gb.disasm_bank(2).next().unwrap();
+        assert_eq!(addr, 0x4000);
+        assert_eq!(instr.opcode, ROM[0x8000]);
+
+        let (addr, instr) = gb.disasm_bank(0).next().unwrap();
+        assert_eq!(addr, 0x0000);
+        assert_eq!(instr.opcode, ROM[0x0000]);
+
+        assert_eq!(gb.disasm_bank(ROM.len() / 0x4000).count(), 0);
+    }
+
+    #[test]
+    fn inserted_rom_runs_like_a_fresh_one() {
+        use crate::mem::MemW;
+
+        // A battery-backed cart with 8KB of RAM
+        let mut cart = vec![0; 0x8000];
+        cart[0x100..0x104].copy_from_slice(&[0x00, 0x18, 0xFE, 0x00]);
+        cart[0x147] = 0x03;
+        cart[0x149] = 0x02;
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(&cart).unwrap();
+        gb.bus.write(0xA010, 0x42).unwrap();
+        gb.cpu_mut().set_breakpoint(0x0101);
+
+        for _ in 0..5 {
+            gb.run_for_vblank().ok();
+        }
+
+        let ram = gb.insert_rom(ROM).unwrap().unwrap();
+        assert_eq!(ram.len(), 0x2000);
+        assert_eq!(ram[0x10], 0x42);
+        assert!(gb.cpu().breakpoints().is_empty());
+        assert!(gb.save_ram().is_none());
+
+        let mut fresh = GameBoy::new();
+        fresh.load_rom(ROM).unwrap();
+
+        for _ in 0..30 {
+            gb.run_for_vblank().unwrap();
+            fresh.run_for_vblank().unwrap();
+        }
+
+        assert_eq!(gb.clock_cycles(), fresh.clock_cycles());
+        assert_eq!(gb.cpu().pc, fresh.cpu().pc);
+        assert_eq!(gb.cpu().af, fresh.cpu().af);
+
+        // Unsupported carts are rejected without ejecting the current one
+        cart[0x147] = 0xFC;
+        assert!(gb.insert_rom(&cart).is_err());
+        gb.run_for_vblank().unwrap();
+    }
+
+    #[test]
+    fn halt_wake_up_takes_the_reference_cycles() {
+        use crate::mem::MemW;
+
+        for &ime in &[true, false] {
+            // ei/di; halt; nop; jr -3
+            let mut rom = vec![0; 0x8000];
+            rom[0x100] = if ime { 0xFB } else { 0xF3 };
+            rom[0x101..0x105].copy_from_slice(&[0x76, 0x00, 0x18, 0xFD]);
+
+            let mut gb = GameBoy::new();
+            gb.load_rom(&rom).unwrap();
+
+            // Fire a timer interrupt a few cycles into the HALT
+            gb.bus.write(0xFFFF, 0x04).unwrap();
+            gb.bus.write(0xFF0F, 0x00).unwrap();
+            gb.bus.write(0xFF05, 0xFE).unwrap();
+            gb.bus.write(0xFF07, 0x05).unwrap();
+
+            gb.step().unwrap();
+            gb.step().unwrap();
+            assert!(*gb.cpu().halted.loaded());
+
+            let mut halted_steps = 0;
+            let elapsed = loop {
+                let start = gb.clock_cycles();
+                gb.step().unwrap();
+
+                if !*gb.cpu().halted.loaded() {
+                    break gb.clock_cycles() - start;
+                }
+                halted_steps += 1;
+            };
+            assert!(halted_steps > 0);
+
+            if ime {
+                // 4 clocks in HALT noticing the request, then the 20 clocks dispatch
+                assert_eq!(elapsed, 24);
+                assert_eq!(gb.cpu().pc, 0x0050);
+            } else {
+                // 4 clocks in HALT noticing the request, then execution simply resumes
+                assert_eq!(elapsed, 4);
+                assert_eq!(gb.cpu().pc, 0x0102);
+            }
+        }
+    }
+
+    #[test]
+    fn halt_with_pending_interrupt_and_ime_off_reads_next_byte_twice() {
+        use crate::mem::MemW;
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // HALT; LD B, 0x04 is executed as LD B, 0x06; INC B
+        for (i, &b) in [0x76, 0x06, 0x04].iter().enumerate() {
+            gb.bus.write(0xC000 + i as u16, b).unwrap();
+        }
+        gb.cpu_mut().pc = 0xC000;
+        gb.cpu_mut().intr_enabled.reset(false);
+
+        // A timer interrupt is pending but disabled by IME
+        gb.bus.write(0xFFFF, 0x04).unwrap();
+        gb.bus.write(0xFF0F, 0x04).unwrap();
+
+        for _ in 0..3 {
+            gb.step().unwrap();
+        }
+
+        assert!(!*gb.cpu().halted.value());
+        assert_eq!(gb.cpu().b(), 0x07);
+        assert_eq!(gb.cpu().pc, 0xC003);
+    }
+
+    #[test]
+    fn interrupts_can_be_requested_by_writing_if() {
+        use crate::mem::{MemR, MemW};
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // Only the lower 5 bits of IF are writable
+        gb.bus.write(0xFF0F, 0xFF).unwrap();
+        assert_eq!(gb.bus.itr.ifg.0, 0x1F);
+        gb.bus.write(0xFF0F, 0x00).unwrap();
+        assert_eq!(gb.bus.read(0xFF0F).unwrap(), 0xE0);
+
+        // NOP; NOP
+        gb.bus.write(0xC000, 0x00).unwrap();
+        gb.bus.write(0xC001, 0x00).unwrap();
+        gb.cpu_mut().pc = 0xC000;
+        gb.cpu_mut().intr_enabled.reset(true);
+
+        // Request a timer interrupt by hand
+        gb.bus.write(0xFFFF, 0x04).unwrap();
+        gb.bus.write(0xFF0F, 0x04).unwrap();
+        assert_eq!(gb.bus.read(0xFF0F).unwrap(), 0xE4);
+
+        gb.step().unwrap();
+
+        assert_eq!(gb.cpu().pc, 0x0050);
+        assert!(!*gb.cpu().intr_enabled.value());
+        assert_eq!(gb.bus.read(0xFF0F).unwrap() & 0x04, 0x00);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        use crate::mem::{MemR, MemW};
+
+        // (program, steps, PC after the steps)
+        let cases: [(&[u8], usize, u16); 3] = [
+            (&[0xFB, 0xF3, 0x00], 3, 0xC003), // EI; DI; NOP: no interrupt is serviced
+            (&[0xFB, 0x00, 0x00], 1, 0xC001), // EI: the interrupt is still pending...
+            (&[0xFB, 0x00, 0x00], 2, 0x0050), // EI; NOP: ...until after the NOP
+        ];
+
+        for &(program, steps, pc) in cases.iter() {
+            let mut gb = GameBoy::new();
+            gb.load_rom(ROM).unwrap();
+
+            for (i, &b) in program.iter().enumerate() {
+                gb.bus.write(0xC000 + i as u16, b).unwrap();
+            }
+            gb.cpu_mut().pc = 0xC000;
+            gb.cpu_mut().intr_enabled.reset(false);
+
+            gb.bus.write(0xFFFF, 0x04).unwrap();
+            gb.bus.write(0xFF0F, 0x04).unwrap();
+
+            for _ in 0..steps {
+                gb.step().unwrap();
+            }
+            assert_eq!(gb.cpu().pc, pc);
+        }
+
+        // The return address is the one of the instruction following the NOP
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+        gb.bus.write(0xC000, 0xFB).unwrap();
+        gb.bus.write(0xC001, 0x00).unwrap();
+        gb.cpu_mut().pc = 0xC000;
+        gb.bus.write(0xFFFF, 0x04).unwrap();
+        gb.bus.write(0xFF0F, 0x04).unwrap();
+
+        gb.step().unwrap();
+        gb.step().unwrap();
+
+        let sp = gb.cpu().sp;
+        assert_eq!(gb.bus.read(sp).unwrap(), 0x02);
+        assert_eq!(gb.bus.read(sp + 1).unwrap(), 0xC0);
+    }
+
+    #[test]
+    fn conditional_breakpoints_only_hit_when_the_condition_holds() {
+        use crate::{
+            cpu::{Comparison, Condition, Operand, Register},
+            dbg::TraceEvent,
+            mem::MemW,
+        };
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // INC A; JR -3
+        for (i, &b) in [0x3C, 0x18, 0xFD].iter().enumerate() {
+            gb.bus.write(0xC000 + i as u16, b).unwrap();
+        }
+        gb.cpu_mut().pc = 0xC000;
+        gb.cpu_mut().af = 0x0000;
+
+        let cond = Condition::new(Operand::Register(Register::A), Comparison::Eq, 0x10);
+        gb.cpu_mut().set_conditional_breakpoint(0xC000, cond);
+        assert!(gb.cpu().breakpoint_at(0xC000));
+
+        let res = (0..100).find_map(|_| gb.step().err());
+        assert!(matches!(res, Some(TraceEvent::Breakpoint(0xC000))));
+        assert_eq!(gb.cpu().a(), 0x10);
+
+        // Execution resumes past the breakpoint, which is not hit again until A wraps
+        gb.bus.write(0xD000, 0x42).unwrap();
+        let cond = Condition::new(Operand::Memory(0xD000), Comparison::Ne, 0x42);
+        gb.cpu_mut().set_conditional_breakpoint(0xC000, cond);
+        assert!((0..100).all(|_| gb.step().is_ok()));
+
+        gb.cpu_mut().clear_breakpoint(0xC000);
+        assert!(!gb.cpu().breakpoint_at(0xC000));
+    }
+
+    #[test]
+    fn conditional_breakpoints_dont_trigger_watchpoints() {
+        use crate::{
+            cpu::{Comparison, Condition, Operand},
+            dbg::{TraceEvent, WatchKind},
+            mem::MemW,
+        };
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // INC A; JR -3
+        for (i, &b) in [0x3C, 0x18, 0xFD].iter().enumerate() {
+            gb.bus.write(0xC000 + i as u16, b).unwrap();
+        }
+        gb.cpu_mut().pc = 0xC000;
+        gb.bus.write(0xD000, 0x42).unwrap();
+        gb.set_watchpoint(0xD000, WatchKind::Read);
+
+        // Evaluating the condition doesn't count as a read of the watched address
+        let cond = Condition::new(Operand::Memory(0xD000), Comparison::Ne, 0x42);
+        gb.cpu_mut().set_conditional_breakpoint(0xC000, cond);
+        assert!((0..100).all(|_| gb.step().is_ok()));
+
+        let cond = Condition::new(Operand::Memory(0xD000), Comparison::Eq, 0x42);
+        gb.cpu_mut().set_conditional_breakpoint(0xC000, cond);
+        let res = (0..100).find_map(|_| gb.step().err());
+        assert!(matches!(res, Some(TraceEvent::Breakpoint(0xC000))));
+    }
+
+    #[test]
+    fn cheats_patch_rom_and_write_ram() {
+        use crate::mem::{MemR, MemW};
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+        assert_eq!(gb.bus.read(0x0150).unwrap(), 0x00);
+        assert_eq!(gb.bus.read(0x4A17).unwrap(), 0x00);
+
+        // 0x0150 holds 0x00 as expected, but 0x4A17 doesn't hold 0xC8
+        gb.add_cheat("421-50F-EEA").unwrap();
+        gb.add_cheat("FFA-17B-C49").unwrap();
+        assert_eq!(gb.bus.read(0x0150).unwrap(), 0x42);
+        assert_eq!(gb.bus.read(0x4A17).unwrap(), 0x00);
+
+        // RAM is written once per frame, while spinning on JR -2
+        gb.bus.write(0xC000, 0x18).unwrap();
+        gb.bus.write(0xC001, 0xFE).unwrap();
+        gb.cpu_mut().pc = 0xC000;
+
+        gb.add_cheat("01630AD0").unwrap();
+        gb.run_for_vblank().unwrap();
+        assert_eq!(gb.bus.read(0xD00A).unwrap(), 0x63);
+
+        gb.bus.write(0xD00A, 0x00).unwrap();
+        gb.run_for_vblank().unwrap();
+        assert_eq!(gb.bus.read(0xD00A).unwrap(), 0x63);
+
+        assert!(gb.remove_cheat("421-50f-eea"));
+        assert!(!gb.remove_cheat("421-50F-EEA"));
+        assert_eq!(gb.bus.read(0x0150).unwrap(), 0x00);
+        assert_eq!(gb.bus.cheats().len(), 2);
+
+        assert!(gb.add_cheat("not a cheat").is_err());
+    }
+
+    #[test]
+    fn step_targets_step_over_and_out_of_calls() {
+        use crate::{dbg::TraceEvent, mem::MemW};
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        let code: &[(u16, &[u8])] = &[
+            (0xC000, &[0xCD, 0x10, 0xC0]), // CALL $C010
+            (0xC003, &[0x18, 0xFE]),       // JR -2
+            (0xC010, &[0xCD, 0x20, 0xC0]), // CALL $C020
+            (0xC013, &[0xC9]),             // RET
+            (0xC020, &[0x3C, 0xC9]),       // INC A; RET
+        ];
+        for (addr, bytes) in code {
+            for (i, &b) in bytes.iter().enumerate() {
+                gb.bus.write(addr + i as u16, b).unwrap();
+            }
+        }
+        gb.cpu_mut().pc = 0xC000;
+        gb.cpu_mut().sp = 0xDFFE;
+        gb.cpu_mut().af = 0x0000;
+
+        // Step over the outer call, running the whole of it
+        let target = gb.cpu().step_over_target(&gb.bus).unwrap();
+        assert_eq!(target, Some((0xC003, 0xDFFE)));
+        gb.cpu_mut().set_step_target(target);
+
+        let res = (0..100).find_map(|_| gb.step().err());
+        assert!(matches!(res, Some(TraceEvent::StepComplete(0xC003))));
+        assert_eq!(gb.cpu().a(), 0x01);
+        assert_eq!(gb.cpu().step_over_target(&gb.bus).unwrap(), None);
+
+        // The target is cleared once hit, so spinning on JR -2 doesn't hit it again
+        assert!((0..100).all(|_| gb.step().is_ok()));
+
+        // Step into both calls, then out of the inner one
+        gb.cpu_mut().pc = 0xC000;
+        gb.step().unwrap();
+        gb.step().unwrap();
+        assert_eq!(gb.cpu().pc, 0xC020);
+
+        let target = gb.cpu().step_out_target();
+        assert_eq!(target, Some((0xC013, 0xDFFC)));
+        gb.cpu_mut().set_step_target(target);
+
+        let res = (0..100).find_map(|_| gb.step().err());
+        assert!(matches!(res, Some(TraceEvent::StepComplete(0xC013))));
+        assert_eq!(gb.cpu().a(), 0x02);
+        assert_eq!(gb.cpu().call_stack(), vec![0xC003]);
+        assert!((0..100).all(|_| gb.step().is_ok()));
+
+        // Breakpoints are still hit on the way
+        gb.cpu_mut().pc = 0xC000;
+        gb.cpu_mut().set_breakpoint(0xC020);
+        gb.cpu_mut().set_step_target(Some((0xC003, 0xDFFE)));
+
+        let res = (0..100).find_map(|_| gb.step().err());
+        assert!(matches!(res, Some(TraceEvent::Breakpoint(0xC020))));
+    }
+
+    #[test]
+    fn write_watchpoints_fire_after_the_write() {
+        use crate::{
+            dbg::{TraceEvent, WatchKind},
+            mem::{MemR, MemW},
+        };
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // LD A,(0xC000); LD (0xC000),A; NOP
+        for (i, &b) in [0xFA, 0x00, 0xC0, 0xEA, 0x00, 0xC0, 0x00]
+            .iter()
+            .enumerate()
+        {
+            gb.bus.write(0xC100 + i as u16, b).unwrap();
+        }
+        gb.cpu_mut().pc = 0xC100;
+        gb.cpu_mut().af = 0x4200;
+        gb.bus.write(0xC000, 0x24).unwrap();
+
+        gb.set_watchpoint(0xC000, WatchKind::Write);
+
+        // Reads (including the debugger's ones) don't trigger a write watchpoint
+        assert_eq!(gb.bus.read(0xC000).unwrap(), 0x24);
+        gb.step().unwrap();
+        assert_eq!(gb.cpu().a(), 0x24);
+
+        gb.cpu_mut().af = 0x4200;
+        let res = gb.step();
+        assert!(matches!(
+            res,
+            Err(TraceEvent::Watchpoint {
+                addr: 0xC000,
+                is_write: true,
+                value: 0x42,
+            })
+        ));
+        assert_eq!(gb.bus.read(0xC000).unwrap(), 0x42);
+
+        // Execution goes on from where it stopped
+        gb.step().unwrap();
+        assert_eq!(gb.cpu().pc, 0xC107);
+
+        // Read watchpoints
+        gb.cpu_mut().pc = 0xC100;
+        gb.set_watchpoint(0xC000, WatchKind::Read);
+        assert!(matches!(
+            gb.step(),
+            Err(TraceEvent::Watchpoint {
+                addr: 0xC000,
+                is_write: false,
+                value: 0x42,
+            })
+        ));
+
+        gb.clear_watchpoint(0xC000);
+        gb.cpu_mut().pc = 0xC100;
+        assert!(gb.step().is_ok());
+    }
+
+    #[test]
+    fn interrupts_are_dispatched_by_priority() {
+        use crate::mem::{MemR, MemW};
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        gb.bus.write(0xC000, 0x00).unwrap();
+        gb.cpu_mut().pc = 0xC000;
+        gb.cpu_mut().intr_enabled.reset(true);
+
+        // Serial and Joypad are requested, but only Joypad is enabled
+        gb.bus.write(0xFFFF, 0x10).unwrap();
+        gb.bus.write(0xFF0F, 0x18).unwrap();
+        gb.step().unwrap();
+        assert_eq!(gb.cpu().pc, 0x0060);
+        assert_eq!(gb.bus.read(0xFF0F).unwrap() & 0x18, 0x08);
+
+        // Timer wins over Serial
+        gb.cpu_mut().pc = 0xC000;
+        gb.cpu_mut().intr_enabled.reset(true);
+        gb.bus.write(0xFFFF, 0x0C).unwrap();
+        gb.bus.write(0xFF0F, 0x0C).unwrap();
+        gb.step().unwrap();
+        assert_eq!(gb.cpu().pc, 0x0050);
+        assert_eq!(gb.bus.read(0xFF0F).unwrap() & 0x0C, 0x08);
+    }
+
+    #[test]
+    fn pushing_pc_over_ie_cancels_the_dispatch() {
+        use crate::mem::{MemR, MemW};
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        gb.bus.write(0xC000, 0x00).unwrap();
+        gb.cpu_mut().pc = 0xC000;
+        gb.cpu_mut().sp = 0x0000;
+        gb.cpu_mut().intr_enabled.reset(true);
+
+        gb.bus.write(0xFFFF, 0x04).unwrap();
+        gb.bus.write(0xFF0F, 0x04).unwrap();
+        gb.step().unwrap();
+
+        // The high byte of PC (0xC0) overwrites IE, disabling the timer interrupt
+        assert_eq!(gb.bus.read(0xFFFF).unwrap(), 0xC0);
+        assert_eq!(gb.cpu().pc, 0x0000);
+        assert_eq!(gb.cpu().sp, 0xFFFE);
+        assert_eq!(gb.bus.read(0xFF0F).unwrap() & 0x04, 0x04);
+    }
+
+    #[test]
+    fn stop_after_key1_write_switches_speed() {
+        use crate::mem::MemW;
+
+        let mut gb = GameBoy::with_model(Model::Cgb);
+        gb.load_rom(ROM).unwrap();
+
+        // LD A, 0x01; LDH (0x4D), A; STOP
+        for (i, &b) in [0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00].iter().enumerate() {
+            gb.bus.write(0xC000 + i as u16, b).unwrap();
+        }
+        gb.cpu_mut().pc = 0xC000;
+
+        for _ in 0..3 {
+            gb.step().unwrap();
+        }
+        assert!(gb.bus().double_speed());
+        assert!(!*gb.cpu().halted.value());
+
+        // A NOP only takes half the clock cycles
+        let start = gb.clock_cycles();
+        gb.step().unwrap();
+        assert_eq!(gb.clock_cycles() - start, 2);
+    }
+
+    #[test]
+    fn entry_points_are_listed_in_order() {
+        let entries = GameBoy::entry_points();
+
+        assert_eq!(entries.len(), 14);
+        assert!(entries.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(dbg::entry_point_label(0x0040), Some("VBlank ISR"));
+        assert_eq!(dbg::entry_point_label(0x0100), Some("Cartridge entry"));
+        assert_eq!(dbg::entry_point_label(0x0101), None);
+    }
+
+    #[test]
+    fn rom_checksums_are_computed() {
+        let mut gb = GameBoy::new();
+        assert!(!gb.header_checksum_valid());
+
+        gb.load_rom(ROM).unwrap();
+
+        let sum = ROM[..0x14E]
+            .iter()
+            .chain(ROM[0x150..].iter())
+            .fold(0u16, |sum, b| sum.wrapping_add(u16::from(*b)));
+
+        assert!(gb.header_checksum_valid());
+        assert_eq!(gb.rom_global_checksum(), sum);
+        assert_eq!(
+            gb.stored_global_checksum(),
+            u16::from_be_bytes([ROM[0x14E], ROM[0x14F]])
+        );
+
+        let mut rom = ROM.to_vec();
+        rom[0x134] ^= 0xFF;
+        rom[0x200] = rom[0x200].wrapping_add(1);
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(&rom).unwrap();
+
+        assert!(!gb.header_checksum_valid());
+        assert_ne!(gb.rom_global_checksum(), sum);
+    }
+
+    #[test]
+    fn cgb_only_rom_runs_on_dmg() {
+        #[rustfmt::skip]
+        const PROGRAM: &[u8] = &[
+            0xFE, 0x11,       // cp 0x11
+            0x28, 0x1D,       // jr z, hang
+            0xAF,             // xor a
+            0xE0, 0x40,       // ldh (LCDC), a
+            0x21, 0x10, 0x80, // ld hl, 0x8010
+            0x06, 0x10,       // ld b, 16
+            0x3E, 0xFF,       // ld a, 0xFF
+            0x22,             // ld (hl+), a
+            0x05,             // dec b
+            0x20, 0xFC,       // jr nz, -4
+            0x21, 0x00, 0x98, // ld hl, 0x9800
+            0x06, 0x14,       // ld b, 20
+            0x3E, 0x01,       // ld a, 1
+            0x22,             // ld (hl+), a
+            0x05,             // dec b
+            0x20, 0xFC,       // jr nz, -4
+            0x3E, 0x91,       // ld a, 0x91
+            0xE0, 0x40,       // ldh (LCDC), a
+            0x18, 0xFE,       // hang: jr hang
+        ];
+
+        // A CGB-only ROM which, like commercial games, draws a message (here, a black bar
+        // at the top of the screen) when it finds itself on a DMG
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x143] = 0xC0;
+        rom[0x150..0x150 + PROGRAM.len()].copy_from_slice(PROGRAM);
+
+        let mut gb = GameBoy::with_model(Model::Dmg);
+        gb.load_rom(&rom).unwrap();
+
+        for _ in 0..5 {
+            gb.run_for_vblank().unwrap();
+        }
+
+        let mut vbuf = vec![0; 160 * 144 * 4];
+        gb.rasterize(&mut vbuf);
+
+        let (bar, rest) = vbuf.split_at(160 * 8 * 4);
+        assert!(bar.chunks(4).all(|px| px[..3] == [0, 0, 0]));
+        assert!(rest.chunks(4).all(|px| px[..3] == [0xFF, 0xFF, 0xFF]));
+    }
+
+    #[test]
+    fn tapped_key_is_held_for_one_frame() {
+        use crate::mem::{MemR, MemW};
+
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        let start_pressed = |gb: &mut GameBoy| {
+            gb.bus.write(0xFF00, 0x10).unwrap();
+            gb.bus.read(0xFF00).unwrap() & 0x08 == 0
+        };
+
+        gb.tap_button(JoypadState::START);
+        assert!(start_pressed(&mut gb));
+
+        gb.step_scanline().unwrap();
+        assert!(start_pressed(&mut gb));
+
+        gb.run_for_vblank().unwrap();
+        assert!(!start_pressed(&mut gb));
+    }
+
+    #[test]
+    fn execution_from_illegal_region_is_reported() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // Nothing is reported by default
+        gb.cpu_mut().pc = 0x8000;
+        gb.step().unwrap();
+
+        gb.cpu_mut()
+            .set_exec_regions(Some(dbg::ExecRegions::DEFAULT));
+
+        gb.cpu_mut().pc = 0x8000;
+        assert!(matches!(
+            gb.step(),
+            Err(dbg::TraceEvent::ExecuteFromIllegalRegion(0x8000))
+        ));
+
+        gb.cpu_mut().pc = 0xFF80;
+        gb.step().unwrap();
+
+        gb.cpu_mut()
+            .set_exec_regions(Some(dbg::ExecRegions::DEFAULT | dbg::ExecRegions::VRAM));
+        gb.cpu_mut().pc = 0x8000;
+        gb.step().unwrap();
+    }
+
+    #[test]
+    fn state_with_wrong_version_is_rejected() {
+        let mut gb = GameBoy::new();
+        gb.version = STATE_VERSION + 1;
+
+        let state = bincode::serialize(&gb).unwrap();
+        assert!(bincode::deserialize::<GameBoy>(&state).is_err());
+
+        let mut gb = GameBoy::new();
+        assert!(gb.load_state(&state).is_err());
+        assert!(gb.load_state(&[]).is_err());
+    }
+
+    #[test]
+    fn state_from_another_rom_is_rejected() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+        let state = gb.save_state();
+
+        let mut other = GameBoy::new();
+        other
+            .load_rom(include_bytes!("../../roms/blargg/instr_timing.gb"))
+            .unwrap();
+
+        assert!(matches!(
+            other.load_state(&state),
+            Err(StateError::RomMismatch)
+        ));
+    }
+
+    #[test]
+    fn rewind_goes_back_one_frame_at_a_time() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // Nothing is captured unless enabled
+        for _ in 0..30 {
+            gb.run_for_vblank().unwrap();
+        }
+        assert_eq!(gb.rewind_frames(), 0);
+        assert!(!gb.rewind());
+
+        gb.set_rewind_limits(4, usize::MAX);
+
+        let mut frames = vec![];
+        for _ in 0..6 {
+            gb.run_for_vblank().unwrap();
+            frames.push(gb.frame());
+        }
+        assert_eq!(gb.rewind_frames(), 4);
+
+        // Snapshots are taken at the start of VBlank, when the frame is complete
+        assert!(gb.rewind());
+        assert_eq!(gb.bus().ppu.ly(), 144);
+        assert_eq!(gb.frame(), frames[5]);
+
+        assert!(gb.rewind());
+        assert_eq!(gb.frame(), frames[4]);
+        assert_eq!(gb.rewind_frames(), 2);
+
+        // The oldest snapshots are dropped to stay within the byte limit
+        let size = gb.save_state().len();
+        gb.set_rewind_limits(4, size * 3 / 2);
+        assert_eq!(gb.rewind_frames(), 1);
+    }
+
+    #[test]
+    fn headless_runs_report_executed_cycles() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        // The LCD is turned on after a few frames
+        let start = gb.clock_cycles();
+        let cycles = gb.run_frames(30).unwrap();
+        assert!(cycles >= 30 * CYCLES_PER_FRAME);
+        assert_eq!(gb.clock_cycles() - start, cycles);
+
+        // Stops as soon as the predicate holds
+        let cycles = gb
+            .run_until(|gb| gb.bus().ppu.ly() == 100, u64::MAX)
+            .unwrap();
+        assert!(cycles > 0 && cycles <= CYCLES_PER_FRAME);
+        assert_eq!(gb.bus().ppu.ly(), 100);
+
+        // The predicate is checked before running anything
+        assert_eq!(gb.run_until(|_| true, 1000).unwrap(), 0);
+
+        // Otherwise, stops at the end of the instruction reaching the limit
+        let cycles = gb.run_until(|_| false, 1000).unwrap();
+        assert!((1000..1024).contains(&cycles));
+
+        // Events end the run early
+        let pc = gb.cpu().pc;
+        gb.cpu_mut().set_breakpoint(pc);
+        assert!(gb.run_until(|_| false, 1000).is_err());
+    }
+
+    #[test]
+    fn serial_output_is_captured() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+        gb.set_capture_serial_output(true);
+
+        gb.run_frames(30).unwrap();
+
+        assert!(gb.take_serial_output().starts_with("cpu_instrs"));
+        assert_eq!(gb.take_serial_output(), "");
+    }
+
+    #[test]
+    fn frame_buffer_is_updated_on_vblank() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        assert_eq!(gb.frame_buffer().len(), SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+        assert!(gb.frame_buffer().iter().all(|&b| b == 0xFF));
+
+        for _ in 0..30 {
+            gb.run_for_vblank().unwrap();
+        }
+        while gb.bus().ppu.ly() != 144 {
+            gb.step().unwrap();
+        }
+
+        let frame = gb.frame();
+        assert_eq!(gb.frame_buffer(), &frame[..]);
+        assert!(frame.iter().any(|&b| b != 0xFF));
+
+        // The buffer keeps the complete frame while the next one is being drawn
+        while gb.bus().ppu.ly() != 72 {
+            gb.step().unwrap();
+        }
+        assert_eq!(gb.frame_buffer(), &frame[..]);
+
+        gb.reset();
+        assert!(gb.frame_buffer().iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn loading_a_state_keeps_debugging_settings() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+        gb.run_for_vblank().unwrap();
+
+        let state = gb.save_state();
+        let (pc, cycles) = (gb.cpu().pc, gb.clock_cycles());
+
+        gb.run_for_vblank().unwrap();
+        gb.cpu_mut().set_breakpoint(0x1234);
+        gb.set_watchpoint(0xC000, dbg::WatchKind::Write);
+        gb.set_sprite_order(SpriteOrder::Oam);
+
+        gb.load_state(&state).unwrap();
+
+        assert_eq!(gb.cpu().pc, pc);
+        assert_eq!(gb.clock_cycles(), cycles);
+        assert!(gb.cpu().breakpoint_at(0x1234));
+        assert!(gb.bus().watchpoints().contains_key(&0xC000));
+        assert_eq!(gb.sprite_order(), SpriteOrder::Oam);
+    }
+
+    #[test]
+    fn boot_rom_is_unmapped_by_writing_to_ff50() {
+        // LD A,$01 ; LDH ($50),A ; JP $0100
+        let mut boot = vec![0x3E, 0x01, 0xE0, 0x50, 0xC3, 0x00, 0x01];
+        boot.resize(0x100, 0x00);
+
+        let mut gb = GameBoy::with_boot_rom(&boot);
+        gb.load_rom(ROM).unwrap();
+
+        assert_eq!(gb.cpu().pc, 0x0000);
+        assert!(gb.bus().boot_rom_mapped());
+        assert_eq!(gb.bus().read(0x0000).unwrap(), 0x3E);
+        assert_eq!(gb.bus().read(0x0100).unwrap(), ROM[0x100]);
+
+        gb.run_until(|gb| gb.cpu().pc == 0x0100, 1000).unwrap();
+
+        assert!(!gb.bus().boot_rom_mapped());
+        for addr in 0x0000..0x0100 {
+            assert_eq!(gb.bus().read(addr).unwrap(), ROM[usize::from(addr)]);
+        }
+
+        // The boot ROM runs again after a reset
+        gb.reset();
+        assert_eq!(gb.cpu().pc, 0x0000);
+        assert_eq!(gb.bus().read(0x0000).unwrap(), 0x3E);
+    }
+
+    #[test]
+    fn registers_hold_their_post_boot_values() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        let cpu = gb.cpu();
+        assert_eq!(
+            (cpu.af, cpu.bc, cpu.de, cpu.hl, cpu.sp, cpu.pc),
+            (0x01B0, 0x0013, 0x00D8, 0x014D, 0xFFFE, 0x0100)
+        );
+
+        let regs = [
+            (0xFF00, 0xCF), // P1
+            (0xFF02, 0x7E), // SC
+            (0xFF04, 0xAB), // DIV
+            (0xFF05, 0x00), // TIMA
+            (0xFF06, 0x00), // TMA
+            (0xFF07, 0xF8), // TAC
+            (0xFF0F, 0xE1), // IF
+            (0xFF10, 0x80), // NR10
+            (0xFF11, 0xBF), // NR11
+            (0xFF12, 0xF3), // NR12
+            (0xFF14, 0xBF), // NR14
+            (0xFF16, 0x3F), // NR21
+            (0xFF17, 0x00), // NR22
+            (0xFF19, 0xBF), // NR24
+            (0xFF1A, 0x7F), // NR30
+            (0xFF1B, 0xFF), // NR31
+            (0xFF1C, 0x9F), // NR32
+            (0xFF1E, 0xBF), // NR34
+            (0xFF20, 0xFF), // NR41
+            (0xFF21, 0x00), // NR42
+            (0xFF22, 0x00), // NR43
+            (0xFF23, 0xBF), // NR44
+            (0xFF24, 0x77), // NR50
+            (0xFF25, 0xF3), // NR51
+            (0xFF26, 0xF1), // NR52
+            (0xFF40, 0x91), // LCDC
+            (0xFF42, 0x00), // SCY
+            (0xFF43, 0x00), // SCX
+            (0xFF45, 0x00), // LYC
+            (0xFF47, 0xFC), // BGP
+            (0xFF4A, 0x00), // WY
+            (0xFF4B, 0x00), // WX
+            (0xFFFF, 0x00), // IE
+        ];
+        for &(addr, val) in regs.iter() {
+            assert_eq!(gb.bus().read(addr).unwrap(), val, "{:04X}", addr);
+        }
+    }
+
+    /// A writer whose output can be inspected while the emulator owns it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn instruction_trace_is_logged() {
+        let mut gb = GameBoy::new();
+        gb.load_rom(ROM).unwrap();
+
+        let buf = SharedBuf::default();
+        let start = gb.trace_line();
+
+        gb.set_trace(Box::new(buf.clone()));
+        for _ in 0..3 {
+            gb.step().unwrap();
+        }
+        assert!(gb.take_trace().is_some());
+        gb.step().unwrap();
+
+        let log = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            format!(
+                "{} PCMEM:{:02X},{:02X},{:02X},{:02X} ; {}",
+                start,
+                ROM[0x100],
+                ROM[0x101],
+                ROM[0x102],
+                ROM[0x103],
+                OPCODES[usize::from(ROM[0x100])].0
+            )
+        );
+        assert_eq!(lines[0].parse::<dbg::TraceLine>().unwrap(), start);
+    }
 }
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dbg,
+    io::IoReg,
+    mem::{MemR, MemRW, MemW},
+};
+
+/// CGB infrared communications port (RP register).
+///
+/// The port is only mapped for CGB-enhanced cartridges, since it does not exist on DMG.
+/// It is currently a stub modeling a port with nothing on the other side:
+/// writes are accepted and can be read back, but no light is ever received
+/// (ie. bit 1 always reads as 1). This is enough for games to get past IR checks.
+///
+/// Register layout:
+///
+/// * bit 0: LED on/off (R/W)
+/// * bit 1: read data, 0 = receiving IR signal, 1 = normal (R)
+/// * bit 6-7: data read enable, 3 = enable (R/W)
+#[derive(Serialize, Deserialize)]
+pub struct Infrared {
+    rp: IoReg<u8>,
+}
+
+impl Default for Infrared {
+    fn default() -> Infrared {
+        Infrared { rp: IoReg(0x00) }
+    }
+}
+
+impl Infrared {
+    pub fn new() -> Infrared {
+        Infrared::default()
+    }
+
+    /// Returns whether the IR LED is currently turned on.
+    pub fn led_on(&self) -> bool {
+        self.rp.bit(0)
+    }
+}
+
+impl MemR for Infrared {
+    fn read(&self, addr: u16) -> Result<u8, dbg::TraceEvent> {
+        // TODO: no IR signal is ever received, so bit 1 always reads as 1
+        Ok(match addr {
+            0xFF56 => (self.rp.0 & 0xC1) | 0x3E,
+            _ => unreachable!(),
+        })
+    }
+}
+
+impl MemW for Infrared {
+    fn write(&mut self, addr: u16, val: u8) -> Result<(), dbg::TraceEvent> {
+        match addr {
+            0xFF56 => self.rp.0 = val & 0xC1,
+            _ => unreachable!(),
+        };
+        Ok(())
+    }
+}
+
+impl MemRW for Infrared {}
@@ -1,3 +1,6 @@
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+
 use crate::{
     dbg,
     io::IoReg,
@@ -25,19 +28,34 @@ impl From<IrqSource> for usize {
     }
 }
 
+bitflags! {
+    /// The interrupt bits of the IE and IF registers.
+    pub struct IrqFlags: u8 {
+        const VBLANK   = 0b_0000_0001;
+        const LCD_STAT = 0b_0000_0010;
+        const TIMER    = 0b_0000_0100;
+        const SERIAL   = 0b_0000_1000;
+        const JOYPAD   = 0b_0001_0000;
+    }
+}
+
 pub trait InterruptSource {
     fn get_and_clear_irq(&mut self) -> Option<IrqSource>;
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct IrqController {
     pub ien: IoReg<u8>,
     pub ifg: IoReg<u8>,
 }
 
 impl IrqController {
+    /// Creates a controller in its post-boot state, where the VBlank interrupt is requested.
     pub fn new() -> IrqController {
-        IrqController::default()
+        IrqController {
+            ifg: IoReg(0x01),
+            ..IrqController::default()
+        }
     }
 
     pub fn pending_irqs(&self) -> bool {
@@ -75,7 +93,8 @@ impl MemR for IrqController {
 impl MemW for IrqController {
     fn write(&mut self, addr: u16, val: u8) -> Result<(), dbg::TraceEvent> {
         match addr {
-            0xFF0F => self.ifg.0 = val,
+            // Only the 5 interrupt bits are writable, the others always read as 1
+            0xFF0F => self.ifg.0 = val & 0x1F,
             0xFFFF => self.ien.0 = val,
             _ => unreachable!(),
         };
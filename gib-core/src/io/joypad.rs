@@ -1,11 +1,14 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     dbg,
+    io::{InterruptSource, IrqSource},
     mem::{MemR, MemRW, MemW},
 };
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     pub struct JoypadState: u8 {
         const DOWN   = 0b_1000_0000;
         const UP     = 0b_0100_0000;
@@ -21,6 +24,7 @@ bitflags! {
 }
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     struct JoyP: u8 {
         const SEL_BTNS = 0b_0010_0000;
         const SEL_DIRS = 0b_0001_0000;
@@ -32,10 +36,13 @@ bitflags! {
 
 mem_rw!(JoyP, 0xC0);
 
+#[derive(Serialize, Deserialize)]
 pub struct Joypad {
     joyp: JoyP,
 
     state: JoypadState,
+
+    irq_pending: bool,
 }
 
 impl Default for Joypad {
@@ -43,6 +50,7 @@ impl Default for Joypad {
         Joypad {
             joyp: JoyP::DEFAULT,
             state: JoypadState::DEFAULT,
+            irq_pending: false,
         }
     }
 }
@@ -53,26 +61,55 @@ impl Joypad {
     }
 
     pub fn set_pressed_keys(&mut self, pressed: JoypadState) {
-        self.state &= !pressed;
+        self.update_lines(|joy| joy.state &= !pressed);
     }
 
     pub fn set_release_keys(&mut self, released: JoypadState) {
-        self.state |= released;
+        self.update_lines(|joy| joy.state |= released);
+    }
+
+    /// Returns the state of the P10-P13 input lines (active low).
+    ///
+    /// Each line is pulled low by the pressed keys of any selected row,
+    /// so if both rows are selected, the keys sharing a line are OR-ed together.
+    fn input_lines(&self) -> u8 {
+        let mut lines = JoyP::BTN_MASK.bits();
+
+        if !self.joyp.contains(JoyP::SEL_BTNS) {
+            lines &= self.state.bits();
+        }
+        if !self.joyp.contains(JoyP::SEL_DIRS) {
+            lines &= self.state.bits() >> 4;
+        }
+        lines
+    }
+
+    /// Applies `f`, then requests an interrupt if any input line went from high to low,
+    /// either because a key was pressed or because a row with pressed keys was selected.
+    fn update_lines<F: FnOnce(&mut Joypad)>(&mut self, f: F) {
+        let old = self.input_lines();
+        f(self);
+
+        if old & !self.input_lines() != 0 {
+            self.irq_pending = true;
+        }
     }
 }
 
-impl MemR for Joypad {
-    fn read(&self, _addr: u16) -> Result<u8, dbg::TraceEvent> {
-        // Assign upper, lower or no half of state depending on the selection bits
-        let res = if !self.joyp.contains(JoyP::SEL_BTNS) {
-            self.state.bits()
-        } else if !self.joyp.contains(JoyP::SEL_DIRS) {
-            self.state.bits() >> 4
+impl InterruptSource for Joypad {
+    fn get_and_clear_irq(&mut self) -> Option<IrqSource> {
+        if self.irq_pending {
+            self.irq_pending = false;
+            Some(IrqSource::Joypad)
         } else {
-            0x0F
-        };
+            None
+        }
+    }
+}
 
-        let joyp = (self.joyp | JoyP::BTN_MASK) & JoyP::from_bits_truncate(res | 0xF0);
+impl MemR for Joypad {
+    fn read(&self, _addr: u16) -> Result<u8, dbg::TraceEvent> {
+        let joyp = (self.joyp - JoyP::BTN_MASK) | JoyP::from_bits_truncate(self.input_lines());
 
         (&joyp).read(0)
     }
@@ -80,8 +117,56 @@ impl MemR for Joypad {
 
 impl MemW for Joypad {
     fn write(&mut self, _addr: u16, val: u8) -> Result<(), dbg::TraceEvent> {
-        (&mut self.joyp).write(0, val)
+        let mut res = Ok(());
+        self.update_lines(|joy| res = (&mut joy.joyp).write(0, val));
+        res
     }
 }
 
 impl MemRW for Joypad {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_rows_are_read_active_low() {
+        let mut joy = Joypad::new();
+        joy.set_pressed_keys(JoypadState::START | JoypadState::LEFT);
+
+        // Action buttons
+        joy.write(0xFF00, 0x10).unwrap();
+        assert_eq!(joy.read(0xFF00).unwrap(), 0xD7);
+
+        // Directions
+        joy.write(0xFF00, 0x20).unwrap();
+        assert_eq!(joy.read(0xFF00).unwrap(), 0xED);
+
+        // Both rows, or none
+        joy.write(0xFF00, 0x00).unwrap();
+        assert_eq!(joy.read(0xFF00).unwrap(), 0xC5);
+        joy.write(0xFF00, 0x30).unwrap();
+        assert_eq!(joy.read(0xFF00).unwrap(), 0xFF);
+    }
+
+    #[test]
+    fn pressing_a_selected_key_requests_an_interrupt() {
+        let mut joy = Joypad::new();
+        joy.write(0xFF00, 0x10).unwrap();
+
+        // Keys in the other row don't affect the input lines
+        joy.set_pressed_keys(JoypadState::UP);
+        assert!(joy.get_and_clear_irq().is_none());
+
+        joy.set_pressed_keys(JoypadState::A);
+        assert!(matches!(joy.get_and_clear_irq(), Some(IrqSource::Joypad)));
+
+        // Releasing a key doesn't
+        joy.set_release_keys(JoypadState::A);
+        assert!(joy.get_and_clear_irq().is_none());
+
+        // Selecting a row with a key held down does
+        joy.write(0xFF00, 0x20).unwrap();
+        assert!(matches!(joy.get_and_clear_irq(), Some(IrqSource::Joypad)));
+    }
+}
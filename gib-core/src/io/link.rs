@@ -0,0 +1,269 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Maximum time to wait for the peer to answer a transfer, before giving up and reading 0xFF.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The other end of a link cable.
+///
+/// Transfers are exchanged one byte at a time, and none of the methods block. The side
+/// providing the clock (ie. the one starting a transfer with the internal clock) calls
+/// `start_transfer`, then `take_reply` until the peer has replied. The other side finds
+/// the byte through `poll`, and must answer with `reply`.
+pub trait SerialLink: Send {
+    /// Sends `byte` to the peer, starting a transfer clocked by this side.
+    fn start_transfer(&mut self, byte: u8);
+
+    /// Returns the byte shifted out by the peer in exchange for the last transfer started,
+    /// or `None` if it has yet to answer. Reads 0xFF if there is no peer to answer.
+    fn take_reply(&mut self) -> Option<u8>;
+
+    /// Returns the byte sent by the peer, if it started a transfer with its internal clock.
+    fn poll(&mut self) -> Option<u8>;
+
+    /// Answers the transfer returned by `poll` with `byte`.
+    fn reply(&mut self, byte: u8);
+}
+
+/// Messages sent over a `TcpLink`, each followed by the byte being transferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Message {
+    Transfer(u8),
+    Reply(u8),
+}
+
+impl Message {
+    fn encode(self) -> [u8; 2] {
+        match self {
+            Message::Transfer(b) => [0x00, b],
+            Message::Reply(b) => [0x01, b],
+        }
+    }
+
+    fn decode(buf: [u8; 2]) -> Option<Message> {
+        match buf[0] {
+            0x00 => Some(Message::Transfer(buf[1])),
+            0x01 => Some(Message::Reply(buf[1])),
+            _ => None,
+        }
+    }
+}
+
+/// A virtual link cable connecting two emulators over TCP.
+///
+/// Incoming messages are received on a background thread. Until a peer is connected,
+/// or after it disconnects, transfers behave as if no cable was plugged in.
+pub struct TcpLink {
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    rx: Receiver<Message>,
+
+    // When the transfer waiting for a reply was started, if any
+    sent_at: Option<Instant>,
+}
+
+impl TcpLink {
+    /// Connects to a peer listening at `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        let stream = TcpStream::connect(addr)?;
+        let (tx, rx) = mpsc::channel();
+        let link = TcpLink {
+            stream: Arc::new(Mutex::new(None)),
+            rx,
+            sent_at: None,
+        };
+
+        TcpLink::attach(&link.stream, stream, tx)?;
+        Ok(link)
+    }
+
+    /// Waits for a peer to connect on `port`, in the background.
+    ///
+    /// Only connections from the local host are accepted: use `listen_on` to open the link
+    /// to other hosts.
+    pub fn listen(port: u16) -> io::Result<TcpLink> {
+        TcpLink::listen_on(("127.0.0.1", port))
+    }
+
+    /// Waits for a peer to connect to `addr`, in the background.
+    pub fn listen_on<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        TcpLink::from_listener(TcpListener::bind(addr)?)
+    }
+
+    fn from_listener(listener: TcpListener) -> io::Result<TcpLink> {
+        let (tx, rx) = mpsc::channel();
+        let link = TcpLink {
+            stream: Arc::new(Mutex::new(None)),
+            rx,
+            sent_at: None,
+        };
+        let shared = link.stream.clone();
+
+        thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                TcpLink::attach(&shared, stream, tx).ok();
+            }
+        });
+
+        Ok(link)
+    }
+
+    /// Makes `stream` the connection to the peer, and starts receiving messages from it.
+    fn attach(
+        shared: &Arc<Mutex<Option<TcpStream>>>,
+        stream: TcpStream,
+        tx: Sender<Message>,
+    ) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+
+        let mut reader = stream.try_clone()?;
+        *shared.lock().unwrap() = Some(stream);
+
+        let shared = shared.clone();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 2];
+
+            while reader.read_exact(&mut buf).is_ok() {
+                match Message::decode(buf) {
+                    Some(msg) if tx.send(msg).is_ok() => (),
+                    _ => break,
+                }
+            }
+
+            // The peer is gone: stop sending anything to it
+            *shared.lock().unwrap() = None;
+        });
+
+        Ok(())
+    }
+
+    /// Sends `msg` to the peer, returning whether it is still connected.
+    fn send(&self, msg: Message) -> bool {
+        let mut stream = self.stream.lock().unwrap();
+
+        let sent = match *stream {
+            Some(ref mut s) => s.write_all(&msg.encode()).is_ok(),
+            None => false,
+        };
+        if !sent {
+            *stream = None;
+        }
+        sent
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn start_transfer(&mut self, byte: u8) {
+        // Drop replies to transfers which timed out, and turn down the peer's own transfers,
+        // since this side is providing the clock
+        while let Ok(msg) = self.rx.try_recv() {
+            if let Message::Transfer(_) = msg {
+                self.send(Message::Reply(0xFF));
+            }
+        }
+
+        self.sent_at = if self.send(Message::Transfer(byte)) {
+            Some(Instant::now())
+        } else {
+            None
+        };
+    }
+
+    fn take_reply(&mut self) -> Option<u8> {
+        // The transfer could not even be sent
+        let sent_at = match self.sent_at {
+            Some(t) => t,
+            None => return Some(0xFF),
+        };
+
+        loop {
+            match self.rx.try_recv() {
+                Ok(Message::Reply(b)) => {
+                    self.sent_at = None;
+                    return Some(b);
+                }
+                Ok(Message::Transfer(_)) => {
+                    self.send(Message::Reply(0xFF));
+                }
+                Err(TryRecvError::Empty) if sent_at.elapsed() < EXCHANGE_TIMEOUT => return None,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                    self.sent_at = None;
+                    return Some(0xFF);
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self) -> Option<u8> {
+        // Late replies to transfers which timed out are just dropped
+        while let Ok(msg) = self.rx.try_recv() {
+            if let Message::Transfer(b) = msg {
+                return Some(b);
+            }
+        }
+        None
+    }
+
+    fn reply(&mut self, byte: u8) {
+        self.send(Message::Reply(byte));
+    }
+}
+
+impl Drop for TcpLink {
+    fn drop(&mut self) {
+        if let Some(ref s) = *self.stream.lock().unwrap() {
+            s.shutdown(Shutdown::Both).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_link_exchanges_bytes_until_disconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut slave = TcpLink::from_listener(listener).unwrap();
+        let mut master = TcpLink::connect(addr).unwrap();
+
+        let slave = thread::spawn(move || {
+            let byte = loop {
+                if let Some(b) = slave.poll() {
+                    break b;
+                }
+                thread::yield_now();
+            };
+            slave.reply(0x99);
+            byte
+        });
+
+        assert_eq!(exchange(&mut master, 0x42), 0x99);
+        assert_eq!(slave.join().unwrap(), 0x42);
+
+        // The slave has been dropped, so nobody is there to answer anymore
+        assert_eq!(exchange(&mut master, 0x42), 0xFF);
+        assert_eq!(master.poll(), None);
+    }
+
+    /// Starts a transfer on `link`, and waits for the peer to answer it.
+    fn exchange(link: &mut TcpLink, byte: u8) -> u8 {
+        link.start_transfer(byte);
+        loop {
+            if let Some(b) = link.take_reply() {
+                break b;
+            }
+            thread::yield_now();
+        }
+    }
+}
@@ -1,6 +1,10 @@
+pub use infrared::*;
 pub use interrupts::*;
 pub use joypad::*;
+pub use link::*;
+pub use printer::*;
 pub use reg::*;
+pub use rtc::*;
 pub use serial::*;
 pub use sound::*;
 pub use timer::*;
@@ -8,8 +12,12 @@ pub use video::*;
 
 #[macro_use]
 mod reg;
+mod infrared;
 mod interrupts;
 mod joypad;
+mod link;
+mod printer;
+mod rtc;
 mod serial;
 mod sound;
 mod timer;
@@ -0,0 +1,380 @@
+use std::sync::{Arc, Mutex};
+
+use super::SerialLink;
+
+/// Width in pixels of the printed image.
+pub const PRINTER_WIDTH: usize = 160;
+
+/// Size of the printer's image buffer, ie. 9 bands of 2 tile rows.
+const BUFFER_SIZE: usize = 9 * 2 * TILE_ROW_SIZE;
+
+/// Size in bytes of a row of 20 tiles.
+const TILE_ROW_SIZE: usize = (PRINTER_WIDTH / 8) * 16;
+
+// Bits of the status byte
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_PRINTING: u8 = 1 << 1;
+const STATUS_IMAGE_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+/// Byte shifted out by the printer while the game sends the first byte after the checksum.
+const DEVICE_ID: u8 = 0x81;
+
+/// Position within a command packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLo,
+    LengthHi,
+    Data,
+    ChecksumLo,
+    ChecksumHi,
+    DeviceId,
+    Status,
+}
+
+#[derive(Debug)]
+struct PrinterState {
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    status: u8,
+    /// Decompressed tile data waiting to be printed
+    buffer: Vec<u8>,
+    /// Printed sheets, as RGBA pixels
+    image: Vec<u8>,
+}
+
+impl Default for PrinterState {
+    fn default() -> Self {
+        Self {
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            status: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            image: Vec::new(),
+        }
+    }
+}
+
+impl PrinterState {
+    /// Returns the byte shifted out while `byte` is received, and advances the packet state.
+    fn exchange(&mut self, byte: u8) -> u8 {
+        use PacketState::*;
+
+        let out = match self.state {
+            DeviceId => DEVICE_ID,
+            Status => self.status,
+            _ => 0x00,
+        };
+
+        self.state = match self.state {
+            Magic1 if byte == 0x88 => Magic2,
+            Magic1 => Magic1,
+            Magic2 if byte == 0x33 => Command,
+            Magic2 if byte == 0x88 => Magic2,
+            Magic2 => Magic1,
+            Command => {
+                self.command = byte;
+                self.checksum = u16::from(byte);
+                Compression
+            }
+            Compression => {
+                self.compressed = byte & 0x1 != 0;
+                self.checksum = self.checksum.wrapping_add(u16::from(byte));
+                LengthLo
+            }
+            LengthLo => {
+                self.length = u16::from(byte);
+                self.checksum = self.checksum.wrapping_add(u16::from(byte));
+                LengthHi
+            }
+            LengthHi => {
+                self.length |= u16::from(byte) << 8;
+                self.checksum = self.checksum.wrapping_add(u16::from(byte));
+                self.data.clear();
+
+                if self.length == 0 {
+                    ChecksumLo
+                } else {
+                    Data
+                }
+            }
+            Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(u16::from(byte));
+
+                if self.data.len() == usize::from(self.length) {
+                    ChecksumLo
+                } else {
+                    Data
+                }
+            }
+            ChecksumLo => {
+                self.checksum ^= u16::from(byte);
+                ChecksumHi
+            }
+            ChecksumHi => {
+                if self.checksum ^ (u16::from(byte) << 8) == 0 {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.run_command();
+                } else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+                DeviceId
+            }
+            DeviceId => Status,
+            Status => {
+                // Printing completes instantly, but it is still reported once as busy
+                if out & STATUS_PRINTING != 0 {
+                    self.status &= !STATUS_PRINTING;
+                }
+                Magic1
+            }
+        };
+
+        out
+    }
+
+    /// Executes the command of a packet which has been received correctly.
+    fn run_command(&mut self) {
+        match self.command {
+            // Initialize
+            0x01 => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            // Print
+            0x02 => {
+                if let [_sheets, _margins, palette, _exposure] = self.data[..] {
+                    self.print(palette);
+                }
+            }
+            // Image data (an empty packet just marks the end of the data)
+            0x04 => {
+                let data = std::mem::take(&mut self.data);
+
+                if self.compressed {
+                    decompress(&data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+                self.buffer.truncate(BUFFER_SIZE);
+                self.data = data;
+
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            // Status inquiry, and anything unknown
+            _ => (),
+        }
+    }
+
+    /// Appends the buffered tile data to the printed image, using `palette` for the colors.
+    fn print(&mut self, palette: u8) {
+        // Most games send a palette of 0 to mean the default one
+        let palette = if palette == 0 { 0xE4 } else { palette };
+
+        for row in self.buffer.chunks_exact(TILE_ROW_SIZE) {
+            for y in 0..8 {
+                for x in 0..PRINTER_WIDTH {
+                    let tile = &row[(x / 8) * 16..];
+                    let bit = 7 - (x % 8);
+                    let lo = (tile[y * 2] >> bit) & 0x1;
+                    let hi = (tile[y * 2 + 1] >> bit) & 0x1;
+
+                    let shade = match (palette >> (((hi << 1) | lo) * 2)) & 0x3 {
+                        0b00 => 0xFF, // White
+                        0b01 => 0xAA, // Light gray
+                        0b10 => 0x55, // Dark gray
+                        _ => 0x00,    // Black
+                    };
+                    self.image.extend_from_slice(&[shade, shade, shade, 0xFF]);
+                }
+            }
+        }
+
+        self.buffer.clear();
+        self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+        self.status |= STATUS_PRINTING;
+    }
+}
+
+/// Expands the run-length encoded `data` into `out`.
+///
+/// Each run starts with a control byte: if its MSB is set, the following byte is repeated
+/// `(ctrl & 0x7F) + 2` times, otherwise `ctrl + 1` bytes are copied as they are.
+fn decompress(data: &[u8], out: &mut Vec<u8>) {
+    let mut bytes = data.iter().copied();
+
+    while let Some(ctrl) = bytes.next() {
+        if ctrl & 0x80 != 0 {
+            if let Some(b) = bytes.next() {
+                out.resize(out.len() + usize::from(ctrl & 0x7F) + 2, b);
+            }
+        } else {
+            out.extend(bytes.by_ref().take(usize::from(ctrl) + 1));
+        }
+    }
+}
+
+/// Emulation of the Game Boy Printer.
+///
+/// The printer is a serial peer driven by the game's clock. Printed sheets are collected
+/// into a single image, which can be retrieved through `printer_image`. Clones of a printer
+/// share the same state, so one can be plugged in the emulator and another kept aside
+/// to inspect what has been printed.
+#[derive(Debug, Default, Clone)]
+pub struct Printer {
+    state: Arc<Mutex<PrinterState>>,
+
+    // Byte shifted out during the last transfer
+    reply: u8,
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns everything printed so far, as a `PRINTER_WIDTH` pixels wide RGBA bitmap.
+    pub fn printer_image(&self) -> Vec<u8> {
+        self.state.lock().unwrap().image.clone()
+    }
+
+    /// Returns the height in pixels of the printed image.
+    pub fn printer_image_height(&self) -> usize {
+        self.state.lock().unwrap().image.len() / (PRINTER_WIDTH * 4)
+    }
+
+    /// Throws away the printed image.
+    pub fn clear_image(&self) {
+        self.state.lock().unwrap().image.clear();
+    }
+}
+
+impl SerialLink for Printer {
+    fn start_transfer(&mut self, byte: u8) {
+        self.reply = self.state.lock().unwrap().exchange(byte);
+    }
+
+    fn take_reply(&mut self) -> Option<u8> {
+        Some(self.reply)
+    }
+
+    fn poll(&mut self) -> Option<u8> {
+        // The printer never starts a transfer on its own
+        None
+    }
+
+    fn reply(&mut self, _byte: u8) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `byte` to `printer`, returning the byte it answered with.
+    fn exchange(printer: &mut Printer, byte: u8) -> u8 {
+        printer.start_transfer(byte);
+        printer.take_reply().unwrap()
+    }
+
+    /// Sends a packet to `printer`, returning the device ID and status it answered with.
+    fn send_packet(printer: &mut Printer, cmd: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let len = data.len() as u16;
+        let mut packet = vec![cmd, u8::from(compressed), len as u8, (len >> 8) as u8];
+        packet.extend_from_slice(data);
+
+        let checksum = packet
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(u16::from(b)));
+
+        for &b in [0x88, 0x33]
+            .iter()
+            .chain(packet.iter())
+            .chain(checksum.to_le_bytes().iter())
+        {
+            assert_eq!(exchange(printer, b), 0x00);
+        }
+        (exchange(printer, 0x00), exchange(printer, 0x00))
+    }
+
+    #[test]
+    fn printer_assembles_printed_image() {
+        let printer = Printer::new();
+        let mut link = printer.clone();
+
+        assert_eq!(send_packet(&mut link, 0x01, false, &[]), (DEVICE_ID, 0x00));
+
+        // First tile row: color 3 everywhere
+        let row = vec![0xFF; TILE_ROW_SIZE];
+        assert_eq!(
+            send_packet(&mut link, 0x04, false, &row),
+            (DEVICE_ID, STATUS_UNPROCESSED)
+        );
+
+        // Second tile row, compressed: a literal run of 2 bytes (color 1 on the first line),
+        // then 0x00 repeated until the end of the row
+        let mut compressed = vec![0x01, 0xFF, 0x00];
+        let mut left = TILE_ROW_SIZE - 2;
+        while left > 0 {
+            let run = left.min(0x81);
+            compressed.extend_from_slice(&[0x80 | (run - 2) as u8, 0x00]);
+            left -= run;
+        }
+        assert_eq!(
+            send_packet(&mut link, 0x04, true, &compressed),
+            (DEVICE_ID, STATUS_UNPROCESSED)
+        );
+
+        // A corrupted packet is rejected
+        let mut bad = link.clone();
+        for &b in &[0x88, 0x33, 0x04, 0x00, 0x01, 0x00, 0xAA, 0x00, 0x00] {
+            exchange(&mut bad, b);
+        }
+        assert_eq!(exchange(&mut bad, 0x00), DEVICE_ID);
+        assert_eq!(
+            exchange(&mut bad, 0x00),
+            STATUS_UNPROCESSED | STATUS_CHECKSUM_ERROR
+        );
+
+        assert_eq!(
+            send_packet(&mut link, 0x04, false, &[]).1,
+            STATUS_UNPROCESSED
+        );
+        assert_eq!(
+            send_packet(&mut link, 0x02, false, &[1, 0x13, 0xE4, 0x40]),
+            (DEVICE_ID, STATUS_PRINTING)
+        );
+        assert_eq!(send_packet(&mut link, 0x0F, false, &[]), (DEVICE_ID, 0x00));
+
+        let image = printer.printer_image();
+        let px = |x: usize, y: usize| image[(y * PRINTER_WIDTH + x) * 4];
+
+        assert_eq!(printer.printer_image_height(), 16);
+        assert_eq!(px(0, 0), 0x00);
+        assert_eq!(px(159, 7), 0x00);
+        assert_eq!(px(0, 8), 0xAA);
+        assert_eq!(px(7, 8), 0xAA);
+        assert_eq!(px(8, 8), 0xFF);
+        assert_eq!(px(0, 9), 0xFF);
+
+        printer.clear_image();
+        assert_eq!(printer.printer_image_height(), 0);
+    }
+}
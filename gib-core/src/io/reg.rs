@@ -1,5 +1,7 @@
 use std::ops::{BitAnd, BitAndAssign, BitOrAssign, Not, Shl};
 
+use serde::{Deserialize, Serialize};
+
 /// Blanket implementation of MemR/MemW/MemRW for a bitflags!-generated struct
 macro_rules! mem_rw {
     ($reg:ident, $mask:expr) => {
@@ -42,7 +44,7 @@ impl Zero for u16 {
     }
 }
 
-#[derive(Default, Copy, Clone, Debug, PartialEq)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct IoReg<T>(pub T);
 
 impl<T> IoReg<T>
@@ -86,7 +88,7 @@ where
 ///
 /// When `load` is called on a Latch, the new value is not presented until `tick` is called.
 /// A Latch also provides an asynchronous `reset` to override the latching mechanism.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Latch<T: Copy + Clone>(T, T);
 
 impl<T: Copy + Clone> Latch<T> {
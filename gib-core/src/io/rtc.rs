@@ -0,0 +1,294 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Number of days after which the day counter overflows.
+const DAYS_WRAP: u64 = 512;
+
+/// Snapshot of the real-time clock, eg. to persist it alongside the cartridge RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcState {
+    /// Running counter, in register layout
+    pub counter: [u8; 5],
+    /// Latched registers
+    pub latched: [u8; 5],
+    /// Host time (in seconds since the UNIX epoch) at which `counter` was taken
+    pub timestamp: u64,
+}
+
+impl RtcState {
+    /// Size of a serialized state, in bytes.
+    pub const SIZE: usize = 18;
+
+    /// Serializes the state as the counter and the latched registers,
+    /// followed by the timestamp as a little-endian 64-bit integer.
+    pub fn to_bytes(&self) -> [u8; RtcState::SIZE] {
+        let mut bytes = [0; RtcState::SIZE];
+
+        bytes[..5].copy_from_slice(&self.counter);
+        bytes[5..10].copy_from_slice(&self.latched);
+        bytes[10..].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes
+    }
+
+    /// Parses a state serialized with `to_bytes`, returning `None` if `bytes` has the wrong size.
+    pub fn from_bytes(bytes: &[u8]) -> Option<RtcState> {
+        if bytes.len() != RtcState::SIZE {
+            return None;
+        }
+
+        let mut state = RtcState {
+            counter: [0; 5],
+            latched: [0; 5],
+            timestamp: 0,
+        };
+        state.counter.copy_from_slice(&bytes[..5]);
+        state.latched.copy_from_slice(&bytes[5..10]);
+
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[10..]);
+        state.timestamp = u64::from_le_bytes(timestamp);
+
+        Some(state)
+    }
+}
+
+/// Real-time clock of MBC3 cartridges.
+///
+/// The clock is a counter of elapsed seconds which follows the host's wall-clock time,
+/// so it keeps running while the emulation is paused, like the battery-backed original.
+/// Games don't access the counter directly: they latch it into the RTC registers first,
+/// which then stay stable while being read.
+///
+/// Register layout:
+///
+/// * 0x08: seconds (0-59)
+/// * 0x09: minutes (0-59)
+/// * 0x0A: hours (0-23)
+/// * 0x0B: lower 8 bits of the day counter
+/// * 0x0C: bit 0 = bit 8 of the day counter, bit 6 = halt, bit 7 = day counter carry
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Rtc {
+    /// Seconds counted as of `synced_at`
+    secs: u64,
+    /// Host time (in seconds since the UNIX epoch) at which `secs` was last brought up to date
+    synced_at: u64,
+    halted: bool,
+    carry: bool,
+    latched: [u8; 5],
+    /// Whether 0x00 was just written to the latch register, so that 0x01 latches the clock
+    latch_armed: bool,
+}
+
+impl Default for Rtc {
+    fn default() -> Rtc {
+        Rtc::new()
+    }
+}
+
+impl Rtc {
+    /// Creates a clock starting from zero at the current host time.
+    pub fn new() -> Rtc {
+        Rtc {
+            secs: 0,
+            synced_at: Rtc::now(),
+            halted: false,
+            carry: false,
+            latched: [0; 5],
+            latch_armed: false,
+        }
+    }
+
+    /// Returns the current host time, in seconds since the UNIX epoch.
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs())
+    }
+
+    /// Returns the latched value of RTC register `reg` (0x08-0x0C).
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched[usize::from(reg - 0x08)]
+    }
+
+    /// Writes `val` to RTC register `reg` (0x08-0x0C), updating the running counter.
+    pub fn write(&mut self, reg: u8, val: u8) {
+        self.write_at(reg, val, Rtc::now());
+    }
+
+    /// Handles a write to the latch clock data register (0x6000-0x7FFF).
+    ///
+    /// Writing 0x00 followed by 0x01 copies the running counter to the RTC registers.
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_armed && val == 0x01 {
+            self.latch_at(Rtc::now());
+        }
+        self.latch_armed = val == 0x00;
+    }
+
+    /// Returns the latched RTC registers.
+    pub fn registers(&self) -> [u8; 5] {
+        self.latched
+    }
+
+    /// Returns the running counter, as of the current host time, and the latched registers.
+    pub fn state(&self) -> RtcState {
+        self.state_at(Rtc::now())
+    }
+
+    /// Restores the clock from a previous call to `state`.
+    ///
+    /// Unless the clock was halted, the counter is advanced by the host time elapsed since then,
+    /// as if the battery had kept it running in the meantime.
+    pub fn set_state(&mut self, state: RtcState) {
+        self.set_state_at(state, Rtc::now());
+    }
+
+    fn state_at(&self, now: u64) -> RtcState {
+        let mut rtc = self.clone();
+        rtc.sync(now);
+
+        RtcState {
+            counter: rtc.counter(),
+            latched: rtc.latched,
+            timestamp: now,
+        }
+    }
+
+    fn set_state_at(&mut self, state: RtcState, now: u64) {
+        self.set_counter(state.counter, state.timestamp);
+        self.sync(now);
+        self.latched = state.latched;
+    }
+
+    /// Brings the counter up to date with the host time `now`.
+    fn sync(&mut self, now: u64) {
+        if !self.halted {
+            self.secs += now.saturating_sub(self.synced_at);
+        }
+        self.synced_at = now;
+
+        if self.secs >= DAYS_WRAP * SECS_PER_DAY {
+            self.secs %= DAYS_WRAP * SECS_PER_DAY;
+            self.carry = true;
+        }
+    }
+
+    fn latch_at(&mut self, now: u64) {
+        self.sync(now);
+        self.latched = self.counter();
+    }
+
+    fn write_at(&mut self, reg: u8, val: u8, now: u64) {
+        self.sync(now);
+
+        let mut regs = self.counter();
+        regs[usize::from(reg - 0x08)] = val;
+
+        self.set_counter(regs, now);
+        self.latched[usize::from(reg - 0x08)] = val;
+    }
+
+    /// Returns the running counter in register layout.
+    fn counter(&self) -> [u8; 5] {
+        let days = self.secs / SECS_PER_DAY;
+        let mut dh = (days >> 8) as u8 & 0x01;
+
+        if self.halted {
+            dh |= 0x40;
+        }
+        if self.carry {
+            dh |= 0x80;
+        }
+
+        [
+            (self.secs % 60) as u8,
+            (self.secs / 60 % 60) as u8,
+            (self.secs / 3600 % 24) as u8,
+            days as u8,
+            dh,
+        ]
+    }
+
+    /// Loads the running counter from `regs`, as of host time `now`.
+    fn set_counter(&mut self, regs: [u8; 5], now: u64) {
+        let days = u64::from(regs[3]) | (u64::from(regs[4] & 0x01) << 8);
+
+        self.secs = days * SECS_PER_DAY
+            + u64::from(regs[2] & 0x1F) * 3600
+            + u64::from(regs[1] & 0x3F) * 60
+            + u64::from(regs[0] & 0x3F);
+        self.halted = regs[4] & 0x40 != 0;
+        self.carry = regs[4] & 0x80 != 0;
+        self.synced_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_follows_host_time() {
+        let mut rtc = Rtc::new();
+        rtc.set_counter([0; 5], 1000);
+
+        // Registers only change when latched
+        rtc.latch_at(1000 + 3661);
+        assert_eq!(rtc.registers(), [1, 1, 1, 0, 0]);
+        rtc.sync(1000 + 7200);
+        assert_eq!(rtc.read(0x0A), 1);
+
+        // Day counter overflows into bit 0 of the upper register, then sets the carry
+        rtc.latch_at(1000 + 300 * SECS_PER_DAY);
+        assert_eq!(rtc.registers(), [0, 0, 0, 44, 0x01]);
+        rtc.latch_at(1000 + 513 * SECS_PER_DAY);
+        assert_eq!(rtc.registers(), [0, 0, 0, 1, 0x80]);
+    }
+
+    #[test]
+    fn halted_counter_does_not_advance() {
+        let mut rtc = Rtc::new();
+        rtc.set_counter([0; 5], 1000);
+
+        rtc.write_at(0x0C, 0x40, 1010);
+        rtc.write_at(0x08, 30, 1010);
+        rtc.latch_at(2000);
+        assert_eq!(rtc.registers(), [30, 0, 0, 0, 0x40]);
+
+        rtc.write_at(0x0C, 0x00, 2000);
+        rtc.latch_at(2005);
+        assert_eq!(rtc.registers(), [35, 0, 0, 0, 0x00]);
+    }
+
+    #[test]
+    fn restored_state_catches_up_with_host_time() {
+        let mut rtc = Rtc::new();
+        rtc.set_counter([0; 5], 1000);
+        rtc.latch_at(1010);
+
+        // The running counter is saved, not the stale latched registers
+        let state = rtc.state_at(1030);
+        assert_eq!(state.counter, [30, 0, 0, 0, 0]);
+        assert_eq!(state.latched, [10, 0, 0, 0, 0]);
+        assert_eq!(RtcState::from_bytes(&state.to_bytes()), Some(state));
+
+        // The time elapsed since then is accounted for when the state is restored
+        let mut restored = Rtc::new();
+        restored.set_state_at(state, 1030 + 3600);
+        assert_eq!(restored.registers(), [10, 0, 0, 0, 0]);
+        restored.latch_at(1030 + 3600);
+        assert_eq!(restored.registers(), [30, 0, 1, 0, 0]);
+
+        // ...unless the clock was halted
+        let halted = RtcState {
+            counter: [30, 0, 0, 0, 0x40],
+            ..state
+        };
+        restored.set_state_at(halted, 1030 + 3600);
+        restored.latch_at(1030 + 3600);
+        assert_eq!(restored.registers(), [30, 0, 0, 0, 0x40]);
+    }
+}
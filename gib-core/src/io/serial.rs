@@ -1,12 +1,35 @@
+use serde::{Deserialize, Serialize};
+
 use crate::{
     dbg,
-    io::{InterruptSource, IoReg, IrqSource},
+    io::{InterruptSource, IoReg, IrqSource, SerialLink},
     mem::{MemR, MemRW, MemW},
 };
 
+/// Number of M-cycles needed to shift a single bit using the internal clock (8192 Hz).
+const CYCLES_PER_BIT: u16 = 128;
+
+#[derive(Serialize, Deserialize)]
 pub struct Serial {
     sb: IoReg<u8>,
     sc: IoReg<u8>,
+
+    // Byte being shifted in from the link peer
+    sb_in: u8,
+    bits_left: u8,
+    bit_cycles: u16,
+    irq_pending: bool,
+
+    // Whether the link peer has yet to answer the transfer in progress
+    #[serde(skip)]
+    awaiting_reply: bool,
+
+    // Bytes sent with the internal clock, not taken yet, if capture is enabled
+    #[serde(skip)]
+    output: Option<Vec<u8>>,
+
+    #[serde(skip)]
+    link: Option<Box<dyn SerialLink>>,
 }
 
 impl Default for Serial {
@@ -14,6 +37,17 @@ impl Default for Serial {
         Serial {
             sb: IoReg(0x00),
             sc: IoReg(0x00),
+
+            sb_in: 0xFF,
+            bits_left: 0,
+            bit_cycles: 0,
+            irq_pending: false,
+
+            awaiting_reply: false,
+
+            output: None,
+
+            link: None,
         }
     }
 }
@@ -22,17 +56,144 @@ impl Serial {
     pub fn new() -> Serial {
         Serial::default()
     }
+
+    /// Advances an ongoing transfer by a single M-cycle.
+    ///
+    /// Transfers driven by the internal clock shift in the byte received from the link peer
+    /// one bit at a time, or all 1s if no peer is connected. Since the peer may be on
+    /// another host, the transfer is held until its byte has arrived, or the link gave up on it.
+    /// With the external clock selected, the clock is provided by the peer, so the transfer
+    /// only completes when the peer starts one of its own. Without a peer, it never completes
+    /// and SC bit 7 stays set, exactly like on real hardware: games which support
+    /// a link cable are expected to handle this with a timeout.
+    pub fn tick(&mut self) {
+        if !self.transferring() || !self.sc.bit(0) {
+            // A transfer started by the peer takes a while on its side too,
+            // so there's no need to look for one on every cycle
+            self.bit_cycles += 1;
+            if self.bit_cycles >= CYCLES_PER_BIT {
+                self.bit_cycles = 0;
+                self.poll_link();
+            }
+            return;
+        }
+
+        if self.awaiting_reply && !self.fetch_reply() {
+            return;
+        }
+
+        self.bit_cycles += 1;
+        if self.bit_cycles < CYCLES_PER_BIT {
+            return;
+        }
+        self.bit_cycles = 0;
+
+        self.sb.0 = (self.sb.0 << 1) | (self.sb_in >> 7);
+        self.sb_in = (self.sb_in << 1) | 0x01;
+        self.bits_left -= 1;
+
+        if self.bits_left == 0 {
+            self.sc.clear_bit(7);
+            self.irq_pending = true;
+        }
+    }
+
+    /// Returns whether a transfer is in progress (ie. SC bit 7 is set).
+    pub fn transferring(&self) -> bool {
+        self.sc.bit(7)
+    }
+
+    /// Fetches the link peer's answer to the transfer in progress, returning whether it arrived.
+    fn fetch_reply(&mut self) -> bool {
+        let reply = match self.link {
+            Some(ref mut link) => link.take_reply(),
+            None => Some(0xFF),
+        };
+
+        if let Some(byte) = reply {
+            self.sb_in = byte;
+            self.awaiting_reply = false;
+        }
+        reply.is_some()
+    }
+
+    /// Answers a transfer started by the link peer, if any.
+    ///
+    /// The whole byte is exchanged at once, completing a transfer waiting on the external clock.
+    /// If no such transfer is in progress, the peer reads all 1s, like with no cable plugged in.
+    fn poll_link(&mut self) {
+        let link = match self.link {
+            Some(ref mut link) => link,
+            None => return,
+        };
+
+        if let Some(byte) = link.poll() {
+            if self.sc.bit(7) && !self.sc.bit(0) {
+                link.reply(self.sb.0);
+                self.sb.0 = byte;
+                self.sc.clear_bit(7);
+                self.irq_pending = true;
+            } else {
+                link.reply(0xFF);
+            }
+        }
+    }
+
+    /// Plugs a link cable connected to `link` in, replacing the current one.
+    pub fn set_link(&mut self, link: Option<Box<dyn SerialLink>>) {
+        self.link = link;
+    }
+
+    /// Unplugs the link cable, if any, returning its other end.
+    pub fn take_link(&mut self) -> Option<Box<dyn SerialLink>> {
+        self.link.take()
+    }
+
+    /// Enables or disables capturing the bytes sent, to be retrieved with `take_output`.
+    ///
+    /// Capture is disabled by default, since the bytes pile up until they are taken.
+    /// Disabling it drops any byte not taken yet.
+    pub fn set_capture_output(&mut self, enabled: bool) {
+        if enabled != self.output.is_some() {
+            self.output = if enabled { Some(Vec::new()) } else { None };
+        }
+    }
+
+    /// Returns whether the bytes sent are captured.
+    pub fn capture_output(&self) -> bool {
+        self.output.is_some()
+    }
+
+    /// Returns the bytes sent since the last call, clearing them.
+    ///
+    /// A byte is recorded when a transfer using the internal clock is started,
+    /// which is how test ROMs (eg. Blargg's) print their results.
+    /// Nothing is recorded unless capture was enabled with `set_capture_output`.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.as_mut().map_or_else(Vec::new, std::mem::take)
+    }
+
+    /// Takes over the link cable and the bytes sent but not taken yet from `prev`,
+    /// which are not part of the state.
+    pub(crate) fn take_host_state(&mut self, prev: &mut Serial) {
+        self.output = prev.output.take();
+        self.link = prev.link.take();
+    }
 }
 
 impl InterruptSource for Serial {
     fn get_and_clear_irq(&mut self) -> Option<IrqSource> {
-        None
+        if self.irq_pending {
+            self.irq_pending = false;
+            Some(IrqSource::Serial)
+        } else {
+            None
+        }
     }
 }
 
 impl MemR for Serial {
     fn read(&self, addr: u16) -> Result<u8, dbg::TraceEvent> {
-        // TODO: it's gonna be a while before serial link is implemented :)
         Ok(match addr {
             0xFF01 => self.sb.0,
             0xFF02 => self.sc.0 | 0x7E,
@@ -43,10 +204,32 @@ impl MemR for Serial {
 
 impl MemW for Serial {
     fn write(&mut self, addr: u16, val: u8) -> Result<(), dbg::TraceEvent> {
-        // TODO: it's gonna be a while before serial link is implemented :)
         match addr {
             0xFF01 => self.sb.0 = val,
-            0xFF02 => self.sc.0 = val,
+            0xFF02 => {
+                self.sc.0 = val;
+
+                // Setting bit 7 (re)starts a transfer
+                if self.transferring() {
+                    self.bits_left = 8;
+                    self.bit_cycles = 0;
+
+                    // With the internal clock, the byte is sent to the peer right away
+                    if self.sc.bit(0) {
+                        if let Some(ref mut output) = self.output {
+                            output.push(self.sb.0);
+                        }
+                        self.sb_in = 0xFF;
+                        self.awaiting_reply = match self.link {
+                            Some(ref mut link) => {
+                                link.start_transfer(self.sb.0);
+                                true
+                            }
+                            None => false,
+                        };
+                    }
+                }
+            }
             _ => unreachable!(),
         };
         Ok(())
@@ -54,3 +237,169 @@ impl MemW for Serial {
 }
 
 impl MemRW for Serial {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// A peer which answers every transfer with a fixed byte after `delay` polls,
+    /// and starts one when told to.
+    #[derive(Default)]
+    struct FakePeer {
+        sent: Arc<Mutex<Vec<u8>>>,
+        pending: Option<u8>,
+        answer: u8,
+        delay: u32,
+    }
+
+    impl SerialLink for FakePeer {
+        fn start_transfer(&mut self, byte: u8) {
+            self.sent.lock().unwrap().push(byte);
+        }
+
+        fn take_reply(&mut self) -> Option<u8> {
+            if self.delay > 0 {
+                self.delay -= 1;
+                None
+            } else {
+                Some(self.answer)
+            }
+        }
+
+        fn poll(&mut self) -> Option<u8> {
+            self.pending.take()
+        }
+
+        fn reply(&mut self, byte: u8) {
+            self.sent.lock().unwrap().push(byte);
+        }
+    }
+
+    #[test]
+    fn only_internal_clock_transfers_complete() {
+        // Internal clock: done after 8 bits, with all 1s shifted in from the missing peer
+        let mut serial = Serial::new();
+        serial.set_capture_output(true);
+        serial.write(0xFF01, 0x42).unwrap();
+        serial.write(0xFF02, 0x81).unwrap();
+
+        for _ in 0..8 * CYCLES_PER_BIT - 1 {
+            serial.tick();
+        }
+        assert_eq!(serial.read(0xFF02).unwrap(), 0xFF);
+        assert!(serial.get_and_clear_irq().is_none());
+
+        serial.tick();
+        assert_eq!(serial.read(0xFF02).unwrap(), 0x7F);
+        assert_eq!(serial.read(0xFF01).unwrap(), 0xFF);
+        assert!(matches!(
+            serial.get_and_clear_irq(),
+            Some(IrqSource::Serial)
+        ));
+        assert_eq!(serial.take_output(), [0x42]);
+        assert!(serial.take_output().is_empty());
+
+        // External clock: the transfer is left pending forever
+        let mut serial = Serial::new();
+        serial.set_capture_output(true);
+        serial.write(0xFF01, 0x42).unwrap();
+        serial.write(0xFF02, 0x80).unwrap();
+
+        for _ in 0..100 * CYCLES_PER_BIT {
+            serial.tick();
+        }
+        assert_eq!(serial.read(0xFF02).unwrap(), 0xFE);
+        assert_eq!(serial.read(0xFF01).unwrap(), 0x42);
+        assert!(serial.get_and_clear_irq().is_none());
+        assert!(serial.take_output().is_empty());
+
+        // Nothing is captured unless requested
+        let mut serial = Serial::new();
+        serial.write(0xFF01, 0x42).unwrap();
+        serial.write(0xFF02, 0x81).unwrap();
+        assert!(serial.take_output().is_empty());
+        assert!(serial.output.is_none());
+    }
+
+    #[test]
+    fn bytes_are_exchanged_with_the_link_peer() {
+        let sent = Arc::new(Mutex::new(vec![]));
+
+        // Internal clock: the peer's byte is shifted in
+        let mut serial = Serial::new();
+        serial.set_link(Some(Box::new(FakePeer {
+            sent: sent.clone(),
+            answer: 0xA5,
+            ..FakePeer::default()
+        })));
+        serial.write(0xFF01, 0x42).unwrap();
+        serial.write(0xFF02, 0x81).unwrap();
+
+        for _ in 0..4 * CYCLES_PER_BIT {
+            serial.tick();
+        }
+        assert_eq!(serial.read(0xFF01).unwrap(), 0x2A);
+
+        for _ in 0..4 * CYCLES_PER_BIT {
+            serial.tick();
+        }
+        assert_eq!(serial.read(0xFF01).unwrap(), 0xA5);
+        assert!(serial.get_and_clear_irq().is_some());
+        assert_eq!(*sent.lock().unwrap(), [0x42]);
+
+        // Internal clock, with a slow peer: the transfer waits for its byte
+        serial.set_link(Some(Box::new(FakePeer {
+            sent: sent.clone(),
+            answer: 0x5A,
+            delay: 1000,
+            ..FakePeer::default()
+        })));
+        serial.write(0xFF02, 0x81).unwrap();
+
+        for _ in 0..1000 {
+            serial.tick();
+        }
+        assert_eq!(serial.read(0xFF01).unwrap(), 0xA5);
+
+        for _ in 0..8 * CYCLES_PER_BIT {
+            serial.tick();
+        }
+        assert_eq!(serial.read(0xFF01).unwrap(), 0x5A);
+        assert!(serial.get_and_clear_irq().is_some());
+        assert_eq!(*sent.lock().unwrap(), [0x42, 0xA5]);
+
+        // External clock: the transfer completes when the peer starts one
+        let mut serial = Serial::new();
+        serial.write(0xFF01, 0x17).unwrap();
+        serial.write(0xFF02, 0x80).unwrap();
+        serial.set_link(Some(Box::new(FakePeer {
+            sent: sent.clone(),
+            pending: Some(0x71),
+            ..FakePeer::default()
+        })));
+
+        for _ in 0..CYCLES_PER_BIT {
+            serial.tick();
+        }
+        assert_eq!(serial.read(0xFF01).unwrap(), 0x71);
+        assert_eq!(serial.read(0xFF02).unwrap(), 0x7E);
+        assert!(serial.get_and_clear_irq().is_some());
+        assert_eq!(*sent.lock().unwrap(), [0x42, 0xA5, 0x17]);
+
+        // No transfer in progress: the peer reads all 1s
+        serial.set_link(Some(Box::new(FakePeer {
+            sent: sent.clone(),
+            pending: Some(0x33),
+            ..FakePeer::default()
+        })));
+
+        for _ in 0..CYCLES_PER_BIT {
+            serial.tick();
+        }
+        assert_eq!(serial.read(0xFF01).unwrap(), 0x71);
+        assert!(serial.get_and_clear_irq().is_none());
+        assert_eq!(*sent.lock().unwrap(), [0x42, 0xA5, 0x17, 0xFF]);
+    }
+}
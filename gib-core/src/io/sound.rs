@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use bitflags::bitflags;
 use crossbeam::queue::ArrayQueue;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     dbg,
@@ -9,14 +10,16 @@ use crate::{
     mem::{MemR, MemW},
 };
 
-const FRAME_SEQUENCER_CLOCK_RELOAD: u32 = 4_194_304 / 512;
-
 // Maximum length counter value for tone channels
 const TONE_CH_LEN_MAX: u32 = 64;
 const WAVE_CH_LEN_MAX: u32 = 256;
 
+// Fraction of the high-pass filter capacitor charge kept at every clock cycle (DMG value)
+const HPF_CHARGE_PER_CYCLE: f32 = 0.999958;
+
 bitflags! {
     // NRx0 - Channel x Sweep register (R/W)
+    #[derive(Serialize, Deserialize)]
     struct NRx0: u8 {
         const SWEEP_TIME  = 0b_0111_0000;
         const SWEEP_NEG   = 0b_0000_1000;
@@ -28,6 +31,7 @@ bitflags! {
 
 bitflags! {
     // NRx1 - Channel x Sound Length/Wave Pattern Duty (R/W)
+    #[derive(Serialize, Deserialize)]
     struct NRx1: u8 {
         const WAVE_DUTY = 0b_1100_0000;
         const SOUND_LEN = 0b_0011_1111;
@@ -38,6 +42,7 @@ bitflags! {
 
 bitflags! {
     // NRx2 - Channel x Volume Envelope (R/W)
+    #[derive(Serialize, Deserialize)]
     struct NRx2: u8 {
         const START_VOL  = 0b_1111_0000;
         const ENV_DIR    = 0b_0000_1000;
@@ -51,6 +56,7 @@ bitflags! {
 
 bitflags! {
     // NR43 - Channel 4 Polynomial Counter (R/W)
+    #[derive(Serialize, Deserialize)]
     struct NRx3: u8 {
         const CLOCK_SHIFT = 0b_1111_0000;
         const WIDTH_7_BIT = 0b_0000_1000;
@@ -60,6 +66,7 @@ bitflags! {
 
 bitflags! {
     // NRx4 - Channel x Frequency hi data (R/W)
+    #[derive(Serialize, Deserialize)]
     struct NRx4: u8 {
         const TRIGGER = 0b_1000_0000;
         const LEN_EN  = 0b_0100_0000;
@@ -69,6 +76,7 @@ bitflags! {
 
 bitflags! {
     // NR50 - Channel control / ON-OFF / Volume (R/W)
+    #[derive(Serialize, Deserialize)]
     struct NR50: u8 {
         const VIN_L_EN  = 0b_1000_0000;
         const LEFT_VOL  = 0b_0111_0000;
@@ -79,6 +87,7 @@ bitflags! {
 
 bitflags! {
     // NR51 - Selection of Sound output terminal (R/W)
+    #[derive(Serialize, Deserialize)]
     struct NR51: u8 {
         const OUT4_L = 0b_1000_0000;
         const OUT3_L = 0b_0100_0000;
@@ -93,6 +102,7 @@ bitflags! {
 
 bitflags! {
     // NR52 - Sound on/off
+    #[derive(Serialize, Deserialize)]
     struct NR52: u8 {
         const PWR_CTRL = 0b_1000_0000;
         const OUT_4_EN = 0b_0000_1000;
@@ -104,6 +114,7 @@ bitflags! {
 
 /// A sound channel able to produce quadrangular wave patterns
 /// with optional sweep and envelope functions.
+#[derive(Serialize, Deserialize)]
 pub struct ToneChannel {
     // Channel registers
     nrx0: NRx0,
@@ -442,6 +453,7 @@ impl MemW for ToneChannel {
 
 /// A sound channel used to output digital sound
 /// from a 32-digit sample buffer (Wave RAM).
+#[derive(Serialize, Deserialize)]
 pub struct WaveChannel {
     // Channel registers
     nrx0: NRx0,
@@ -487,21 +499,44 @@ impl Default for WaveChannel {
 impl WaveChannel {
     /// Advances the internal timer state by one M-cycle.
     fn tick(&mut self) {
+        let mut cycles = 4;
+
         // Every N input clocks, advance the position counter and latch the new sample.
-        if self.timer_counter < 4 {
-            self.timer_counter = self.get_period() - self.timer_counter;
+        // With the shortest periods, this can happen more than once per M-cycle.
+        while self.timer_counter <= cycles {
+            cycles -= self.timer_counter;
+            self.timer_counter = self.get_period();
+            self.step_position();
+        }
+        self.timer_counter -= cycles;
+    }
 
-            self.position_counter = (self.position_counter + 1) % 32;
-            self.sample_buffer = self.wave_ram[self.position_counter >> 1];
+    /// Moves to the next of the 32 samples in Wave RAM and latches it in the sample buffer.
+    fn step_position(&mut self) {
+        self.position_counter = (self.position_counter + 1) % 32;
+        self.sample_buffer = self.wave_ram[self.position_counter >> 1];
 
-            // Select the correct nibble
-            if self.position_counter & 0x1 == 0 {
-                self.sample_buffer >>= 4;
-            } else {
-                self.sample_buffer &= 0x0F;
-            }
+        // Select the correct nibble
+        if self.position_counter & 0x1 == 0 {
+            self.sample_buffer >>= 4;
         } else {
-            self.timer_counter -= 4;
+            self.sample_buffer &= 0x0F;
+        }
+    }
+
+    /// Returns the index in Wave RAM of the byte targeted by CPU accesses.
+    ///
+    /// While the channel is playing, the CPU can only access the byte the channel
+    /// is currently reading from, whatever the requested address.
+    ///
+    /// NOTE: on DMG, such accesses only succeed if they happen on the very same cycle
+    /// the channel reads Wave RAM, otherwise reads return 0xFF and writes are ignored.
+    /// This is not emulated: the current byte is always accessed, like on CGB.
+    fn wave_ram_index(&self, addr: u16) -> usize {
+        if self.enabled {
+            self.position_counter >> 1
+        } else {
+            usize::from(addr) - 0xFF30
         }
     }
 
@@ -531,15 +566,30 @@ impl WaveChannel {
         (hi << 8) | lo
     }
 
-    /// Returns the channel's current volume.
-    fn get_volume(&self) -> u8 {
-        u8::from(self.enabled) * ((self.nrx2 & NRx2::WAVE_VOLUME).bits() >> 5)
+    /// Returns the right shift applied to samples by the current volume code.
+    ///
+    /// Code   Volume   Shift
+    /// ---------------------
+    /// 0      0%       4
+    /// 1      100%     0
+    /// 2      50%      1
+    /// 3      25%      2
+    fn get_volume_shift(&self) -> u8 {
+        match (self.nrx2 & NRx2::WAVE_VOLUME).bits() >> 5 {
+            0 => 4,
+            code => code - 1,
+        }
     }
 
     /// Returns the channel's current output level, ready to be fed to the mixer.
     fn get_channel_out(&self) -> i16 {
         if self.dac_on() {
-            i16::from(self.sample_buffer >> (self.get_volume() - 1))
+            let sample = if self.enabled {
+                self.sample_buffer >> self.get_volume_shift()
+            } else {
+                0
+            };
+            i16::from(sample) * 2 - 15
         } else {
             0
         }
@@ -564,10 +614,12 @@ impl WaveChannel {
                 self.length_counter = 0;
             }
 
-            // Frequency timer is reloaded with period
-            self.timer_counter = self.get_period();
+            // Frequency timer is reloaded with period, plus a 6-cycle delay
+            // before the channel starts fetching samples
+            self.timer_counter = self.get_period() + 6;
 
-            // Wave channel's position is set to 0 but sample buffer is NOT refilled
+            // Wave channel's position is set to 0 but sample buffer is NOT refilled,
+            // so the first sample to be played after a trigger is the one at position 1
             self.position_counter = 0;
 
             // Note that if the channel's DAC is off, after the above actions occur
@@ -614,6 +666,7 @@ impl MemW for WaveChannel {
 }
 
 /// A sound channel used to output white noise.
+#[derive(Serialize, Deserialize)]
 pub struct NoiseChannel {
     // Channel registers
     nrx1: NRx1,
@@ -646,7 +699,7 @@ impl Default for NoiseChannel {
             nrx3: NRx3::from_bits_truncate(0x00),
             nrx4: NRx4::from_bits_truncate(0xBF),
 
-            lfsr: 0xFFFF,
+            lfsr: 0x7FFF,
             enabled: false,
             timer_counter: 0,
 
@@ -670,17 +723,30 @@ impl NoiseChannel {
         if self.timer_counter < 4 {
             self.timer_counter = self.get_period() - self.timer_counter;
 
-            let x = (self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
-            self.lfsr = (self.lfsr >> 1) | (x << 14);
-
-            if self.nrx3.contains(NRx3::WIDTH_7_BIT) {
-                self.lfsr = (self.lfsr & !0b_1000_0000) | (x << 7);
+            // With a clock shift of 14 or 15, the LFSR receives no clocks
+            if self.get_clock_shift() < 14 {
+                self.clock_lfsr();
             }
         } else {
             self.timer_counter -= 4;
         }
 
-        self.waveform_level = !(self.lfsr & 0x1) as i16;
+        // The output is bit 0 of the LFSR, inverted
+        self.waveform_level = i16::from(self.lfsr & 0x1 == 0);
+    }
+
+    /// Shifts the LFSR by one bit.
+    ///
+    /// The low two bits are XORed and the result is put into the now-empty bit 14.
+    /// In 7-bit mode, the result is also put into bit 6, which shortens the sequence
+    /// from 32767 to 127 steps.
+    fn clock_lfsr(&mut self) {
+        let x = (self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
+        self.lfsr = (self.lfsr >> 1) | (x << 14);
+
+        if self.nrx3.contains(NRx3::WIDTH_7_BIT) {
+            self.lfsr = (self.lfsr & !0b_0100_0000) | (x << 6);
+        }
     }
 
     /// Advances the volume envelope unit by 1/64th of a second.
@@ -723,10 +789,15 @@ impl NoiseChannel {
         }
     }
 
-    /// Returns the channel's period.
+    /// Returns the channel's period, ie. the divisor from NR43 shifted left by the clock shift.
     pub fn get_period(&self) -> u32 {
         let div: u32 = (self.nrx3 & NRx3::DIV_CODE).bits().into();
-        (if div == 0 { 8 } else { div << 4 }) << 4
+        (if div == 0 { 8 } else { div << 4 }) << self.get_clock_shift()
+    }
+
+    /// Returns the clock shift from NR43.
+    fn get_clock_shift(&self) -> u8 {
+        (self.nrx3 & NRx3::CLOCK_SHIFT).bits() >> 4
     }
 
     /// Returns the channel's current volume.
@@ -777,7 +848,7 @@ impl NoiseChannel {
             self.vol_env_enabled = true;
 
             // Noise channel's LFSR bits are all set to 1.
-            self.lfsr = 0xFFFF;
+            self.lfsr = 0x7FFF;
 
             // Note that if the channel's DAC is off, after the above actions occur
             // the channel will be immediately disabled again.
@@ -825,6 +896,7 @@ impl MemW for NoiseChannel {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct APU {
     // Channels
     pub ch1: ToneChannel,
@@ -839,12 +911,27 @@ pub struct APU {
 
     // Audio sample channel
     sample_rate_counter: f32,
+    #[serde(skip)]
     sample_channel: Option<Arc<ArrayQueue<i16>>>,
     sample_period: f32,
 
-    // Frame sequencer clocks
-    frame_sequencer_clock: u32,
+    // High-pass filter removing the DC offset from the output, one capacitor per terminal
+    #[serde(skip)]
+    high_pass_filter: bool,
+    #[serde(skip)]
+    hpf_charge_factor: f32,
+    #[serde(skip)]
+    hpf_capacitors: [f32; 2],
+
+    // Frame sequencer, clocked by the falling edges of the DIV-APU bit
+    div_apu: bool,
     frame_sequencer_ticks: u32,
+
+    // Debug
+    #[serde(skip)]
+    report_writes_while_off: bool,
+    #[serde(skip)]
+    write_while_off_reported: bool,
 }
 
 impl Default for APU {
@@ -880,8 +967,15 @@ impl Default for APU {
             sample_channel: None,
             sample_period: std::f32::INFINITY,
 
-            frame_sequencer_clock: FRAME_SEQUENCER_CLOCK_RELOAD,
+            high_pass_filter: true,
+            hpf_charge_factor: 0f32,
+            hpf_capacitors: [0f32; 2],
+
+            div_apu: false,
             frame_sequencer_ticks: 7,
+
+            report_writes_while_off: false,
+            write_while_off_reported: false,
         }
     }
 }
@@ -895,28 +989,31 @@ impl APU {
     }
 
     /// Advances the sound controller state machine by a single M-cycle.
-    pub fn tick(&mut self) {
-        // Tick the frame sequencer
-        self.frame_sequencer_clock -= 4;
-
-        let (clk_64, clk_128, clk_256) = if self.frame_sequencer_clock == 0 {
-            self.frame_sequencer_clock = FRAME_SEQUENCER_CLOCK_RELOAD;
-            self.frame_sequencer_ticks = (self.frame_sequencer_ticks + 1) % 8;
-            (
-                self.frame_sequencer_ticks == 7,
-                self.frame_sequencer_ticks & 0b11 == 2,
-                self.frame_sequencer_ticks & 0b1 == 0,
-            )
-        } else {
-            (false, false, false)
-        };
-
+    ///
+    /// `div_apu` is the current level of the DIV bit driving the frame sequencer,
+    /// which is clocked on its falling edges (see `frame_sequencer_step`).
+    pub fn tick(&mut self, div_apu: bool) {
         // Internal timer clock tick
         self.ch1.tick();
         self.ch2.tick();
         self.ch3.tick();
         self.ch4.tick();
 
+        if self.div_apu && !div_apu {
+            self.clock_frame_sequencer();
+        }
+        self.div_apu = div_apu;
+
+        self.tick_mixer();
+    }
+
+    /// Advances the frame sequencer to its next step, clocking the units scheduled on it.
+    fn clock_frame_sequencer(&mut self) {
+        self.frame_sequencer_ticks = (self.frame_sequencer_ticks + 1) % 8;
+
+        let step = self.frame_sequencer_ticks;
+        let (clk_64, clk_128, clk_256) = (step == 7, step & 0b11 == 2, step & 0b1 == 0);
+
         // Volume envelope clock tick
         if clk_64 {
             self.ch1.tick_vol_env();
@@ -936,8 +1033,6 @@ impl APU {
             self.ch3.tick_len_ctr();
             self.ch4.tick_len_ctr();
         }
-
-        self.tick_mixer();
     }
 
     /// Update mixer output
@@ -948,57 +1043,96 @@ impl APU {
         if self.sample_rate_counter > self.sample_period {
             self.sample_rate_counter -= self.sample_period;
 
-            if let Some(ref mut sink) = self.sample_channel {
-                let ch1 = self.ch1.get_channel_out();
-                let ch2 = self.ch2.get_channel_out();
-                let ch3 = self.ch3.get_channel_out();
-                let ch4 = self.ch4.get_channel_out();
+            // Samples are pushed as (left, right) pairs: if there is no room
+            // for both, drop the pair to keep the two channels in order.
+            match self.sample_channel {
+                Some(ref sink) if sink.capacity() - sink.len() >= 2 => (),
+                _ => return,
+            }
 
-                let mut so2 = 0;
-                let mut so1 = 0;
+            let mut samples = self.mix();
 
-                // If the peripheral is disabled, no sound is emitted.
-                if !self.nr52.contains(NR52::PWR_CTRL) {
-                    sink.push(0).unwrap_or(());
-                } else {
-                    // Update LEFT speaker
-                    if self.nr51.contains(NR51::OUT1_L) {
-                        so2 += ch1;
-                    }
-                    if self.nr51.contains(NR51::OUT2_L) {
-                        so2 += ch2;
-                    }
-                    if self.nr51.contains(NR51::OUT3_L) {
-                        so2 += ch3;
-                    }
-                    if self.nr51.contains(NR51::OUT4_L) {
-                        so2 += ch4;
-                    }
-
-                    // Update RIGHT speaker
-                    if self.nr51.contains(NR51::OUT1_R) {
-                        so1 += ch1;
-                    }
-                    if self.nr51.contains(NR51::OUT2_R) {
-                        so1 += ch2;
-                    }
-                    if self.nr51.contains(NR51::OUT3_R) {
-                        so1 += ch3;
-                    }
-                    if self.nr51.contains(NR51::OUT4_R) {
-                        so1 += ch4;
-                    }
-
-                    // Adjust master volumes
-                    so2 *= 1 + i16::from((self.nr50 & NR50::LEFT_VOL).bits() >> 4);
-                    so1 *= 1 + i16::from((self.nr50 & NR50::RIGHT_VOL).bits());
-
-                    // Produce a sample which is an average of the two channels.
-                    // TODO implement true stero sound.
-                    sink.push((so1 + so2) / 2).unwrap_or(());
-                }
+            if self.high_pass_filter {
+                samples = self.high_pass(samples);
+            }
+
+            if let Some(ref sink) = self.sample_channel {
+                sink.push(samples[0]).unwrap_or(());
+                sink.push(samples[1]).unwrap_or(());
+            }
+        }
+    }
+
+    /// Mixes the channel outputs into a (left, right) sample pair.
+    fn mix(&self) -> [i16; 2] {
+        let ch1 = self.ch1.get_channel_out();
+        let ch2 = self.ch2.get_channel_out();
+        let ch3 = self.ch3.get_channel_out();
+        let ch4 = self.ch4.get_channel_out();
+
+        let mut so2 = 0;
+        let mut so1 = 0;
+
+        // If the peripheral is disabled, no sound is emitted.
+        if self.nr52.contains(NR52::PWR_CTRL) {
+            // Update LEFT speaker
+            if self.nr51.contains(NR51::OUT1_L) {
+                so2 += ch1;
+            }
+            if self.nr51.contains(NR51::OUT2_L) {
+                so2 += ch2;
             }
+            if self.nr51.contains(NR51::OUT3_L) {
+                so2 += ch3;
+            }
+            if self.nr51.contains(NR51::OUT4_L) {
+                so2 += ch4;
+            }
+
+            // Update RIGHT speaker
+            if self.nr51.contains(NR51::OUT1_R) {
+                so1 += ch1;
+            }
+            if self.nr51.contains(NR51::OUT2_R) {
+                so1 += ch2;
+            }
+            if self.nr51.contains(NR51::OUT3_R) {
+                so1 += ch3;
+            }
+            if self.nr51.contains(NR51::OUT4_R) {
+                so1 += ch4;
+            }
+
+            // Adjust master volumes
+            so2 *= 1 + i16::from((self.nr50 & NR50::LEFT_VOL).bits() >> 4);
+            so1 *= 1 + i16::from((self.nr50 & NR50::RIGHT_VOL).bits());
         }
+
+        [so2, so1]
+    }
+
+    /// Runs a sample pair through the high-pass filter found between the DACs and the
+    /// speaker, which removes the DC offset of the channel outputs.
+    ///
+    /// The filter is a capacitor, which only charges while at least one DAC is on.
+    fn high_pass(&mut self, samples: [i16; 2]) -> [i16; 2] {
+        let dacs_on =
+            self.ch1.dac_on() || self.ch2.dac_on() || self.ch3.dac_on() || self.ch4.dac_on();
+
+        if !dacs_on {
+            return [0; 2];
+        }
+
+        let mut out = [0; 2];
+
+        for (i, &sample) in samples.iter().enumerate() {
+            let input = f32::from(sample);
+            let filtered = input - self.hpf_capacitors[i];
+
+            self.hpf_capacitors[i] = input - filtered * self.hpf_charge_factor;
+            out[i] = filtered.round() as i16;
+        }
+        out
     }
 
     /// Handles a read operation to the power register, mainly to read the sound register status.
@@ -1047,7 +1181,6 @@ impl APU {
             // When powered on, the frame sequencer is reset so that the next step will be 0,
             // the square duty units are reset to the first step of the waveform,
             // and the wave channel's sample buffer is reset to 0.
-            self.frame_sequencer_clock = Self::default().frame_sequencer_clock;
             self.frame_sequencer_ticks = Self::default().frame_sequencer_ticks;
             self.ch2.timer_counter = 0;
             self.ch3.sample_buffer = 0;
@@ -1062,12 +1195,94 @@ impl APU {
     pub fn set_sample_rate(&mut self, sample_rate: f32) {
         self.sample_period = (crate::CPU_CLOCK as f32) / sample_rate;
         self.sample_rate_counter = 0f32;
+
+        // The capacitor discharges once per clock cycle, so once per sample period here
+        self.hpf_charge_factor = HPF_CHARGE_PER_CYCLE.powf(self.sample_period);
+    }
+
+    /// Enables or disables the high-pass filter applied to the output samples.
+    ///
+    /// Bypassing the filter makes the raw DAC output, DC offset included, reach the sink,
+    /// which can be useful when debugging the channels.
+    pub fn set_high_pass_filter(&mut self, enabled: bool) {
+        self.high_pass_filter = enabled;
+        self.hpf_capacitors = [0f32; 2];
+    }
+
+    /// Returns whether the high-pass filter is applied to the output samples.
+    pub fn high_pass_filter(&self) -> bool {
+        self.high_pass_filter
     }
 
-    /// Sets the current audio sink.
+    /// Sets the current audio sink, which receives interleaved stereo samples (left, right).
     pub fn set_audio_sink(&mut self, sink: Arc<ArrayQueue<i16>>) {
         self.sample_channel = Some(sink);
     }
+
+    /// Takes over the audio sink and the debugging settings of `prev`,
+    /// which are not part of the state.
+    pub(crate) fn take_host_state(&mut self, prev: &mut APU) {
+        self.sample_channel = prev.sample_channel.take();
+        self.sample_period = prev.sample_period;
+        self.sample_rate_counter = prev.sample_rate_counter;
+        self.high_pass_filter = prev.high_pass_filter;
+        self.hpf_charge_factor = prev.hpf_charge_factor;
+        self.hpf_capacitors = prev.hpf_capacitors;
+        self.report_writes_while_off = prev.report_writes_while_off;
+    }
+
+    /// Enables or disables reporting of writes to the sound registers while the APU is off.
+    ///
+    /// Such writes are either ignored or only affect the length counters, which usually means
+    /// that the sound engine initializes the channels before powering the APU on.
+    /// When enabled, the first offending write after powering off raises a
+    /// `TraceEvent::ApuWriteWhileOff`, so that execution can still be resumed afterwards.
+    /// The write itself is performed (or ignored) as usual.
+    pub fn set_report_writes_while_off(&mut self, enabled: bool) {
+        self.report_writes_while_off = enabled;
+    }
+
+    /// Returns whether writes to the sound registers while the APU is off are reported.
+    pub fn report_writes_while_off(&self) -> bool {
+        self.report_writes_while_off
+    }
+
+    /// Returns the step (0-7) most recently clocked by the frame sequencer.
+    ///
+    /// The frame sequencer runs at 512 Hz, being clocked by the falling edges of DIV bit 4
+    /// (bit 5 in CGB double speed mode, where DIV runs twice as fast).
+    /// Each step clocks the following units:
+    ///
+    /// ```text
+    /// Step | Length (256 Hz) | Sweep (128 Hz) | Envelope (64 Hz)
+    /// -----+-----------------+----------------+-----------------
+    /// 0    | Yes             |                |
+    /// 1    |                 |                |
+    /// 2    | Yes             | Yes            |
+    /// 3    |                 |                |
+    /// 4    | Yes             |                |
+    /// 5    |                 |                |
+    /// 6    | Yes             | Yes            |
+    /// 7    |                 |                | Yes
+    /// ```
+    ///
+    /// Powering the APU on resets the sequencer so that the next step is 0.
+    pub fn frame_sequencer_step(&self) -> u8 {
+        self.frame_sequencer_ticks as u8
+    }
+
+    /// Resets the APU to its power-up state, keeping the current audio sink, sample rate
+    /// and high-pass filter setting.
+    pub fn reset(&mut self) {
+        *self = APU {
+            sample_channel: self.sample_channel.take(),
+            sample_period: self.sample_period,
+            high_pass_filter: self.high_pass_filter,
+            hpf_charge_factor: self.hpf_charge_factor,
+            report_writes_while_off: self.report_writes_while_off,
+            ..APU::default()
+        };
+    }
 }
 
 impl InterruptSource for APU {
@@ -1088,7 +1303,7 @@ impl MemR for APU {
             0xFF25 => self.nr51.bits(),
             0xFF26 => self.read_pwr_reg() | 0x70,
 
-            0xFF30..=0xFF3F => self.ch3.wave_ram[usize::from(addr) - 0xFF30],
+            0xFF30..=0xFF3F => self.ch3.wave_ram[self.ch3.wave_ram_index(addr)],
 
             // Unused regs in this range: 0xFF15, 0xFF1F, 0xFF27..=0xFF2F
             _ => 0xFF,
@@ -1108,10 +1323,21 @@ impl MemW for APU {
                 0xFF1B => self.ch3.write(addr - 0xFF1A, val)?,
                 0xFF20 => self.ch4.write(addr - 0xFF1F, val & 0b_0011_1111)?,
                 0xFF26 => self.write_to_pwr_reg(val)?,
-                0xFF30..=0xFF3F => self.ch3.wave_ram[usize::from(addr) - 0xFF30] = val,
+                0xFF30..=0xFF3F => self.ch3.wave_ram[self.ch3.wave_ram_index(addr)] = val,
                 _ => (),
             }
+
+            // Report only once per power-off period, otherwise resuming would hit it again
+            if self.report_writes_while_off
+                && !self.write_while_off_reported
+                && (0xFF10..=0xFF25).contains(&addr)
+            {
+                self.write_while_off_reported = true;
+                return Err(dbg::TraceEvent::ApuWriteWhileOff(addr));
+            }
         } else {
+            self.write_while_off_reported = false;
+
             match addr {
                 0xFF10..=0xFF14 => self.ch1.write(addr - 0xFF10, val)?,
                 0xFF15..=0xFF19 => self.ch2.write(addr - 0xFF15, val)?,
@@ -1122,7 +1348,7 @@ impl MemW for APU {
                 0xFF25 => self.nr51 = NR51::from_bits_truncate(val),
                 0xFF26 => self.write_to_pwr_reg(val)?,
 
-                0xFF30..=0xFF3F => self.ch3.wave_ram[usize::from(addr) - 0xFF30] = val,
+                0xFF30..=0xFF3F => self.ch3.wave_ram[self.ch3.wave_ram_index(addr)] = val,
 
                 // Unused regs in this range: 0xFF15, 0xFF1F, 0xFF27..=0xFF2F
                 _ => (),
@@ -1132,3 +1358,312 @@ impl MemW for APU {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of M-cycles in a frame sequencer step.
+    const SEQUENCER_PERIOD: u32 = 4_194_304 / 512 / 4;
+
+    /// Ticks `apu` until the frame sequencer clocks its next step,
+    /// driving the DIV-APU bit like DIV would.
+    fn tick_sequencer_step(apu: &mut APU) {
+        for i in 1..=SEQUENCER_PERIOD {
+            apu.tick(i % SEQUENCER_PERIOD >= SEQUENCER_PERIOD / 2);
+        }
+    }
+
+    #[test]
+    fn frame_sequencer_clocks_units_on_schedule() {
+        let mut apu = APU::default();
+
+        apu.write(0xFF26, 0x00).unwrap();
+        apu.write(0xFF26, 0x80).unwrap();
+        assert_eq!(apu.frame_sequencer_step(), 7);
+
+        // Sweep without frequency changes, length enabled, volume envelope going down
+        apu.write(0xFF10, 0x70).unwrap();
+        apu.write(0xFF11, 0x00).unwrap();
+        apu.write(0xFF12, 0xF7).unwrap();
+        apu.write(0xFF14, 0xC0).unwrap();
+
+        // (length, sweep, envelope) units clocked on each step
+        let expected = [
+            (true, false, false),
+            (false, false, false),
+            (true, true, false),
+            (false, false, false),
+            (true, false, false),
+            (false, false, false),
+            (true, true, false),
+            (false, false, true),
+        ];
+
+        for (step, &units) in expected.iter().enumerate() {
+            let length = apu.ch1.length_counter;
+            let sweep = apu.ch1.sweep_timer;
+            let volume = apu.ch1.volume;
+
+            tick_sequencer_step(&mut apu);
+
+            assert_eq!(usize::from(apu.frame_sequencer_step()), step);
+            assert_eq!(
+                (
+                    apu.ch1.length_counter != length,
+                    apu.ch1.sweep_timer != sweep,
+                    apu.ch1.volume != volume,
+                ),
+                units,
+                "step {}",
+                step
+            );
+        }
+    }
+
+    #[test]
+    fn sweep_shifts_frequency_until_overflow() {
+        let mut apu = APU::default();
+
+        // Sweep up every sweep clock by 1/2 of the frequency, starting from 0x100
+        apu.write(0xFF10, 0x11).unwrap();
+        apu.write(0xFF12, 0xF0).unwrap();
+        apu.write(0xFF13, 0x00).unwrap();
+        apu.write(0xFF14, 0x81).unwrap();
+
+        let mut freqs = vec![];
+
+        while apu.ch1.enabled() {
+            tick_sequencer_step(&mut apu);
+
+            if apu.frame_sequencer_step() & 0b11 == 2 {
+                freqs.push(apu.ch1.get_frequency());
+            }
+        }
+
+        // The last update is applied, but the following calculation overflows
+        assert_eq!(freqs, [384, 576, 864, 1296, 1944]);
+
+        // With a sweep period of 2, the frequency only changes every other sweep clock
+        apu.write(0xFF10, 0x29).unwrap();
+        apu.write(0xFF13, 0x00).unwrap();
+        apu.write(0xFF14, 0x84).unwrap();
+
+        let mut freqs = vec![];
+
+        for _ in 0..16 {
+            tick_sequencer_step(&mut apu);
+
+            if apu.frame_sequencer_step() & 0b11 == 2 {
+                freqs.push(apu.ch1.get_frequency());
+            }
+        }
+        assert!(apu.ch1.enabled());
+        assert_eq!(freqs, [1024, 512, 512, 256]);
+    }
+
+    #[test]
+    fn wave_channel_plays_wave_ram_with_volume_shift() {
+        let mut apu = APU::default();
+
+        // Two ramps from 0 to 15
+        for (i, addr) in (0xFF30..=0xFF3F).enumerate() {
+            let i = (i as u8 % 8) * 2;
+            apu.write(addr, (i << 4) | (i + 1)).unwrap();
+        }
+
+        for &(nr32, shift) in &[(0x20u8, 0u8), (0x40, 1), (0x60, 2), (0x00, 4)] {
+            // With frequency 2046, the channel steps through one sample per M-cycle
+            apu.write(0xFF1A, 0x80).unwrap();
+            apu.write(0xFF1C, nr32).unwrap();
+            apu.write(0xFF1D, 0xFE).unwrap();
+            apu.write(0xFF1E, 0x87).unwrap();
+
+            let mut samples = vec![];
+            let mut position = apu.ch3.position_counter;
+
+            while samples.len() < 32 {
+                apu.tick(false);
+
+                if apu.ch3.position_counter != position {
+                    position = apu.ch3.position_counter;
+                    samples.push(apu.ch3.get_channel_out());
+                }
+            }
+
+            // Playback starts from the second sample after a trigger
+            let expected = (1..=32)
+                .map(|i| i16::from((i % 16) as u8 >> shift) * 2 - 15)
+                .collect::<Vec<_>>();
+            assert_eq!(samples, expected);
+        }
+
+        // While playing, the CPU can only access the byte being read by the channel
+        apu.write(0xFF30, 0xAA).unwrap();
+        assert_eq!(apu.ch3.wave_ram[apu.ch3.position_counter >> 1], 0xAA);
+        assert_eq!(apu.read(0xFF3F).unwrap(), 0xAA);
+
+        // Turning the DAC off silences the channel
+        apu.write(0xFF1A, 0x00).unwrap();
+        assert_eq!(apu.ch3.get_channel_out(), 0);
+        assert_eq!(apu.read(0xFF26).unwrap() & 0x04, 0);
+    }
+
+    #[test]
+    fn noise_lfsr_follows_known_sequences() {
+        const OUT_15_BIT: [i16; 32] = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0,
+            1, 1, 1,
+        ];
+        const OUT_7_BIT: [i16; 32] = [
+            0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 0, 1, 0, 1, 1,
+            1, 0, 0,
+        ];
+
+        for &(nr43, expected, len, mask) in &[
+            (0x00u8, OUT_15_BIT, 32767, 0x7FFF),
+            (0x08, OUT_7_BIT, 127, 0x007F),
+        ] {
+            let mut ch4 = NoiseChannel::default();
+            ch4.write(3, nr43).unwrap();
+
+            let out = (0..32)
+                .map(|_| {
+                    ch4.clock_lfsr();
+                    i16::from(ch4.lfsr & 0x1 == 0)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(out, expected);
+
+            // The sequence repeats once every possible non-zero state has been visited
+            let mut steps = 32;
+            while ch4.lfsr & mask != mask {
+                ch4.clock_lfsr();
+                steps += 1;
+            }
+            assert_eq!(steps, len);
+        }
+    }
+
+    #[test]
+    fn noise_period_follows_divisor_and_shift() {
+        let mut ch4 = NoiseChannel::default();
+
+        for &(nr43, period) in &[(0x00u8, 8), (0x01, 16), (0x07, 112), (0x25, 320)] {
+            ch4.write(3, nr43).unwrap();
+            assert_eq!(ch4.get_period(), period);
+        }
+    }
+
+    #[test]
+    fn channels_are_panned_and_scaled_by_master_volume() {
+        let mut apu = APU::new(crate::CPU_CLOCK as f32 / 64.0);
+        let sink = Arc::new(ArrayQueue::new(64));
+        apu.set_audio_sink(sink.clone());
+        apu.set_high_pass_filter(false);
+
+        // Channel 2 routed to the left only, at full master volume on the left
+        apu.write(0xFF24, 0x70).unwrap();
+        apu.write(0xFF25, 0x20).unwrap();
+        apu.write(0xFF16, 0x80).unwrap();
+        apu.write(0xFF17, 0xF0).unwrap();
+        apu.write(0xFF18, 0xC0).unwrap();
+        apu.write(0xFF19, 0x87).unwrap();
+
+        for _ in 0..32 * 16 {
+            apu.tick(false);
+        }
+
+        let mut left = vec![];
+        while let (Some(l), Some(r)) = (sink.pop(), sink.pop()) {
+            assert_eq!(r, 0);
+            left.push(l);
+        }
+
+        assert!(left.len() > 16);
+        assert!(left.iter().all(|&l| l == 15 * 8 || l == -15 * 8));
+        assert!(left.contains(&(15 * 8)));
+    }
+
+    #[test]
+    fn high_pass_filter_removes_dc_offset() {
+        for &enabled in &[true, false] {
+            let mut apu = APU::new(48000.0);
+            let sink = Arc::new(ArrayQueue::new(2 * 8192));
+            apu.set_audio_sink(sink.clone());
+            apu.set_high_pass_filter(enabled);
+
+            // A silent channel with its DAC on outputs a constant negative level
+            apu.write(0xFF24, 0x77).unwrap();
+            apu.write(0xFF25, 0x22).unwrap();
+            apu.write(0xFF17, 0x08).unwrap();
+
+            for _ in 0..crate::CPU_CLOCK / 4 / 10 {
+                apu.tick(false);
+            }
+
+            let mut samples = vec![];
+            while let Some(sample) = sink.pop() {
+                samples.push(sample);
+            }
+
+            assert_eq!(samples[0], -15 * 8);
+
+            if enabled {
+                assert_eq!(*samples.last().unwrap(), 0);
+            } else {
+                assert!(samples.iter().all(|&s| s == -15 * 8));
+            }
+        }
+    }
+
+    #[test]
+    fn writes_while_off_are_reported_once() {
+        let mut apu = APU::default();
+
+        // Nothing is reported unless requested
+        apu.write(0xFF26, 0x00).unwrap();
+        apu.write(0xFF12, 0xF0).unwrap();
+
+        apu.set_report_writes_while_off(true);
+
+        assert!(matches!(
+            apu.write(0xFF11, 0x3F),
+            Err(dbg::TraceEvent::ApuWriteWhileOff(0xFF11))
+        ));
+        apu.write(0xFF12, 0xF0).unwrap();
+
+        // Wave RAM can be freely written while off
+        apu.write(0xFF30, 0x12).unwrap();
+
+        // A new power-off period reports again
+        apu.write(0xFF26, 0x80).unwrap();
+        apu.write(0xFF26, 0x00).unwrap();
+        assert!(apu.write(0xFF24, 0x77).is_err());
+    }
+
+    #[test]
+    fn wave_position_advances_at_channel_frequency() {
+        for &(freq, period) in &[(1024u16, 2048u64), (2047, 2)] {
+            let mut apu = APU::default();
+
+            apu.write(0xFF1A, 0x80).unwrap();
+            apu.write(0xFF1D, freq as u8).unwrap();
+            apu.write(0xFF1E, 0x80 | (freq >> 8) as u8).unwrap();
+            assert_eq!(apu.ch3.position_counter, 0);
+
+            // Count the sample steps over one second
+            let mut steps = 0;
+            let mut position = apu.ch3.position_counter;
+
+            for _ in 0..crate::CPU_CLOCK / 4 {
+                apu.tick(false);
+                steps += (apu.ch3.position_counter + 32 - position) % 32;
+                position = apu.ch3.position_counter;
+            }
+
+            // The first step is delayed by 6 cycles after the trigger
+            assert_eq!(steps as u64, (crate::CPU_CLOCK - 6) / period);
+        }
+    }
+}
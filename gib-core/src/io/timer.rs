@@ -1,9 +1,12 @@
+use serde::{Deserialize, Serialize};
+
 use crate::{
     dbg,
     io::{InterruptSource, IoReg, IrqSource},
     mem::{MemR, MemRW, MemW},
 };
 
+#[derive(Serialize, Deserialize)]
 pub struct Timer {
     pub sys_counter: IoReg<u16>,
     pub tima: IoReg<u8>,
@@ -96,7 +99,8 @@ impl Timer {
     }
 
     fn write_to_tac(&mut self, val: u8) {
-        let val = IoReg(val);
+        // Only the lower 3 bits of TAC are used, the others always read as 1
+        let val = IoReg(val & 0x07);
 
         // HW BUG: when changing TAC register value, if the old selected bit
         // by the multiplexer was 0, the new one is 1, and the new enable bit
@@ -195,13 +199,46 @@ impl MemRW for Timer {}
 mod tests {
     use super::*;
 
-    // TODO: this tests are failing after 4ad06f9. Fix them.
+    #[test]
+    fn tac_unused_bits_read_as_one() {
+        let mut timer = Timer::default();
+
+        for val in [0x00, 0x05, 0xF8, 0xFF, 0x5A].iter() {
+            timer.write(0xFF07, *val).unwrap();
+
+            assert_eq!(timer.read(0xFF07).unwrap(), *val | 0xF8);
+            assert_eq!(timer.tac.0, *val & 0x07);
+        }
+    }
 
     #[test]
-    #[should_panic]
-    fn system_counter_tick() {
+    fn div_write_always_resets() {
         let mut timer = Timer::default();
 
+        for val in [0x00, 0x42, 0xFF].iter() {
+            for _ in 0..100 {
+                timer.tick();
+            }
+            assert_ne!(timer.read(0xFF04).unwrap(), 0);
+
+            timer.write(0xFF04, *val).unwrap();
+
+            assert_eq!(timer.read(0xFF04).unwrap(), 0);
+            assert_eq!(timer.sys_counter.0, 0);
+        }
+    }
+
+    /// Returns a timer whose system counter starts from 0, rather than from its post-boot value.
+    fn timer_from_zero() -> Timer {
+        let mut timer = Timer::default();
+        timer.sys_counter.0 = 0;
+        timer
+    }
+
+    #[test]
+    fn system_counter_tick() {
+        let mut timer = timer_from_zero();
+
         // Counter starts at 0
         assert_eq!(timer.div().0, 0);
 
@@ -230,9 +267,8 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn system_counter_reset() {
-        let mut timer = Timer::default();
+        let mut timer = timer_from_zero();
 
         for _ in 0..129 {
             timer.tick();
@@ -247,9 +283,8 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn timer_tick() {
-        let mut timer = Timer::default();
+        let mut timer = timer_from_zero();
 
         // Ticking does not affect a stopped timer
         for _ in 0..512 {
@@ -276,11 +311,10 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn replicate_timer_hw_bugs() {
         // Test 1: when writing to DIV register the TIMA register can be increased
         // if the counter has reached half the clocks it needs to increase.
-        let mut timer = Timer::default();
+        let mut timer = timer_from_zero();
         timer.tac.0 = 0b101;
 
         for _ in 0..3 {
@@ -291,4 +325,48 @@ mod tests {
         timer.reset_sys_counter();
         assert_eq!(timer.tima.0, 1);
     }
+
+    #[test]
+    fn tima_counts_at_tac_frequency() {
+        // TAC clock select and the corresponding TIMA period, in clock cycles
+        let rates = [(0b00, 1024), (0b01, 16), (0b10, 64), (0b11, 256)];
+
+        for &(select, period) in rates.iter() {
+            let mut timer = timer_from_zero();
+            timer.write(0xFF07, 0b100 | select).unwrap();
+
+            for _ in 0..(period * 10 / 4 - 1) {
+                timer.tick();
+            }
+            assert_eq!(timer.read(0xFF05).unwrap(), 9, "TAC={:03b}", 0b100 | select);
+
+            timer.tick();
+            assert_eq!(
+                timer.read(0xFF05).unwrap(),
+                10,
+                "TAC={:03b}",
+                0b100 | select
+            );
+        }
+    }
+
+    #[test]
+    fn tima_overflow_reloads_tma_and_requests_irq() {
+        let mut timer = timer_from_zero();
+        timer.write(0xFF05, 0xFF).unwrap();
+        timer.write(0xFF06, 0x80).unwrap();
+        timer.write(0xFF07, 0b101).unwrap();
+
+        for _ in 0..4 {
+            timer.tick();
+        }
+
+        // TIMA reads 00 for one cycle before being reloaded
+        assert_eq!(timer.read(0xFF05).unwrap(), 0x00);
+        assert!(timer.get_and_clear_irq().is_none());
+
+        timer.tick();
+        assert_eq!(timer.read(0xFF05).unwrap(), 0x80);
+        assert!(matches!(timer.get_and_clear_irq(), Some(IrqSource::Timer)));
+    }
 }
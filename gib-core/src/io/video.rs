@@ -1,9 +1,14 @@
+use std::cmp::Reverse;
+
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     dbg,
     io::{InterruptSource, IoReg, IrqSource},
     mem::{MemR, MemRW, MemW},
+    state::big_array,
+    Model,
 };
 
 /// A Tile is the bit representation of an 8x8 sprite or BG tile,
@@ -16,7 +21,7 @@ use crate::{
 /// For each line, the first byte defines the least significant bits of the color numbers
 /// for each pixel, and the second byte defines the upper bits of the color numbers.
 /// In either case, Bit 7 is the leftmost pixel, and Bit 0 the rightmost.
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Serialize, Deserialize)]
 struct Tile([u8; 16]);
 
 impl Tile {
@@ -39,7 +44,7 @@ impl Tile {
 /// A Sprite is an entry in the Sprite Attribute Table (or OAM - Object Attribute Memory).
 ///
 /// Each Sprite consists of 4 bytes representing the sprite's position, associated tile and attributes.
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Serialize, Deserialize)]
 struct Sprite {
     y: u8,
     x: u8,
@@ -48,11 +53,13 @@ struct Sprite {
 }
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     struct SpriteAttributes: u8 {
         const BG_PRIO = 0b_1000_0000;
         const FLIP_Y  = 0b_0100_0000;
         const FLIP_X  = 0b_0010_0000;
         const PAL_NUM = 0b_0001_0000;
+        const VRAM_BANK = 0b_0000_1000; // CGB only
 
         const DEFAULT = 0b_0000_0000;
     }
@@ -106,6 +113,7 @@ impl<'a> MemRW for &'a mut [Sprite] {}
 
 bitflags! {
     /// FF40 - LCDC - LCD Control (R/W)
+    #[derive(Serialize, Deserialize)]
     struct LCDC: u8 {
         const DISP_EN         = 0b_1000_0000; /// Bit 7 - LCD Display Enable             (0=Off, 1=On)
         const WIN_DISP_SEL    = 0b_0100_0000; /// Bit 6 - Window Tile Map Display Select (0=9800-9BFF, 1=9C00-9FFF)
@@ -124,6 +132,7 @@ mem_rw!(LCDC, 0x00);
 
 bitflags! {
     /// FF41 - STAT - LCDC Status (R/W)
+    #[derive(Serialize, Deserialize)]
     struct STAT: u8 {
         const LYC_INTR = 0b_0100_0000; /// Bit 6 - LYC=LY Coincidence Interrupt (1=Enable) (Read/Write)
         const OAM_INTR = 0b_0010_0000; /// Bit 5 - Mode 2 OAM Interrupt         (1=Enable) (Read/Write)
@@ -145,6 +154,7 @@ mem_rw!(STAT, 0x80);
 
 bitflags! {
     /// Used to keep track of which STAT IRQs are currently active.
+    #[derive(Serialize, Deserialize)]
     struct STATIRQ: u8 {
         const LYC = 0b_0100_0000;
         const OAM = 0b_0010_0000;
@@ -155,7 +165,111 @@ bitflags! {
     }
 }
 
+/// The location in VRAM of the data used to draw a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelSource {
+    /// Address of the tile map entry.
+    pub tile_map_addr: u16,
+    /// Tile ID stored in the tile map entry.
+    pub tile_index: u8,
+    /// Address of the two bytes encoding the tile line containing the pixel.
+    pub tile_data_addr: u16,
+    /// Horizontal position of the pixel within the tile.
+    pub px: u8,
+    /// Vertical position of the pixel within the tile.
+    pub py: u8,
+}
+
+/// Rendering registers as latched by the PPU at the start of pixel transfer on a scanline.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanlineRegs {
+    pub lcdc: u8,
+    pub scx: u8,
+    pub scy: u8,
+    pub wx: u8,
+    pub wy: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+}
+
+/// Registers captured on each line of the frame being drawn and of the last complete one.
+#[derive(Default)]
+struct ScanlineCapture {
+    current: Vec<ScanlineRegs>,
+    last: Vec<ScanlineRegs>,
+}
+
+/// Maximum number of sprites that can be displayed on a single line.
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+/// Returns the index in the Tile Data Table of a BG or Window tile ID,
+/// using unsigned (0x8000) or signed (0x8800) addressing.
+fn tile_index(tile_id: u8, unsigned_data: bool) -> usize {
+    if unsigned_data {
+        usize::from(tile_id)
+    } else {
+        (256 + i32::from(tile_id as i8)) as usize
+    }
+}
+
+/// Size of the tile data table, as rasterized by `PPU::rasterize_tiles`.
+pub const TILE_DATA_WIDTH: usize = 16 * 8;
+pub const TILE_DATA_HEIGHT: usize = 24 * 8;
+
+/// Order in which overlapping sprites are drawn.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SpriteOrder {
+    /// Follow the hardware priority rules: on DMG, the sprite with the smallest X coordinate
+    /// is drawn on top, while on CGB the first one in OAM is.
+    #[default]
+    Hardware,
+    /// Draw sprites in OAM order, each one over the previous ones.
+    ///
+    /// This is a debugging aid which shows the raw OAM layout, and it breaks hardware accuracy.
+    Oam,
+}
+
+/// An entry of the Sprite Attribute Table, as decoded by `PPU::sprite`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpriteInfo {
+    /// Y coordinate, plus 16
+    pub y: u8,
+    /// X coordinate, plus 8
+    pub x: u8,
+    /// Tile index (in 8x16 mode, bit 0 is ignored)
+    pub tile: u8,
+    /// DMG palette number, ie. 0 for OBP0 and 1 for OBP1
+    pub palette: u8,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    /// Whether BG colors 1-3 are drawn over the sprite
+    pub behind_bg: bool,
+    /// Height in pixels, according to LCDC
+    pub height: u8,
+    /// Whether the sprite lies entirely outside of the screen
+    pub offscreen: bool,
+    /// Whether the sprite is left out of some of its lines, because the sprites before it
+    /// in OAM already fill them up
+    pub dropped: bool,
+}
+
+/// The sprites selected for a single line during OAM scan (mode 2), in OAM order.
+#[derive(Default, Copy, Clone, Serialize, Deserialize)]
+struct OamScan {
+    len: u8,
+    ids: [u8; MAX_SPRITES_PER_LINE],
+}
+
+impl OamScan {
+    /// Returns the OAM indices of the selected sprites.
+    fn sprites(&self) -> &[u8] {
+        &self.ids[..usize::from(self.len)]
+    }
+}
+
 /// A DMA transfer from ROM/RAM to OAM.
+#[derive(Serialize, Deserialize)]
 struct DMATransfer {
     src: u16,
     dst: u16,
@@ -191,16 +305,33 @@ impl DMATransfer {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct PPU {
-    tdt: [Tile; 384],  // Tile Data Table
+    model: Model,
+
+    #[serde(with = "big_array")]
+    tdt: [Tile; 384], // Tile Data Table
+    #[serde(with = "big_array")]
     oam: [Sprite; 40], // Object Attribute Memory
+    #[serde(with = "big_array")]
     bgtm0: [u8; 1024], // Background Tile Map #0
+    #[serde(with = "big_array")]
     bgtm1: [u8; 1024], // Background Tile Map #1
 
+    // CGB-only VRAM bank #1
+    vbk_reg: IoReg<u8>,
+    #[serde(with = "big_array")]
+    tdt1: [Tile; 384], // Tile Data Table (bank #1)
+    #[serde(with = "big_array")]
+    bgattr0: [u8; 1024], // Background Attribute Map #0
+    #[serde(with = "big_array")]
+    bgattr1: [u8; 1024], // Background Attribute Map #1
+
     // Ctrl/status IO registes
     lcdc_reg: LCDC,
     stat_reg: STAT,
     stat_irq: STATIRQ,
+    stat_line: bool,
 
     // Position/scrolling registers
     scx_reg: IoReg<u8>,
@@ -215,6 +346,32 @@ pub struct PPU {
     obp1_reg: IoReg<u8>,
     bgp_reg: IoReg<u8>,
 
+    // CGB color palette registers and memory
+    bcps_reg: IoReg<u8>,
+    ocps_reg: IoReg<u8>,
+    #[serde(with = "big_array")]
+    bg_palettes: [u8; 64],
+    #[serde(with = "big_array")]
+    obj_palettes: [u8; 64],
+
+    // Sprites selected for each line during OAM scan
+    #[serde(with = "big_array")]
+    oam_scan: [OamScan; 144],
+
+    // Rendering registers latched on each line, if capture is enabled
+    #[serde(skip)]
+    scanline_capture: Option<ScanlineCapture>,
+
+    // Rendering registers latched on the current line, and shades of the lines drawn so far
+    line_regs: ScanlineRegs,
+    lcd: Vec<u8>,
+
+    // Window internal line counter, and the WX and window line drawn on each line
+    win_line: u8,
+    wy_triggered: bool,
+    #[serde(with = "big_array")]
+    win_lines: [Option<(u8, u8)>; 144],
+
     // DMA register & counter
     dma_reg: IoReg<u8>,
     dma_xfer: Option<DMATransfer>,
@@ -225,19 +382,37 @@ pub struct PPU {
 
     // IRQ handling
     vblank_irq_pending: bool,
+
+    // Debug
+    #[serde(skip)]
+    dot_breakpoint: Option<(u8, u16)>,
+
+    // Host settings
+    #[serde(skip)]
+    rendering_disabled: bool,
+    #[serde(skip)]
+    sprite_order: SpriteOrder,
 }
 
 impl Default for PPU {
     fn default() -> PPU {
         PPU {
+            model: Model::Dmg,
+
             tdt: [Tile::default(); 384],
             oam: [Sprite::default(); 40],
             bgtm0: [0; 1024],
             bgtm1: [0; 1024],
 
+            vbk_reg: IoReg(0x00),
+            tdt1: [Tile::default(); 384],
+            bgattr0: [0; 1024],
+            bgattr1: [0; 1024],
+
             lcdc_reg: LCDC::DEFAULT,
             stat_reg: STAT::DEFAULT,
             stat_irq: STATIRQ::DEFAULT,
+            stat_line: false,
 
             scx_reg: IoReg(0x00),
             scy_reg: IoReg(0x00),
@@ -250,6 +425,22 @@ impl Default for PPU {
             obp0_reg: IoReg(0xFF),
             obp1_reg: IoReg(0xFF),
 
+            // The CGB boot ROM initializes all the BG palettes to white
+            bcps_reg: IoReg(0x00),
+            ocps_reg: IoReg(0x00),
+            bg_palettes: [0xFF; 64],
+            obj_palettes: [0x00; 64],
+
+            oam_scan: [OamScan::default(); 144],
+            scanline_capture: None,
+
+            line_regs: ScanlineRegs::default(),
+            lcd: vec![0xFF; 160 * 144],
+
+            win_line: 0,
+            wy_triggered: false,
+            win_lines: [None; 144],
+
             dma_reg: IoReg(0x00),
             dma_xfer: None,
             dma_xfer_queue: [None, None],
@@ -257,6 +448,11 @@ impl Default for PPU {
             tstate: 70164,
 
             vblank_irq_pending: true,
+
+            dot_breakpoint: None,
+
+            rendering_disabled: false,
+            sprite_order: SpriteOrder::Hardware,
         }
     }
 }
@@ -266,8 +462,32 @@ impl PPU {
         PPU::default()
     }
 
+    /// Creates a PPU which renders like the given hardware model.
+    pub fn with_model(model: Model) -> PPU {
+        PPU {
+            model,
+            ..PPU::default()
+        }
+    }
+
+    /// Advances the LCD controller state machine by `cycles` M-cycles (4 dots each).
+    ///
+    /// Every line goes through OAM scan (mode 2), pixel transfer (mode 3) and H-Blank
+    /// (mode 0), and is drawn when H-Blank starts. Lines 144 to 153 are spent in V-Blank.
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.tick_m_cycle();
+        }
+    }
+
     /// Advances the LCD controller state machine by a single M-cycle.
-    pub fn tick(&mut self) {
+    fn tick_m_cycle(&mut self) {
+        // While the LCD is off, the PPU is idle: LY stays at 0 in mode 0,
+        // so no STAT interrupt can be requested.
+        if !self.lcdc_reg.contains(LCDC::DISP_EN) {
+            return;
+        }
+
         // Update ticks
         self.tstate = (self.tstate + 4) % 70224;
         let tstate = self.tstate % 456;
@@ -278,12 +498,225 @@ impl PPU {
         // V-Blank IRQ happens at the beginning of the 144th line
         if v_line == 144 && tstate == 0 {
             self.vblank_irq_pending = true;
+
+            // Only frames captured from the very first line are reported
+            if let Some(ref mut cap) = self.scanline_capture {
+                if cap.current.len() == 144 {
+                    std::mem::swap(&mut cap.current, &mut cap.last);
+                }
+                cap.current.clear();
+            }
+        }
+
+        // Sprite selection is complete by the end of mode 2
+        if v_line < 144 && tstate == 76 {
+            self.oam_scan[v_line as usize] = self.scan_oam(v_line as u8);
+        }
+
+        // Rendering registers are latched when pixel transfer begins
+        if v_line < 144 && tstate == 80 {
+            let regs = self.scanline_regs();
+
+            if let Some(ref mut cap) = self.scanline_capture {
+                if v_line == 0 {
+                    cap.current.clear();
+                }
+                cap.current.push(regs);
+            }
+
+            self.line_regs = regs;
+            self.win_lines[v_line as usize] = self.advance_window(v_line as u8);
+        }
+
+        // The line is drawn by the time H-Blank starts
+        if v_line < 144 && tstate == 256 && !self.rendering_disabled {
+            self.render_line(v_line as usize);
         }
 
         // This should be called last, after every other counter has been updated!
         self.tick_stat(tstate, v_line);
     }
 
+    /// Enables or disables capturing the rendering registers on each scanline.
+    ///
+    /// Capture is disabled by default, since it comes at a cost on every line.
+    pub fn set_scanline_capture(&mut self, enabled: bool) {
+        if enabled != self.scanline_capture.is_some() {
+            self.scanline_capture = if enabled {
+                Some(ScanlineCapture::default())
+            } else {
+                None
+            };
+        }
+    }
+
+    /// Returns whether rendering registers are captured on each scanline.
+    pub fn scanline_capture_enabled(&self) -> bool {
+        self.scanline_capture.is_some()
+    }
+
+    /// Returns the rendering registers latched on each of the 144 lines of the last
+    /// complete frame, eg. to find out which raster effects a game used.
+    ///
+    /// The result is empty unless capture was enabled with `set_scanline_capture`
+    /// before the start of the last frame.
+    pub fn per_scanline_registers(&self) -> Vec<ScanlineRegs> {
+        self.scanline_capture
+            .as_ref()
+            .map_or_else(Vec::new, |cap| cap.last.clone())
+    }
+
+    /// Decides whether the window is drawn on line `ly`, advancing its internal line counter
+    /// if so, and returns the WX value and window line to draw it with.
+    ///
+    /// The window is only drawn once LY has matched WY at some point during the frame,
+    /// and only on lines where it is enabled and WX places it on-screen (WX < 167).
+    /// Its line counter is separate from LY: lines where the window is hidden don't
+    /// advance it, so a window disabled mid-frame resumes where it left off.
+    fn advance_window(&mut self, ly: u8) -> Option<(u8, u8)> {
+        if ly == 0 {
+            self.win_line = 0;
+            self.wy_triggered = false;
+        }
+        if ly == self.wy_reg.0 {
+            self.wy_triggered = true;
+        }
+
+        let wx = self.wx_reg.0;
+
+        if !self.wy_triggered || !self.lcdc_reg.contains(LCDC::WIN_DISP_EN) || wx > 166 {
+            return None;
+        }
+
+        let line = self.win_line;
+        self.win_line = self.win_line.wrapping_add(1);
+
+        Some((wx, line))
+    }
+
+    /// Returns the current value of the rendering registers.
+    fn scanline_regs(&self) -> ScanlineRegs {
+        ScanlineRegs {
+            lcdc: self.lcdc_reg.bits(),
+            scx: self.scx_reg.0,
+            scy: self.scy_reg.0,
+            wx: self.wx_reg.0,
+            wy: self.wy_reg.0,
+            bgp: self.bgp_reg.0,
+            obp0: self.obp0_reg.0,
+            obp1: self.obp1_reg.0,
+        }
+    }
+
+    /// Returns whether the LCD is on (LCDC bit 7).
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc_reg.contains(LCDC::DISP_EN)
+    }
+
+    /// Returns the value of the LCDC register.
+    pub fn lcdc(&self) -> u8 {
+        self.lcdc_reg.bits()
+    }
+
+    /// Returns whether the BG uses the tile map at 0x9C00 rather than the one at 0x9800
+    /// (LCDC bit 3).
+    pub fn bg_map_high(&self) -> bool {
+        self.lcdc_reg.contains(LCDC::BG_DISP_SEL)
+    }
+
+    /// Returns whether the BG and window use the tile data at 0x8000 with unsigned tile IDs,
+    /// rather than the one at 0x8800 with signed IDs (LCDC bit 4).
+    pub fn unsigned_tile_data(&self) -> bool {
+        self.lcdc_reg.contains(LCDC::BG_WIN_DATA_SEL)
+    }
+
+    /// Returns the background scroll position, ie. `(SCX, SCY)`.
+    pub fn scroll(&self) -> (u8, u8) {
+        (self.scx_reg.0, self.scy_reg.0)
+    }
+
+    /// Returns the current scanline (LY).
+    pub fn ly(&self) -> u8 {
+        self.ly_reg.0
+    }
+
+    /// Returns the current dot (0-455) within the current scanline.
+    ///
+    /// Together with LY, this identifies the exact position of the beam.
+    /// On visible lines (LY < 144), dots map to LCD modes as follows:
+    ///
+    /// * 0-79: mode 2 (OAM search)
+    /// * 80-253: mode 3 (pixel transfer)
+    /// * 254-455: mode 0 (H-Blank)
+    ///
+    /// On lines 144-153, every dot belongs to mode 1 (V-Blank).
+    ///
+    /// NOTE: the PPU is advanced one M-cycle at a time, so the returned value
+    /// is always a multiple of 4.
+    pub fn dot(&self) -> u16 {
+        (self.tstate % 456) as u16
+    }
+
+    /// Sets a breakpoint on the given dot of scanline `ly`, replacing any previous one.
+    ///
+    /// Since the PPU advances 4 dots at a time, the breakpoint is hit on the M-cycle
+    /// during which `dot` is drawn.
+    pub fn set_dot_breakpoint(&mut self, ly: u8, dot: u16) {
+        self.dot_breakpoint = Some((ly, dot));
+    }
+
+    /// Clears the dot breakpoint, if any.
+    pub fn clear_dot_breakpoint(&mut self) {
+        self.dot_breakpoint = None;
+    }
+
+    /// Returns the dot breakpoint as a pair of scanline and dot, if set.
+    pub fn dot_breakpoint(&self) -> Option<(u8, u16)> {
+        self.dot_breakpoint
+    }
+
+    /// Returns a `TraceEvent::DotBreakpoint` if the beam has just reached the dot breakpoint.
+    pub fn check_dot_breakpoint(&self) -> Result<(), dbg::TraceEvent> {
+        match self.dot_breakpoint {
+            Some((ly, dot)) if ly == self.ly_reg.0 && dot / 4 == self.dot() / 4 => {
+                Err(dbg::TraceEvent::DotBreakpoint(ly, dot))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Performs the OAM scan for line `ly`, selecting the sprites to be displayed on it.
+    ///
+    /// Sprites are selected in OAM order, based solely on their Y coordinate,
+    /// until `MAX_SPRITES_PER_LINE` sprites are found. Note that their X coordinate
+    /// is not taken into account, so off-screen sprites still count towards the limit.
+    fn scan_oam(&self, ly: u8) -> OamScan {
+        let height = if self.lcdc_reg.contains(LCDC::OBJ_SIZE) {
+            16
+        } else {
+            8
+        };
+
+        let mut scan = OamScan::default();
+
+        let ly = i16::from(ly);
+
+        for (id, sprite) in self.oam.iter().enumerate() {
+            let y = i16::from(sprite.y) - 16;
+
+            if (y..y + height).contains(&ly) {
+                scan.ids[usize::from(scan.len)] = id as u8;
+                scan.len += 1;
+
+                if usize::from(scan.len) == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+
+        scan
+    }
+
     /// Returns a pair of source and destination addresses for DMA transfer
     /// if one is currently in progress, otherwise `None`.
     pub fn advance_dma_xfer(&mut self) -> Option<(u16, u16)> {
@@ -309,6 +742,11 @@ impl PPU {
         ret
     }
 
+    /// Returns the address the ongoing OAM DMA transfer is reading from, if any.
+    pub fn dma_source(&self) -> Option<u16> {
+        self.dma_xfer.as_ref().map(|xfer| xfer.src)
+    }
+
     /// Writes `val` to OAM. `addr` should be in range 0xFE00..=0xFE9F.
     ///
     /// This is a utility function that bypassed the OAM DMA access checks
@@ -317,151 +755,336 @@ impl PPU {
         (&mut self.oam[..]).write(addr - 0xFE00, val)
     }
 
-    /// Rasterizes the current contents of the Video RAM to the provided video buffer.
+    /// Enables or disables pixel rendering.
+    ///
+    /// When disabled, `rasterize` leaves the video buffer untouched, but the LCD timings
+    /// (LY, STAT and interrupts) keep running as usual.
+    pub fn set_rendering_enabled(&mut self, enabled: bool) {
+        self.rendering_disabled = !enabled;
+    }
+
+    /// Returns whether pixel rendering is enabled.
+    pub fn rendering_enabled(&self) -> bool {
+        !self.rendering_disabled
+    }
+
+    /// Overrides the order in which overlapping sprites are drawn.
+    ///
+    /// Anything other than `SpriteOrder::Hardware` is meant for debugging only,
+    /// since games may look wrong when their sprites are not drawn like on real hardware.
+    pub fn set_sprite_order(&mut self, order: SpriteOrder) {
+        self.sprite_order = order;
+    }
+
+    /// Returns the order in which overlapping sprites are drawn.
+    pub fn sprite_order(&self) -> SpriteOrder {
+        self.sprite_order
+    }
+
+    /// Returns the decoded OAM entry of sprite `idx`, or `None` if there's no such sprite.
+    pub fn sprite(&self, idx: usize) -> Option<SpriteInfo> {
+        let sprite = self.oam.get(idx)?;
+        let attr = sprite.attributes;
+
+        let height = if self.lcdc_reg.contains(LCDC::OBJ_SIZE) {
+            16
+        } else {
+            8
+        };
+
+        let y = i16::from(sprite.y) - 16;
+        let x = i16::from(sprite.x) - 8;
+        let lines = y.max(0)..(y + i16::from(height)).min(144);
+
+        // A sprite is dropped from a line when the sprites before it in OAM already fill it up
+        let dropped = lines.clone().any(|line| {
+            let before = self.oam[..idx].iter().filter(|other| {
+                let y = i16::from(other.y) - 16;
+                (y..y + i16::from(height)).contains(&line)
+            });
+            before.count() >= MAX_SPRITES_PER_LINE
+        });
+
+        Some(SpriteInfo {
+            y: sprite.y,
+            x: sprite.x,
+            tile: sprite.tid,
+            palette: attr.contains(SpriteAttributes::PAL_NUM) as u8,
+            flip_x: attr.contains(SpriteAttributes::FLIP_X),
+            flip_y: attr.contains(SpriteAttributes::FLIP_Y),
+            behind_bg: attr.contains(SpriteAttributes::BG_PRIO),
+            height,
+            offscreen: lines.is_empty() || x <= -8 || x >= 160,
+            dropped,
+        })
+    }
+
+    /// Takes over the debugging and host settings of `prev`, which are not part of the state.
+    pub(crate) fn take_host_state(&mut self, prev: &mut PPU) {
+        self.scanline_capture = prev.scanline_capture.take();
+        self.dot_breakpoint = prev.dot_breakpoint;
+        self.rendering_disabled = prev.rendering_disabled;
+        self.sprite_order = prev.sprite_order;
+    }
+
+    /// Copies the lines drawn so far to the provided video buffer. Lines of the current frame
+    /// which haven't been drawn yet still hold the previous one.
     ///
     /// NOTE: the buffer is assumed to be in U8U8U8U8 RGBA format.
     pub fn rasterize(&self, vbuf: &mut [u8]) {
-        // When the LCD display is disabled, show a white screen
-        if !self.lcdc_reg.contains(LCDC::DISP_EN) {
-            for b in vbuf.iter_mut() {
-                *b = 0xFF;
-            }
+        if self.rendering_disabled {
             return;
         }
 
-        // Draw BG, Window and sprites
-        self.rasterize_bg(vbuf);
-        self.rasterize_window(vbuf);
-        self.rasterize_sprites(vbuf);
+        for (px, &shade) in vbuf.chunks_exact_mut(4).zip(self.lcd.iter()) {
+            px.copy_from_slice(&[shade, shade, shade, 0xFF]);
+        }
     }
 
-    /// Rasterizes the current background map to the video buffer.
-    fn rasterize_bg(&self, vbuf: &mut [u8]) {
-        if !self.lcdc_reg.contains(LCDC::BG_DISP) {
-            // When BG displaying is disabled, show a white background
-            for b in vbuf.iter_mut() {
-                *b = 0xFF;
-            }
-            return;
-        }
+    /// Rasterizes the whole 256x256 background map to the provided video buffer,
+    /// regardless of the current viewport and BG display enable bit.
+    ///
+    /// If `scrolled` is true, the map is wrapped around so that the top-left corner
+    /// of the viewport (ie. `(SCX, SCY)`) ends up at the origin of the buffer.
+    ///
+    /// NOTE: the buffer is assumed to be in U8U8U8U8 RGBA format.
+    pub fn rasterize_bg_map(&self, vbuf: &mut [u8], scrolled: bool) {
+        let origin = if scrolled {
+            (usize::from(self.scx_reg.0), usize::from(self.scy_reg.0))
+        } else {
+            (0, 0)
+        };
 
-        // The active area is displayed from coordinates (SCX, SCY) in the BG area
-        let scy = u16::from(self.scy_reg.0);
-        let scx = u16::from(self.scx_reg.0);
-
-        // Iterate over each pixel in the screen
-        for py in 0u16..144 {
-            for px in 0u16..160 {
-                // Compute the corresponding logical pixel.
-                // Wrap to the top-left in case the scroll registers cause any overflows.
-                let ly = usize::from(py + scy) % 256;
-                let lx = usize::from(px + scx) % 256;
-
-                self.rasterize_tile(
-                    self.get_bg_tile(lx, ly),
-                    (lx, ly),
-                    (px as usize, py as usize),
-                    vbuf,
-                );
-            }
-        }
+        self.rasterize_map(
+            self.lcdc_reg.contains(LCDC::BG_DISP_SEL),
+            self.lcdc_reg.contains(LCDC::BG_WIN_DATA_SEL),
+            origin,
+            vbuf,
+        );
     }
 
-    /// Rasterizes the current window map to the video buffer, if enabled.
-    fn rasterize_window(&self, vbuf: &mut [u8]) {
-        if !self.lcdc_reg.contains(LCDC::WIN_DISP_EN) {
-            return;
+    /// Rasterizes the whole 256x256 window map to the provided video buffer,
+    /// regardless of the current window position and enable bit.
+    ///
+    /// NOTE: the buffer is assumed to be in U8U8U8U8 RGBA format.
+    pub fn rasterize_win_map(&self, vbuf: &mut [u8]) {
+        self.rasterize_map(
+            self.lcdc_reg.contains(LCDC::WIN_DISP_SEL),
+            self.lcdc_reg.contains(LCDC::BG_WIN_DATA_SEL),
+            (0, 0),
+            vbuf,
+        );
+    }
+
+    /// Rasterizes a whole 256x256 tile map to the provided video buffer, regardless of the
+    /// settings in LCDC: `high_map` selects the map at 0x9C00 rather than the one at 0x9800,
+    /// and `unsigned_data` the tile data at 0x8000 rather than the one at 0x8800.
+    ///
+    /// NOTE: the buffer is assumed to be in U8U8U8U8 RGBA format.
+    pub fn rasterize_tile_map(&self, vbuf: &mut [u8], high_map: bool, unsigned_data: bool) {
+        self.rasterize_map(high_map, unsigned_data, (0, 0), vbuf);
+    }
+
+    /// Returns the color numbers of tile `idx` in the tile data table (VRAM bank 0),
+    /// line by line, or `None` if there's no such tile.
+    pub fn tile_pixels(&self, idx: usize) -> Option<[u8; 64]> {
+        let tile = self.tdt.get(idx)?;
+        let mut pixels = [0; 64];
+
+        for (i, px) in pixels.iter_mut().enumerate() {
+            *px = tile.pixel((i % 8) as u8, (i / 8) as u8);
         }
+        Some(pixels)
+    }
 
-        // The window is displayed from coordinates (WX-7, WY) in the active area
-        let wy = i16::from(self.wy_reg.0);
-        let wx = i16::from(self.wx_reg.0) - 7;
+    /// Rasterizes the 384 tiles of the tile data table (VRAM bank 0) to a
+    /// `TILE_DATA_WIDTH`x`TILE_DATA_HEIGHT` video buffer, 16 tiles per row, using the BG palette.
+    ///
+    /// NOTE: the buffer is assumed to be in U8U8U8U8 RGBA format.
+    pub fn rasterize_tiles(&self, vbuf: &mut [u8]) {
+        for (idx, tile) in self.tdt.iter().enumerate() {
+            let (ox, oy) = ((idx % 16) * 8, (idx / 16) * 8);
 
-        // Iterate over each physical pixel in the window area
-        for py in wy.max(0)..(wy + 144).min(144) {
-            for px in wx.max(0)..(wx + 160).min(160) {
-                // Compute the corresponding logical pixel in the BG map
-                let ly = (py - wy) as usize % 256;
-                let lx = (px - wx) as usize % 256;
+            for y in 0..8 {
+                for x in 0..8 {
+                    let shade = self.get_shade(self.bgp_reg.0, tile.pixel(x as u8, y as u8));
+                    let pid = ((oy + y) * TILE_DATA_WIDTH + ox + x) * 4;
 
-                self.rasterize_tile(
-                    self.get_win_tile(lx, ly),
-                    (lx, ly),
-                    (px as usize, py as usize),
-                    vbuf,
-                );
+                    vbuf[pid] = shade;
+                    vbuf[pid + 1] = shade;
+                    vbuf[pid + 2] = shade;
+                    vbuf[pid + 3] = 0xFF;
+                }
             }
         }
     }
 
-    /// Rasterizes the `tile` located at logical coordinates `(lx, ly)` to the video buffer
-    /// at physical coordinates `(px, py)`.
-    fn rasterize_tile(
+    /// Rasterizes the selected tile map to a 256x256 video buffer,
+    /// starting from logical coordinates `(ox, oy)`.
+    fn rasterize_map(
         &self,
-        tile: &Tile,
-        (lx, ly): (usize, usize),
-        (px, py): (usize, usize),
+        disp_sel: bool,
+        unsigned_data: bool,
+        (ox, oy): (usize, usize),
         vbuf: &mut [u8],
     ) {
-        // Obtain the color of the tile's pixel corresponding to (lx, ly)
-        let pixel = tile.pixel((lx & 0x07) as u8, (ly & 0x7) as u8);
-        let shade = self.get_shade(self.bgp_reg.0, pixel);
+        for py in 0..256 {
+            for px in 0..256 {
+                let ly = (py + oy) % 256;
+                let lx = (px + ox) % 256;
+
+                let id = ((ly >> 3) << 5) + (lx >> 3);
+                let tile_id = if disp_sel {
+                    self.bgtm1[id]
+                } else {
+                    self.bgtm0[id]
+                };
+                let tile = &self.tdt[tile_index(tile_id, unsigned_data)];
+                let pixel = tile.pixel((lx & 0x07) as u8, (ly & 0x7) as u8);
+                let shade = self.get_shade(self.bgp_reg.0, pixel);
+
+                let pid = py * 256 * 4 + px * 4;
+
+                vbuf[pid] = shade;
+                vbuf[pid + 1] = shade;
+                vbuf[pid + 2] = shade;
+                vbuf[pid + 3] = 0xFF;
+            }
+        }
+    }
+
+    /// Draws line `ly` of the frame, using the registers latched when its pixel transfer began.
+    fn render_line(&mut self, ly: usize) {
+        let regs = self.line_regs;
+        let lcdc = LCDC::from_bits_truncate(regs.lcdc);
 
-        // Compute the index in the video buffer
-        let pid = (py as usize) * 160 * 4 + (px as usize) * 4;
+        // When BG displaying is disabled, show a white background
+        let mut line = [0xFF; 160];
 
-        vbuf[pid] = shade;
-        vbuf[pid + 1] = shade;
-        vbuf[pid + 2] = shade;
+        if self.bg_win_enabled(lcdc) {
+            self.render_bg(&regs, ly, &mut line);
+            self.render_window(&regs, ly, &mut line);
+        }
+        if lcdc.contains(LCDC::OBJ_DISP_EN) {
+            self.render_sprites(&regs, ly, &mut line);
+        }
+
+        self.lcd[ly * 160..(ly + 1) * 160].copy_from_slice(&line);
     }
 
-    /// Rasterizes any visible sprite to the video buffer.
-    fn rasterize_sprites(&self, vbuf: &mut [u8]) {
-        // Do nothing if sprite displaying is disabled
-        if !self.lcdc_reg.contains(LCDC::OBJ_DISP_EN) {
-            return;
+    /// Draws line `ly` of the background map.
+    fn render_bg(&self, regs: &ScanlineRegs, ly: usize, line: &mut [u8; 160]) {
+        let lcdc = LCDC::from_bits_truncate(regs.lcdc);
+
+        // The active area is displayed from coordinates (SCX, SCY) in the BG area.
+        // Wrap to the top-left in case the scroll registers cause any overflows.
+        let y = (ly + usize::from(regs.scy)) % 256;
+
+        for (px, shade) in line.iter_mut().enumerate() {
+            let x = (px + usize::from(regs.scx)) % 256;
+
+            let tile = self.get_bg_win_tile(x, y, lcdc.contains(LCDC::BG_DISP_SEL), lcdc);
+            *shade = self.get_shade(regs.bgp, tile.pixel((x & 0x07) as u8, (y & 0x07) as u8));
         }
+    }
+
+    /// Draws line `ly` of the window map, if the window is shown on it.
+    fn render_window(&self, regs: &ScanlineRegs, ly: usize, line: &mut [u8; 160]) {
+        let lcdc = LCDC::from_bits_truncate(regs.lcdc);
+
+        let (wx, win_line) = match self.win_lines[ly] {
+            Some(line) => line,
+            None => return,
+        };
+
+        // The window is displayed from X coordinate WX-7 of the active area.
+        // With WX < 7, its leftmost pixels are cut off by the left edge of the screen.
+        let wx = i16::from(wx) - 7;
+        let y = usize::from(win_line);
 
-        let is_8x16 = self.lcdc_reg.contains(LCDC::OBJ_SIZE);
+        for px in wx.max(0)..160 {
+            let x = (px - wx) as usize;
+
+            let tile = self.get_bg_win_tile(x, y, lcdc.contains(LCDC::WIN_DISP_SEL), lcdc);
+            line[px as usize] =
+                self.get_shade(regs.bgp, tile.pixel((x & 0x07) as u8, (y & 0x07) as u8));
+        }
+    }
+
+    /// Returns whether the BG and window are displayed at all.
+    ///
+    /// On DMG, clearing LCDC bit 0 blanks both of them. On CGB, the bit instead makes them
+    /// lose priority over sprites, but they are still displayed.
+    fn bg_win_enabled(&self, lcdc: LCDC) -> bool {
+        self.model == Model::Cgb || lcdc.contains(LCDC::BG_DISP)
+    }
+
+    /// Draws the sprites selected during OAM scan on line `ly`.
+    fn render_sprites(&self, regs: &ScanlineRegs, ly: usize, line: &mut [u8; 160]) {
+        let is_8x16 = LCDC::from_bits_truncate(regs.lcdc).contains(LCDC::OBJ_SIZE);
+        let scan = &self.oam_scan[ly];
+
+        // Draw the sprites from the lowest to the highest priority
+        let mut ids = [0; MAX_SPRITES_PER_LINE];
+        let ids = &mut ids[..scan.sprites().len()];
+        ids.copy_from_slice(scan.sprites());
+
+        match (self.sprite_order, self.model) {
+            (SpriteOrder::Hardware, Model::Dmg) => {
+                ids.sort_by_key(|&id| Reverse((self.oam[usize::from(id)].x, id)))
+            }
+            (SpriteOrder::Hardware, Model::Cgb) => ids.sort_by_key(|&id| Reverse(id)),
+            (SpriteOrder::Oam, _) => (),
+        }
+
+        for &id in ids.iter() {
+            let sprite = &self.oam[usize::from(id)];
 
-        for sprite in self.oam.iter() {
             let y = i16::from(sprite.y) - 16;
             let x = i16::from(sprite.x) - 8;
             let attr = sprite.attributes;
 
             // In 8x16 mode, the upper 8x8 tile is "tid & 0xFE",
             // and the lower 8x8 tile is "tid | 0x01".
-            let tile = if is_8x16 {
-                self.get_sprite_tile((sprite.tid & 0xFE).into())
-            } else {
-                self.get_sprite_tile(sprite.tid.into())
-            };
+            if is_8x16 {
+                let (mut upper, mut lower) = (sprite.tid & 0xFE, sprite.tid | 0x01);
 
-            self.rasterize_sprite(tile, x, y, attr, vbuf);
+                // Flipping a 8x16 sprite vertically also swaps its two halves
+                if attr.contains(SpriteAttributes::FLIP_Y) {
+                    std::mem::swap(&mut upper, &mut lower);
+                }
 
-            // In 8x16 mode, rasterize the lower sprite too
-            if is_8x16 {
-                let tile = self.get_sprite_tile((sprite.tid | 0x01).into());
+                let tile = self.get_sprite_tile(upper.into(), attr);
+                self.render_sprite(tile, (x, y), attr, regs, ly as i16, line);
 
-                self.rasterize_sprite(tile, x, y + 8, attr, vbuf);
+                let tile = self.get_sprite_tile(lower.into(), attr);
+                self.render_sprite(tile, (x, y + 8), attr, regs, ly as i16, line);
+            } else {
+                let tile = self.get_sprite_tile(sprite.tid.into(), attr);
+                self.render_sprite(tile, (x, y), attr, regs, ly as i16, line);
             }
         }
     }
 
-    /// Rasterizes a single sprite to screen at coordinates `(x,y)`.
-    fn rasterize_sprite(
+    /// Draws line `ly` of a single 8x8 sprite tile located at coordinates `(x,y)`.
+    fn render_sprite(
         &self,
         tile: &Tile,
-        x: i16,
-        y: i16,
+        (x, y): (i16, i16),
         attr: SpriteAttributes,
-        vbuf: &mut [u8],
+        regs: &ScanlineRegs,
+        ly: i16,
+        line: &mut [u8; 160],
     ) {
+        if !(y..y + 8).contains(&ly) {
+            return;
+        }
+
         // The palette used in rasterizing the srpite depends on its attributes
         let palette = if attr.contains(SpriteAttributes::PAL_NUM) {
-            self.obp1_reg.0
+            regs.obp1
         } else {
-            self.obp0_reg.0
+            regs.obp0
         };
 
         // Flip sprite horizontally
@@ -482,21 +1105,14 @@ impl PPU {
         let _behind_bg = attr.contains(SpriteAttributes::BG_PRIO);
 
         // Clip to currently visible area
-        for py in y.max(0)..(y + 8).min(144) {
-            for px in x.max(0)..(x + 8).min(160) {
-                let x = (off_x - (px - x) as i16).abs() as u8;
-                let y = (off_y - (py - y) as i16).abs() as u8;
-
-                let pixel = tile.pixel(x, y);
-                let shade = self.get_shade(palette, pixel);
+        let ty = (off_y - (ly - y)).abs() as u8;
 
-                let pid = (py as usize) * 160 * 4 + (px as usize) * 4;
+        for px in x.max(0)..(x + 8).min(160) {
+            let tx = (off_x - (px - x)).abs() as u8;
+            let pixel = tile.pixel(tx, ty);
 
-                if pixel != 0 {
-                    vbuf[pid] = shade;
-                    vbuf[pid + 1] = shade;
-                    vbuf[pid + 2] = shade;
-                }
+            if pixel != 0 {
+                line[px as usize] = self.get_shade(palette, pixel);
             }
         }
     }
@@ -516,19 +1132,29 @@ impl PPU {
 
         let lyc_coinc = self.ly_reg == self.lyc_reg;
 
-        // Set STAT interrupt flags depending on the enable bits in STAT
-        if self.stat_reg.contains(STAT::LYC_INTR) && lyc_coinc && tstate == 0 {
-            self.stat_irq |= STATIRQ::LYC;
+        // Compute which of the sources enabled in STAT are asserting the STAT interrupt line
+        let mut sources = STATIRQ::DEFAULT;
+
+        if self.stat_reg.contains(STAT::LYC_INTR) && lyc_coinc {
+            sources |= STATIRQ::LYC;
         }
-        if self.stat_reg.contains(STAT::OAM_INTR) && mode == STAT::MOD_2 && tstate == 0 {
-            self.stat_irq |= STATIRQ::OAM;
+        if self.stat_reg.contains(STAT::OAM_INTR) && mode == STAT::MOD_2 {
+            sources |= STATIRQ::OAM;
         }
-        if self.stat_reg.contains(STAT::VBK_INTR) && v_line == 144 && tstate == 0 {
-            self.stat_irq |= STATIRQ::VBK;
+        if self.stat_reg.contains(STAT::VBK_INTR) && mode == STAT::MOD_1 {
+            sources |= STATIRQ::VBK;
         }
-        if self.stat_reg.contains(STAT::HBK_INTR) && mode == STAT::MOD_0 && tstate == 256 {
-            self.stat_irq |= STATIRQ::HBK;
+        if self.stat_reg.contains(STAT::HBK_INTR) && mode == STAT::MOD_0 {
+            sources |= STATIRQ::HBK;
+        }
+
+        // All the sources are ORed together on a single line, and the interrupt is requested
+        // only on its rising edge: while a source keeps the line high, others can't trigger
+        // an interrupt (aka "STAT blocking").
+        if !self.stat_line && !sources.is_empty() {
+            self.stat_irq |= sources;
         }
+        self.stat_line = !sources.is_empty();
 
         // Update coincidence flag
         if lyc_coinc {
@@ -541,6 +1167,29 @@ impl PPU {
         self.stat_reg = (self.stat_reg & !STAT::MOD_FLAG) | mode;
     }
 
+    /// Handles a write to the LCDC register, stopping or restarting the LCD as needed.
+    fn write_to_lcdc(&mut self, val: u8) {
+        let was_on = self.lcdc_reg.contains(LCDC::DISP_EN);
+        self.lcdc_reg = LCDC::from_bits_truncate(val);
+
+        match (was_on, self.lcdc_reg.contains(LCDC::DISP_EN)) {
+            // Turning the LCD off resets LY and the mode, releases the STAT line
+            // and blanks the screen
+            (true, false) => {
+                for shade in self.lcd.iter_mut() {
+                    *shade = 0xFF;
+                }
+                self.tstate = 0;
+                self.ly_reg.0 = 0;
+                self.stat_reg &= !STAT::MOD_FLAG;
+                self.stat_line = false;
+            }
+            // Turning it back on starts a new frame from the first line
+            (false, true) => self.tstate = 0,
+            _ => (),
+        }
+    }
+
     /// Queues a new DMA transfer from RAM or ROM to OAM.
     ///
     /// A DMA transfer lasts 160 cycles, during which the CPU can only access HRAM.
@@ -569,44 +1218,68 @@ impl PPU {
         }
     }
 
-    /// Returns the BG tile corresponding to the given ID.
-    fn get_bg_tile(&self, x: usize, y: usize) -> &Tile {
-        self.get_bg_win_tile(
-            ((y >> 3) << 5) + (x >> 3), // coords to 8x8 tile ID
-            self.lcdc_reg.contains(LCDC::BG_DISP_SEL),
-        )
-    }
-
-    /// Returns the Window tile corresponding to the given ID.
-    fn get_win_tile(&self, x: usize, y: usize) -> &Tile {
-        self.get_bg_win_tile(
-            ((y >> 3) << 5) + (x >> 3), // coords to 8x8 tile ID
-            self.lcdc_reg.contains(LCDC::WIN_DISP_SEL),
-        )
-    }
-
-    /// Returns the BG or Window tile corresponding to the given ID.
+    /// Returns the BG or Window tile at logical coordinates `(x, y)` in the map
+    /// selected by `disp_sel`.
     ///
-    /// The resulting Tile depends on the selected BG/Window Tile Map
-    /// and addressing mode in LCDC register.
-    fn get_bg_win_tile(&self, id: usize, disp_sel: bool) -> &Tile {
+    /// The resulting Tile depends on the addressing mode in the given LCDC value.
+    fn get_bg_win_tile(&self, x: usize, y: usize, disp_sel: bool, lcdc: LCDC) -> &Tile {
+        let id = ((y >> 3) << 5) + (x >> 3); // coords to 8x8 tile ID
+
         let tile_id = if disp_sel {
             self.bgtm1[id]
         } else {
             self.bgtm0[id]
         };
 
-        if self.lcdc_reg.contains(LCDC::BG_WIN_DATA_SEL) {
-            &self.tdt[usize::from(tile_id)]
-        } else {
-            &self.tdt[(256 + i32::from(tile_id as i8)) as usize]
+        &self.tdt[tile_index(tile_id, lcdc.contains(LCDC::BG_WIN_DATA_SEL))]
+    }
+
+    /// Returns the index in the Tile Data Table of a BG or Window tile ID,
+    /// depending on the addressing mode in LCDC register.
+    fn get_bg_win_tile_index(&self, tile_id: u8) -> usize {
+        tile_index(tile_id, self.lcdc_reg.contains(LCDC::BG_WIN_DATA_SEL))
+    }
+
+    /// Returns where in VRAM the background pixel at the given screen coordinates comes from,
+    /// or `None` if the coordinates are off-screen.
+    ///
+    /// This follows the same scrolling, tile map and addressing mode logic used for rendering,
+    /// but only considers the background: the window or a sprite might cover the pixel.
+    pub fn bg_pixel_source(&self, screen_x: u8, screen_y: u8) -> Option<PixelSource> {
+        if screen_x >= 160 || screen_y >= 144 {
+            return None;
         }
+
+        let ly = (usize::from(screen_y) + usize::from(self.scy_reg.0)) % 256;
+        let lx = (usize::from(screen_x) + usize::from(self.scx_reg.0)) % 256;
+
+        let id = ((ly >> 3) << 5) + (lx >> 3);
+
+        let (tile_map_addr, tile_index) = if self.lcdc_reg.contains(LCDC::BG_DISP_SEL) {
+            (0x9C00 + id as u16, self.bgtm1[id])
+        } else {
+            (0x9800 + id as u16, self.bgtm0[id])
+        };
+
+        let tile = self.get_bg_win_tile_index(tile_index) as u16;
+
+        Some(PixelSource {
+            tile_map_addr,
+            tile_index,
+            tile_data_addr: 0x8000 + tile * 16 + (ly as u16 % 8) * 2,
+            px: (lx % 8) as u8,
+            py: (ly % 8) as u8,
+        })
     }
 
     /// Returns the sprite tile corresponding to the given ID.
-    fn get_sprite_tile(&self, id: usize) -> &Tile {
-        // TODO support loading 8x16 sprites
-        &self.tdt[id]
+    fn get_sprite_tile(&self, id: usize, attr: SpriteAttributes) -> &Tile {
+        // On CGB, sprites can take their tile data from either VRAM bank
+        if self.model == Model::Cgb && attr.contains(SpriteAttributes::VRAM_BANK) {
+            &self.tdt1[id]
+        } else {
+            &self.tdt[id]
+        }
     }
 }
 
@@ -627,13 +1300,18 @@ impl InterruptSource for PPU {
 
 impl MemR for PPU {
     fn read(&self, addr: u16) -> Result<u8, dbg::TraceEvent> {
+        let bank1 = self.vbk_reg.bit(0);
+
         Ok(match addr {
             0x8000..=0x97FF => {
                 let addr = addr - 0x8000;
                 let tid = usize::from(addr >> 4);
                 let bid = usize::from(addr & 0xF);
-                self.tdt[tid].data()[bid]
+                let tdt = if bank1 { &self.tdt1 } else { &self.tdt };
+                tdt[tid].data()[bid]
             }
+            0x9800..=0x9BFF if bank1 => self.bgattr0[usize::from(addr - 0x9800)],
+            0x9C00..=0x9FFF if bank1 => self.bgattr1[usize::from(addr - 0x9C00)],
             0x9800..=0x9BFF => self.bgtm0[usize::from(addr - 0x9800)],
             0x9C00..=0x9FFF => self.bgtm1[usize::from(addr - 0x9C00)],
 
@@ -660,20 +1338,41 @@ impl MemR for PPU {
             0xFF4A => self.wy_reg.0,
             0xFF4B => self.wx_reg.0,
 
+            0xFF4F => self.vbk_reg.0 | 0xFE,
+            0xFF68 => self.bcps_reg.0 | 0x40,
+            0xFF69 => self.bg_palettes[usize::from(self.bcps_reg.0 & 0x3F)],
+            0xFF6A => self.ocps_reg.0 | 0x40,
+            0xFF6B => self.obj_palettes[usize::from(self.ocps_reg.0 & 0x3F)],
+
             _ => unreachable!(),
         })
     }
 }
 
+/// Writes `val` to the palette memory byte selected by the index register `ps`,
+/// advancing the index if auto-increment (bit 7) is enabled.
+fn write_palette(palettes: &mut [u8; 64], ps: &mut IoReg<u8>, val: u8) {
+    palettes[usize::from(ps.0 & 0x3F)] = val;
+
+    if ps.bit(7) {
+        ps.0 = 0x80 | (ps.0.wrapping_add(1) & 0x3F);
+    }
+}
+
 impl MemW for PPU {
     fn write(&mut self, addr: u16, val: u8) -> Result<(), dbg::TraceEvent> {
+        let bank1 = self.vbk_reg.bit(0);
+
         match addr {
             0x8000..=0x97FF => {
                 let addr = addr - 0x8000;
                 let tid = usize::from(addr >> 4);
                 let bid = usize::from(addr & 0xF);
-                self.tdt[tid].data_mut()[bid] = val;
+                let tdt = if bank1 { &mut self.tdt1 } else { &mut self.tdt };
+                tdt[tid].data_mut()[bid] = val;
             }
+            0x9800..=0x9BFF if bank1 => self.bgattr0[usize::from(addr - 0x9800)] = val,
+            0x9C00..=0x9FFF if bank1 => self.bgattr1[usize::from(addr - 0x9C00)] = val,
             0x9800..=0x9BFF => self.bgtm0[usize::from(addr - 0x9800)] = val,
             0x9C00..=0x9FFF => self.bgtm1[usize::from(addr - 0x9C00)] = val,
 
@@ -684,7 +1383,7 @@ impl MemW for PPU {
                 }
             }
 
-            0xFF40 => (&mut self.lcdc_reg).write(0, val)?,
+            0xFF40 => self.write_to_lcdc(val),
             0xFF41 => (&mut self.stat_reg).write(0, val)?,
             0xFF42 => self.scy_reg.0 = val,
             0xFF43 => self.scx_reg.0 = val,
@@ -697,9 +1396,561 @@ impl MemW for PPU {
             0xFF4A => self.wy_reg.0 = val,
             0xFF4B => self.wx_reg.0 = val,
 
+            0xFF4F => self.vbk_reg.0 = val & 0x01,
+            0xFF68 => self.bcps_reg.0 = val & 0xBF,
+            0xFF69 => write_palette(&mut self.bg_palettes, &mut self.bcps_reg, val),
+            0xFF6A => self.ocps_reg.0 = val & 0xBF,
+            0xFF6B => write_palette(&mut self.obj_palettes, &mut self.ocps_reg, val),
+
             _ => unreachable!(),
         };
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bg_map_is_rasterized_with_scrolling() {
+        let mut ppu = PPU::new();
+        let mut vbuf = vec![0; 256 * 256 * 4];
+
+        // Make tile #1 solid black and place it at the top-left corner of the map
+        for addr in 0x8010..0x8020 {
+            ppu.write(addr, 0xFF).unwrap();
+        }
+        ppu.write(0x9800, 0x01).unwrap();
+
+        ppu.rasterize_bg_map(&mut vbuf, false);
+        assert_eq!(&vbuf[..4], &[0x00, 0x00, 0x00, 0xFF]);
+        assert_eq!(&vbuf[8 * 4..9 * 4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // Scroll right by 8 pixels, the tile wraps around to the right edge
+        ppu.write(0xFF43, 8).unwrap();
+
+        ppu.rasterize_bg_map(&mut vbuf, true);
+        assert_eq!(&vbuf[..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(&vbuf[248 * 4..249 * 4], &[0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn tile_maps_are_rasterized_regardless_of_lcdc() {
+        let mut ppu = PPU::new();
+        let mut vbuf = vec![0; 256 * 256 * 4];
+
+        // Make tile #1 solid black with unsigned addressing only, and place it in the high map
+        for addr in 0x8010..0x8020 {
+            ppu.write(addr, 0xFF).unwrap();
+        }
+        ppu.write(0x9C00, 0x01).unwrap();
+        ppu.write(0xFF40, 0x80).unwrap();
+
+        ppu.rasterize_tile_map(&mut vbuf, true, true);
+        assert_eq!(&vbuf[..4], &[0x00, 0x00, 0x00, 0xFF]);
+
+        ppu.rasterize_tile_map(&mut vbuf, false, true);
+        assert_eq!(&vbuf[..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        ppu.rasterize_tile_map(&mut vbuf, true, false);
+        assert_eq!(&vbuf[..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        ppu.write(0xFF42, 0x12).unwrap();
+        ppu.write(0xFF43, 0x34).unwrap();
+        assert_eq!(ppu.scroll(), (0x34, 0x12));
+        assert_eq!(ppu.lcdc(), 0x80);
+        assert!(!ppu.bg_map_high() && !ppu.unsigned_tile_data());
+
+        ppu.write(0xFF40, 0x98).unwrap();
+        assert!(ppu.bg_map_high() && ppu.unsigned_tile_data());
+    }
+
+    #[test]
+    fn tile_data_is_rasterized_in_a_grid() {
+        let mut ppu = PPU::new();
+        let mut vbuf = vec![0; TILE_DATA_WIDTH * TILE_DATA_HEIGHT * 4];
+
+        // Tile #17: first line in color 1, everything else in color 3
+        ppu.write(0x8110, 0xFF).unwrap();
+        for addr in 0x8112..0x8120 {
+            ppu.write(addr, 0xFF).unwrap();
+        }
+
+        let pixels = ppu.tile_pixels(17).unwrap();
+        assert!(pixels[..8].iter().all(|&px| px == 1));
+        assert!(pixels[8..].iter().all(|&px| px == 3));
+        assert!(ppu.tile_pixels(384).is_none());
+
+        ppu.write(0xFF47, 0xE4).unwrap();
+        ppu.rasterize_tiles(&mut vbuf);
+
+        // Tile #17 is the second one of the second row
+        let px = |x: usize, y: usize| &vbuf[(y * TILE_DATA_WIDTH + x) * 4..][..4];
+        assert_eq!(px(8, 8), &[0xAA, 0xAA, 0xAA, 0xFF]);
+        assert_eq!(px(15, 9), &[0x00, 0x00, 0x00, 0xFF]);
+        assert_eq!(px(7, 9), &[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(px(8, 16), &[0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn scanline_registers_are_captured_on_request() {
+        let mut ppu = PPU::new();
+
+        // Run to the start of a frame, with capture disabled
+        while !(ppu.ly() == 0 && ppu.dot() == 0) {
+            ppu.tick(1);
+        }
+        for _ in 0..70224 / 4 {
+            ppu.tick(1);
+        }
+        assert!(ppu.per_scanline_registers().is_empty());
+
+        // Change the horizontal scroll on every line, like a wavy raster effect would
+        ppu.set_scanline_capture(true);
+        for _ in 0..70224 / 4 {
+            if ppu.dot() == 0 {
+                ppu.write(0xFF43, ppu.ly().wrapping_mul(2)).unwrap();
+            }
+            ppu.tick(1);
+        }
+
+        let regs = ppu.per_scanline_registers();
+        assert_eq!(regs.len(), 144);
+        assert_eq!(regs[0].scx, 0);
+        assert_eq!(regs[100].scx, 200);
+        assert_eq!(regs[100].bgp, 0xFC);
+    }
+
+    #[test]
+    fn bg_and_window_are_disabled_on_dmg_only() {
+        let mut vbuf = vec![0; 160 * 144 * 4];
+
+        for &model in &[Model::Dmg, Model::Cgb] {
+            let mut ppu = PPU::with_model(model);
+
+            // Solid black BG, with the window covering the bottom half of the screen
+            for addr in 0x8000..0x8010 {
+                ppu.write(addr, 0xFF).unwrap();
+            }
+            ppu.write(0xFF4A, 72).unwrap();
+            ppu.write(0xFF4B, 7).unwrap();
+            ppu.write(0xFF40, 0b_1011_0000).unwrap();
+
+            for _ in 0..70224 / 4 {
+                ppu.tick(1);
+            }
+            ppu.rasterize(&mut vbuf);
+
+            let shade = if model == Model::Dmg { 0xFF } else { 0x00 };
+            assert!(vbuf.chunks(4).all(|px| px[..3] == [shade; 3]));
+        }
+    }
+
+    #[test]
+    fn lines_are_drawn_with_their_latched_registers() {
+        let mut vbuf = vec![0; 160 * 144 * 4];
+        let mut ppu = PPU::new();
+
+        // With an empty BG, the color of each line comes from BGP alone
+        ppu.write(0xFF40, 0b_1001_0001).unwrap();
+
+        // Go black from line 72 on, then back to white while line 100 is being drawn,
+        // which is too late for that line
+        for _ in 0..70224 / 4 {
+            match (ppu.ly(), ppu.dot()) {
+                (72, 0) => ppu.write(0xFF47, 0xFF).unwrap(),
+                (100, 120) => ppu.write(0xFF47, 0x00).unwrap(),
+                _ => (),
+            }
+            ppu.tick(1);
+        }
+        ppu.rasterize(&mut vbuf);
+
+        let px = |y: usize| vbuf[y * 160 * 4];
+        assert_eq!(px(71), 0xFF);
+        assert_eq!(px(72), 0x00);
+        assert_eq!(px(100), 0x00);
+        assert_eq!(px(101), 0xFF);
+    }
+
+    #[test]
+    fn window_keeps_its_own_line_counter() {
+        let mut vbuf = vec![0; 160 * 144 * 4];
+        let px = |vbuf: &[u8], x: usize, y: usize| vbuf[(y * 160 + x) * 4];
+
+        let mut ppu = PPU::new();
+
+        // Solid black tile #1 in the first column of the second row of the window map
+        for addr in 0x8010..0x8020 {
+            ppu.write(addr, 0xFF).unwrap();
+        }
+        ppu.write(0x9C20, 0x01).unwrap();
+        ppu.write(0xFF47, 0xE4).unwrap();
+        ppu.write(0xFF4A, 10).unwrap();
+
+        for &wx in &[3, 7, 167] {
+            ppu.write(0xFF4B, wx).unwrap();
+
+            // Hide the window on lines 12-21 only
+            for _ in 0..70224 / 4 {
+                if ppu.dot() == 0 {
+                    let lcdc = if (12..22).contains(&ppu.ly()) {
+                        0b_1101_0001
+                    } else {
+                        0b_1111_0001
+                    };
+                    ppu.write(0xFF40, lcdc).unwrap();
+                }
+                ppu.tick(1);
+            }
+            ppu.rasterize(&mut vbuf);
+
+            if wx > 166 {
+                assert!(vbuf.chunks(4).all(|px| px[0] == 0xFF));
+                continue;
+            }
+
+            // Window line 8 is reached on LY 28, since hidden lines don't count
+            assert_eq!(px(&vbuf, 0, 27), 0xFF);
+            assert_eq!(px(&vbuf, 0, 28), 0x00);
+            assert_eq!(px(&vbuf, 0, 35), 0x00);
+            assert_eq!(px(&vbuf, 0, 36), 0xFF);
+
+            // With WX < 7, the tile is partially cut off by the left edge of the screen
+            let width = usize::from(wx + 1);
+            assert_eq!(px(&vbuf, width - 1, 28), 0x00);
+            assert_eq!(px(&vbuf, width, 28), 0xFF);
+        }
+    }
+
+    #[test]
+    fn sprite_tiles_are_fetched_from_selected_bank_on_cgb() {
+        let mut vbuf = vec![0xFF; 160 * 144 * 4];
+
+        for &model in &[Model::Dmg, Model::Cgb] {
+            let mut ppu = PPU::with_model(model);
+
+            // Make tile #1 solid black in VRAM bank 1 only
+            ppu.write(0xFF4F, 0x01).unwrap();
+            for addr in 0x8010..0x8020 {
+                ppu.write(addr, 0xFF).unwrap();
+            }
+            ppu.write(0xFF4F, 0x00).unwrap();
+
+            // Place a sprite using it in the top-left corner
+            ppu.write_to_oam(0xFE00, 16).unwrap();
+            ppu.write_to_oam(0xFE01, 8).unwrap();
+            ppu.write_to_oam(0xFE02, 0x01).unwrap();
+            ppu.write_to_oam(0xFE03, 0x08).unwrap();
+
+            ppu.write(0xFF48, 0xE4).unwrap();
+            ppu.write(0xFF40, 0b_1001_0011).unwrap();
+
+            for _ in 0..70224 / 4 {
+                ppu.tick(1);
+            }
+            ppu.rasterize(&mut vbuf);
+
+            // On DMG, the bank bit is ignored and the empty tile in bank 0 is used
+            let shade = if model == Model::Cgb { 0x00 } else { 0xFF };
+            assert_eq!(&vbuf[..4], &[shade, shade, shade, 0xFF]);
+            assert_eq!(
+                &vbuf[(7 * 160 + 7) * 4..(7 * 160 + 8) * 4],
+                &[shade, shade, shade, 0xFF]
+            );
+            assert_eq!(&vbuf[8 * 4..9 * 4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+        }
+    }
+
+    #[test]
+    fn sprite_order_can_be_overridden() {
+        let mut vbuf = vec![0xFF; 160 * 144 * 4];
+
+        for &(model, order, shade) in &[
+            (Model::Dmg, SpriteOrder::Hardware, 0x00),
+            (Model::Cgb, SpriteOrder::Hardware, 0x00),
+            (Model::Dmg, SpriteOrder::Oam, 0xAA),
+        ] {
+            let mut ppu = PPU::with_model(model);
+            ppu.set_sprite_order(order);
+
+            // Tile #1 is solid black, tile #2 is solid light gray
+            for addr in 0x8010..0x8020 {
+                ppu.write(addr, 0xFF).unwrap();
+            }
+            for addr in (0x8020..0x8030).step_by(2) {
+                ppu.write(addr, 0xFF).unwrap();
+            }
+
+            // Sprite #0 is black at X=8, sprite #1 is light gray at X=12, overlapping it
+            for (id, &(x, tid)) in [(16, 0x01), (20, 0x02)].iter().enumerate() {
+                let addr = 0xFE00 + id as u16 * 4;
+                ppu.write_to_oam(addr, 16).unwrap();
+                ppu.write_to_oam(addr + 1, x).unwrap();
+                ppu.write_to_oam(addr + 2, tid).unwrap();
+            }
+
+            ppu.write(0xFF48, 0xE4).unwrap();
+            ppu.write(0xFF40, 0b_1000_0010).unwrap();
+
+            for _ in 0..70224 / 4 {
+                ppu.tick(1);
+            }
+            ppu.rasterize(&mut vbuf);
+
+            let px = 12 * 4;
+            assert_eq!(&vbuf[px..px + 3], &[shade, shade, shade]);
+        }
+    }
+
+    #[test]
+    fn tall_sprites_are_flipped_as_a_whole() {
+        let mut vbuf = vec![0xFF; 160 * 144 * 4];
+        let mut ppu = PPU::new();
+
+        // Tile #2 has its leftmost column in color 1, tile #3 is solid color 3
+        for row in 0..8 {
+            ppu.write(0x8020 + row * 2, 0x80).unwrap();
+            ppu.write(0x8030 + row * 2, 0xFF).unwrap();
+            ppu.write(0x8031 + row * 2, 0xFF).unwrap();
+        }
+
+        // 8x16 sprite in the top-left corner, flipped on both axes and using OBP1
+        ppu.write_to_oam(0xFE00, 16).unwrap();
+        ppu.write_to_oam(0xFE01, 8).unwrap();
+        ppu.write_to_oam(0xFE02, 0x03).unwrap();
+        ppu.write_to_oam(0xFE03, 0x70).unwrap();
+
+        ppu.write(0xFF48, 0x00).unwrap();
+        ppu.write(0xFF49, 0xE4).unwrap();
+        ppu.write(0xFF40, 0b_1000_0110).unwrap();
+
+        for _ in 0..70224 / 4 {
+            ppu.tick(1);
+        }
+        ppu.rasterize(&mut vbuf);
+
+        let px = |x: usize, y: usize| &vbuf[(y * 160 + x) * 4..(y * 160 + x) * 4 + 3];
+
+        // The lower tile is drawn on top, and the upper one at the bottom,
+        // with its color 1 column moved to the right and color 0 left transparent
+        assert_eq!(px(0, 0), &[0x00, 0x00, 0x00]);
+        assert_eq!(px(7, 7), &[0x00, 0x00, 0x00]);
+        assert_eq!(px(0, 8), &[0xFF, 0xFF, 0xFF]);
+        assert_eq!(px(7, 15), &[0xAA, 0xAA, 0xAA]);
+        assert_eq!(px(8, 0), &[0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn oam_scan_selects_first_ten_sprites() {
+        let mut ppu = PPU::new();
+
+        // Place 12 sprites on line 20, plus one on a different line
+        for id in 0..12 {
+            ppu.write_to_oam(0xFE00 + id * 4, 20 + 16 - (id % 8) as u8)
+                .unwrap();
+            ppu.write_to_oam(0xFE01 + id * 4, 8 + (id as u8) * 8)
+                .unwrap();
+        }
+        ppu.write_to_oam(0xFE00 + 12 * 4, 30 + 16).unwrap();
+
+        let scan = ppu.scan_oam(20);
+        assert_eq!(scan.sprites(), &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+        let scan = ppu.scan_oam(30);
+        assert_eq!(scan.sprites(), &[12]);
+    }
+
+    #[test]
+    fn oam_scan_happens_during_mode_2() {
+        let mut ppu = PPU::new();
+
+        for id in 0..12 {
+            ppu.write_to_oam(0xFE00 + id * 4, 16).unwrap();
+        }
+
+        // Run a whole frame
+        for _ in 0..70224 / 4 {
+            ppu.tick(1);
+        }
+
+        assert_eq!(ppu.oam_scan[0].sprites(), &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(ppu.oam_scan[8].sprites().is_empty());
+    }
+
+    #[test]
+    fn sprites_are_decoded_from_oam() {
+        let mut ppu = PPU::new();
+
+        // 11 sprites on the first line, the last of which doesn't fit
+        for id in 0..11 {
+            ppu.write_to_oam(0xFE00 + id * 4, 16).unwrap();
+            ppu.write_to_oam(0xFE01 + id * 4, 8).unwrap();
+        }
+        ppu.write_to_oam(0xFE02, 0x42).unwrap();
+        ppu.write_to_oam(0xFE03, 0xD0).unwrap();
+
+        // Sprite #11 is hidden past the right edge of the screen
+        ppu.write_to_oam(0xFE00 + 11 * 4, 16).unwrap();
+        ppu.write_to_oam(0xFE01 + 11 * 4, 168).unwrap();
+
+        // No frame needs to be drawn, nor the LCD to be on, to know which sprites are dropped
+        ppu.write(0xFF40, 0x00).unwrap();
+
+        assert_eq!(
+            ppu.sprite(0),
+            Some(SpriteInfo {
+                y: 16,
+                x: 8,
+                tile: 0x42,
+                palette: 1,
+                flip_x: false,
+                flip_y: true,
+                behind_bg: true,
+                height: 8,
+                offscreen: false,
+                dropped: false,
+            })
+        );
+
+        let sprite = ppu.sprite(10).unwrap();
+        assert!(!sprite.offscreen);
+        assert!(sprite.dropped);
+
+        let sprite = ppu.sprite(11).unwrap();
+        assert!(sprite.offscreen);
+
+        // Sprites with Y = 0 are above the screen, and don't take part in OAM scan
+        let sprite = ppu.sprite(39).unwrap();
+        assert!(sprite.offscreen);
+        assert!(!sprite.dropped);
+
+        assert!(ppu.sprite(40).is_none());
+
+        // Moving a sprite out of the way makes room for the next ones right away
+        ppu.write_to_oam(0xFE00 + 5 * 4, 100).unwrap();
+        assert!(!ppu.sprite(10).unwrap().dropped);
+    }
+
+    #[test]
+    fn vram_bank_and_palettes_are_switchable() {
+        let mut ppu = PPU::new();
+
+        ppu.write(0x8000, 0x12).unwrap();
+        ppu.write(0xFF4F, 0x01).unwrap();
+        ppu.write(0x8000, 0x34).unwrap();
+        assert_eq!(ppu.read(0xFF4F).unwrap(), 0xFF);
+        assert_eq!(ppu.read(0x8000).unwrap(), 0x34);
+
+        ppu.write(0xFF4F, 0x00).unwrap();
+        assert_eq!(ppu.read(0x8000).unwrap(), 0x12);
+
+        // Auto-increment wraps around the 64 bytes of palette memory
+        ppu.write(0xFF68, 0x80 | 0x3F).unwrap();
+        ppu.write(0xFF69, 0x11).unwrap();
+        ppu.write(0xFF69, 0x22).unwrap();
+        assert_eq!(ppu.read(0xFF68).unwrap(), 0xC1);
+        assert_eq!(ppu.bg_palettes[0x3F], 0x11);
+        assert_eq!(ppu.bg_palettes[0x00], 0x22);
+
+        // Without auto-increment the index stays put
+        ppu.write(0xFF6A, 0x02).unwrap();
+        ppu.write(0xFF6B, 0x33).unwrap();
+        assert_eq!(ppu.read(0xFF6A).unwrap(), 0x42);
+        assert_eq!(ppu.read(0xFF6B).unwrap(), 0x33);
+    }
+
+    #[test]
+    fn bg_pixel_source_follows_scrolling_and_addressing() {
+        let mut ppu = PPU::new();
+
+        // Tile map #1, signed addressing
+        ppu.write(0xFF40, 0x89).unwrap();
+        ppu.write(0xFF42, 4).unwrap();
+        ppu.write(0xFF43, 250).unwrap();
+        ppu.write(0x9C20, 0xFE).unwrap();
+
+        // (10, 5) is at (4, 9) in the map, ie. in the first tile of the second row
+        let src = ppu.bg_pixel_source(10, 5).unwrap();
+        assert_eq!(
+            src,
+            PixelSource {
+                tile_map_addr: 0x9C20,
+                tile_index: 0xFE,
+                tile_data_addr: 0x8FE2,
+                px: 4,
+                py: 1,
+            }
+        );
+
+        // Unsigned addressing
+        ppu.write(0xFF40, 0x99).unwrap();
+        assert_eq!(ppu.bg_pixel_source(10, 5).unwrap().tile_data_addr, 0x8FE2);
+        ppu.write(0x9C20, 0x01).unwrap();
+        assert_eq!(ppu.bg_pixel_source(10, 5).unwrap().tile_data_addr, 0x8012);
+
+        assert!(ppu.bg_pixel_source(160, 0).is_none());
+    }
+
+    #[test]
+    fn stat_irq_is_requested_on_rising_edge_only() {
+        let mut ppu = PPU::new();
+
+        // Start counting from V-Blank, where no source is active
+        while ppu.ly() != 150 {
+            ppu.tick(1);
+        }
+
+        // Enable both the OAM and H-Blank sources: the line stays high
+        // from mode 0 of one line through mode 2 of the next one.
+        ppu.write(0xFF41, 0x28).unwrap();
+        ppu.get_and_clear_irq();
+
+        let mut stat_irqs = 0;
+
+        for _ in 0..70224 / 4 {
+            ppu.tick(1);
+
+            while let Some(irq) = ppu.get_and_clear_irq() {
+                if let IrqSource::LcdStat = irq {
+                    stat_irqs += 1;
+                }
+            }
+        }
+
+        // One H-Blank interrupt per visible line, plus the OAM one of line 0,
+        // which comes after V-Blank where the line was low.
+        assert_eq!(stat_irqs, 144 + 1);
+    }
+
+    #[test]
+    fn lcd_off_stops_stat_irqs() {
+        let mut ppu = PPU::new();
+
+        // Enable all the STAT sources, then turn the LCD off mid-frame
+        ppu.write(0xFF41, 0x78).unwrap();
+        while ppu.ly() != 10 {
+            ppu.tick(1);
+        }
+        ppu.write(0xFF40, 0x11).unwrap();
+        while ppu.get_and_clear_irq().is_some() {}
+
+        for _ in 0..70224 / 4 {
+            ppu.tick(1);
+            assert!(ppu.get_and_clear_irq().is_none());
+        }
+        assert_eq!(ppu.ly(), 0);
+        assert_eq!(ppu.read(0xFF41).unwrap() & 0x03, 0);
+
+        // Turning it back on restarts from the first line
+        ppu.write(0xFF40, 0x91).unwrap();
+        ppu.tick(1);
+        assert_eq!((ppu.ly(), ppu.dot()), (0, 4));
+        assert!(matches!(ppu.get_and_clear_irq(), Some(IrqSource::LcdStat)));
+
+        ppu.tick(19);
+        assert_eq!((ppu.ly(), ppu.dot()), (0, 80));
+    }
+}
@@ -1,9 +1,12 @@
 pub use gameboy::*;
 
 pub mod bus;
+pub mod cartridge;
+pub mod cheats;
 pub mod cpu;
 pub mod dbg;
 pub mod io;
 pub mod mem;
+pub mod state;
 
 mod gameboy;
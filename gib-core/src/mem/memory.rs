@@ -1,8 +1,10 @@
+use serde::{Deserialize, Serialize};
+
 use crate::dbg;
 
 use super::{MemR, MemRW, MemW};
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Memory {
     data: Vec<u8>,
 }
@@ -13,19 +15,56 @@ impl Memory {
             data: vec![0; usize::from(size)],
         }
     }
+
+    /// Returns the raw contents of the memory.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..]
+    }
+
+    /// Returns the raw contents of the memory as a mutable slice.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[..]
+    }
 }
 
 impl MemR for Memory {
     fn read(&self, addr: u16) -> Result<u8, dbg::TraceEvent> {
-        Ok(self.data[usize::from(addr)])
+        self.data
+            .get(usize::from(addr))
+            .copied()
+            .ok_or(dbg::TraceEvent::MemFault(addr))
     }
 }
 
 impl MemW for Memory {
     fn write(&mut self, addr: u16, val: u8) -> Result<(), dbg::TraceEvent> {
-        self.data[usize::from(addr)] = val;
+        let cell = self
+            .data
+            .get_mut(usize::from(addr))
+            .ok_or(dbg::TraceEvent::MemFault(addr))?;
+        *cell = val;
         Ok(())
     }
 }
 
 impl MemRW for Memory {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_range_accesses_are_memory_faults() {
+        let mut mem = Memory::new(0x10);
+
+        assert!(matches!(
+            mem.read(0x10),
+            Err(dbg::TraceEvent::MemFault(0x10))
+        ));
+        assert!(matches!(
+            mem.write(0x10, 0xAA),
+            Err(dbg::TraceEvent::MemFault(0x10))
+        ));
+        assert_eq!(mem.read(0x0F).unwrap(), 0);
+    }
+}
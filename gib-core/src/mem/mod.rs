@@ -6,6 +6,12 @@ mod memory;
 
 pub trait MemR {
     fn read(&self, addr: u16) -> Result<u8, dbg::TraceEvent>;
+
+    /// Reads from `addr` on behalf of the debugger, without any side effect.
+    /// Returns `None` if `addr` can't be read.
+    fn peek(&self, addr: u16) -> Option<u8> {
+        self.read(addr).ok()
+    }
 }
 
 pub trait MemW {
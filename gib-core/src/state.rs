@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Version of the serialized machine state layout.
+///
+/// This must be bumped whenever a change to any of the serialized types
+/// makes previously saved states incompatible.
+pub const STATE_VERSION: u32 = 18;
+
+/// The error type returned when a save state can't be loaded.
+#[derive(thiserror::Error, Debug)]
+pub enum StateError {
+    #[error("invalid save state: {0}")]
+    Invalid(#[from] bincode::Error),
+    #[error("save state belongs to another ROM")]
+    RomMismatch,
+}
+
+/// Ring buffer of save states, taken once per frame to rewind the emulation.
+///
+/// The buffer holds up to a maximum number of snapshots and of bytes:
+/// when either limit is exceeded, the oldest snapshots are dropped.
+#[derive(Default)]
+pub(crate) struct RewindBuffer {
+    snapshots: VecDeque<Vec<u8>>,
+    bytes: usize,
+    max_snapshots: usize,
+    max_bytes: usize,
+    /// Allocation of the last dropped snapshot, reused for the next one
+    spare: Vec<u8>,
+}
+
+impl RewindBuffer {
+    pub fn set_limits(&mut self, max_snapshots: usize, max_bytes: usize) {
+        self.max_snapshots = max_snapshots;
+        self.max_bytes = max_bytes;
+        self.trim();
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_snapshots > 0 && self.max_bytes > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.bytes = 0;
+    }
+
+    /// Returns an empty buffer to serialize the next snapshot into.
+    pub fn take_spare(&mut self) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.spare);
+        buf.clear();
+        buf
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        self.bytes += snapshot.len();
+        self.snapshots.push_back(snapshot);
+        self.trim();
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let snapshot = self.snapshots.pop_back()?;
+        self.bytes -= snapshot.len();
+        Some(snapshot)
+    }
+
+    /// Hands a popped snapshot back, so that its allocation can be reused.
+    pub fn recycle(&mut self, snapshot: Vec<u8>) {
+        self.spare = snapshot;
+    }
+
+    fn trim(&mut self) {
+        while self.snapshots.len() > self.max_snapshots || self.bytes > self.max_bytes {
+            match self.snapshots.pop_front() {
+                Some(oldest) => {
+                    self.bytes -= oldest.len();
+                    self.spare = oldest;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Rejects a serialized state whose version does not match `STATE_VERSION`.
+pub(crate) fn check_version<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let version = u32::deserialize(deserializer)?;
+
+    if version != STATE_VERSION {
+        return Err(de::Error::custom(format!(
+            "unsupported state version {} (expected {})",
+            version, STATE_VERSION
+        )));
+    }
+    Ok(version)
+}
+
+/// (De)serialization of fixed-size arrays of arbitrary length.
+///
+/// serde only implements its traits for arrays of up to 32 elements,
+/// so larger arrays (eg. VRAM or OAM) need to go through this helper.
+pub(crate) mod big_array {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    pub fn serialize<S, T, const N: usize>(arr: &[T; N], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        serializer.collect_seq(arr.iter())
+    }
+
+    pub fn deserialize<'de, D, T, const N: usize>(deserializer: D) -> Result<[T; N], D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        let v = Vec::<T>::deserialize(deserializer)?;
+        let len = v.len();
+
+        <[T; N]>::try_from(v).map_err(|_| de::Error::invalid_length(len, &"a fixed-size array"))
+    }
+}
@@ -5,6 +5,7 @@ mod ui;
 
 fn main() {
     use clap::{App, Arg};
+    use gib_core::io::TcpLink;
 
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -16,11 +17,40 @@ fn main() {
                 .long("devel")
                 .help("Open development GUI"),
         )
+        .arg(
+            Arg::with_name("link-connect")
+                .long("link-connect")
+                .value_name("HOST:PORT")
+                .help("Connect the link cable to an emulator listening at HOST:PORT")
+                .conflicts_with("link-listen"),
+        )
+        .arg(
+            Arg::with_name("link-listen")
+                .long("link-listen")
+                .value_name("[HOST:]PORT")
+                .help(
+                    "Wait for another emulator to connect its link cable on PORT \
+                     (only from this machine, unless HOST is given)",
+                ),
+        )
         .arg(Arg::with_name("ROM").help("ROM file to run").index(1))
         .get_matches();
 
     let mut emu = ui::EmuUi::new(matches.is_present("devel")).unwrap();
 
+    if let Some(addr) = matches.value_of("link-connect") {
+        let link = TcpLink::connect(addr).expect("error connecting link cable");
+        emu.set_serial_link(Box::new(link));
+    } else if let Some(addr) = matches.value_of("link-listen") {
+        let link = if addr.contains(':') {
+            TcpLink::listen_on(addr)
+        } else {
+            TcpLink::listen(addr.parse().expect("invalid link cable port"))
+        };
+        let link = link.expect("error listening for link cable");
+        emu.set_serial_link(Box::new(link));
+    }
+
     if let Some(ref rom) = matches.value_of("ROM") {
         emu.load_rom(rom).expect("error loading rom");
     }
@@ -0,0 +1,351 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use gib_core::{io::JoypadState, Model};
+use serde::{Deserialize, Serialize};
+use winit::event::VirtualKeyCode;
+
+/// The hardware model to run ROMs on, as chosen by the user.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ModelSelection {
+    /// Pick the model based on the ROM header.
+    Auto,
+    /// Always run on a DMG.
+    Dmg,
+    /// Always run on a CGB.
+    Cgb,
+}
+
+impl ModelSelection {
+    pub const ALL: [ModelSelection; 3] = [
+        ModelSelection::Auto,
+        ModelSelection::Dmg,
+        ModelSelection::Cgb,
+    ];
+
+    /// Returns the model that should be used to run `rom`.
+    pub fn resolve(self, rom: &[u8]) -> Model {
+        match self {
+            ModelSelection::Auto => Model::detect(rom),
+            ModelSelection::Dmg => Model::Dmg,
+            ModelSelection::Cgb => Model::Cgb,
+        }
+    }
+
+    /// Returns the selection following this one, wrapping around.
+    pub fn next(self) -> ModelSelection {
+        match self {
+            ModelSelection::Auto => ModelSelection::Dmg,
+            ModelSelection::Dmg => ModelSelection::Cgb,
+            ModelSelection::Cgb => ModelSelection::Auto,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ModelSelection::Auto => "Auto-detect",
+            ModelSelection::Dmg => "DMG",
+            ModelSelection::Cgb => "CGB",
+        }
+    }
+}
+
+/// Keyboard bindings of the joypad buttons and of the emulator hotkeys.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub up: VirtualKeyCode,
+    pub down: VirtualKeyCode,
+    pub left: VirtualKeyCode,
+    pub right: VirtualKeyCode,
+    pub a: VirtualKeyCode,
+    pub b: VirtualKeyCode,
+    pub select: VirtualKeyCode,
+    pub start: VirtualKeyCode,
+
+    /// Autofire variant of the A button
+    pub autofire_a: VirtualKeyCode,
+    /// Autofire variant of the B button
+    pub autofire_b: VirtualKeyCode,
+
+    /// Runs the emulation as fast as possible while held
+    pub turbo: VirtualKeyCode,
+    /// Runs the emulation backwards while held
+    pub rewind: VirtualKeyCode,
+    /// Cycles through the emulated hardware models
+    pub cycle_model: VirtualKeyCode,
+    /// Saves the emulation state to the quick-save slot
+    pub quick_save: VirtualKeyCode,
+    /// Restores the emulation state from the quick-save slot
+    pub quick_load: VirtualKeyCode,
+    /// Steps over the instruction at PC, in debug mode
+    pub step_over: VirtualKeyCode,
+    /// Runs until the current function returns, in debug mode
+    pub step_out: VirtualKeyCode,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings {
+            up: VirtualKeyCode::Up,
+            down: VirtualKeyCode::Down,
+            left: VirtualKeyCode::Left,
+            right: VirtualKeyCode::Right,
+            a: VirtualKeyCode::X,
+            b: VirtualKeyCode::Z,
+            select: VirtualKeyCode::Back,
+            start: VirtualKeyCode::Return,
+
+            autofire_a: VirtualKeyCode::S,
+            autofire_b: VirtualKeyCode::A,
+
+            turbo: VirtualKeyCode::Space,
+            rewind: VirtualKeyCode::R,
+            cycle_model: VirtualKeyCode::F2,
+            quick_save: VirtualKeyCode::F5,
+            quick_load: VirtualKeyCode::F9,
+            step_over: VirtualKeyCode::F10,
+            step_out: VirtualKeyCode::F11,
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Returns the keys bound to each joypad button.
+    pub fn joypad(&self) -> [(VirtualKeyCode, JoypadState); 8] {
+        [
+            (self.up, JoypadState::UP),
+            (self.down, JoypadState::DOWN),
+            (self.left, JoypadState::LEFT),
+            (self.right, JoypadState::RIGHT),
+            (self.a, JoypadState::A),
+            (self.b, JoypadState::B),
+            (self.select, JoypadState::SELECT),
+            (self.start, JoypadState::START),
+        ]
+    }
+
+    /// Returns the keys bound to the autofire variants of the A and B buttons.
+    pub fn autofire(&self) -> [(VirtualKeyCode, JoypadState); 2] {
+        [
+            (self.autofire_a, JoypadState::A),
+            (self.autofire_b, JoypadState::B),
+        ]
+    }
+
+    /// Binds `key` to the joypad button `button`.
+    ///
+    /// If `key` was bound to another button, that button takes the key previously bound
+    /// to `button`, so that every button stays reachable. Returns `false` if `button`
+    /// is not a single joypad button.
+    // Not exposed in the UI yet, it's meant for a future settings window
+    #[allow(dead_code)]
+    pub fn rebind(&mut self, button: JoypadState, key: VirtualKeyCode) -> bool {
+        let old = match self.button_key_mut(button) {
+            Some(slot) => std::mem::replace(slot, key),
+            None => return false,
+        };
+
+        let other = self
+            .joypad()
+            .iter()
+            .find(|(k, b)| *k == key && *b != button)
+            .map(|(_, b)| *b);
+
+        if let Some(slot) = other.and_then(|b| self.button_key_mut(b)) {
+            *slot = old;
+        }
+        true
+    }
+
+    fn button_key_mut(&mut self, button: JoypadState) -> Option<&mut VirtualKeyCode> {
+        match button {
+            JoypadState::UP => Some(&mut self.up),
+            JoypadState::DOWN => Some(&mut self.down),
+            JoypadState::LEFT => Some(&mut self.left),
+            JoypadState::RIGHT => Some(&mut self.right),
+            JoypadState::A => Some(&mut self.a),
+            JoypadState::B => Some(&mut self.b),
+            JoypadState::SELECT => Some(&mut self.select),
+            JoypadState::START => Some(&mut self.start),
+            _ => None,
+        }
+    }
+}
+
+/// Maximum number of entries in the recently opened ROMs list.
+const MAX_RECENT_ROMS: usize = 8;
+
+/// Maximum length of the rewind buffer, in seconds.
+const MAX_REWIND_SECONDS: u32 = 60;
+
+/// User settings persisted across sessions.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub model: ModelSelection,
+    pub autofire_rate: f32,
+    pub recent_roms: Vec<PathBuf>,
+    /// How far back the emulation can be rewound, in seconds (0 disables rewinding)
+    pub rewind_seconds: u32,
+    pub keys: KeyBindings,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            model: ModelSelection::Auto,
+            autofire_rate: super::DEFAULT_AUTOFIRE_RATE,
+            recent_roms: Vec::new(),
+            rewind_seconds: 10,
+            keys: KeyBindings::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from disk, falling back to the defaults
+    /// if it does not exist or can't be parsed.
+    pub fn load() -> Config {
+        Config::path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|s| Config::parse(&s))
+            .unwrap_or_default()
+    }
+
+    /// Parses a configuration, using the default value for any setting
+    /// which is missing or invalid, so that a single bad entry doesn't reset everything.
+    fn parse(s: &str) -> Config {
+        let table = match s.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            _ => return Config::default(),
+        };
+
+        let mut config = toml::Value::try_from(Config::default()).unwrap();
+
+        for (key, value) in table {
+            let mut candidate = config.clone();
+            candidate.as_table_mut().unwrap().insert(key, value);
+
+            if candidate.clone().try_into::<Config>().is_ok() {
+                config = candidate;
+            }
+        }
+
+        config.try_into::<Config>().unwrap().sanitized()
+    }
+
+    /// Brings out-of-range settings back to valid values.
+    fn sanitized(mut self) -> Config {
+        if !(1.0..=30.0).contains(&self.autofire_rate) {
+            self.autofire_rate = Config::default().autofire_rate;
+        }
+        self.recent_roms.truncate(MAX_RECENT_ROMS);
+        self.rewind_seconds = self.rewind_seconds.min(MAX_REWIND_SECONDS);
+        self
+    }
+
+    /// Moves `rom` to the top of the recently opened ROMs list.
+    pub fn add_recent_rom(&mut self, rom: &Path) {
+        self.recent_roms.retain(|r| r != rom);
+        self.recent_roms.insert(0, rom.to_path_buf());
+        self.recent_roms.truncate(MAX_RECENT_ROMS);
+    }
+
+    /// Writes the configuration to disk.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = match Config::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        std::fs::write(&path, toml::to_string(self)?)
+            .with_context(|| format!("could not write {}", path.display()))
+    }
+
+    /// Returns the location of the configuration file, if the platform has a config directory.
+    fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("gib").join("config.toml"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_settings_fall_back_to_defaults() {
+        let config = Config::parse(
+            r#"
+            model = "Cgb"
+            autofire_rate = "fast"
+            recent_roms = ["a.gb", "b.gb"]
+            unknown = 42
+            "#,
+        );
+
+        assert_eq!(config.model, ModelSelection::Cgb);
+        assert_eq!(config.autofire_rate, Config::default().autofire_rate);
+        assert_eq!(config.recent_roms.len(), 2);
+
+        let config = Config::parse("autofire_rate = 500.0\nmodel = 3");
+        assert_eq!(config.model, ModelSelection::Auto);
+        assert_eq!(config.autofire_rate, Config::default().autofire_rate);
+
+        assert_eq!(Config::parse("not toml").model, ModelSelection::Auto);
+    }
+
+    #[test]
+    fn key_bindings_are_configurable() {
+        let config = Config::parse(
+            r#"
+            [keys]
+            a = "K"
+            quick_save = "F1"
+            "#,
+        );
+
+        assert_eq!(config.keys.a, VirtualKeyCode::K);
+        assert_eq!(config.keys.quick_save, VirtualKeyCode::F1);
+        assert_eq!(config.keys.b, KeyBindings::default().b);
+
+        let config = Config::parse("[keys]\na = \"NotAKey\"");
+        assert_eq!(config.keys, KeyBindings::default());
+
+        let config = Config::parse(&toml::to_string(&config).unwrap());
+        assert_eq!(config.keys, KeyBindings::default());
+    }
+
+    #[test]
+    fn rebinding_a_used_key_swaps_buttons() {
+        let mut keys = KeyBindings::default();
+
+        assert!(keys.rebind(JoypadState::A, VirtualKeyCode::K));
+        assert_eq!(keys.a, VirtualKeyCode::K);
+
+        // B was bound to Z, which A now takes over
+        assert!(keys.rebind(JoypadState::A, VirtualKeyCode::Z));
+        assert_eq!(keys.a, VirtualKeyCode::Z);
+        assert_eq!(keys.b, VirtualKeyCode::K);
+
+        assert!(!keys.rebind(JoypadState::A | JoypadState::B, VirtualKeyCode::Q));
+    }
+
+    #[test]
+    fn recent_roms_are_deduplicated() {
+        let mut config = Config::default();
+
+        for i in 0..10 {
+            config.add_recent_rom(Path::new(&format!("{}.gb", i)));
+        }
+        config.add_recent_rom(Path::new("5.gb"));
+
+        assert_eq!(config.recent_roms.len(), MAX_RECENT_ROMS);
+        assert_eq!(config.recent_roms[0], Path::new("5.gb"));
+        assert_eq!(config.recent_roms[1], Path::new("9.gb"));
+    }
+}
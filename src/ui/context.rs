@@ -38,8 +38,10 @@ pub struct UiContext {
     queue: wgpu::Queue,
 
     key_state: HashSet<VirtualKeyCode>,
+    key_presses: HashSet<VirtualKeyCode>,
     should_quit: bool,
     focused: bool,
+    title: String,
 }
 
 impl UiContext {
@@ -122,8 +124,10 @@ impl UiContext {
             event_loop: Rc::new(RefCell::from(event_loop)),
 
             key_state: HashSet::new(),
+            key_presses: HashSet::new(),
             should_quit: false,
             focused: true,
+            title: String::from("gib"),
         }
     }
 
@@ -132,6 +136,8 @@ impl UiContext {
 
         let event_loop = self.event_loop.clone();
 
+        self.key_presses.clear();
+
         event_loop
             .borrow_mut()
             .run_return(|event, _, control_flow| {
@@ -163,7 +169,10 @@ impl UiContext {
 
                             if let Some(vk) = input.virtual_keycode {
                                 if pressed {
-                                    self.key_state.insert(vk);
+                                    // Ignore auto-repeat events for one-shot presses
+                                    if self.key_state.insert(vk) {
+                                        self.key_presses.insert(vk);
+                                    }
                                 } else {
                                     self.key_state.remove(&vk);
                                 }
@@ -186,16 +195,36 @@ impl UiContext {
         self.should_quit
     }
 
+    /// Sets the title of the main window.
+    pub fn set_title(&mut self, title: &str) {
+        if self.title != title {
+            self.window.set_title(title);
+            self.title = title.to_string();
+        }
+    }
+
     /// Creates a new texture displaying the currently emulated screen,
     /// ready to be presented during the next rendering step.
     pub fn prepare_screen_texture(
         &mut self,
         texture_id: &mut Option<TextureId>,
         vpu_buffer: &[u8],
+    ) {
+        self.prepare_texture(texture_id, vpu_buffer, EMU_X_RES, EMU_Y_RES);
+    }
+
+    /// Creates a new `width`x`height` texture from an RGBA buffer,
+    /// ready to be presented during the next rendering step.
+    pub fn prepare_texture(
+        &mut self,
+        texture_id: &mut Option<TextureId>,
+        vpu_buffer: &[u8],
+        width: usize,
+        height: usize,
     ) {
         let size = wgpu::Extent3d {
-            width: EMU_X_RES as u32,
-            height: EMU_Y_RES as u32,
+            width: width as u32,
+            height: height as u32,
             ..Default::default()
         };
 
@@ -275,7 +304,7 @@ impl UiContext {
 
         // Write data into the texture
         let texture = Texture::from_raw_parts(texture, view, bind_group, size);
-        texture.write(&self.queue, vpu_buffer, EMU_X_RES as u32, EMU_Y_RES as u32);
+        texture.write(&self.queue, vpu_buffer, width as u32, height as u32);
 
         // If this is the first time rendering, insert the new texture, otherwise replace an existing one
         if let Some(ref mut vpu_texture) = texture_id {
@@ -353,6 +382,11 @@ impl UiContext {
         self.key_state.contains(&key)
     }
 
+    /// Returns whether the given virtual key went down during the last call to `poll_events`.
+    pub fn was_key_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.key_presses.contains(&key)
+    }
+
     fn load_fonts(imgui: &mut Context, hidpi_factor: f64) {
         let font_size = (13.0 * hidpi_factor) as f32;
 
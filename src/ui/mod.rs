@@ -1,48 +1,97 @@
-use std::{cell::RefCell, collections::HashMap, path::Path, rc::Rc, time::Instant};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Error;
+use config::{Config, ModelSelection};
 use context::UiContext;
 use crossbeam::queue::ArrayQueue;
-use gib_core::{self, io::JoypadState};
-use imgui::{im_str, Condition, Image, MenuItem, StyleVar, TextureId, Ui, Window, WindowFlags};
+use gib_core::{
+    self,
+    dbg::ExecRegions,
+    io::{SerialLink, SpriteOrder, TILE_DATA_HEIGHT, TILE_DATA_WIDTH},
+};
+use imgui::{
+    im_str, Condition, ImString, Image, MenuItem, Slider, StyleVar, TextureId, Ui, Window,
+    WindowFlags,
+};
 use sound::SoundEngine;
 use state::EmuState;
 use views::{
-    DebuggerView, DisassemblyView, MemEditView, MemMapView, PeripheralView, View, WindowView,
+    DebuggerView, DisassemblyView, MemEditView, MemMapView, PeripheralView, SpriteView, View,
+    WindowView,
 };
-use winit::event::VirtualKeyCode;
 
+mod config;
 mod context;
 mod sound;
 mod state;
 mod utils;
 mod views;
 
-const EMU_X_RES: usize = 160;
-const EMU_Y_RES: usize = 144;
+const EMU_X_RES: usize = gib_core::SCREEN_WIDTH;
+const EMU_Y_RES: usize = gib_core::SCREEN_HEIGHT;
 
 /// Emulator window width (in gaming mode)
 const EMU_WIN_X_RES: f64 = (EMU_X_RES * 2) as f64;
 /// Emulator window height (in gaming mode)
 const EMU_WIN_Y_RES: f64 = (EMU_Y_RES * 2) as f64 + 19.5;
 
-/// Mapping between VirtualKey and joypad button
-const KEYMAP: [(VirtualKeyCode, JoypadState); 8] = [
-    (VirtualKeyCode::Up, JoypadState::UP),
-    (VirtualKeyCode::Down, JoypadState::DOWN),
-    (VirtualKeyCode::Left, JoypadState::LEFT),
-    (VirtualKeyCode::Right, JoypadState::RIGHT),
-    (VirtualKeyCode::Z, JoypadState::B),
-    (VirtualKeyCode::X, JoypadState::A),
-    (VirtualKeyCode::Back, JoypadState::SELECT),
-    (VirtualKeyCode::Return, JoypadState::START),
-];
+/// Default autofire rate, in presses per second
+const DEFAULT_AUTOFIRE_RATE: f32 = 15.0;
+
+/// How long short notices (eg. "State saved") stay on screen
+const NOTICE_DURATION: Duration = Duration::from_secs(2);
+
+/// Breakdown of the time spent on the last rendered frame.
+#[derive(Default, Clone, Copy)]
+struct FrameMetrics {
+    /// Time spent running the emulator (CPU, PPU and APU) since the previous frame.
+    emulation: Duration,
+    /// Time spent uploading the screen texture and drawing the previous frame.
+    presentation: Duration,
+    /// Total time elapsed since the previous frame.
+    frame: Duration,
+}
+
+impl FrameMetrics {
+    fn fps(&self) -> f32 {
+        if self.frame.as_secs_f32() > 0.0 {
+            1.0 / self.frame.as_secs_f32()
+        } else {
+            0.0
+        }
+    }
+}
 
 pub struct GuiState {
     debug: bool,
     should_quit: bool,
     file_dialog: Option<utils::FileDialog>,
     views: HashMap<View, Box<dyn WindowView>>,
+    error: Option<String>,
+    notice: Option<(String, Instant)>,
+
+    // Performance overlay
+    show_metrics: bool,
+    metrics: FrameMetrics,
+
+    // Tile data viewer
+    show_tiles: bool,
+
+    // Background map viewer
+    show_bg_map: bool,
+    bg_map_follow_lcdc: bool,
+    bg_map_high: bool,
+    bg_map_unsigned: bool,
+
+    // Autofire settings
+    autofire_rate: f32,
+    autofire_phase: f32,
 }
 
 impl Default for GuiState {
@@ -52,34 +101,76 @@ impl Default for GuiState {
             should_quit: false,
             file_dialog: None,
             views: HashMap::new(),
+            error: None,
+            notice: None,
+
+            show_metrics: false,
+            metrics: FrameMetrics::default(),
+
+            show_tiles: false,
+
+            show_bg_map: false,
+            bg_map_follow_lcdc: true,
+            bg_map_high: false,
+            bg_map_unsigned: true,
+
+            autofire_rate: DEFAULT_AUTOFIRE_RATE,
+            autofire_phase: 0.0,
         }
     }
 }
 
+impl GuiState {
+    /// Advances the autofire clock by `delta_s` seconds, returning whether
+    /// autofire buttons should currently be held down.
+    fn autofire_tick(&mut self, delta_s: f32) -> bool {
+        let period = 1.0 / self.autofire_rate;
+
+        self.autofire_phase = (self.autofire_phase + delta_s) % period;
+        self.autofire_phase < period / 2.0
+    }
+
+    /// Shows `msg` on screen for a short while.
+    fn notify<S: Into<String>>(&mut self, msg: S) {
+        self.notice = Some((msg.into(), Instant::now()));
+    }
+}
+
 use std::sync::Arc;
 
 pub struct EmuUi {
     ctx: Rc<RefCell<UiContext>>,
     snd: SoundEngine,
     gui: GuiState,
+    config: Config,
 
     emu: Option<EmuState>,
     vpu_buffer: Vec<u8>,
     vpu_texture: Option<TextureId>,
+    tiles_buffer: Vec<u8>,
+    tiles_texture: Option<TextureId>,
+    bg_map_buffer: Vec<u8>,
+    bg_map_texture: Option<TextureId>,
 
     snd_sink: Arc<ArrayQueue<i16>>,
+
+    // Link cable to plug into the next emulator started
+    link: Option<Box<dyn SerialLink>>,
 }
 
 impl EmuUi {
     pub fn new(debug: bool) -> Result<EmuUi, Error> {
+        let config = Config::load();
+
         let gui = GuiState {
             debug,
+            autofire_rate: config.autofire_rate,
             ..Default::default()
         };
 
-        // Create a sample channel that can hold up to 1024 samples.
+        // Create a sample channel that can hold up to 1024 stereo samples.
         // At 44.1KHz, this is about 23ms worth of audio.
-        let sink = Arc::new(ArrayQueue::new(1024));
+        let sink = Arc::new(ArrayQueue::new(2 * 1024));
 
         // Start audio thread.
         // NOTE(windows): this needs to happen before the GUI is created, or the process
@@ -98,24 +189,62 @@ impl EmuUi {
             ctx: Rc::from(RefCell::from(ctx)),
             snd,
             gui,
+            config,
 
             emu: None,
             vpu_buffer: vec![0xFFu8; EMU_X_RES * EMU_Y_RES * 4],
             vpu_texture: None,
+            tiles_buffer: vec![0xFFu8; TILE_DATA_WIDTH * TILE_DATA_HEIGHT * 4],
+            tiles_texture: None,
+            bg_map_buffer: vec![0xFFu8; 256 * 256 * 4],
+            bg_map_texture: None,
 
             snd_sink: sink,
+
+            link: None,
         })
     }
 
+    /// Plugs a link cable connected to `link` in, which stays plugged in across ROMs.
+    pub fn set_serial_link(&mut self, link: Box<dyn SerialLink>) {
+        match self.emu {
+            Some(ref mut emu) => emu.gameboy_mut().set_serial_link(link),
+            None => self.link = Some(link),
+        }
+    }
+
     /// Loads the ROM file and starts the emulation.
     pub fn load_rom<P: AsRef<Path>>(&mut self, rom: P) -> Result<(), Error> {
+        let rom = rom.as_ref();
+
+        // Don't lose progress in the currently running ROM, if any
+        if let Some(ref emu) = self.emu {
+            emu.save_ram()?;
+        }
+
         self.emu = {
-            let mut emu = EmuState::new(rom)?;
+            let mut emu = EmuState::new(rom, self.config.model)?;
             emu.set_audio_sink(self.snd_sink.clone(), self.snd.get_sample_rate());
+            emu.set_rewind_length(self.config.rewind_seconds);
+
+            let link = match self.emu {
+                Some(ref mut prev) => prev.gameboy_mut().take_serial_link(),
+                None => self.link.take(),
+            };
+            if let Some(link) = link {
+                emu.gameboy_mut().set_serial_link(link);
+            }
+
             emu.set_running();
             Some(emu)
         };
 
+        // The new ROM is already running, so failing to remember it is not worth an error
+        self.config.add_recent_rom(rom);
+        if let Err(e) = self.config.save() {
+            eprintln!("Warning: could not save the configuration: {}", e);
+        }
+
         if self.gui.debug {
             let views = &mut self.gui.views;
 
@@ -131,12 +260,77 @@ impl EmuUi {
         Ok(())
     }
 
+    /// Changes the emulated hardware model and remembers the choice for later sessions.
+    ///
+    /// If a ROM is running, the emulation is restarted on the new model.
+    fn set_model(&mut self, model: ModelSelection) -> Result<(), Error> {
+        if let Some(ref mut emu) = self.emu {
+            emu.switch_model(model)?;
+        }
+
+        self.config.model = model;
+        self.config.save()
+    }
+
+    /// Saves the emulation state to the quick-save slot, if a ROM is running.
+    fn quick_save(&mut self) {
+        if let Some(ref emu) = self.emu {
+            match emu.quick_save() {
+                Ok(()) => self.gui.notify("State saved"),
+                Err(e) => self.gui.error = Some(format!("{:#}", e)),
+            }
+        }
+    }
+
+    /// Restores the emulation state from the quick-save slot, if a ROM is running.
+    fn quick_load(&mut self) {
+        if let Some(ref mut emu) = self.emu {
+            match emu.quick_load() {
+                Ok(true) => self.gui.notify("State loaded"),
+                Ok(false) => self.gui.notify("No quick-save found"),
+                Err(e) => self.gui.error = Some(format!("{:#}", e)),
+            }
+        }
+    }
+
+    /// Returns the main window title, showing the running game and the emulated model.
+    ///
+    /// The game is named after the title in the cartridge header, or the ROM file if there's none.
+    fn window_title(&self) -> String {
+        let model = match self.config.model {
+            ModelSelection::Auto => match self.emu {
+                Some(ref emu) => format!("Auto: {}", emu.gameboy().model()),
+                None => ModelSelection::Auto.name().to_string(),
+            },
+            model => model.name().to_string(),
+        };
+
+        match self.emu {
+            Some(ref emu) => {
+                let game = match emu.gameboy().header() {
+                    Some(header) if !header.title.is_empty() => header.title.clone(),
+                    _ => {
+                        let rom = emu.rom_file().file_name().unwrap_or_default();
+                        rom.to_string_lossy().into_owned()
+                    }
+                };
+                format!("gib - {} [{}]", game, model)
+            }
+            None => format!("gib [{}]", model),
+        }
+    }
+
     /// Run the emulator UI.
     ///
     /// This function loops until the window is closed or an error occurs.
     pub fn run(&mut self) -> Result<(), Error> {
         let mut last_frame = Instant::now();
 
+        // Accumulators for the performance metrics, reported on each rendered frame
+        let mut last_render = Instant::now();
+        let mut emulation = Duration::default();
+        let mut presentation = Duration::default();
+
         loop {
             let ctx = self.ctx.clone();
             let mut ctx = ctx.borrow_mut();
@@ -150,37 +344,120 @@ impl EmuUi {
             let do_render = ctx.poll_events();
 
             if self.gui.should_quit || ctx.should_quit() {
-                return Ok(());
+                return self.shutdown();
             }
 
+            let keys = self.config.keys.clone();
+
+            if ctx.was_key_pressed(keys.cycle_model) {
+                if let Err(e) = self.set_model(self.config.model.next()) {
+                    self.gui.error = Some(format!("{:#}", e));
+                }
+            }
+
+            if ctx.was_key_pressed(keys.quick_save) {
+                self.quick_save();
+            }
+
+            if ctx.was_key_pressed(keys.quick_load) {
+                self.quick_load();
+            }
+
+            // Stepping hotkeys would leave the game stuck without the debugger to resume it
+            if self.gui.debug {
+                if let Some(ref mut emu) = self.emu {
+                    if ctx.was_key_pressed(keys.step_over) {
+                        emu.step_over();
+                    }
+                    if ctx.was_key_pressed(keys.step_out) {
+                        emu.step_out();
+                    }
+                }
+            }
+
+            ctx.set_title(&self.window_title());
+
             // Sync the emulator state to the GUI
+            let emulation_start = Instant::now();
+
             if let Some(ref mut emu) = self.emu {
+                // Autofire buttons are pressed during the first half of each period
+                let autofire_on = self.gui.autofire_tick(delta.as_secs_f32());
+
                 // Forward keypresses to the emulator
-                for (vk, js) in KEYMAP.iter() {
-                    if ctx.is_key_pressed(*vk) {
-                        emu.gameboy_mut().press_key(*js);
-                    } else {
-                        emu.gameboy_mut().release_key(*js);
-                    }
+                for (vk, js) in keys.joypad().iter() {
+                    let autofire = keys
+                        .autofire()
+                        .iter()
+                        .any(|(avk, ajs)| ajs == js && ctx.is_key_pressed(*avk));
+
+                    let pressed = ctx.is_key_pressed(*vk) || (autofire && autofire_on);
+                    emu.gameboy_mut().set_button(*js, pressed);
                 }
 
                 // Enable/disable turbo mode
-                emu.set_turbo(ctx.is_key_pressed(VirtualKeyCode::Space));
+                emu.set_turbo(ctx.is_key_pressed(keys.turbo));
+
+                // Scrub backwards while the rewind key is held
+                emu.set_rewinding(ctx.is_key_pressed(keys.rewind));
 
                 // Perform a single emulator step
                 emu.do_step();
             }
 
+            emulation += emulation_start.elapsed();
+
             // Render if requested
             if do_render {
                 // TODO this really needs to be done only if some changes
                 // have happened in the last interval.
                 if let Some(ref emu) = self.emu {
+                    let rasterize_start = Instant::now();
                     emu.gameboy().rasterize(&mut self.vpu_buffer[..]);
+                    emulation += rasterize_start.elapsed();
                 }
 
+                let render_start = Instant::now();
+
+                self.gui.metrics = FrameMetrics {
+                    emulation,
+                    presentation,
+                    frame: render_start - last_render,
+                };
+                emulation = Duration::default();
+                last_render = render_start;
+
                 ctx.prepare_screen_texture(&mut self.vpu_texture, &self.vpu_buffer);
 
+                if self.gui.show_tiles {
+                    if let Some(ref emu) = self.emu {
+                        emu.bus().ppu.rasterize_tiles(&mut self.tiles_buffer[..]);
+                    }
+                    ctx.prepare_texture(
+                        &mut self.tiles_texture,
+                        &self.tiles_buffer,
+                        TILE_DATA_WIDTH,
+                        TILE_DATA_HEIGHT,
+                    );
+                }
+
+                if self.gui.show_bg_map {
+                    if let Some(ref emu) = self.emu {
+                        let ppu = &emu.bus().ppu;
+
+                        if self.gui.bg_map_follow_lcdc {
+                            self.gui.bg_map_high = ppu.bg_map_high();
+                            self.gui.bg_map_unsigned = ppu.unsigned_tile_data();
+                        }
+                        ppu.rasterize_tile_map(
+                            &mut self.bg_map_buffer[..],
+                            self.gui.bg_map_high,
+                            self.gui.bg_map_unsigned,
+                        );
+                    }
+                    ctx.prepare_texture(&mut self.bg_map_texture, &self.bg_map_buffer, 256, 256);
+                }
+
                 ctx.render(delta, |ui| {
                     if self.gui.debug {
                         self.draw_debug_ui(delta.as_secs_f32(), ui)
@@ -188,10 +465,27 @@ impl EmuUi {
                         self.draw_game_ui(delta.as_secs_f32(), ui)
                     }
                 });
+
+                presentation = render_start.elapsed();
             }
         }
     }
 
+    /// Stops the current emulation, if any, flushing battery-backed RAM to disk.
+    ///
+    /// If saving fails, the emulation is left in place and the error is returned,
+    /// so that no data is silently lost.
+    fn shutdown(&mut self) -> Result<(), Error> {
+        if let Some(ref emu) = self.emu {
+            emu.save_ram()?;
+        }
+
+        self.emu = None;
+
+        self.config.autofire_rate = self.gui.autofire_rate;
+        self.config.save()
+    }
+
     /// Draws the gaming-mode interface, with just a simple menu bar
     /// and a fullscreen emulator screen view.
     fn draw_game_ui(&mut self, delta_s: f32, ui: &Ui) {
@@ -218,7 +512,9 @@ impl EmuUi {
                     | WindowFlags::NO_RESIZE
                     | WindowFlags::NO_MOVE
                     | WindowFlags::NO_SCROLLBAR
-                    | WindowFlags::NO_SCROLL_WITH_MOUSE,
+                    | WindowFlags::NO_SCROLL_WITH_MOUSE
+                    // Keep the performance overlay on top
+                    | WindowFlags::NO_BRING_TO_FRONT_ON_FOCUS,
             )
             .build(ui, || {
                 // Display event, if any
@@ -234,6 +530,9 @@ impl EmuUi {
             });
 
         style_tok.pop(ui);
+
+        self.draw_metrics_overlay(ui);
+        self.draw_notice_overlay(ui);
     }
 
     /// Draws the debug-mode interface
@@ -242,17 +541,81 @@ impl EmuUi {
 
         if self.emu.is_some() {
             self.draw_screen_window(ui);
+            self.draw_tiles_window(ui);
+            self.draw_bg_map_window(ui);
         }
 
         if let Some(ref mut emu) = self.emu {
             self.gui.views.retain(|_, view| view.draw(ui, emu));
         }
+
+        self.draw_metrics_overlay(ui);
+        self.draw_notice_overlay(ui);
+    }
+
+    /// Shows the current notice (if any) in the bottom-left corner, until it expires.
+    fn draw_notice_overlay(&mut self, ui: &Ui) {
+        let msg = match self.gui.notice {
+            Some((ref msg, since)) if since.elapsed() < NOTICE_DURATION => msg,
+            Some(_) => {
+                self.gui.notice = None;
+                return;
+            }
+            None => return,
+        };
+
+        let [_, h] = ui.io().display_size;
+
+        Window::new(im_str!("Notice"))
+            .position([4.0, h - 4.0], Condition::Always)
+            .position_pivot([0.0, 1.0])
+            .bg_alpha(0.5)
+            .flags(
+                WindowFlags::NO_DECORATION
+                    | WindowFlags::ALWAYS_AUTO_RESIZE
+                    | WindowFlags::NO_SAVED_SETTINGS
+                    | WindowFlags::NO_INPUTS,
+            )
+            .build(ui, || {
+                ui.text(msg);
+            });
+    }
+
+    /// Draws the frame rate and frame time breakdown in the top-left corner, if enabled.
+    fn draw_metrics_overlay(&self, ui: &Ui) {
+        if !self.gui.show_metrics {
+            return;
+        }
+
+        let metrics = &self.gui.metrics;
+
+        Window::new(im_str!("Performance"))
+            .position([4.0, 24.0], Condition::Always)
+            .bg_alpha(0.5)
+            .flags(
+                WindowFlags::NO_DECORATION
+                    | WindowFlags::ALWAYS_AUTO_RESIZE
+                    | WindowFlags::NO_SAVED_SETTINGS
+                    | WindowFlags::NO_INPUTS,
+            )
+            .build(ui, || {
+                ui.text(format!("FPS:          {:.1}", metrics.fps()));
+                ui.text(format!(
+                    "Emulation:    {:.2} ms",
+                    metrics.emulation.as_secs_f32() * 1000.0
+                ));
+                ui.text(format!(
+                    "Presentation: {:.2} ms",
+                    metrics.presentation.as_secs_f32() * 1000.0
+                ));
+            });
     }
 
     fn draw_menu_bar(&mut self, delta_s: f32, ui: &Ui) {
         let emu_running = self.emu.is_some();
 
         self.draw_file_dialog(delta_s, ui);
+        self.draw_error_popup(ui);
 
         ui.main_menu_bar(|| {
             ui.menu(im_str!("Emulator"), true, || {
@@ -260,18 +623,82 @@ impl EmuUi {
                     self.gui.file_dialog = Some(utils::FileDialog::new("Load ROM..."));
                 }
 
+                ui.menu(
+                    im_str!("Recent ROMs"),
+                    !self.config.recent_roms.is_empty(),
+                    || {
+                        for rom in self.config.recent_roms.clone() {
+                            let label = ImString::new(rom.to_string_lossy());
+
+                            if MenuItem::new(&label).build(ui) {
+                                if let Err(e) = self.load_rom(&rom) {
+                                    self.gui.error = Some(format!("{:#}", e));
+                                }
+                            }
+                        }
+                    },
+                );
+
                 ui.separator();
 
                 if MenuItem::new(im_str!("Save screen")).build(ui) {
                     std::fs::write("screen-dump.bin", &self.vpu_buffer[..]).unwrap();
                 }
 
+                ui.menu(im_str!("Model"), true, || {
+                    for model in ModelSelection::ALL.iter() {
+                        let label = ImString::new(model.name());
+
+                        if MenuItem::new(&label)
+                            .selected(self.config.model == *model)
+                            .build(ui)
+                        {
+                            if let Err(e) = self.set_model(*model) {
+                                self.gui.error = Some(format!("{:#}", e));
+                            }
+                        }
+                    }
+                });
+
+                Slider::new(im_str!("Autofire rate (Hz)"))
+                    .range(1.0..=30.0)
+                    .display_format(im_str!("%.0f"))
+                    .build(ui, &mut self.gui.autofire_rate);
+
+                MenuItem::new(im_str!("Show performance overlay"))
+                    .build_with_ref(ui, &mut self.gui.show_metrics);
+
+                ui.separator();
+
+                let quick_save_key = ImString::new(format!("{:?}", self.config.keys.quick_save));
+                let quick_load_key = ImString::new(format!("{:?}", self.config.keys.quick_load));
+
+                if MenuItem::new(im_str!("Quick save"))
+                    .shortcut(&quick_save_key)
+                    .enabled(emu_running)
+                    .build(ui)
+                {
+                    self.quick_save();
+                }
+
+                if MenuItem::new(im_str!("Quick load"))
+                    .shortcut(&quick_load_key)
+                    .enabled(emu_running)
+                    .build(ui)
+                {
+                    self.quick_load();
+                }
+
+                ui.separator();
+
                 if MenuItem::new(im_str!("Reset"))
                     .enabled(emu_running)
                     .build(ui)
                 {
                     if let Some(ref mut emu) = self.emu {
-                        emu.reset().expect("error during reset");
+                        if let Err(e) = emu.reset() {
+                            self.gui.error = Some(format!("{:#}", e));
+                        }
                     }
                 }
 
@@ -300,6 +727,24 @@ impl EmuUi {
                             .entry(View::Peripherals)
                             .or_insert_with(|| Box::new(PeripheralView::new()));
                     }
+
+                    if MenuItem::new(im_str!("Sprites"))
+                        .enabled(emu_running)
+                        .build(ui)
+                    {
+                        self.gui
+                            .views
+                            .entry(View::Sprites)
+                            .or_insert_with(|| Box::new(SpriteView::new()));
+                    }
+
+                    MenuItem::new(im_str!("Tile Data"))
+                        .enabled(emu_running)
+                        .build_with_ref(ui, &mut self.gui.show_tiles);
+
+                    MenuItem::new(im_str!("BG Map"))
+                        .enabled(emu_running)
+                        .build_with_ref(ui, &mut self.gui.show_bg_map);
                 });
 
                 ui.menu(im_str!("Debugging"), true, || {
@@ -332,11 +777,73 @@ impl EmuUi {
                             .entry(View::MemEditor)
                             .or_insert_with(|| Box::new(MemEditView::new()));
                     }
+
+                    ui.separator();
+
+                    if let Some(ref mut emu) = self.emu {
+                        let gb = emu.gameboy_mut();
+                        let mut report = gb.report_apu_writes_while_off();
+
+                        if MenuItem::new(im_str!("Break on APU writes while off"))
+                            .build_with_ref(ui, &mut report)
+                        {
+                            gb.set_report_apu_writes_while_off(report);
+                        }
+
+                        let mut high_pass = gb.audio_high_pass_filter();
+
+                        if MenuItem::new(im_str!("Audio high-pass filter"))
+                            .build_with_ref(ui, &mut high_pass)
+                        {
+                            gb.set_audio_high_pass_filter(high_pass);
+                        }
+
+                        let mut oam_order = gb.sprite_order() == SpriteOrder::Oam;
+
+                        if MenuItem::new(im_str!("Draw sprites in OAM order"))
+                            .build_with_ref(ui, &mut oam_order)
+                        {
+                            gb.set_sprite_order(if oam_order {
+                                SpriteOrder::Oam
+                            } else {
+                                SpriteOrder::Hardware
+                            });
+                        }
+
+                        let cpu = gb.cpu_mut();
+                        let mut check_exec = cpu.exec_regions().is_some();
+
+                        if MenuItem::new(im_str!("Break on execution outside ROM/RAM"))
+                            .build_with_ref(ui, &mut check_exec)
+                        {
+                            cpu.set_exec_regions(if check_exec {
+                                Some(ExecRegions::DEFAULT)
+                            } else {
+                                None
+                            });
+                        }
+                    }
                 })
             }
         });
     }
 
+    fn draw_error_popup(&mut self, ui: &Ui) {
+        if self.gui.error.is_some() {
+            ui.open_popup(im_str!("Error"));
+        }
+
+        ui.popup_modal(im_str!("Error")).build(|| {
+            if let Some(ref err) = self.gui.error {
+                ui.text(err);
+            }
+            if ui.button(im_str!("OK"), [0.0, 0.0]) {
+                self.gui.error = None;
+                ui.close_current_popup();
+            }
+        });
+    }
+
     fn draw_file_dialog(&mut self, delta_s: f32, ui: &Ui) {
         let mut fd_closed = false;
         let mut fd_chosen = None;
@@ -372,7 +879,136 @@ impl EmuUi {
             .build(ui, || {
                 if let Some(texture) = self.vpu_texture {
                     Image::new(texture, [EMU_X_RES as f32, EMU_Y_RES as f32]).build(ui);
+
+                    if ui.is_item_hovered() {
+                        self.draw_pixel_source_tooltip(ui);
+                    }
                 }
             });
     }
+
+    /// Draws the tile data table, scaled up twice, with the tile under the cursor in a tooltip.
+    fn draw_tiles_window(&mut self, ui: &Ui) {
+        if !self.gui.show_tiles {
+            return;
+        }
+
+        let (w, h) = (TILE_DATA_WIDTH as f32 * 2.0, TILE_DATA_HEIGHT as f32 * 2.0);
+        let mut open = true;
+
+        Window::new(im_str!("Tile Data"))
+            .size([w + 15.0, h + 40.0], Condition::FirstUseEver)
+            .position([450.0, 30.0], Condition::FirstUseEver)
+            .resizable(false)
+            .opened(&mut open)
+            .build(ui, || {
+                if let Some(texture) = self.tiles_texture {
+                    Image::new(texture, [w, h]).build(ui);
+
+                    if ui.is_item_hovered() {
+                        let [mx, my] = ui.io().mouse_pos;
+                        let [ox, oy] = ui.item_rect_min();
+
+                        let col = ((mx - ox) / 16.0) as usize;
+                        let row = ((my - oy) / 16.0) as usize;
+                        let idx = (row * 16 + col.min(15)).min(383);
+
+                        ui.tooltip_text(format!("Tile {}\nAddr: 0x{:04X}", idx, 0x8000 + idx * 16));
+                    }
+                }
+            });
+
+        self.gui.show_tiles = open;
+    }
+
+    /// Draws the whole 256x256 background map, scaled up twice, with the area currently
+    /// visible on screen outlined.
+    ///
+    /// The map and tile data follow LCDC by default, but can also be picked by hand.
+    fn draw_bg_map_window(&mut self, ui: &Ui) {
+        if !self.gui.show_bg_map {
+            return;
+        }
+
+        let (scx, scy) = match self.emu {
+            Some(ref emu) => emu.bus().ppu.scroll(),
+            None => return,
+        };
+
+        let gui = &mut self.gui;
+        let texture = self.bg_map_texture;
+        let mut open = true;
+
+        Window::new(im_str!("BG Map"))
+            .size([527.0, 600.0], Condition::FirstUseEver)
+            .position([480.0, 60.0], Condition::FirstUseEver)
+            .resizable(false)
+            .opened(&mut open)
+            .build(ui, || {
+                ui.checkbox(im_str!("Follow LCDC"), &mut gui.bg_map_follow_lcdc);
+
+                if gui.bg_map_follow_lcdc {
+                    ui.text_disabled(format!(
+                        "Map: 0x{:04X}  Data: 0x{:04X}",
+                        if gui.bg_map_high { 0x9C00 } else { 0x9800 },
+                        if gui.bg_map_unsigned { 0x8000 } else { 0x8800 }
+                    ));
+                } else {
+                    ui.radio_button(im_str!("0x9800"), &mut gui.bg_map_high, false);
+                    ui.same_line(0.0);
+                    ui.radio_button(im_str!("0x9C00"), &mut gui.bg_map_high, true);
+                    ui.same_line_with_spacing(0.0, 20.0);
+                    ui.radio_button(im_str!("0x8000"), &mut gui.bg_map_unsigned, true);
+                    ui.same_line(0.0);
+                    ui.radio_button(im_str!("0x8800"), &mut gui.bg_map_unsigned, false);
+                }
+
+                if let Some(texture) = texture {
+                    Image::new(texture, [512.0, 512.0]).build(ui);
+
+                    let [ox, oy] = ui.item_rect_min();
+                    let (x, y) = (f32::from(scx) * 2.0, f32::from(scy) * 2.0);
+                    let (w, h) = (EMU_X_RES as f32 * 2.0, EMU_Y_RES as f32 * 2.0);
+                    let draw_list = ui.get_window_draw_list();
+
+                    // The viewport wraps around the edges of the map, so draw it
+                    // once for each quadrant it might spill into
+                    draw_list.with_clip_rect_intersect([ox, oy], [ox + 512.0, oy + 512.0], || {
+                        for &dx in &[0.0, -512.0] {
+                            for &dy in &[0.0, -512.0] {
+                                let p1 = [ox + x + dx, oy + y + dy];
+                                let p2 = [p1[0] + w, p1[1] + h];
+
+                                draw_list
+                                    .add_rect(p1, p2, [1.0, 0.0, 0.0, 1.0])
+                                    .thickness(2.0)
+                                    .build();
+                            }
+                        }
+                    });
+                }
+            });
+
+        self.gui.show_bg_map = open;
+    }
+
+    /// Shows where the background pixel under the mouse cursor comes from in VRAM.
+    fn draw_pixel_source_tooltip(&self, ui: &Ui) {
+        let emu = match self.emu {
+            Some(ref emu) => emu,
+            None => return,
+        };
+
+        let [mx, my] = ui.io().mouse_pos;
+        let [ox, oy] = ui.item_rect_min();
+
+        let (x, y) = ((mx - ox) as u8, (my - oy) as u8);
+
+        if let Some(src) = emu.bus().ppu.bg_pixel_source(x, y) {
+            ui.tooltip_text(format!(
+                "Pixel ({}, {})\nMap:  0x{:04X} = {:02X}\nTile: 0x{:04X} ({}, {})",
+                x, y, src.tile_map_addr, src.tile_index, src.tile_data_addr, src.px, src.py
+            ));
+        }
+    }
 }
@@ -37,16 +37,21 @@ impl SoundEngine {
     }
 
     /// Starts the sound engine. The audio playback happens in a seprate thread,
-    /// with audio samples being received from the provided sample queue.
+    /// with interleaved stereo samples (left, right) being received from the provided queue.
+    ///
+    /// On mono output devices, the two channels are averaged.
     ///
     /// An error is returned if a new audio stream cannot be created.
     pub fn start(&mut self, sample_queue: Arc<ArrayQueue<i16>>) -> Result<(), Error> {
-        // This closure will fetch the next sample from the queue, or replicate the last sample
-        // if no new sample is available.
-        let mut last_sample = 0f32;
+        // This closure will fetch the next stereo sample from the queue, or replicate the last
+        // one if no new sample is available. Samples are only popped in pairs, so that the
+        // channels never get swapped while the emulator is still pushing the right one.
+        let mut last_sample = (0f32, 0f32);
         let mut next_sample = move || {
-            if let Some(sample) = sample_queue.pop() {
-                last_sample = sample as f32 * 0.001;
+            if sample_queue.len() >= 2 {
+                let left = sample_queue.pop().unwrap_or_default();
+                let right = sample_queue.pop().unwrap_or_default();
+                last_sample = (left as f32 * 0.001, right as f32 * 0.001);
             }
             last_sample
         };
@@ -57,10 +62,21 @@ impl SoundEngine {
                 &self.config,
                 move |output: &mut [f32], _: &OutputCallbackInfo| {
                     // Push the new sample to the stream
-                    for sample in output.chunks_mut(channels) {
-                        let value = Sample::from::<f32>(&next_sample());
-                        for out in sample.iter_mut() {
-                            *out = value;
+                    for frame in output.chunks_mut(channels) {
+                        let (left, right) = next_sample();
+
+                        match frame {
+                            [mono] => *mono = Sample::from::<f32>(&((left + right) / 2.0)),
+                            [l, r, rest @ ..] => {
+                                *l = Sample::from::<f32>(&left);
+                                *r = Sample::from::<f32>(&right);
+
+                                // Extra channels (eg. surround) are left silent
+                                for out in rest.iter_mut() {
+                                    *out = 0.0;
+                                }
+                            }
+                            [] => (),
                         }
                     }
                 },
@@ -1,11 +1,26 @@
 use std::{
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use crossbeam::queue::ArrayQueue;
-use gib_core::{bus::Bus, cpu::CPU, dbg, GameBoy};
+use gib_core::{bus::Bus, cpu::CPU, dbg, io::RtcState, GameBoy, CPU_CLOCK};
+
+use super::config::ModelSelection;
+
+/// How long the audio queue can stay full before falling back to video sync.
+const AUDIO_STALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Maximum number of cycles emulated in a single audio-sync run.
+const AUDIO_SYNC_MAX_CYCLES: u64 = CPU_CLOCK;
+
+/// Memory budget of the rewind buffer, whatever its length.
+const REWIND_MAX_BYTES: usize = 256 << 20;
+
+/// Number of frames emulated per second, used to size the rewind buffer.
+const FRAMES_PER_SEC: u32 = 60;
 
 pub struct EmuState {
     gb: GameBoy,
@@ -14,45 +29,86 @@ pub struct EmuState {
     // Sound-related fields
     snd_sink: Option<Arc<ArrayQueue<i16>>>,
     snd_sample_rate: f32,
+    snd_stalled_since: Option<Instant>,
+    snd_fallback: bool,
 
     // Emulation-related fields
     turbo_mode: bool,
+    rewinding: bool,
+    rewind_frames: usize,
     step_to_next: bool,
+    step_to_next_line: bool,
     run_to_breakpoint: bool,
     trace_event: Option<dbg::TraceEvent>,
+
+    // Debug-related fields
+    symbols: dbg::SymbolTable,
+    pc_edited: bool,
 }
 
 impl EmuState {
-    pub fn new<P: AsRef<Path>>(rom: P) -> Result<EmuState, Error> {
-        let mut gb = GameBoy::new();
-        let rom_buf = std::fs::read(rom.as_ref())?;
-
-        gb.load_rom(&rom_buf[..])?;
+    pub fn new<P: AsRef<Path>>(rom: P, model: ModelSelection) -> Result<EmuState, Error> {
+        let gb = EmuState::power_on(rom.as_ref(), model)?;
 
-        Ok(EmuState {
+        let mut emu = EmuState {
             gb,
             rom_file: rom.as_ref().to_path_buf(),
 
             snd_sink: None,
             snd_sample_rate: 0f32,
+            snd_stalled_since: None,
+            snd_fallback: false,
 
             turbo_mode: false,
+            rewinding: false,
+            rewind_frames: 0,
             step_to_next: false,
+            step_to_next_line: false,
             run_to_breakpoint: false,
             trace_event: None,
-        })
+
+            symbols: dbg::SymbolTable::default(),
+            pc_edited: false,
+        };
+
+        emu.load_ram()?;
+
+        // Pick up the labels of the ROM, if it was built along with a symbol file.
+        // They are only a debugging aid, so the ROM runs without them if they can't be read.
+        let sym_file = emu.rom_file.with_extension("sym");
+        if sym_file.exists() {
+            if let Err(e) = emu.load_symbols(sym_file) {
+                eprintln!("Warning: {:#}, continuing without symbols", e);
+            }
+        }
+
+        Ok(emu)
+    }
+
+    /// Creates a Game Boy of the selected model with `rom` loaded.
+    fn power_on(rom: &Path, model: ModelSelection) -> Result<GameBoy, Error> {
+        let rom_buf = std::fs::read(rom)?;
+
+        let mut gb = GameBoy::with_model(model.resolve(&rom_buf[..]));
+        gb.load_rom(&rom_buf[..])?;
+
+        Ok(gb)
     }
 
     pub fn pause(&mut self) {
         self.turbo_mode = false;
         self.step_to_next = false;
+        self.step_to_next_line = false;
         self.run_to_breakpoint = false;
+        self.gb.cpu_mut().set_step_target(None);
         self.gb.cpu_mut().pause();
     }
 
     /// Performs a single emulation step, depending on the emulator's state:
     ///
     /// * if we are in step mode, execute a single instruction
+    /// * if we are in line step mode, run until the next scanline
+    /// * if we are rewinding, go back one frame
     /// * if we are in run mode, run to audio sync (ie. audio queue full)
     ///
     /// In both cases, if an event happens, pause the emulator.
@@ -67,6 +123,13 @@ impl EmuState {
             let r = self.gb.step();
             self.pause();
             r
+        } else if self.step_to_next_line {
+            let r = self.gb.step_scanline();
+            self.pause();
+            r
+        } else if self.rewinding {
+            self.gb.rewind();
+            Ok(())
         } else if self.turbo_mode {
             self.gb.run_for_vblank()
         } else if self.run_to_breakpoint {
@@ -75,23 +138,67 @@ impl EmuState {
             Ok(())
         };
 
-        if let Err(ref evt) = res {
-            self.trace_event = Some(*evt);
-            self.pause();
-        };
+        match res {
+            // Reaching the end of a step over/out is expected, there's nothing to report
+            Err(dbg::TraceEvent::StepComplete(_)) => self.pause(),
+            Err(evt) => {
+                self.trace_event = Some(evt);
+                self.pause();
+            }
+            Ok(()) => (),
+        }
     }
 
     /// Runs the emulator until the audio queue is full, to avoid dropping
     /// audio samples and cause skipping/popping.
+    ///
+    /// If the audio backend stops draining the queue, or the queue never fills up,
+    /// the emulator falls back to video sync (dropping samples) instead of stalling,
+    /// until the queue starts draining again.
     fn run_to_audio_sync(&mut self) -> Result<(), dbg::TraceEvent> {
-        if let Some(ref sink) = self.snd_sink {
-            while sink.len() < sink.capacity() {
+        let sink = match self.snd_sink {
+            Some(ref sink) => sink.clone(),
+            None => return Ok(()),
+        };
+
+        if !audio_queue_full(&sink) {
+            self.snd_stalled_since = None;
+
+            // Once in fallback mode, keep running one frame at a time until the queue fills up
+            if self.snd_fallback {
+                self.gb.run_for_vblank()?;
+                self.snd_fallback = !audio_queue_full(&sink);
+                return Ok(());
+            }
+
+            let until = self.gb.clock_cycles() + AUDIO_SYNC_MAX_CYCLES;
+
+            while !audio_queue_full(&sink) {
+                if self.gb.clock_cycles() >= until {
+                    self.fall_back_to_video_sync("audio queue is not filling up");
+                    break;
+                }
                 self.gb.step()?;
             }
+        } else {
+            let stalled_since = *self.snd_stalled_since.get_or_insert_with(Instant::now);
+
+            if stalled_since.elapsed() >= AUDIO_STALL_TIMEOUT {
+                self.fall_back_to_video_sync("audio queue is not being drained");
+                self.gb.run_for_vblank()?;
+            }
         }
         Ok(())
     }
 
+    /// Switches to video sync, logging a warning unless already done.
+    fn fall_back_to_video_sync(&mut self, reason: &str) {
+        if !self.snd_fallback {
+            eprintln!("Warning: {}, falling back to video sync", reason);
+            self.snd_fallback = true;
+        }
+    }
+
     /// Sets the emulator's audio sink and sample rate.
     pub fn set_audio_sink(&mut self, sink: Arc<ArrayQueue<i16>>, sample_rate: f32) {
         self.snd_sink = Some(sink.clone());
@@ -108,6 +215,32 @@ impl EmuState {
         self.step_to_next = true;
     }
 
+    pub fn set_line_step(&mut self) {
+        self.step_to_next_line = true;
+    }
+
+    /// Steps over the instruction at PC: a call runs until it returns (or a breakpoint
+    /// is hit), while anything else is single-stepped.
+    pub fn step_over(&mut self) {
+        match self.gb.cpu().step_over_target(self.gb.bus()) {
+            Ok(Some(target)) => {
+                self.gb.cpu_mut().set_step_target(Some(target));
+                self.set_running();
+            }
+            _ => self.set_single_step(),
+        }
+    }
+
+    /// Runs until the current function returns (or a breakpoint is hit).
+    ///
+    /// Outside of any tracked call, this does nothing.
+    pub fn step_out(&mut self) {
+        if let Some(target) = self.gb.cpu().step_out_target() {
+            self.gb.cpu_mut().set_step_target(Some(target));
+            self.set_running();
+        }
+    }
+
     pub fn set_running(&mut self) {
         self.run_to_breakpoint = true;
     }
@@ -120,25 +253,66 @@ impl EmuState {
         self.turbo_mode = enable;
     }
 
+    /// Sets or resets rewind mode, in which the emulation goes back one frame per step.
+    pub fn set_rewinding(&mut self, enable: bool) {
+        self.rewinding = enable;
+    }
+
+    /// Sets how far back the emulation can be rewound, in seconds (0 disables rewinding).
+    pub fn set_rewind_length(&mut self, seconds: u32) {
+        self.rewind_frames = (seconds * FRAMES_PER_SEC) as usize;
+        self.gb
+            .set_rewind_limits(self.rewind_frames, REWIND_MAX_BYTES);
+    }
+
     pub fn paused(&mut self) -> bool {
-        self.gb.cpu().paused() && !(self.step_to_next || self.run_to_breakpoint)
+        self.gb.cpu().paused()
+            && !(self.step_to_next || self.step_to_next_line || self.run_to_breakpoint)
     }
 
-    /// Reset the emulator's sate.
-    pub fn reset(&mut self) -> Result<(), Error> {
-        // Save breakpoints to restore after reset
-        let bkps = self.cpu().breakpoints().clone();
+    /// Restores the cartridge RAM and real-time clock from the `.sav` and `.rtc` files
+    /// next to the ROM, if any.
+    fn load_ram(&mut self) -> Result<(), Error> {
+        let sav_file = self.rom_file.with_extension("sav");
+        let rtc_file = self.rom_file.with_extension("rtc");
 
-        self.gb = GameBoy::new();
-        self.gb.load_rom(&(std::fs::read(&self.rom_file)?)[..])?;
+        if sav_file.exists() {
+            self.gb.load_ram(&std::fs::read(sav_file)?[..]);
+        }
+        if rtc_file.exists() {
+            let state = RtcState::from_bytes(&std::fs::read(&rtc_file)?[..])
+                .with_context(|| format!("invalid RTC file {}", rtc_file.display()))?;
+            self.gb.set_rtc_state(state);
+        }
+        Ok(())
+    }
+
+    /// Writes the battery-backed cartridge RAM (if any) to a `.sav` file next to the ROM,
+    /// and the state of the real-time clock (if any) to a `.rtc` file.
+    ///
+    /// Carts without a battery never produce a save file.
+    pub fn save_ram(&self) -> Result<(), Error> {
+        if let Some(ram) = self.gb.save_ram() {
+            let sav_file = self.rom_file.with_extension("sav");
 
-        if let Some(ref sink) = self.snd_sink {
-            self.gb.set_audio_sink(sink.clone(), self.snd_sample_rate);
+            std::fs::write(&sav_file, ram)
+                .with_context(|| format!("could not write {}", sav_file.display()))?;
         }
+        if let Some(state) = self.gb.rtc_state() {
+            let rtc_file = self.rom_file.with_extension("rtc");
 
-        for b in bkps.iter() {
-            self.cpu_mut().set_breakpoint(*b);
+            std::fs::write(&rtc_file, state.to_bytes())
+                .with_context(|| format!("could not write {}", rtc_file.display()))?;
         }
+        Ok(())
+    }
+
+    /// Reset the emulator's sate.
+    ///
+    /// Battery-backed RAM survives the reset, but it's also flushed to the save file.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.save_ram()?;
+        self.gb.reset();
 
         // Default to running state
         self.set_running();
@@ -146,6 +320,72 @@ impl EmuState {
         Ok(())
     }
 
+    /// Restarts the emulation on a different hardware model.
+    ///
+    /// Battery-backed RAM is carried over through the save file, and breakpoints are kept.
+    /// If the ROM can't run on the chosen model, the current emulation is left untouched.
+    pub fn switch_model(&mut self, model: ModelSelection) -> Result<(), Error> {
+        let gb = EmuState::power_on(&self.rom_file, model)?;
+
+        self.save_ram()?;
+
+        self.replace_gameboy(gb);
+        self.load_ram()?;
+
+        self.set_running();
+
+        Ok(())
+    }
+
+    /// Writes the whole machine state to the quick-save (`.qs`) file next to the ROM.
+    pub fn quick_save(&self) -> Result<(), Error> {
+        let qs_file = self.rom_file.with_extension("qs");
+
+        std::fs::write(&qs_file, self.gb.save_state())
+            .with_context(|| format!("could not write {}", qs_file.display()))
+    }
+
+    /// Restores the machine state from the quick-save file next to the ROM.
+    ///
+    /// Returns `false` if no quick-save exists. Breakpoints are kept across the load.
+    pub fn quick_load(&mut self) -> Result<bool, Error> {
+        let qs_file = self.rom_file.with_extension("qs");
+
+        if !qs_file.exists() {
+            return Ok(false);
+        }
+
+        self.gb
+            .load_state(&std::fs::read(&qs_file)?)
+            .with_context(|| format!("could not load {}", qs_file.display()))?;
+
+        self.trace_event = None;
+        self.set_running();
+
+        Ok(true)
+    }
+
+    /// Swaps in a new Game Boy, carrying over the audio sink and debugging settings,
+    /// which are not part of the machine state.
+    fn replace_gameboy(&mut self, mut gb: GameBoy) {
+        gb.take_debug_state(&mut self.gb);
+
+        self.gb = gb;
+        self.gb
+            .set_rewind_limits(self.rewind_frames, REWIND_MAX_BYTES);
+
+        if let Some(sink) = self.snd_sink.clone() {
+            self.gb.set_audio_sink(sink, self.snd_sample_rate);
+        }
+
+        self.trace_event = None;
+    }
+
+    /// Returns the path of the ROM being emulated.
+    pub fn rom_file(&self) -> &Path {
+        &self.rom_file
+    }
+
     pub fn gameboy(&self) -> &GameBoy {
         &self.gb
     }
@@ -165,4 +405,51 @@ impl EmuState {
     pub fn bus(&self) -> &Bus {
         self.gb.bus()
     }
+
+    /// Moves the program counter to `pc`, eg. to skip or repeat some code while debugging.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.gb.cpu_mut().pc = pc;
+        self.pc_edited = true;
+    }
+
+    /// Returns whether PC has been moved by `set_pc` since the last call.
+    pub fn take_pc_edited(&mut self) -> bool {
+        std::mem::take(&mut self.pc_edited)
+    }
+
+    /// Loads the labels of a `.sym` file, replacing any previously loaded ones.
+    pub fn load_symbols<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+
+        self.symbols = dbg::SymbolTable::load(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Returns the label at `addr`, in whichever bank is currently mapped there.
+    pub fn symbol(&self, addr: u16) -> Option<&str> {
+        self.symbols.lookup(self.symbol_bank(addr), addr)
+    }
+
+    /// Returns the closest label at or before `addr`, along with the offset from it.
+    pub fn nearest_symbol(&self, addr: u16) -> Option<(&str, u16)> {
+        self.symbols.nearest(self.symbol_bank(addr), addr)
+    }
+
+    /// Returns the bank mapped at `addr`, as numbered in symbol files.
+    fn symbol_bank(&self, addr: u16) -> u16 {
+        let bus = self.gb.bus();
+
+        match addr {
+            0x4000..=0x7FFF => bus.rom_nn as u16,
+            0xA000..=0xBFFF => bus.ram_nn as u16,
+            0xD000..=0xDFFF => bus.wram_nn as u16,
+            _ => 0,
+        }
+    }
+}
+
+/// Returns whether the audio queue has no room left for another stereo sample.
+fn audio_queue_full(sink: &ArrayQueue<i16>) -> bool {
+    sink.capacity() - sink.len() < 2
 }
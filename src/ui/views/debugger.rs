@@ -1,3 +1,4 @@
+use gib_core::io::IrqFlags;
 use imgui::{im_str, ChildWindow, CollapsingHeader, Condition, Ui, Window};
 
 use crate::ui::{state::EmuState, utils};
@@ -13,7 +14,9 @@ impl DebuggerView {
 }
 
 impl DebuggerView {
-    fn draw_cpu_state(&mut self, ui: &Ui, state: &EmuState) {
+    fn draw_cpu_state(&mut self, ui: &Ui, state: &mut EmuState) {
+        // Registers can only be edited while the emulation is paused
+        let editable = state.paused();
         let cpu = state.cpu();
 
         ui.text(format!(
@@ -21,6 +24,16 @@ impl DebuggerView {
             state.gameboy().clock_cycles()
         ));
 
+        ui.same_line_with_spacing(0.0, 20.0);
+        ui.text(format!(
+            "LY: {:3} Dot: {:3}",
+            state.bus().ppu.ly(),
+            state.bus().ppu.dot()
+        ));
+
+        ui.same_line_with_spacing(0.0, 20.0);
+        ui.text(format!("Step: {:<16}", cpu.micro_state().to_string()));
+
         if *cpu.halted.value() {
             ui.same_line_with_spacing(0.0, 20.0);
             ui.text_colored(utils::RED, "HALT");
@@ -33,54 +46,137 @@ impl DebuggerView {
 
         ui.separator();
 
-        utils::input_addr(ui, "AF", &mut Some(cpu.af), false);
-        ui.same_line(0.0);
-        utils::input_addr(ui, "BC", &mut Some(cpu.bc), false);
+        self.draw_registers(ui, state, editable);
+
+        ui.same_line_with_spacing(0.0, 20.0);
+
+        if let Some(ref evt) = state.last_event() {
+            ui.text_colored(utils::RED, evt.to_string());
+        } else {
+            ui.text("");
+        }
+    }
+
+    /// Draws the CPU registers and flags, applying any change made to them if `editable`.
+    fn draw_registers(&mut self, ui: &Ui, state: &mut EmuState, editable: bool) {
+        let cpu = state.cpu();
+        let mut regs = [
+            ("AF", Some(cpu.af)),
+            ("BC", Some(cpu.bc)),
+            ("DE", Some(cpu.de)),
+            ("HL", Some(cpu.hl)),
+            ("SP", Some(cpu.sp)),
+            ("PC", Some(cpu.pc)),
+        ];
+
+        for (i, (name, val)) in regs.iter_mut().enumerate() {
+            if i > 0 {
+                ui.same_line(0.0);
+            }
+            utils::input_addr(ui, name, val, editable);
+        }
+
+        let flags = (cpu.zf(), cpu.sf(), cpu.hc(), cpu.cy());
+        let (mut zf, mut sf, mut hc, mut cy) = flags;
+
+        ui.text("Flags:");
         ui.same_line(0.0);
-        utils::input_addr(ui, "DE", &mut Some(cpu.de), false);
+        ui.checkbox(im_str!("Z"), &mut zf);
         ui.same_line(0.0);
-        utils::input_addr(ui, "HL", &mut Some(cpu.hl), false);
+        ui.checkbox(im_str!("N"), &mut sf);
         ui.same_line(0.0);
-        utils::input_addr(ui, "SP", &mut Some(cpu.sp), false);
+        ui.checkbox(im_str!("H"), &mut hc);
         ui.same_line(0.0);
-        utils::input_addr(ui, "PC", &mut Some(cpu.pc), false);
+        ui.checkbox(im_str!("C"), &mut cy);
 
-        ui.text(format!(
-            "Flags: {} {} {} {}",
-            if cpu.zf() { 'Z' } else { '-' },
-            if cpu.sf() { 'N' } else { '-' },
-            if cpu.hc() { 'H' } else { '-' },
-            if cpu.cy() { 'C' } else { '-' },
-        ));
+        // Otherwise, any change is discarded on the next frame
+        if !editable {
+            return;
+        }
 
-        ui.same_line(150.0);
+        for (name, val) in regs.iter() {
+            let val = match val {
+                Some(val) => *val,
+                None => continue,
+            };
+
+            match *name {
+                // The lower nibble of F always reads back as zero
+                "AF" => state.cpu_mut().af = val & 0xFFF0,
+                "BC" => state.cpu_mut().bc = val,
+                "DE" => state.cpu_mut().de = val,
+                "HL" => state.cpu_mut().hl = val,
+                "SP" => state.cpu_mut().sp = val,
+                _ if val != state.cpu().pc => state.set_pc(val),
+                _ => (),
+            }
+        }
 
-        if let Some(ref evt) = state.last_event() {
-            ui.text_colored(utils::RED, evt.to_string());
-        } else {
-            ui.text("");
+        if (zf, sf, hc, cy) != flags {
+            let cpu = state.cpu_mut();
+
+            cpu.set_zf(zf);
+            cpu.set_sf(sf);
+            cpu.set_hc(hc);
+            cpu.set_cy(cy);
+        }
+    }
+
+    fn draw_interrupts(&mut self, ui: &Ui, state: &EmuState) {
+        const IRQS: [(&str, IrqFlags); 5] = [
+            ("VBL", IrqFlags::VBLANK),
+            ("STAT", IrqFlags::LCD_STAT),
+            ("TIM", IrqFlags::TIMER),
+            ("SER", IrqFlags::SERIAL),
+            ("JOY", IrqFlags::JOYPAD),
+        ];
+
+        let (cpu, bus) = (state.cpu(), state.bus());
+
+        let rows = [
+            ("IE", cpu.enabled_interrupts(bus)),
+            ("IF", cpu.requested_interrupts(bus)),
+            ("IE&IF", cpu.pending_interrupts(bus)),
+        ];
+
+        for (name, flags) in rows.iter() {
+            let flags = flags.unwrap_or_else(|_| IrqFlags::empty());
+
+            ui.text(format!("{:5}", name));
+
+            // Checkboxes are read-only, any change is discarded on the next frame
+            for (irq, flag) in IRQS.iter() {
+                ui.same_line(0.0);
+                ui.checkbox(&im_str!("{}##{}", irq, name), &mut flags.contains(*flag));
+            }
         }
     }
 
     fn draw_call_stack(&mut self, ui: &Ui, state: &EmuState) {
         ChildWindow::new("callstack_frame")
             .size([125.0, 0.0])
+            .horizontal_scrollbar(true)
             .build(ui, || {
                 if CollapsingHeader::new(im_str!("Call Stack"))
                     .default_open(true)
                     .build(ui)
                 {
-                    for (i, addr) in state.cpu().call_stack.iter().rev().enumerate() {
+                    for (i, addr) in state.cpu().call_stack().iter().rev().enumerate() {
                         let c = if i == 0 {
                             utils::WHITE
                         } else {
                             utils::DARK_GREY
                         };
 
-                        ui.text_colored(
-                            c,
-                            im_str!(" {} 0x{:04X}", if i == 0 { '>' } else { ' ' }, addr),
-                        );
+                        let marker = if i == 0 { '>' } else { ' ' };
+
+                        match state.nearest_symbol(*addr) {
+                            Some((label, offset)) => ui.text_colored(
+                                c,
+                                im_str!(" {} 0x{:04X} {}+{}", marker, addr, label, offset),
+                            ),
+                            None => ui.text_colored(c, im_str!(" {} 0x{:04X}", marker, addr)),
+                        }
                     }
                 }
             });
@@ -92,7 +188,7 @@ impl WindowView for DebuggerView {
         let mut open = true;
 
         Window::new(im_str!("Debugger"))
-            .size([390.0, 240.0], Condition::FirstUseEver)
+            .size([390.0, 310.0], Condition::FirstUseEver)
             .position([320.0, 30.0], Condition::FirstUseEver)
             .opened(&mut open)
             .build(ui, || {
@@ -100,6 +196,10 @@ impl WindowView for DebuggerView {
 
                 ui.separator();
 
+                self.draw_interrupts(ui, state);
+
+                ui.separator();
+
                 if ui.button(im_str!("Run"), [0.0, 0.0]) {
                     state.set_running();
                 }
@@ -113,6 +213,21 @@ impl WindowView for DebuggerView {
                 if ui.button(im_str!("Step"), [0.0, 0.0]) {
                     state.set_single_step();
                 }
+                ui.same_line(0.0);
+
+                if ui.button(im_str!("Step over"), [0.0, 0.0]) {
+                    state.step_over();
+                }
+                ui.same_line(0.0);
+
+                if ui.button(im_str!("Step out"), [0.0, 0.0]) {
+                    state.step_out();
+                }
+                ui.same_line(0.0);
+
+                if ui.button(im_str!("Step line"), [0.0, 0.0]) {
+                    state.set_line_step();
+                }
 
                 ui.separator();
 
@@ -2,7 +2,8 @@ use std::{cmp::Ordering, collections::BTreeMap};
 
 use gib_core::{cpu::Immediate, dbg};
 use imgui::{
-    im_str, ChildWindow, Condition, ImString, ListClipper, StyleColor, StyleVar, Ui, Window,
+    im_str, ChildWindow, ComboBox, Condition, ImString, ListClipper, Selectable, StyleColor,
+    StyleVar, Ui, Window,
 };
 
 use crate::ui::{state::EmuState, utils};
@@ -59,20 +60,39 @@ impl DisassemblyView {
                 self.disasm.remove(&addr);
             }
 
-            self.disasm.insert(
+            // Show the label of a memory operand in place of a16, if there's one
+            let mnemonic = match (instr.imm, instr.target) {
+                (Some(Immediate::Imm16(a16)), None) if instr.mnemonic.contains("a16") => {
+                    match state.symbol(a16) {
+                        Some(label) => instr.mnemonic.replace("a16", label),
+                        None => instr.mnemonic.to_string(),
+                    }
+                }
+                _ => instr.mnemonic.to_string(),
+            };
+
+            let mut line = format!(
+                "{:04X}:  {:02X} {:5}    {}",
                 from,
-                ImString::from(format!(
-                    "{:04X}:  {:02X} {:5}    {}",
-                    from,
-                    instr.opcode,
-                    match instr.imm {
-                        Some(Immediate::Imm8(d8)) => format!("{:02X}", d8),
-                        Some(Immediate::Imm16(d16)) => format!("{:04X}", d16),
-                        None => String::new(),
-                    },
-                    instr.mnemonic
-                )),
+                instr.opcode,
+                match instr.imm {
+                    Some(Immediate::Imm8(d8)) => format!("{:02X}", d8),
+                    Some(Immediate::Imm16(d16)) => format!("{:04X}", d16),
+                    None => String::new(),
+                },
+                mnemonic
             );
+            if let Some(target) = instr.target {
+                match state.symbol(target) {
+                    Some(label) => line.push_str(&format!("  -> {}", label)),
+                    None => line.push_str(&format!("  -> 0x{:04X}", target)),
+                }
+            }
+            if let Some(label) = state.symbol(from) {
+                line.push_str(&format!("  <{}>", label));
+            }
+
+            self.disasm.insert(from, ImString::from(line));
             from = next;
         }
     }
@@ -97,7 +117,7 @@ impl DisassemblyView {
 
     fn draw_goto_bar(&mut self, ui: &Ui) -> (bool, bool) {
         let goto_pc;
-        let goto_addr;
+        let mut goto_addr;
 
         utils::input_addr(ui, "", &mut self.goto_addr, true);
         ui.same_line(0.0);
@@ -110,6 +130,20 @@ impl DisassemblyView {
 
         ui.checkbox(im_str!("Follow"), &mut self.follow_pc);
 
+        // Jump to any of the well-known entry points
+        ComboBox::new(im_str!("##entry_points"))
+            .preview_value(im_str!("Entry points..."))
+            .build(ui, || {
+                for (addr, label) in dbg::ENTRY_POINTS.iter() {
+                    let item = ImString::from(format!("{:04X}  {}", addr, label));
+
+                    if Selectable::new(&item).build(ui) {
+                        self.goto_addr = Some(*addr);
+                        goto_addr = true;
+                    }
+                }
+            });
+
         (goto_addr, goto_pc)
     }
 
@@ -178,9 +212,14 @@ impl WindowView for DisassemblyView {
         let mut open = true;
 
         // 99.9% of the time this does nothing, so it's cool
-        // to have it called every draw loop.
+        // to have it called every draw loop. Unless following PC, don't switch
+        // back to its section, or jumping to an address elsewhere would never stick.
+        // An edited PC is always brought into view, though.
         let pc = state.cpu().pc;
-        self.realign_disasm(state, pc);
+        let pc_edited = state.take_pc_edited();
+        if self.follow_pc || pc_edited || self.section.range().contains(&pc) {
+            self.realign_disasm(state, pc);
+        }
 
         Window::new(im_str!("Disassembly"))
             .size([300.0, 650.0], Condition::FirstUseEver)
@@ -191,7 +230,7 @@ impl WindowView for DisassemblyView {
 
                 ui.separator();
 
-                self.draw_disasm_view(ui, state, goto_addr, goto_pc);
+                self.draw_disasm_view(ui, state, goto_addr, goto_pc || pc_edited);
             });
 
         open
@@ -4,6 +4,7 @@ use imgui::Ui;
 pub use memedit::*;
 pub use memmap::*;
 pub use peripherals::*;
+pub use sprites::*;
 
 use super::state::EmuState;
 
@@ -12,6 +13,7 @@ mod disassembly;
 mod memedit;
 mod memmap;
 mod peripherals;
+mod sprites;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum View {
@@ -20,6 +22,7 @@ pub enum View {
     MemEditor,
     MemMap,
     Peripherals,
+    Sprites,
 }
 
 pub trait WindowView {
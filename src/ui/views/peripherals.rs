@@ -134,6 +134,13 @@ impl PeripheralView {
             ui.text("Noise Channel");
             ui.separator();
         }
+
+        ui.spacing();
+
+        ui.text(format!(
+            "Frame sequencer step: {}",
+            apu.frame_sequencer_step()
+        ));
     }
 
     fn draw_timer(&self, ui: &Ui, state: &EmuState) {
@@ -0,0 +1,128 @@
+use imgui::{im_str, ChildWindow, Condition, Ui, Window};
+
+use crate::ui::{state::EmuState, utils};
+
+use super::WindowView;
+
+/// Scale factor of the sprite previews.
+const PREVIEW_SCALE: f32 = 2.0;
+
+pub struct SpriteView;
+
+impl SpriteView {
+    pub fn new() -> SpriteView {
+        SpriteView
+    }
+}
+
+impl WindowView for SpriteView {
+    fn draw(&mut self, ui: &Ui, state: &mut EmuState) -> bool {
+        let mut open = true;
+
+        Window::new(im_str!("Sprites"))
+            .size([420.0, 500.0], Condition::FirstUseEver)
+            .position([720.0, 30.0], Condition::FirstUseEver)
+            .opened(&mut open)
+            .build(ui, || {
+                ui.text_colored(utils::DARK_GREY, "Off-screen");
+                ui.same_line_with_spacing(0.0, 20.0);
+                ui.text_colored(utils::RED, "Dropped by the 10 sprites per line limit");
+                ui.separator();
+
+                ChildWindow::new("sprites_frame").build(ui, || {
+                    for idx in 0..40 {
+                        self.draw_sprite(ui, state, idx);
+                    }
+                });
+            });
+
+        open
+    }
+}
+
+impl SpriteView {
+    /// Draws a preview of sprite `idx`, followed by its decoded attributes.
+    fn draw_sprite(&self, ui: &Ui, state: &EmuState, idx: usize) {
+        let ppu = &state.bus().ppu;
+
+        let sprite = match ppu.sprite(idx) {
+            Some(sprite) => sprite,
+            None => return,
+        };
+
+        // In 8x16 mode, the upper tile is "tile & 0xFE", and the lower one "tile | 0x01"
+        let tiles = if sprite.height == 16 {
+            [sprite.tile & 0xFE, sprite.tile | 0x01]
+        } else {
+            [sprite.tile, sprite.tile]
+        };
+        let tiles = [
+            ppu.tile_pixels(usize::from(tiles[0])).unwrap_or([0; 64]),
+            ppu.tile_pixels(usize::from(tiles[1])).unwrap_or([0; 64]),
+        ];
+
+        let [ox, oy] = ui.cursor_screen_pos();
+        let (w, h) = (8.0 * PREVIEW_SCALE, 16.0 * PREVIEW_SCALE);
+        let draw_list = ui.get_window_draw_list();
+
+        draw_list
+            .add_rect([ox, oy], [ox + w, oy + h], utils::DARK_GREY)
+            .build();
+
+        for y in 0..sprite.height {
+            for x in 0..8 {
+                // Flipping a 8x16 sprite vertically also swaps its two halves
+                let sy = if sprite.flip_y {
+                    sprite.height - 1 - y
+                } else {
+                    y
+                };
+                let sx = if sprite.flip_x { 7 - x } else { x };
+
+                let shade = match tiles[usize::from(sy / 8)][usize::from((sy % 8) * 8 + sx)] {
+                    0 => continue, // Transparent
+                    1 => 0.66,
+                    2 => 0.33,
+                    _ => 0.0,
+                };
+
+                let p1 = [
+                    ox + f32::from(x) * PREVIEW_SCALE,
+                    oy + f32::from(y) * PREVIEW_SCALE,
+                ];
+                let p2 = [p1[0] + PREVIEW_SCALE, p1[1] + PREVIEW_SCALE];
+
+                draw_list
+                    .add_rect(p1, p2, [shade, shade, shade, 1.0])
+                    .filled(true)
+                    .build();
+            }
+        }
+
+        ui.dummy([w, h]);
+        ui.same_line(w + 20.0);
+
+        let color = if sprite.dropped {
+            utils::RED
+        } else if sprite.offscreen {
+            utils::DARK_GREY
+        } else {
+            utils::WHITE
+        };
+
+        ui.text_colored(
+            color,
+            format!(
+                "#{:02}  X:{:3}  Y:{:3}  Tile:{:02X}  OBP{}\n     {} {} {}",
+                idx,
+                sprite.x,
+                sprite.y,
+                sprite.tile,
+                sprite.palette,
+                if sprite.flip_x { "X-FLIP" } else { "      " },
+                if sprite.flip_y { "Y-FLIP" } else { "      " },
+                if sprite.behind_bg { "BEHIND BG" } else { "" },
+            ),
+        );
+    }
+}
@@ -14,17 +14,30 @@ impl RomTest {
     }
 
     pub fn must_run_and_match(&mut self, until: u64, output: &'static [u8]) {
-        let mut vbuf = vec![0xFF; 160 * 144 * 4];
-
         self.gb.load_rom(self.rom).unwrap();
 
         while self.gb.clock_cycles() < until {
             self.gb.step().unwrap();
         }
-        self.gb.rasterize(&mut vbuf[..]);
 
-        if &vbuf[..] != output {
+        if &self.gb.frame()[..] != output {
             panic!("output buffers not matching")
         }
     }
+
+    /// Runs a mooneye-gb test ROM, returning whether it passed.
+    ///
+    /// These ROMs report success by loading the Fibonacci numbers 3, 5, 8, 13, 21, 34
+    /// into registers B, C, D, E, H and L, which is checked independently of the screen output.
+    #[allow(dead_code)]
+    pub fn passes_mooneye(&mut self, until: u64) -> bool {
+        self.gb.load_rom(self.rom).unwrap();
+
+        while self.gb.clock_cycles() < until {
+            self.gb.step().unwrap();
+        }
+
+        let cpu = self.gb.cpu();
+        [cpu.b(), cpu.c(), cpu.d(), cpu.e(), cpu.h(), cpu.l()] == [3, 5, 8, 13, 21, 34]
+    }
 }
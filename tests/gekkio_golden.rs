@@ -456,3 +456,74 @@ fn passes_gekkio_acceptance_timer_tma_write_reloading() {
         include_bytes!("gekkio/acceptance/timer/tma_write_reloading.bin"),
     );
 }
+
+/*
+ * Gekkio's timer acceptance tests, checked through the register fingerprint
+ * rather than the screen output, to report exactly which ones fail
+ */
+
+#[test]
+fn passes_gekkio_acceptance_timer_fingerprints() {
+    const ROMS: &[(&str, &[u8])] = &[
+        (
+            "div_write",
+            include_bytes!("../roms/gekkio/acceptance/timer/div_write.gb"),
+        ),
+        (
+            "rapid_toggle",
+            include_bytes!("../roms/gekkio/acceptance/timer/rapid_toggle.gb"),
+        ),
+        (
+            "tim00",
+            include_bytes!("../roms/gekkio/acceptance/timer/tim00.gb"),
+        ),
+        (
+            "tim00_div_trigger",
+            include_bytes!("../roms/gekkio/acceptance/timer/tim00_div_trigger.gb"),
+        ),
+        (
+            "tim01",
+            include_bytes!("../roms/gekkio/acceptance/timer/tim01.gb"),
+        ),
+        (
+            "tim01_div_trigger",
+            include_bytes!("../roms/gekkio/acceptance/timer/tim01_div_trigger.gb"),
+        ),
+        (
+            "tim10",
+            include_bytes!("../roms/gekkio/acceptance/timer/tim10.gb"),
+        ),
+        (
+            "tim10_div_trigger",
+            include_bytes!("../roms/gekkio/acceptance/timer/tim10_div_trigger.gb"),
+        ),
+        (
+            "tim11",
+            include_bytes!("../roms/gekkio/acceptance/timer/tim11.gb"),
+        ),
+        (
+            "tim11_div_trigger",
+            include_bytes!("../roms/gekkio/acceptance/timer/tim11_div_trigger.gb"),
+        ),
+        (
+            "tima_reload",
+            include_bytes!("../roms/gekkio/acceptance/timer/tima_reload.gb"),
+        ),
+        (
+            "tima_write_reloading",
+            include_bytes!("../roms/gekkio/acceptance/timer/tima_write_reloading.gb"),
+        ),
+        (
+            "tma_write_reloading",
+            include_bytes!("../roms/gekkio/acceptance/timer/tma_write_reloading.gb"),
+        ),
+    ];
+
+    let failed: Vec<&str> = ROMS
+        .iter()
+        .filter(|(_, rom)| !RomTest::new(rom).passes_mooneye(4_000_000u64))
+        .map(|(name, _)| *name)
+        .collect();
+
+    assert!(failed.is_empty(), "failed timer tests: {:?}", failed);
+}